@@ -0,0 +1,15 @@
+//! optimized programs print as source that parses again, and run the same
+//! as they did before optimizing, up to brainfuck_compiler::fuzz::FUEL
+//! instructions
+
+#![no_main]
+
+use brainfuck_compiler::fuzz::{check_optimize, check_reparse};
+use brainfuck_compiler::parser::AstNode;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|case: (AstNode, Vec<u8>)| {
+    let (ast, input) = case;
+    check_reparse(&ast);
+    check_optimize(&ast, &input);
+});
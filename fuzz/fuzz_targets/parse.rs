@@ -0,0 +1,10 @@
+//! the parser returns an error for any token stream it can't parse, and
+//! never panics
+
+#![no_main]
+
+use brainfuck_compiler::fuzz::check_parse;
+use brainfuck_compiler::lexer::Token;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|tokens: Vec<Token>| check_parse(&tokens));
@@ -0,0 +1,166 @@
+//! what a loop does on each iteration, worked out from its body alone. the
+//! optimizer checks this before rewriting a loop, so a rewrite only happens
+//! when it runs the same however often the loop does, and `bfc emit --loops`
+//! shows it next to every loop of the tree
+
+use crate::parser::AstNode;
+
+use std::fmt;
+
+// one iteration of a loop, relative to the cell it tests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopClass {
+    // how far the pointer ends up from where the iteration started. None
+    // when that depends on the tape, e.g. through a scan or an inner loop
+    // that doesn't return to its own cell
+    pub shift: Option<isize>,
+    // what an iteration adds to the tested cell, wrapping. None when that
+    // isn't fixed: the loop moves on to other cells, or reads input into,
+    // sets or clears the cell, or runs an inner loop that could change it
+    pub counter: Option<i32>,
+    // only + - < > and the Add, Sub and Move they fold into, so no I/O,
+    // inner loops, calls or other optimized instructions
+    pub simple: bool,
+}
+
+impl LoopClass {
+    pub fn of(body: &[AstNode]) -> Self {
+        let mut offset = Some(0isize);
+        let mut counter = Some(0i32);
+        let mut simple = true;
+        for node in body {
+            let delta = match node {
+                AstNode::Increment => 1,
+                AstNode::Decrement => -1,
+                AstNode::Add(n) => *n as i32,
+                AstNode::Sub(n) => (*n as i32).wrapping_neg(),
+                AstNode::MoveRight => {
+                    offset = offset.map(|offset| offset + 1);
+                    continue;
+                }
+                AstNode::MoveLeft => {
+                    offset = offset.map(|offset| offset - 1);
+                    continue;
+                }
+                AstNode::Move(n) => {
+                    offset = offset.map(|offset| offset + n);
+                    continue;
+                }
+                AstNode::Output | AstNode::Debug => {
+                    simple = false;
+                    continue;
+                }
+                AstNode::Comment(_) | AstNode::ProcDef(_) => continue,
+                AstNode::Input | AstNode::Set(_) => {
+                    simple = false;
+                    // unless it's known to be another cell
+                    if !matches!(offset, Some(offset) if offset != 0) {
+                        counter = None;
+                    }
+                    continue;
+                }
+                // these may write to any cell they reach, the counter too
+                AstNode::Loop(inner) => {
+                    simple = false;
+                    counter = None;
+                    if LoopClass::of(inner).shift != Some(0) {
+                        offset = None;
+                    }
+                    continue;
+                }
+                AstNode::MulCopy { .. } | AstNode::Swap { .. } => {
+                    simple = false;
+                    counter = None;
+                    continue;
+                }
+                AstNode::ScanRight(_) | AstNode::ScanLeft(_) | AstNode::ProcCall | AstNode::Program(_) => {
+                    simple = false;
+                    counter = None;
+                    offset = None;
+                    continue;
+                }
+            };
+            match offset {
+                Some(0) => counter = counter.map(|counter| counter.wrapping_add(delta)),
+                Some(_) => {}
+                None => counter = None,
+            }
+        }
+        if offset != Some(0) {
+            counter = None;
+        }
+        LoopClass { shift: offset, counter, simple }
+    }
+
+    // comes back to the cell it tests at the end of every iteration
+    pub fn is_balanced(&self) -> bool {
+        self.shift == Some(0)
+    }
+
+    // runs exactly as many times as the tested cell's value when it starts,
+    // taking 1 from it each iteration with nothing else changing it
+    pub fn is_counted(&self) -> bool {
+        self.is_balanced() && self.counter == Some(-1)
+    }
+}
+
+// e.g. "balanced, counter -1, simple" or "shifts by 2"
+impl fmt::Display for LoopClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.shift {
+            Some(0) => write!(f, "balanced")?,
+            Some(shift) => write!(f, "shifts by {}", shift)?,
+            None => write!(f, "unbalanced")?,
+        }
+        if let Some(counter) = self.counter {
+            write!(f, ", counter {:+}", counter)?;
+        }
+        if self.simple {
+            write!(f, ", simple")?;
+        }
+        Ok(())
+    }
+}
+
+// AstNode::tree with each loop's class after it, e.g. "Loop (balanced,
+// counter -1, simple)"
+pub fn annotated_tree(ast: &AstNode) -> String {
+    ast.tree_with(&|body| format!("({})", LoopClass::of(body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn class(source: &str) -> LoopClass {
+        let ast = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+        let AstNode::Program(instructions) = ast else { unreachable!() };
+        let [AstNode::Loop(body)] = instructions.as_slice() else { panic!("expected a single loop") };
+        LoopClass::of(body)
+    }
+
+    #[test]
+    fn test_loop_class() {
+        assert_eq!(class("[->++<]"), LoopClass { shift: Some(0), counter: Some(-1), simple: true });
+        assert!(class("[->++<]").is_counted());
+        // the counter goes up, so it runs 256 - cell times
+        assert_eq!(class("[+>-<]").counter, Some(1));
+        assert_eq!(class("[->+]"), LoopClass { shift: Some(1), counter: None, simple: true });
+        // the inner loop could clear the counter at any time
+        assert_eq!(class("[->[-]<]"), LoopClass { shift: Some(0), counter: None, simple: false });
+        assert_eq!(class("[-.>[>]<]"), LoopClass { shift: None, counter: None, simple: false });
+        // input into another cell leaves the counter alone
+        assert_eq!(class("[->,<]"), LoopClass { shift: Some(0), counter: Some(-1), simple: false });
+        assert_eq!(class("[,]").counter, None);
+    }
+
+    #[test]
+    fn test_annotated_tree() {
+        let ast = crate::parser::parse(crate::lexer::tokenize("+[->+<[>]]").unwrap()).unwrap();
+        assert_eq!(
+            annotated_tree(&ast),
+            "Program\n  Increment\n  Loop (unbalanced)\n    Decrement\n    MoveRight\n    Increment\n    MoveLeft\n    Loop (shifts by 1, simple)\n      MoveRight\n"
+        );
+        assert_eq!(LoopClass::of(&[AstNode::Sub(1)]).to_string(), "balanced, counter -1, simple");
+    }
+}
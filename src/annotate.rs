@@ -0,0 +1,192 @@
+//! the program's source annotated with how often each command ran, written
+//! by bfc --annotate as text or HTML
+
+use crate::lexer::Span;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// width of the column with the busiest command of each line
+const COUNT_WIDTH: usize = 10;
+
+const HTML_HEAD: &str = "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>bfc profile</title>
+<style>
+.count { color: #888; }
+.h0 { color: #aaa; }
+.h1 { background: #fff7d6; }
+.h2 { background: #ffeab0; }
+.h3 { background: #ffdc8a; }
+.h4 { background: #ffc766; }
+.h5 { background: #ffad4d; }
+.h6 { background: #ff9040; }
+.h7 { background: #f86f38; }
+.h8 { background: #ea4a30; color: #fff; }
+.h9 { background: #c8242a; color: #fff; }
+</style>
+</head>
+<body>
+<pre>
+";
+
+pub struct Annotation<'a> {
+    source: &'a str,
+    counts: HashMap<usize, usize>, // byte offset of a command -> times it ran
+}
+
+impl<'a> Annotation<'a> {
+    // `spans` are those of the program's commands and `counts` how often
+    // each was reached, both indexed by position. commands without a count
+    // never ran
+    pub fn new(source: &'a str, spans: &[Span], counts: &[usize]) -> Self {
+        let counts = spans
+            .iter()
+            .enumerate()
+            .map(|(position, span)| (span.offset, counts.get(position).copied().unwrap_or(0)))
+            .collect();
+        Annotation { source, counts }
+    }
+
+    // each line without its line break, with the byte offset it starts at
+    fn lines(&self) -> Vec<(usize, &'a str)> {
+        let mut start = 0;
+        let mut lines = Vec::new();
+        for line in self.source.split_inclusive('\n') {
+            lines.push((start, line.trim_end_matches(['\n', '\r'])));
+            start += line.len();
+        }
+        lines
+    }
+
+    // the count of every character of a line, None for those that aren't commands
+    fn line_counts(&self, start: usize, line: &str) -> Vec<(char, Option<usize>)> {
+        line.char_indices().map(|(i, ch)| (ch, self.counts.get(&(start + i)).copied())).collect()
+    }
+
+    // every line with the count of its busiest command, and under lines with
+    // commands a row with the magnitude of each command's count
+    pub fn to_text(&self) -> String {
+        let mut text = String::from("# the digit under a command is how many digits its count has, . means it never ran\n");
+        for (start, line) in self.lines() {
+            let counts = self.line_counts(start, line);
+            let Some(most) = counts.iter().filter_map(|&(_, count)| count).max() else {
+                writeln!(text, "{:>width$}  {}", "", line, width = COUNT_WIDTH).unwrap();
+                continue;
+            };
+            writeln!(text, "{:>width$}  {}", most, line, width = COUNT_WIDTH).unwrap();
+            let marks: String = counts
+                .iter()
+                .map(|&(ch, count)| match count {
+                    Some(0) => '.',
+                    Some(count) => char::from_digit(count.to_string().len().min(9) as u32, 10).unwrap(),
+                    // keeps the marks lined up with tab-indented commands
+                    None if ch == '\t' => '\t',
+                    None => ' ',
+                })
+                .collect();
+            writeln!(text, "{:>width$}  {}", "", marks.trim_end(), width = COUNT_WIDTH).unwrap();
+        }
+        text
+    }
+
+    // a page with every command shaded by how often it ran, on a log scale
+    // up to the busiest command, with its exact count on hover
+    pub fn to_html(&self) -> String {
+        let most = self.counts.values().copied().max().unwrap_or(0);
+        let mut html = String::from(HTML_HEAD);
+        for (start, line) in self.lines() {
+            let counts = self.line_counts(start, line);
+            match counts.iter().filter_map(|&(_, count)| count).max() {
+                Some(line_most) => write!(html, "<span class=\"count\">{:>width$}</span>  ", line_most, width = COUNT_WIDTH),
+                None => write!(html, "{:>width$}  ", "", width = COUNT_WIDTH),
+            }
+            .unwrap();
+            for (ch, count) in counts {
+                match count {
+                    Some(count) => {
+                        write!(html, "<span class=\"h{}\" title=\"{}\">", heat(count, most), count).unwrap();
+                        push_escaped(&mut html, ch);
+                        html.push_str("</span>");
+                    }
+                    None => push_escaped(&mut html, ch),
+                }
+            }
+            html.push('\n');
+        }
+        html.push_str("</pre>\n</body>\n</html>\n");
+        html
+    }
+}
+
+// 0 for a command that never ran, otherwise 1 to 9 by the log of its count
+// relative to `most`
+fn heat(count: usize, most: usize) -> usize {
+    match count {
+        0 => 0,
+        _ if most <= 1 => 9,
+        _ => 1 + (8.0 * (count as f64).ln() / (most as f64).ln()).round() as usize,
+    }
+}
+
+fn push_escaped(html: &mut String, ch: char) {
+    match ch {
+        '&' => html.push_str("&amp;"),
+        '<' => html.push_str("&lt;"),
+        '>' => html.push_str("&gt;"),
+        '"' => html.push_str("&quot;"),
+        _ => html.push(ch),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::lexer::tokenize_spanned;
+
+    fn annotate(source: &str) -> (Vec<Span>, Vec<usize>) {
+        let tokens = tokenize_spanned(source).unwrap();
+        let spans = tokens.iter().map(|token| token.span).collect();
+        let program = crate::parser::parse(tokens.into_iter().map(|token| token.node).collect()).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_count_commands(true);
+        interpreter.run_and_capture_output(&program).unwrap();
+        (spans, interpreter.command_counts().to_vec())
+    }
+
+    #[test]
+    fn test_text() {
+        let source = "set ten ++++++++++\n[loop\n\t-]\n[never >]\n";
+        let (spans, counts) = annotate(source);
+        let text = Annotation::new(source, &spans, &counts).to_text();
+        assert_eq!(
+            text.lines().skip(1).collect::<Vec<_>>(),
+            [
+                "         1  set ten ++++++++++",
+                "                    1111111111",
+                "         1  [loop",
+                "            1",
+                "        10  \t-]",
+                "            \t22",
+                "         1  [never >]",
+                "            1      ..",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_html() {
+        let source = "\"a & b\" +[-]";
+        let (spans, counts) = annotate(source);
+        let html = Annotation::new(source, &spans, &counts).to_html();
+        // comments are escaped, and the busiest commands are the hottest
+        assert!(html.contains(
+            "<span class=\"count\">         1</span>  &quot;a &amp; b&quot; <span class=\"h9\" title=\"1\">+</span>\
+             <span class=\"h9\" title=\"1\">[</span><span class=\"h9\" title=\"1\">-</span>\
+             <span class=\"h9\" title=\"1\">]</span>\n"
+        ));
+    }
+}
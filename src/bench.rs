@@ -0,0 +1,174 @@
+//! times repeated runs of a program on one or more backends, for bfc bench
+
+use crate::engine::Engine;
+use crate::interpreter::{Interpreter, RuntimeError};
+use crate::io::MemoryIo;
+use crate::parser::AstNode;
+
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+// the ways bench can execute a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Interpreter, // the tree engine as bfc --engine tree runs it
+    Optimized,   // the tree engine with the optimizer on
+    Vm,          // the bytecode vm, what bfc runs programs on by default
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Interpreter => "interpreter",
+            Backend::Optimized => "optimized",
+            Backend::Vm => "vm",
+            #[cfg(feature = "jit")]
+            Backend::Jit => "jit",
+        }
+    }
+
+    // every backend this build has, in the order bench --compare lists them
+    pub fn all() -> Vec<Backend> {
+        vec![
+            Backend::Interpreter,
+            Backend::Optimized,
+            Backend::Vm,
+            #[cfg(feature = "jit")]
+            Backend::Jit,
+        ]
+    }
+
+    // a fresh engine that reads `input` and throws its output away
+    fn engine(self, input: &[u8]) -> Box<dyn Engine> {
+        match self {
+            Backend::Interpreter | Backend::Optimized => {
+                let mut interpreter = Interpreter::new();
+                interpreter.set_optimize(self == Backend::Optimized);
+                interpreter.set_io(Some(Box::new(MemoryIo::new(input))));
+                Box::new(interpreter)
+            }
+            Backend::Vm => {
+                let mut vm = crate::vm::Vm::new();
+                vm.set_io(Some(Box::new(MemoryIo::new(input))));
+                Box::new(vm)
+            }
+            #[cfg(feature = "jit")]
+            Backend::Jit => {
+                let mut jit = crate::jit::JitEngine::new();
+                jit.provide_input(input);
+                jit.set_output_sink(Some(Box::new(std::io::sink())));
+                Box::new(jit)
+            }
+        }
+    }
+}
+
+// the wall time of each measured run of one backend
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    pub backend: Backend,
+    pub times: Vec<Duration>,
+}
+
+impl BenchResult {
+    pub fn min(&self) -> Duration {
+        self.times.iter().copied().min().unwrap_or_default()
+    }
+
+    pub fn max(&self) -> Duration {
+        self.times.iter().copied().max().unwrap_or_default()
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.times.len() {
+            0 => Duration::ZERO,
+            n => self.times.iter().sum::<Duration>() / n as u32,
+        }
+    }
+}
+
+// how many instructions the unoptimized interpreter executes for `ast`. the
+// table reports speed in these, so every backend is measured against the
+// same amount of work whatever it compiles the program into
+pub fn count_instructions(ast: &AstNode, input: &[u8]) -> Result<usize, RuntimeError> {
+    let mut engine = Backend::Interpreter.engine(input);
+    engine.run(ast)?;
+    Ok(engine.instruction_count())
+}
+
+// runs `ast` `warmup` times untimed, then `iterations` times timed, each run
+// on a fresh engine
+pub fn bench(ast: &AstNode, backend: Backend, input: &[u8], warmup: usize, iterations: usize) -> Result<BenchResult, RuntimeError> {
+    let mut times = Vec::with_capacity(iterations);
+    for i in 0..warmup + iterations {
+        let mut engine = backend.engine(input);
+        let start = Instant::now();
+        engine.run(ast)?;
+        let elapsed = start.elapsed();
+        if i >= warmup {
+            times.push(elapsed);
+        }
+    }
+    Ok(BenchResult { backend, times })
+}
+
+// one row per backend, e.g.
+//
+// backend             min       mean        max    instructions/s
+// interpreter     2.114ms    2.203ms    2.541ms          41237855
+pub fn render_table(results: &[BenchResult], instructions: usize) -> String {
+    let millis = |duration: Duration| format!("{:.3}ms", duration.as_secs_f64() * 1000.0);
+    let mut table = format!("{:<12} {:>10} {:>10} {:>10} {:>17}\n", "backend", "min", "mean", "max", "instructions/s");
+    for result in results {
+        let mean = result.mean().as_secs_f64();
+        let speed = if mean > 0.0 { format!("{:.0}", instructions as f64 / mean) } else { "-".to_string() };
+        writeln!(
+            table,
+            "{:<12} {:>10} {:>10} {:>10} {:>17}",
+            result.backend.name(),
+            millis(result.min()),
+            millis(result.mean()),
+            millis(result.max()),
+            speed
+        )
+        .unwrap();
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(source: &str) -> AstNode {
+        crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_bench() {
+        let ast = program(",[>+<-]>.");
+        assert_eq!(count_instructions(&ast, b"\x03").unwrap(), 16);
+        for backend in Backend::all() {
+            let result = bench(&ast, backend, b"\x03", 2, 3).unwrap();
+            assert_eq!(result.times.len(), 3);
+            assert!(result.min() <= result.mean() && result.mean() <= result.max());
+        }
+        // errors come back instead of a result
+        assert!(bench(&program("<"), Backend::Optimized, b"", 0, 1).is_err());
+    }
+
+    #[test]
+    fn test_render_table() {
+        let result = BenchResult {
+            backend: Backend::Interpreter,
+            times: vec![Duration::from_millis(1), Duration::from_millis(3)],
+        };
+        assert_eq!(
+            render_table(&[result], 1000),
+            "backend             min       mean        max    instructions/s\n\
+             interpreter     1.000ms    2.000ms    3.000ms            500000\n"
+        );
+    }
+}
@@ -1,22 +1,91 @@
+pub mod js;
+pub mod llvm;
+pub mod wasm;
+
+use crate::interpreter::{Interpreter, DEBUG_DUMP_CELLS};
 use crate::parser::AstNode;
 
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+// instructions a precomputed program may run at generation time before the
+// generator gives up and translates it instead, see set_precompute
+pub const PRECOMPUTE_FUEL: usize = 100_000_000;
+// and the most output it bakes into the executable
+pub const PRECOMPUTE_MAX_OUTPUT: usize = 1 << 20;
+
+// how generated code is laid out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CodeStyle {
+    Pretty,  // indented by loop depth
+    Compact, // one statement per line, no indentation or blank lines
+}
+
 pub struct CodeGenerator {
     indentation: usize,
+    style: CodeStyle,
+    dump_tape: bool, // see set_dump_tape
+    precompute: bool, // see set_precompute
+    writes_output: bool, // whether the generated main has its `output` writer
+}
+
+impl Default for CodeGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CodeGenerator {
     pub fn new() -> Self {
         CodeGenerator {
-            indentation: 0
+            indentation: 0,
+            style: CodeStyle::Pretty,
+            dump_tape: false,
+            precompute: false,
+            writes_output: false,
         }
     }
 
+    pub fn set_style(&mut self, style: CodeStyle) {
+        self.style = style;
+    }
+
+    // when set, the program ends by writing the pointer on a line of its own
+    // and then the whole tape as raw bytes to stderr, so a test can compare
+    // where it left off with another engine
+    pub fn set_dump_tape(&mut self, dump_tape: bool) {
+        self.dump_tape = dump_tape;
+    }
+
+    // when set, a program that never reads input is run here, and the code
+    // only writes what it printed. programs that take longer than
+    // PRECOMPUTE_FUEL instructions, print more than PRECOMPUTE_MAX_OUTPUT
+    // bytes, leave the tape or use # are translated as usual
+    pub fn set_precompute(&mut self, precompute: bool) {
+        self.precompute = precompute;
+    }
+
     pub fn generate(&mut self, ast: &AstNode) -> String {
-        let mut code = String::from(
-            "fn main() {\n\
-             let mut memory = vec![0u8; 30000];\n\
-             let mut pointer = 0;\n\n"
-        );
+        if self.precompute {
+            if let Some(code) = self.generate_precomputed(ast) {
+                return code;
+            }
+        }
+        // programs that never move or write leave memory/pointer unmutated
+        let mut code = String::from("#[allow(unused_mut)]\nfn main() {\n");
+        self.indentation = 1;
+        code.push_str(&self.line("let mut memory = vec![0u8; 30000];"));
+        code.push_str(&self.line("let mut pointer = 0;"));
+        // stdout is locked once and written through a buffer, which is
+        // flushed before , waits for input and at the end
+        self.writes_output = ast.contains(&|node| matches!(node, AstNode::Output));
+        if self.writes_output {
+            code.push_str(&self.line("let mut output = std::io::BufWriter::new(std::io::stdout().lock());"));
+        }
+        if self.style == CodeStyle::Pretty {
+            code.push('\n');
+        }
 
         match ast {
             AstNode::Program(instructions) => {
@@ -27,32 +96,187 @@ impl CodeGenerator {
             _ => panic!("Expected program node"),
         }
 
+        if self.writes_output {
+            code.push_str(&self.line("std::io::Write::flush(&mut output).unwrap();"));
+        }
+        if self.dump_tape {
+            code.push_str(&self.line("eprintln!(\"{}\", pointer);"));
+            code.push_str(&self.line("std::io::Write::write_all(&mut std::io::stderr(), &memory).unwrap();"));
+        }
         code.push_str("}\n");
         code
     }
 
+    // a Cargo project in the directory `path`, created if needed: a Cargo.toml
+    // and the generated code as src/main.rs, which `cargo build --release`
+    // builds as is. the package is named after the directory. fails without
+    // writing anything when either file is already there
+    pub fn generate_project(&mut self, ast: &AstNode, path: &Path) -> io::Result<()> {
+        let (manifest_path, main_path) = (path.join("Cargo.toml"), path.join("src").join("main.rs"));
+        if let Some(existing) = [&manifest_path, &main_path].into_iter().find(|file| file.exists()) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", existing.display())));
+        }
+        let code = self.generate(ast);
+        fs::create_dir_all(path.join("src"))?;
+        let manifest = format!(
+            "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             # a workspace of its own, so it builds inside another project's directory too\n[workspace]\n",
+            package_name(path),
+        );
+        // create_new, in case either appeared in the meantime
+        let write = |file: &Path, contents: &str| fs::File::create_new(file)?.write_all(contents.as_bytes());
+        write(&manifest_path, &manifest)?;
+        write(&main_path, &code)
+    }
+
+    // the program's output as a single write, and with set_dump_tape the
+    // tape and pointer it ended with
+    fn generate_precomputed(&mut self, ast: &AstNode) -> Option<String> {
+        if ast.contains(&|node| matches!(node, AstNode::Input | AstNode::Debug | AstNode::ProcDef(_) | AstNode::ProcCall)) {
+            return None;
+        }
+        let mut interpreter = Interpreter::new();
+        interpreter.set_optimize(true);
+        interpreter.set_max_instructions(Some(PRECOMPUTE_FUEL));
+        interpreter.set_max_output(Some(PRECOMPUTE_MAX_OUTPUT));
+        let (output, memory, pointer) = interpreter.run_and_capture_bytes(ast).ok()?;
+
+        let mut code = String::from("fn main() {\n");
+        self.indentation = 1;
+        if !output.is_empty() {
+            let literal: String = output.iter().flat_map(|&byte| std::ascii::escape_default(byte)).map(char::from).collect();
+            code.push_str(&self.line(&format!("std::io::Write::write_all(&mut std::io::stdout(), b\"{}\").unwrap();", literal)));
+        }
+        if self.dump_tape {
+            code.push_str(&self.line("let mut memory = vec![0u8; 30000];"));
+            for (index, &value) in memory.iter().enumerate().filter(|(_, &value)| value != 0) {
+                code.push_str(&self.line(&format!("memory[{}] = {};", index, value)));
+            }
+            code.push_str(&self.line(&format!("eprintln!(\"{{}}\", {});", pointer)));
+            code.push_str(&self.line("std::io::Write::write_all(&mut std::io::stderr(), &memory).unwrap();"));
+        }
+        code.push_str("}\n");
+        Some(code)
+    }
+
+    // writes out what's buffered, when there's a buffer
+    fn flush(&self) -> String {
+        if self.writes_output {
+            self.line("std::io::Write::flush(&mut output).unwrap();")
+        } else {
+            String::new()
+        }
+    }
+
+    // a single statement at the current indentation
+    fn line(&self, statement: &str) -> String {
+        match self.style {
+            CodeStyle::Pretty => format!("{}{}\n", "    ".repeat(self.indentation), statement),
+            CodeStyle::Compact => format!("{}\n", statement),
+        }
+    }
+
     fn generate_instruction(&mut self, instruction: &AstNode) -> String {
         match instruction {
-            AstNode::Increment => "    memory[pointer] = memory[pointer].wrapping_add(1);\n".to_string(),
-            AstNode::Decrement => "    memory[pointer] = memory[pointer].wrapping_sub(1);\n".to_string(),
-            AstNode::MoveRight => "    pointer += 1;\n".to_string(),
-            AstNode::MoveLeft => "    pointer -= 1;\n".to_string(),
-            AstNode::Output => "    print!(\"{}\", memory[pointer] as char);\n".to_string(),
-            AstNode::Input => "    memory[pointer] = std::io::stdin().bytes().next().unwrap().unwrap();\n".to_string(),
+            AstNode::Increment => self.line("memory[pointer] = memory[pointer].wrapping_add(1);"),
+            AstNode::Decrement => self.line("memory[pointer] = memory[pointer].wrapping_sub(1);"),
+            AstNode::MoveRight => self.line("pointer += 1;"),
+            AstNode::MoveLeft => self.line("pointer -= 1;"),
+            // cells are u8, so only the amount modulo 256 matters
+            AstNode::Add(n) => self.line(&format!("memory[pointer] = memory[pointer].wrapping_add({});", n % 256)),
+            AstNode::Sub(n) => self.line(&format!("memory[pointer] = memory[pointer].wrapping_sub({});", n % 256)),
+            AstNode::Set(value) => self.line(&format!("memory[pointer] = {};", value)),
+            AstNode::Move(n) if *n < 0 => self.line(&format!("pointer -= {};", -n)),
+            AstNode::Move(n) => self.line(&format!("pointer += {};", n)),
+            AstNode::MulCopy { targets } => {
+                let mut copy_code = self.line("if memory[pointer] != 0 {");
+                self.indentation += 1;
+                for (offset, factor) in targets {
+                    let target = if *offset < 0 {
+                        format!("memory[pointer - {}]", -offset)
+                    } else {
+                        format!("memory[pointer + {}]", offset)
+                    };
+                    copy_code.push_str(&self.line(&format!(
+                        "{} = {}.wrapping_add(memory[pointer].wrapping_mul({}));",
+                        target, target, *factor as u8
+                    )));
+                }
+                copy_code.push_str(&self.line("memory[pointer] = 0;"));
+                self.indentation -= 1;
+                copy_code.push_str(&self.line("}"));
+                copy_code
+            },
+            AstNode::Swap { offset, temp } => {
+                let cell = |offset: isize| if offset < 0 {
+                    format!("memory[pointer - {}]", -offset)
+                } else {
+                    format!("memory[pointer + {}]", offset)
+                };
+                let (other, temp) = (cell(*offset), cell(*temp));
+                let mut swap_code = self.line("{");
+                self.indentation += 1;
+                swap_code.push_str(&self.line("let value = memory[pointer];"));
+                swap_code.push_str(&self.line(&format!("memory[pointer] = {};", other)));
+                swap_code.push_str(&self.line(&format!("{} = {}.wrapping_add(value);", other, temp)));
+                swap_code.push_str(&self.line(&format!("{} = 0;", temp)));
+                self.indentation -= 1;
+                swap_code.push_str(&self.line("}"));
+                swap_code
+            },
+            AstNode::ScanRight(stride) => self.line(&format!("while memory[pointer] != 0 {{ pointer += {}; }}", stride)),
+            AstNode::ScanLeft(stride) => self.line(&format!("while memory[pointer] != 0 {{ pointer -= {}; }}", stride)),
+            AstNode::Output => self.line("std::io::Write::write_all(&mut output, &[memory[pointer]]).unwrap();"),
+            // the cell is left unchanged at end of input
+            AstNode::Input => self.flush()
+                + &self.line("if let Some(Ok(byte)) = std::io::Read::bytes(std::io::stdin()).next() { memory[pointer] = byte; }"),
             AstNode::Loop(instructions) => {
-                let mut loop_code = String::from("    while memory[pointer] != 0 {\n");
+                let mut loop_code = self.line("while memory[pointer] != 0 {");
                 self.indentation += 1;
                 for instruction in instructions {
                     loop_code.push_str(&self.generate_instruction(instruction));
                 }
                 self.indentation -= 1;
-                loop_code.push_str("    }\n");
+                loop_code.push_str(&self.line("}"));
                 loop_code
             },
-            _ => String::new(),
+            // carry the program's own documentation over as line comments
+            AstNode::Comment(text) => text
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(|line| self.line(&format!("// {}", line)))
+                .collect(),
+            // after the output so far, which may be on the same terminal
+            AstNode::Debug => self.flush() + &self.line(&format!(
+                "eprintln!(\"pointer {{}}: {{:?}}\", pointer, &memory[..{}]);",
+                DEBUG_DUMP_CELLS,
+            )),
+            AstNode::ProcDef(_) | AstNode::ProcCall => panic!("pbrain procedures are not supported"),
+            AstNode::Program(_) => panic!("Unexpected nested program node"),
         }
     }
 }
+
+// the directory's name as Cargo accepts it, e.g. "hello-world" for
+// "Hello World", or "program" when nothing of it is left
+fn package_name(path: &Path) -> String {
+    let name = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let name: String = name
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '-' })
+        .collect();
+    let name = name.trim_matches('-');
+    match name.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => name.to_string(),
+        Some(_) => format!("program-{}", name),
+        None => "program".to_string(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -90,4 +314,128 @@ mod tests {
         assert!(code.contains("wrapping_add(1)"));
         assert!(code.contains("wrapping_sub(1)"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_optimized_program() {
+        use crate::optimizer::Optimizer;
+
+        // +++>>[-]<<-- after optimization is Add, Move, Set, Move, Sub
+        let program = AstNode::Program(vec![
+            AstNode::Increment,
+            AstNode::Increment,
+            AstNode::Increment,
+            AstNode::MoveRight,
+            AstNode::MoveRight,
+            AstNode::Loop(vec![AstNode::Decrement]),
+            AstNode::MoveLeft,
+            AstNode::MoveLeft,
+            AstNode::Decrement,
+            AstNode::Decrement,
+        ]);
+        let optimized = Optimizer::new().optimize(&program);
+
+        let code = CodeGenerator::new().generate(&optimized);
+        assert!(code.contains("wrapping_add(3)"));
+        assert!(code.contains("pointer += 2"));
+        assert!(code.contains("memory[pointer] = 0"));
+        assert!(code.contains("pointer -= 2"));
+        assert!(code.contains("wrapping_sub(2)"));
+    }
+
+    #[test]
+    fn test_large_add_wraps() {
+        let program = AstNode::Program(vec![AstNode::Add(300), AstNode::Sub(256)]);
+        let code = CodeGenerator::new().generate(&program);
+        assert!(code.contains("wrapping_add(44)"));
+        assert!(code.contains("wrapping_sub(0)"));
+    }
+
+    #[test]
+    fn test_indentation() {
+        let program = AstNode::Program(vec![
+            AstNode::Loop(vec![AstNode::Loop(vec![AstNode::MoveRight])]),
+        ]);
+
+        let code = CodeGenerator::new().generate(&program);
+        assert!(code.contains("\n    while memory[pointer] != 0 {\n        while memory[pointer] != 0 {\n            pointer += 1;\n        }\n    }\n"));
+
+        let mut generator = CodeGenerator::new();
+        generator.set_style(CodeStyle::Compact);
+        let code = generator.generate(&program);
+        assert!(code.lines().all(|line| !line.starts_with(' ') && !line.is_empty()));
+    }
+
+    #[test]
+    fn test_input() {
+        // no `use` needed, and end of input leaves the cell alone instead of panicking
+        let code = CodeGenerator::new().generate(&AstNode::Program(vec![AstNode::Input]));
+        assert!(code.contains("if let Some(Ok(byte)) = std::io::Read::bytes(std::io::stdin()).next() { memory[pointer] = byte; }"));
+        assert!(!code.contains("output"));
+    }
+
+    #[test]
+    fn test_buffered_output() {
+        // a prompt is written out before , waits, and the rest at the end
+        let program = AstNode::Program(vec![AstNode::Output, AstNode::Input, AstNode::Output]);
+        let code = CodeGenerator::new().generate(&program);
+        assert!(code.contains("    let mut output = std::io::BufWriter::new(std::io::stdout().lock());\n"));
+        assert!(code.contains(
+            "    std::io::Write::write_all(&mut output, &[memory[pointer]]).unwrap();\n    std::io::Write::flush(&mut output).unwrap();\n    if let Some(Ok(byte))"
+        ));
+        assert!(code.ends_with("    std::io::Write::flush(&mut output).unwrap();\n}\n"));
+    }
+
+    #[test]
+    fn test_comments() {
+        let program = AstNode::Program(vec![
+            AstNode::Comment("print a\n\n  then stop ".to_string()),
+            AstNode::Output,
+        ]);
+        let code = CodeGenerator::new().generate(&program);
+        assert!(code.contains("    // print a\n    // then stop\n    std::io::Write::write_all(&mut output"));
+    }
+
+    #[test]
+    fn test_precompute() {
+        let parse = |source: &str| crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+        let mut generator = CodeGenerator::new();
+        generator.set_precompute(true);
+        let code = generator.generate(&parse("++++++++[>++++++++<-]>+.+.>++++++++++."));
+        assert_eq!(code, "fn main() {\n    std::io::Write::write_all(&mut std::io::stdout(), b\"AB\\n\").unwrap();\n}\n");
+
+        // input, and a pointer that leaves the tape, keep the translation
+        assert!(generator.generate(&parse(",.")).contains("std::io::stdin()"));
+        assert!(generator.generate(&parse("+[<]")).contains("while memory[pointer] != 0"));
+    }
+
+    #[test]
+    fn test_generate_project() {
+        let dir = std::env::temp_dir().join(format!("bfc-project-{}", std::process::id())).join("Hello World");
+        CodeGenerator::new().generate_project(&AstNode::Program(vec![AstNode::Output]), &dir).unwrap();
+        let manifest = fs::read_to_string(dir.join("Cargo.toml")).unwrap();
+        let code = fs::read_to_string(dir.join("src/main.rs")).unwrap();
+        let _ = fs::remove_dir_all(dir.parent().unwrap());
+        assert!(manifest.starts_with("[package]\nname = \"hello-world\"\n"));
+        assert!(manifest.contains("\n[workspace]\n"));
+        assert!(code.starts_with("#[allow(unused_mut)]\nfn main() {\n"));
+        assert_eq!(package_name(Path::new("2048")), "program-2048");
+
+        // an existing project is left as it is
+        let dir = std::env::temp_dir().join(format!("bfc-existing-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Cargo.toml"), "[package]\n").unwrap();
+        let err = CodeGenerator::new().generate_project(&AstNode::Program(Vec::new()), &dir).unwrap_err();
+        let manifest = fs::read_to_string(dir.join("Cargo.toml")).unwrap();
+        let wrote_main = dir.join("src").exists();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!((manifest.as_str(), wrote_main), ("[package]\n", false));
+        assert_eq!(package_name(Path::new("/")), "program");
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        let code = CodeGenerator::new().generate(&AstNode::Program(vec![AstNode::Debug]));
+        assert!(code.contains("    eprintln!(\"pointer {}: {:?}\", pointer, &memory[..10]);\n"));
+    }
+}
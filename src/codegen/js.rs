@@ -0,0 +1,177 @@
+//! emits a self-contained JavaScript function that runs the program
+
+use crate::interpreter::DEBUG_DUMP_CELLS;
+use crate::parser::AstNode;
+
+const TAPE_SIZE: usize = 30000;
+
+// builds `function run(input, output)`. input is an array of bytes read by
+// , (the cell is left unchanged once it runs out), output is called with
+// each byte written by . and the tape is returned when the program ends.
+// moving off the tape throws a RangeError
+pub struct JsGenerator {
+    indentation: usize,
+}
+
+impl Default for JsGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JsGenerator {
+    pub fn new() -> Self {
+        JsGenerator {
+            indentation: 0,
+        }
+    }
+
+    pub fn generate(&mut self, ast: &AstNode) -> Result<String, String> {
+        let AstNode::Program(instructions) = ast else {
+            return Err("Expected program node".to_string());
+        };
+
+        let mut code = String::from("function run(input, output) {\n");
+        self.indentation = 1;
+        code.push_str(&self.line(&format!("const tape = new Uint8Array({});", TAPE_SIZE)));
+        code.push_str(&self.line("let pointer = 0;"));
+        code.push_str(&self.line("let position = 0;"));
+        code.push_str(&self.line(&format!(
+            "const checked = (index) => {{ if (index < 0 || index >= {}) throw new RangeError(\"Pointer out of bounds\"); return index; }};",
+            TAPE_SIZE,
+        )));
+        for instruction in instructions {
+            code.push_str(&self.generate_instruction(instruction)?);
+        }
+        code.push_str(&self.line("return tape;"));
+        code.push_str("}\n");
+        Ok(code)
+    }
+
+    fn line(&self, statement: &str) -> String {
+        format!("{}{}\n", "  ".repeat(self.indentation), statement)
+    }
+
+    // Uint8Array stores wrap modulo 256, so plain += and -= are enough
+    fn generate_instruction(&mut self, instruction: &AstNode) -> Result<String, String> {
+        let code = match instruction {
+            AstNode::Increment => self.line("tape[pointer] += 1;"),
+            AstNode::Decrement => self.line("tape[pointer] -= 1;"),
+            AstNode::Add(n) => self.line(&format!("tape[pointer] += {};", n % 256)),
+            AstNode::Sub(n) => self.line(&format!("tape[pointer] -= {};", n % 256)),
+            AstNode::Set(value) => self.line(&format!("tape[pointer] = {};", value)),
+            AstNode::MoveRight => self.line("pointer = checked(pointer + 1);"),
+            AstNode::MoveLeft => self.line("pointer = checked(pointer - 1);"),
+            AstNode::Move(n) => self.line(&format!("pointer = checked({});", shifted("pointer", *n))),
+            AstNode::Output => self.line("output(tape[pointer]);"),
+            AstNode::Input => self.line("if (position < input.length) tape[pointer] = input[position++];"),
+            AstNode::Loop(instructions) => {
+                let mut loop_code = self.line("while (tape[pointer] !== 0) {");
+                self.indentation += 1;
+                for instruction in instructions {
+                    loop_code.push_str(&self.generate_instruction(instruction)?);
+                }
+                self.indentation -= 1;
+                loop_code.push_str(&self.line("}"));
+                loop_code
+            }
+            AstNode::MulCopy { targets } => {
+                let mut copy_code = self.line("if (tape[pointer] !== 0) {");
+                self.indentation += 1;
+                for (offset, factor) in targets {
+                    let target = format!("tape[checked({})]", shifted("pointer", *offset));
+                    copy_code.push_str(&self.line(&format!("{} += tape[pointer] * {};", target, factor)));
+                }
+                copy_code.push_str(&self.line("tape[pointer] = 0;"));
+                self.indentation -= 1;
+                copy_code.push_str(&self.line("}"));
+                copy_code
+            }
+            AstNode::Swap { offset, temp } => {
+                let mut swap_code = self.line("{");
+                self.indentation += 1;
+                swap_code.push_str(&self.line(&format!("const other = checked({});", shifted("pointer", *offset))));
+                swap_code.push_str(&self.line(&format!("const temp = checked({});", shifted("pointer", *temp))));
+                swap_code.push_str(&self.line("const value = tape[pointer];"));
+                swap_code.push_str(&self.line("tape[pointer] = tape[other];"));
+                swap_code.push_str(&self.line("tape[other] = tape[temp] + value;"));
+                swap_code.push_str(&self.line("tape[temp] = 0;"));
+                self.indentation -= 1;
+                swap_code.push_str(&self.line("}"));
+                swap_code
+            }
+            AstNode::ScanRight(stride) => {
+                self.line(&format!("while (tape[pointer] !== 0) pointer = checked(pointer + {});", stride))
+            }
+            AstNode::ScanLeft(stride) => {
+                self.line(&format!("while (tape[pointer] !== 0) pointer = checked(pointer - {});", stride))
+            }
+            AstNode::Comment(text) => text
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(|line| self.line(&format!("// {}", line)))
+                .collect(),
+            AstNode::Debug => self.line(&format!(
+                "console.error(`pointer ${{pointer}}: [${{Array.from(tape.subarray(0, {})).join(\", \")}}]`);",
+                DEBUG_DUMP_CELLS,
+            )),
+            AstNode::ProcDef(_) | AstNode::ProcCall => return Err("pbrain procedures are not supported".to_string()),
+            AstNode::Program(_) => return Err("Unexpected nested program node".to_string()),
+        };
+        Ok(code)
+    }
+}
+
+// `base + n` or `base - n`
+fn shifted(base: &str, n: isize) -> String {
+    if n < 0 {
+        format!("{} - {}", base, -n)
+    } else {
+        format!("{} + {}", base, n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_function_shape() {
+        let program = AstNode::Program(vec![
+            AstNode::Input,
+            AstNode::Loop(vec![AstNode::Output, AstNode::Sub(2), AstNode::MulCopy { targets: vec![(-1, 3)] }]),
+        ]);
+        let code = JsGenerator::new().generate(&program).unwrap();
+        assert!(code.starts_with("function run(input, output) {\n  const tape = new Uint8Array(30000);\n"));
+        assert!(code.contains("\n  while (tape[pointer] !== 0) {\n    output(tape[pointer]);\n    tape[pointer] -= 2;\n"));
+        assert!(code.contains("\n      tape[checked(pointer - 1)] += tape[pointer] * 3;\n"));
+        assert!(code.ends_with("  return tape;\n}\n"));
+    }
+
+    #[test]
+    fn test_evaluate_with_node() {
+        use std::process::Command;
+
+        // runs the generated function and prints its output, or the error it throws
+        let evaluate = |source: &str| {
+            let ast = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            let ast = crate::optimizer::Optimizer::new().optimize(&ast);
+            let code = JsGenerator::new().generate(&ast).unwrap();
+            let script = format!(
+                "{}let text = ''; try {{ run([], byte => text += String.fromCharCode(byte)); }} catch (e) {{ text += e.name; }} process.stdout.write(text);",
+                code,
+            );
+            Command::new("node").arg("-e").arg(script).output().ok().map(|output| output.stdout)
+        };
+
+        let Some(output) = evaluate("++++++++[>++++++++<-]>+.+.") else {
+            eprintln!("node not found, skipping");
+            return;
+        };
+        assert_eq!(output, b"AB");
+        // [<] used to search left forever once it passed cell 0
+        assert_eq!(evaluate("+[<]").unwrap(), b"RangeError");
+        assert_eq!(evaluate("+.[>+]").unwrap(), b"\x01RangeError");
+    }
+}
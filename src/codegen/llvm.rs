@@ -0,0 +1,265 @@
+//! emits LLVM IR text, for compiling with clang or comparing against opt
+
+use crate::interpreter::DEBUG_DUMP_CELLS;
+use crate::parser::AstNode;
+
+use std::fmt::Write;
+
+const TAPE_SIZE: usize = 30000;
+
+// builds a module with a `main` that runs the program on a zeroed global
+// tape of 8 bit cells, using getchar/putchar for , and . (end of input
+// leaves the cell unchanged). every cell access is bounds checked, and
+// main returns 1 when the pointer is off the tape. # writes the start of the
+// tape to stderr with dprintf. uses opaque pointers, so LLVM 15 or newer
+pub struct LlvmGenerator {
+    code: String,
+    next_value: usize,
+    next_label: usize,
+    next_check: usize,
+    dumps: bool, // whether the program uses #
+}
+
+impl Default for LlvmGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LlvmGenerator {
+    pub fn new() -> Self {
+        LlvmGenerator {
+            code: String::new(),
+            next_value: 0,
+            next_label: 0,
+            next_check: 0,
+            dumps: false,
+        }
+    }
+
+    pub fn generate(&mut self, ast: &AstNode) -> Result<String, String> {
+        let AstNode::Program(instructions) = ast else {
+            return Err("Expected program node".to_string());
+        };
+        self.code.clear();
+        self.next_value = 0;
+        self.next_label = 0;
+        self.next_check = 0;
+        self.dumps = false;
+
+        for instruction in instructions {
+            self.generate_instruction(instruction)?;
+        }
+
+        let mut module = String::new();
+        writeln!(module, "@tape = internal global [{} x i8] zeroinitializer", TAPE_SIZE).unwrap();
+        if self.dumps {
+            // pointer %lld: [%u, %u, ...]\n
+            let format = format!("pointer %lld: [{}]\\0A\\00", ["%u"; DEBUG_DUMP_CELLS].join(", "));
+            let length = format.len() - 4;
+            writeln!(module, "@dump_format = private constant [{} x i8] c\"{}\"", length, format).unwrap();
+        }
+        module.push_str("\ndeclare i32 @getchar()\ndeclare i32 @putchar(i32)\n");
+        if self.dumps {
+            module.push_str("declare i32 @dprintf(i32, ptr, ...)\n");
+        }
+        module.push('\n');
+        module.push_str("define i32 @main() {\nentry:\n");
+        module.push_str("  %ptr = alloca i64\n  store i64 0, ptr %ptr\n");
+        module.push_str(&self.code);
+        module.push_str("  ret i32 0\nout_of_bounds:\n  ret i32 1\n}\n");
+        Ok(module)
+    }
+
+    fn generate_instruction(&mut self, instruction: &AstNode) -> Result<(), String> {
+        match instruction {
+            AstNode::Increment => self.add(0, 1),
+            AstNode::Decrement => self.add(0, -1),
+            AstNode::Add(n) => self.add(0, *n as i64),
+            AstNode::Sub(n) => self.add(0, -(*n as i64)),
+            AstNode::Set(value) => {
+                let cell = self.cell(0);
+                self.emit(format!("store i8 {}, ptr {}", *value as i8, cell));
+            }
+            AstNode::MoveRight => self.move_by(1),
+            AstNode::MoveLeft => self.move_by(-1),
+            AstNode::Move(n) => self.move_by(*n),
+            AstNode::Output => {
+                let cell = self.cell(0);
+                let value = self.value(format!("load i8, ptr {}", cell));
+                let char = self.value(format!("zext i8 {} to i32", value));
+                self.value(format!("call i32 @putchar(i32 {})", char));
+            }
+            AstNode::Input => {
+                let cell = self.cell(0);
+                let old = self.value(format!("load i8, ptr {}", cell));
+                let char = self.value("call i32 @getchar()".to_string());
+                let eof = self.value(format!("icmp eq i32 {}, -1", char));
+                let byte = self.value(format!("trunc i32 {} to i8", char));
+                let new = self.value(format!("select i1 {}, i8 {}, i8 {}", eof, old, byte));
+                self.emit(format!("store i8 {}, ptr {}", new, cell));
+            }
+            AstNode::Loop(instructions) => {
+                let label = self.begin_loop();
+                for instruction in instructions {
+                    self.generate_instruction(instruction)?;
+                }
+                self.end_loop(label);
+            }
+            AstNode::MulCopy { targets } => {
+                // adding count * factor does nothing when the count is zero
+                let counter = self.cell(0);
+                let count = self.value(format!("load i8, ptr {}", counter));
+                for (offset, factor) in targets {
+                    let cell = self.cell(*offset);
+                    let old = self.value(format!("load i8, ptr {}", cell));
+                    let product = self.value(format!("mul i8 {}, {}", count, *factor as i8));
+                    let new = self.value(format!("add i8 {}, {}", old, product));
+                    self.emit(format!("store i8 {}, ptr {}", new, cell));
+                }
+                self.emit(format!("store i8 0, ptr {}", counter));
+            }
+            AstNode::Swap { offset, temp } => {
+                let (cell, other, temp) = (self.cell(0), self.cell(*offset), self.cell(*temp));
+                let value = self.value(format!("load i8, ptr {}", cell));
+                let moved = self.value(format!("load i8, ptr {}", other));
+                let kept = self.value(format!("load i8, ptr {}", temp));
+                self.emit(format!("store i8 {}, ptr {}", moved, cell));
+                let sum = self.value(format!("add i8 {}, {}", kept, value));
+                self.emit(format!("store i8 {}, ptr {}", sum, other));
+                self.emit(format!("store i8 0, ptr {}", temp));
+            }
+            AstNode::ScanRight(stride) => {
+                let label = self.begin_loop();
+                self.move_by(*stride as isize);
+                self.end_loop(label);
+            }
+            AstNode::ScanLeft(stride) => {
+                let label = self.begin_loop();
+                self.move_by(-(*stride as isize));
+                self.end_loop(label);
+            }
+            AstNode::Comment(_) => {}
+            AstNode::Debug => {
+                self.dumps = true;
+                let pointer = self.value("load i64, ptr %ptr".to_string());
+                let mut arguments = format!("i32 2, ptr @dump_format, i64 {}", pointer);
+                for index in 0..DEBUG_DUMP_CELLS {
+                    let cell = self.value(format!("getelementptr inbounds [{} x i8], ptr @tape, i64 0, i64 {}", TAPE_SIZE, index));
+                    let value = self.value(format!("load i8, ptr {}", cell));
+                    let value = self.value(format!("zext i8 {} to i32", value));
+                    write!(arguments, ", i32 {}", value).unwrap();
+                }
+                self.value(format!("call i32 (i32, ptr, ...) @dprintf({})", arguments));
+            }
+            AstNode::ProcDef(_) | AstNode::ProcCall => return Err("pbrain procedures are not supported".to_string()),
+            AstNode::Program(_) => return Err("Unexpected nested program node".to_string()),
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, line: String) {
+        self.code.push_str("  ");
+        self.code.push_str(&line);
+        self.code.push('\n');
+    }
+
+    // emits `%vN = <expression>` and returns %vN
+    fn value(&mut self, expression: String) -> String {
+        let name = format!("%v{}", self.next_value);
+        self.next_value += 1;
+        self.emit(format!("{} = {}", name, expression));
+        name
+    }
+
+    // address of the cell `offset` away from the pointer, leaving main
+    // through out_of_bounds when it is off the tape
+    fn cell(&mut self, offset: isize) -> String {
+        let mut index = self.value("load i64, ptr %ptr".to_string());
+        if offset != 0 {
+            index = self.value(format!("add i64 {}, {}", index, offset));
+        }
+        // a negative index is a huge unsigned one, so this covers both ends
+        let inside = self.value(format!("icmp ult i64 {}, {}", index, TAPE_SIZE));
+        let check = self.next_check;
+        self.next_check += 1;
+        self.emit(format!("br i1 {}, label %cell{}, label %out_of_bounds", inside, check));
+        self.code.push_str(&format!("cell{}:\n", check));
+        self.value(format!("getelementptr inbounds [{} x i8], ptr @tape, i64 0, i64 {}", TAPE_SIZE, index))
+    }
+
+    fn add(&mut self, offset: isize, n: i64) {
+        let cell = self.cell(offset);
+        let old = self.value(format!("load i8, ptr {}", cell));
+        let new = self.value(format!("add i8 {}, {}", old, n as i8));
+        self.emit(format!("store i8 {}, ptr {}", new, cell));
+    }
+
+    fn move_by(&mut self, n: isize) {
+        let old = self.value("load i64, ptr %ptr".to_string());
+        let new = self.value(format!("add i64 {}, {}", old, n));
+        self.emit(format!("store i64 {}, ptr %ptr", new));
+    }
+
+    // jumps to a condition block that tests the cell, returns the loop's label number
+    fn begin_loop(&mut self) -> usize {
+        let label = self.next_label;
+        self.next_label += 1;
+        self.emit(format!("br label %loop{}.cond", label));
+        self.code.push_str(&format!("loop{}.cond:\n", label));
+        let cell = self.cell(0);
+        let value = self.value(format!("load i8, ptr {}", cell));
+        let zero = self.value(format!("icmp eq i8 {}, 0", value));
+        self.emit(format!("br i1 {}, label %loop{}.end, label %loop{}.body", zero, label, label));
+        self.code.push_str(&format!("loop{}.body:\n", label));
+        label
+    }
+
+    fn end_loop(&mut self, label: usize) {
+        self.emit(format!("br label %loop{}.cond", label));
+        self.code.push_str(&format!("loop{}.end:\n", label));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_module_layout() {
+        let program = AstNode::Program(vec![AstNode::Add(3), AstNode::Output]);
+        let ir = LlvmGenerator::new().generate(&program).unwrap();
+        assert!(ir.contains("@tape = internal global [30000 x i8] zeroinitializer"));
+        assert!(ir.contains("define i32 @main() {"));
+        assert!(ir.contains("icmp ult i64 %v0, 30000"));
+        assert!(ir.contains("add i8 %v3, 3"));
+        assert!(ir.contains("call i32 @putchar(i32 %v9)"));
+        assert!(ir.ends_with("  ret i32 0\nout_of_bounds:\n  ret i32 1\n}\n"));
+    }
+
+    #[test]
+    fn test_nested_loops() {
+        let program = AstNode::Program(vec![AstNode::Loop(vec![
+            AstNode::Loop(vec![AstNode::Decrement]),
+            AstNode::ScanRight(1),
+        ])]);
+        let ir = LlvmGenerator::new().generate(&program).unwrap();
+        for label in ["loop0", "loop1", "loop2"] {
+            assert!(ir.contains(&format!("\n{}.cond:\n", label)));
+            assert!(ir.contains(&format!("\n{}.body:\n", label)));
+            assert!(ir.contains(&format!("\n{}.end:\n", label)));
+        }
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        let ir = LlvmGenerator::new().generate(&AstNode::Program(vec![AstNode::Debug])).unwrap();
+        assert!(ir.contains("@dump_format = private constant [56 x i8] c\"pointer %lld: [%u, %u, %u, %u, %u, %u, %u, %u, %u, %u]\\0A\\00\""));
+        assert!(ir.contains("declare i32 @dprintf(i32, ptr, ...)"));
+        assert!(ir.contains("call i32 (i32, ptr, ...) @dprintf(i32 2, ptr @dump_format, i64 %v0, i32 %v3, "));
+
+        // nothing is declared for programs without #
+        let ir = LlvmGenerator::new().generate(&AstNode::Program(vec![AstNode::Output])).unwrap();
+        assert!(!ir.contains("dprintf"));
+    }
+}
@@ -0,0 +1,268 @@
+//! emits a standalone WebAssembly module that runs the program
+
+use crate::parser::AstNode;
+
+use wasm_encoder::{
+    BlockType, CodeSection, EntityType, ExportKind, ExportSection, Function, FunctionSection,
+    ImportSection, Instruction, MemArg, MemorySection, MemoryType, Module, TypeSection, ValType,
+};
+
+// the tape lives at the start of the exported memory, one byte per cell
+const TAPE_PAGES: u64 = 1; // 64 KiB, enough for the usual 30000 cells
+
+// function indices, imports come first
+const READ_BYTE: u32 = 0;
+const WRITE_BYTE: u32 = 1;
+const POINTER: u32 = 0; // the only local of `run`
+
+// builds a module importing `env.read_byte: () -> i32` for , and
+// `env.write_byte: (i32) -> ()` for ., and exporting `memory` and a `run`
+// function. read_byte returns the value to store, so the host decides what
+// end of input looks like
+pub struct WasmGenerator {
+    body: Function,
+}
+
+impl Default for WasmGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WasmGenerator {
+    pub fn new() -> Self {
+        WasmGenerator {
+            body: Function::new([(1, ValType::I32)]),
+        }
+    }
+
+    pub fn generate(&mut self, ast: &AstNode) -> Result<Vec<u8>, String> {
+        let AstNode::Program(instructions) = ast else {
+            return Err("Expected program node".to_string());
+        };
+        self.body = Function::new([(1, ValType::I32)]);
+        for instruction in instructions {
+            self.generate_instruction(instruction)?;
+        }
+        self.body.instruction(&Instruction::End);
+
+        let mut types = TypeSection::new();
+        types.ty().function([], [ValType::I32]); // read_byte
+        types.ty().function([ValType::I32], []); // write_byte
+        types.ty().function([], []); // run
+
+        let mut imports = ImportSection::new();
+        imports.import("env", "read_byte", EntityType::Function(0));
+        imports.import("env", "write_byte", EntityType::Function(1));
+
+        let mut functions = FunctionSection::new();
+        functions.function(2);
+
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType {
+            minimum: TAPE_PAGES,
+            maximum: None,
+            memory64: false,
+            shared: false,
+            page_size_log2: None,
+        });
+
+        let mut exports = ExportSection::new();
+        exports.export("memory", ExportKind::Memory, 0);
+        exports.export("run", ExportKind::Func, 2);
+
+        let mut code = CodeSection::new();
+        code.function(&self.body);
+
+        let mut module = Module::new();
+        module
+            .section(&types)
+            .section(&imports)
+            .section(&functions)
+            .section(&memories)
+            .section(&exports)
+            .section(&code);
+        Ok(module.finish())
+    }
+
+    fn generate_instruction(&mut self, instruction: &AstNode) -> Result<(), String> {
+        match instruction {
+            AstNode::Increment => self.add(0, 1),
+            AstNode::Decrement => self.add(0, -1),
+            AstNode::Add(n) => self.add(0, *n as i32),
+            AstNode::Sub(n) => self.add(0, -(*n as i32)),
+            AstNode::Set(value) => {
+                self.address(0);
+                self.body.instruction(&Instruction::I32Const(*value as i32));
+                self.body.instruction(&Instruction::I32Store8(byte()));
+            }
+            AstNode::MoveRight => self.move_by(1),
+            AstNode::MoveLeft => self.move_by(-1),
+            AstNode::Move(n) => self.move_by(*n),
+            AstNode::Output => {
+                self.load(0);
+                self.body.instruction(&Instruction::Call(WRITE_BYTE));
+            }
+            AstNode::Input => {
+                self.address(0);
+                self.body.instruction(&Instruction::Call(READ_BYTE));
+                self.body.instruction(&Instruction::I32Store8(byte()));
+            }
+            AstNode::Loop(instructions) => {
+                self.begin_loop();
+                for instruction in instructions {
+                    self.generate_instruction(instruction)?;
+                }
+                self.end_loop();
+            }
+            AstNode::MulCopy { targets } => {
+                self.load(0);
+                self.body.instruction(&Instruction::If(BlockType::Empty));
+                for (offset, factor) in targets {
+                    // cell[offset] += cell * factor
+                    self.address(*offset);
+                    self.load(*offset);
+                    self.load(0);
+                    self.body.instruction(&Instruction::I32Const(*factor));
+                    self.body.instruction(&Instruction::I32Mul);
+                    self.body.instruction(&Instruction::I32Add);
+                    self.body.instruction(&Instruction::I32Store8(byte()));
+                }
+                self.address(0);
+                self.body.instruction(&Instruction::I32Const(0));
+                self.body.instruction(&Instruction::I32Store8(byte()));
+                self.body.instruction(&Instruction::End);
+            }
+            AstNode::Swap { offset, temp } => {
+                // the cell's store waits on the stack under the other cell's,
+                // so the old value of the other cell is read first
+                self.address(0);
+                self.load(*offset);
+                self.address(*offset);
+                self.load(*temp);
+                self.load(0);
+                self.body.instruction(&Instruction::I32Add);
+                self.body.instruction(&Instruction::I32Store8(byte()));
+                self.body.instruction(&Instruction::I32Store8(byte()));
+                self.address(*temp);
+                self.body.instruction(&Instruction::I32Const(0));
+                self.body.instruction(&Instruction::I32Store8(byte()));
+            }
+            AstNode::ScanRight(stride) => {
+                self.begin_loop();
+                self.move_by(*stride as isize);
+                self.end_loop();
+            }
+            AstNode::ScanLeft(stride) => {
+                self.begin_loop();
+                self.move_by(-(*stride as isize));
+                self.end_loop();
+            }
+            AstNode::Comment(_) => {}
+            // modules have no stderr to dump the tape to
+            AstNode::Debug => return Err("the # debug command is not supported".to_string()),
+            AstNode::ProcDef(_) | AstNode::ProcCall => return Err("pbrain procedures are not supported".to_string()),
+            AstNode::Program(_) => return Err("Unexpected nested program node".to_string()),
+        }
+        Ok(())
+    }
+
+    // pushes the address of the cell `offset` away from the pointer
+    fn address(&mut self, offset: isize) {
+        self.body.instruction(&Instruction::LocalGet(POINTER));
+        if offset != 0 {
+            self.body.instruction(&Instruction::I32Const(offset as i32));
+            self.body.instruction(&Instruction::I32Add);
+        }
+    }
+
+    fn load(&mut self, offset: isize) {
+        self.address(offset);
+        self.body.instruction(&Instruction::I32Load8U(byte()));
+    }
+
+    fn add(&mut self, offset: isize, n: i32) {
+        self.address(offset);
+        self.load(offset);
+        self.body.instruction(&Instruction::I32Const(n));
+        self.body.instruction(&Instruction::I32Add);
+        self.body.instruction(&Instruction::I32Store8(byte()));
+    }
+
+    fn move_by(&mut self, n: isize) {
+        self.address(n);
+        self.body.instruction(&Instruction::LocalSet(POINTER));
+    }
+
+    // block { loop { if cell == 0 break; ...
+    fn begin_loop(&mut self) {
+        self.body.instruction(&Instruction::Block(BlockType::Empty));
+        self.body.instruction(&Instruction::Loop(BlockType::Empty));
+        self.load(0);
+        self.body.instruction(&Instruction::I32Eqz);
+        self.body.instruction(&Instruction::BrIf(1));
+    }
+
+    // ... continue } }
+    fn end_loop(&mut self) {
+        self.body.instruction(&Instruction::Br(0));
+        self.body.instruction(&Instruction::End);
+        self.body.instruction(&Instruction::End);
+    }
+}
+
+// memory access to a single byte at the computed address
+fn byte() -> MemArg {
+    MemArg {
+        offset: 0,
+        align: 0,
+        memory_index: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_module() {
+        let program = AstNode::Program(vec![
+            AstNode::Input,
+            AstNode::Loop(vec![AstNode::Output, AstNode::Input]),
+            AstNode::Add(3),
+            AstNode::MulCopy { targets: vec![(1, 2), (-1, -1)] },
+            AstNode::Swap { offset: 1, temp: -1 },
+            AstNode::Move(-2),
+            AstNode::ScanLeft(1),
+            AstNode::Set(0),
+        ]);
+        let bytes = WasmGenerator::new().generate(&program).unwrap();
+        assert_eq!(&bytes[..4], b"\0asm");
+        wasmparser::validate(&bytes).unwrap();
+    }
+
+    #[test]
+    fn test_imports_and_exports() {
+        let bytes = WasmGenerator::new().generate(&AstNode::Program(vec![])).unwrap();
+        let mut imports = Vec::new();
+        let mut exports = Vec::new();
+        for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+            match payload.unwrap() {
+                wasmparser::Payload::ImportSection(section) => {
+                    for import in section {
+                        let import = import.unwrap();
+                        imports.push(format!("{}.{}", import.module, import.name));
+                    }
+                }
+                wasmparser::Payload::ExportSection(section) => {
+                    for export in section {
+                        exports.push(export.unwrap().name.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(imports, ["env.read_byte", "env.write_byte"]);
+        assert_eq!(exports, ["memory", "run"]);
+    }
+}
@@ -0,0 +1,79 @@
+//! the state a failed run left behind, saved by bfc --core-dump so it can be
+//! looked at after the fact
+
+use crate::engine::Engine;
+use crate::interpreter::{ExecutedInstruction, RuntimeError};
+use crate::lexer::Span;
+
+use serde::{Deserialize, Serialize};
+
+// instructions bfc --core-dump keeps in the history
+pub const DEFAULT_HISTORY: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CoreDump {
+    pub error: String,
+    pub position: usize,             // command index of the failing instruction
+    pub span: Option<Span>,          // where that command is in the source
+    pub instruction: Option<String>, // the failing instruction, when the history has it
+    pub instruction_count: usize,
+    pub pointer: usize,
+    pub tape_size: usize,
+    // the tape up to the last cell that isn't zero, or the pointer if that's further
+    pub memory: Vec<u32>,
+    // oldest first, empty when the engine doesn't keep one
+    pub history: Vec<ExecutedInstruction>,
+}
+
+impl CoreDump {
+    // `engine` is the one `error` came from, `span` where the command at
+    // error.position starts
+    pub fn new(error: &RuntimeError, engine: &dyn Engine, span: Option<Span>) -> Self {
+        let memory = engine.memory();
+        let used = memory.iter().rposition(|&cell| cell != 0).map_or(0, |last| last + 1);
+        let end = used.max(error.pointer + 1).min(memory.len());
+        let history = engine.history();
+        let instruction = history.last().filter(|last| last.position == error.position).map(|last| last.instruction.clone());
+        CoreDump {
+            error: error.kind.to_string(),
+            position: error.position,
+            span,
+            instruction,
+            instruction_count: error.instruction_count,
+            pointer: error.pointer,
+            tape_size: memory.len(),
+            memory: memory[..end].to_vec(),
+            history,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        // only plain fields, which can't fail
+        serde_json::to_string_pretty(self).unwrap() + "\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+
+    #[test]
+    fn test_core_dump() {
+        let source = "++>+\n<<";
+        let tokens = crate::lexer::tokenize_spanned(source).unwrap();
+        let ast = crate::parser::parse_spanned(tokens.clone()).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_history_size(3);
+        let error = interpreter.run_and_capture_output(&ast).unwrap_err();
+
+        let dump = CoreDump::new(&error, &interpreter, tokens.get(error.position).map(|token| token.span));
+        assert_eq!(dump.error, "Pointer out of bounds");
+        assert_eq!(dump.span, Some(Span { offset: 6, line: 2, column: 2 }));
+        assert_eq!(dump.instruction.as_deref(), Some("MoveLeft"));
+        assert_eq!((dump.pointer, dump.tape_size, &dump.memory[..]), (0, 30000, &[2, 1][..]));
+        let positions: Vec<usize> = dump.history.iter().map(|executed| executed.position).collect();
+        assert_eq!(positions, [3, 4, 5]);
+        assert_eq!(serde_json::from_str::<CoreDump>(&dump.to_json()).unwrap(), dump);
+    }
+}
@@ -0,0 +1,86 @@
+//! an abstract cost per instruction, so runs can be compared by "cycles"
+//! instead of wall-clock time, which is noisy and depends on the machine
+
+use crate::parser::AstNode;
+
+// the cost of each kind of instruction. an optimized instruction costs the
+// same as the command it stands for, so Add(5) is one add and a copy loop
+// folded into MulCopy is one `other`, which is what makes optimizations show
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CostModel {
+    pub moves: usize,  // > < and Move
+    pub adds: usize,   // + - Add, Sub and Set
+    pub loops: usize,  // each test of the cell by [ or ]
+    pub input: usize,  // ,
+    pub output: usize, // .
+    pub other: usize,  // MulCopy, Swap, scans, # and pbrain procedures
+}
+
+impl Default for CostModel {
+    // i/o is the expensive part of a real machine
+    fn default() -> Self {
+        CostModel { moves: 1, adds: 1, loops: 1, input: 10, output: 10, other: 1 }
+    }
+}
+
+impl CostModel {
+    // the default costs with some replaced, e.g. "move=2,io=100", or
+    // "default". the names are move, add, loop, input, output, io for both
+    // of those, and other
+    pub fn from_spec(spec: &str) -> Result<Self, String> {
+        let mut model = CostModel::default();
+        if spec == "default" {
+            return Ok(model);
+        }
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let Some((name, cost)) = entry.split_once('=') else {
+                return Err(format!("Invalid cost: {} (expected NAME=COST)", entry));
+            };
+            let Ok(cost) = cost.trim().parse() else {
+                return Err(format!("Invalid cost for {}: {}", name, cost));
+            };
+            match name.trim() {
+                "move" => model.moves = cost,
+                "add" => model.adds = cost,
+                "loop" => model.loops = cost,
+                "input" => model.input = cost,
+                "output" => model.output = cost,
+                "io" => (model.input, model.output) = (cost, cost),
+                "other" => model.other = cost,
+                name => return Err(format!("Unknown cost: {} (expected move, add, loop, input, output, io or other)", name)),
+            }
+        }
+        Ok(model)
+    }
+
+    // what executing `instruction` once costs. a loop's own cost is the test
+    // at its [, the tests at ] are charged separately
+    pub fn cost(&self, instruction: &AstNode) -> usize {
+        match instruction {
+            AstNode::MoveRight | AstNode::MoveLeft | AstNode::Move(_) => self.moves,
+            AstNode::Increment | AstNode::Decrement | AstNode::Add(_) | AstNode::Sub(_) | AstNode::Set(_) => self.adds,
+            AstNode::Loop(_) => self.loops,
+            AstNode::Input => self.input,
+            AstNode::Output => self.output,
+            AstNode::Comment(_) | AstNode::Program(_) => 0,
+            _ => self.other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_spec() {
+        let model = CostModel::from_spec("move=2, io=100").unwrap();
+        assert_eq!(model, CostModel { moves: 2, input: 100, output: 100, ..CostModel::default() });
+        assert_eq!(model.cost(&AstNode::Move(-3)), 2);
+        assert_eq!(model.cost(&AstNode::Output), 100);
+        assert_eq!(CostModel::from_spec("default"), Ok(CostModel::default()));
+        assert!(CostModel::from_spec("jump=1").is_err());
+        assert!(CostModel::from_spec("move").is_err());
+        assert!(CostModel::from_spec("move=-1").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! errors that point at a place in the source
+
+use crate::interpreter::RuntimeError;
+use crate::lexer::{BracketError, Lexer, Span};
+
+use std::fmt;
+
+// a message about the source, with the position it is about when known
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Option<Span>) -> Self {
+        Diagnostic { message: message.into(), span }
+    }
+
+    // a runtime error pointing at its command in `source`, the program the
+    // error came from
+    pub fn from_runtime_error(error: &RuntimeError, source: &str) -> Self {
+        Diagnostic::from_runtime_error_lexed(error, Lexer::new(source))
+    }
+
+    // like from_runtime_error, for a program that needs a lexer with
+    // extensions enabled. `lexer` must be set up the way the program was
+    // lexed and not have been read from yet
+    pub fn from_runtime_error_lexed<I: Iterator<Item = char>>(error: &RuntimeError, mut lexer: Lexer<I>) -> Self {
+        let span = lexer.nth(error.position).map(|token| token.span);
+        let message = format!("{} (instruction {}, pointer {})", error.kind, error.instruction_count, error.pointer);
+        Diagnostic::new(message, span)
+    }
+
+    // the message followed by the offending source line with a ^ under the
+    // spanned character, e.g.
+    //
+    // Unclosed loop - missing ] at line 2, column 1
+    //   [->+<
+    //   ^
+    pub fn render(&self, source: &str) -> String {
+        let Some(span) = self.span else {
+            return self.to_string();
+        };
+        let Some(line) = source.lines().nth(span.line - 1) else {
+            return self.to_string();
+        };
+        // keep tabs so the marker lines up however wide the terminal shows them
+        let padding: String = line
+            .chars()
+            .take(span.column - 1)
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!("{}\n  {}\n  {}^", self, line, padding)
+    }
+}
+
+// every error from lexer::validate rendered against `source`, one after the other
+pub fn render_bracket_errors(errors: Vec<BracketError>, source: &str) -> String {
+    errors.into_iter().map(|error| Diagnostic::from(error).render(source)).collect::<Vec<_>>().join("\n")
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{} at line {}, column {}", self.message, span.line, span.column),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl From<BracketError> for Diagnostic {
+    fn from(error: BracketError) -> Diagnostic {
+        Diagnostic::new(error.to_string(), Some(error.span))
+    }
+}
+
+impl From<Diagnostic> for String {
+    fn from(diagnostic: Diagnostic) -> String {
+        diagnostic.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let span = Span { offset: 8, line: 2, column: 3 };
+        let diagnostic = Diagnostic::new("Unclosed loop - missing ]", Some(span));
+        assert_eq!(
+            diagnostic.render("+++++\n\t [-"),
+            "Unclosed loop - missing ] at line 2, column 3\n  \t [-\n  \t ^"
+        );
+
+        // without a span there is nothing to point at
+        let diagnostic = Diagnostic::new("Expected program node", None);
+        assert_eq!(diagnostic.render("+"), "Expected program node");
+    }
+
+    #[test]
+    fn test_from_runtime_error() {
+        use crate::interpreter::Interpreter;
+
+        let source = "+[-] comment\n >\n<<";
+        let ast = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+        let error = Interpreter::new().run(&ast).unwrap_err();
+        assert_eq!(
+            Diagnostic::from_runtime_error(&error, source).render(source),
+            "Pointer out of bounds (instruction 6, pointer 0) at line 3, column 2\n  <<\n   ^"
+        );
+    }
+}
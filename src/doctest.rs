@@ -0,0 +1,112 @@
+//! self-testing BF files: comment directives declaring input and expected output
+//!
+//! a line starting with `#input:` queues bytes for `,` and one starting with
+//! `#expect:` declares output the program must produce. repeated directives
+//! are concatenated and `\n`, `\t`, `\0` and `\\` escapes are supported.
+//! output is compared byte for byte with the UTF-8 text of `#expect:`.
+
+use crate::{lexer, parser};
+use crate::interpreter::{EofBehavior, Interpreter};
+use crate::io::MemoryIo;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Directives {
+    pub input: Vec<u8>,
+    pub expected: Option<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DocTestResult {
+    Passed,
+    Failed { expected: Vec<u8>, actual: Vec<u8> },
+    NoDirectives, // nothing to check
+}
+
+// collects the directives from a program's source
+pub fn parse_directives(source: &str) -> Directives {
+    let mut directives = Directives::default();
+    for line in source.lines() {
+        if let Some(text) = line.strip_prefix("#input:") {
+            directives.input.extend(unescape(text).bytes());
+        } else if let Some(text) = line.strip_prefix("#expect:") {
+            directives.expected.get_or_insert_with(Vec::new).extend(unescape(text).bytes());
+        }
+    }
+    directives
+}
+
+// runs a program against its own #input:/#expect: directives
+pub fn run_doc_test(source: &str) -> Result<DocTestResult, String> {
+    let directives = parse_directives(source);
+    let Some(expected) = directives.expected else {
+        return Ok(DocTestResult::NoDirectives);
+    };
+
+    let tokens = lexer::tokenize(source)?;
+    let ast = parser::parse(tokens)?;
+    let mut interpreter = Interpreter::new();
+    interpreter.set_eof_behavior(EofBehavior::Zero);
+    interpreter.set_io(Some(Box::new(MemoryIo::new(&directives.input))));
+    interpreter.run(&ast)?;
+    let actual = interpreter.io::<MemoryIo>().map(|io| io.output.clone()).unwrap_or_default();
+
+    if actual == expected {
+        Ok(DocTestResult::Passed)
+    } else {
+        Ok(DocTestResult::Failed { expected, actual })
+    }
+}
+
+// strips the space after the colon and expands escapes
+fn unescape(text: &str) -> String {
+    let text = text.strip_prefix(' ').unwrap_or(text);
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('0') => result.push('\0'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_directives() {
+        let source = "#input: ab\n#expect: x, y.\\n\n+\n#expect: z";
+        assert_eq!(parse_directives(source), Directives {
+            input: b"ab".to_vec(),
+            expected: Some(b"x, y.\nz".to_vec()),
+        });
+    }
+
+    #[test]
+    fn test_run_doc_test() {
+        // echoes two input bytes, one of which is a command character
+        let source = "#input: h,\n#expect: h,\n,.>,.";
+        assert_eq!(run_doc_test(source), Ok(DocTestResult::Passed));
+
+        let source = "#expect: B\n++++++++[>++++++++<-]>+.";
+        assert_eq!(run_doc_test(source), Ok(DocTestResult::Failed {
+            expected: b"B".to_vec(),
+            actual: b"A".to_vec(),
+        }));
+
+        // non-ASCII text is expected as its UTF-8 bytes
+        let source = format!("#expect: \u{e9}\n{}.{}.", "-".repeat(61), "-".repeat(26));
+        assert_eq!(run_doc_test(&source), Ok(DocTestResult::Passed));
+
+        assert_eq!(run_doc_test("+."), Ok(DocTestResult::NoDirectives));
+    }
+}
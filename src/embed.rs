@@ -0,0 +1,155 @@
+//! the short way to use the compiler from another Rust project: `compile`
+//! a program to its syntax tree, or `run` it on some input, both set up by
+//! one `Config`. the modules behind them stay public for anything finer
+
+use crate::io::OutputEncoding;
+use crate::interpreter::{CellWidth, EofBehavior, Interpreter, RuntimeError, TapePolicy, DEFAULT_TAPE_SIZE};
+use crate::lexer::{self, Dialect, Lexer};
+use crate::optimizer::Optimizer;
+use crate::parser::{AstNode, Parser};
+use crate::diagnostic::Diagnostic;
+use crate::stats::ExecutionStats;
+
+use std::fmt;
+
+// the language a program is written in and how it runs, what bfc sets from
+// its command line options
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub dialect: Dialect,
+    pub debug_command: bool, // read # as a command
+    pub optimize: bool,
+    pub partial_evaluation: bool, // see Optimizer::set_partial_evaluation
+    pub tape_size: usize,
+    pub cell_width: CellWidth,
+    pub eof_behavior: EofBehavior,
+    pub tape_policy: TapePolicy,
+    pub max_instructions: Option<usize>,
+    pub max_output: Option<usize>, // bytes
+    pub output_encoding: OutputEncoding, // how RunOutput.output is decoded
+}
+
+impl Default for Config {
+    // the same defaults as bfc run, except that programs are optimized
+    fn default() -> Self {
+        Config {
+            dialect: Dialect::Brainfuck,
+            debug_command: false,
+            optimize: true,
+            partial_evaluation: false,
+            tape_size: DEFAULT_TAPE_SIZE,
+            cell_width: CellWidth::U8,
+            eof_behavior: EofBehavior::Unchanged,
+            tape_policy: TapePolicy::Abort,
+            max_instructions: None,
+            max_output: None,
+            output_encoding: OutputEncoding::Latin1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    // every problem with the source, each rendered against it
+    Syntax(Vec<String>),
+    Runtime(RuntimeError),
+    // a Config the interpreter can't run with, such as a tape size of 0
+    Config(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Syntax(messages) => write!(f, "{}", messages.join("\n")),
+            Error::Runtime(error) => write!(f, "{}", error),
+            Error::Config(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<RuntimeError> for Error {
+    fn from(error: RuntimeError) -> Self {
+        Error::Runtime(error)
+    }
+}
+
+// what a finished run left behind
+#[derive(Debug, Clone)]
+pub struct RunOutput {
+    pub output: String, // decoded by Config.output_encoding
+    pub bytes: Vec<u8>, // the output exactly as it was written
+    pub memory: Vec<u32>,
+    pub pointer: usize,
+    pub stats: ExecutionStats,
+}
+
+// the syntax tree of `source`, optimized if `config` says so
+pub fn compile(source: &str, config: &Config) -> Result<AstNode, Error> {
+    let ast = parse(source, config)?;
+    Ok(if config.optimize { Optimizer::new().optimize(&ast) } else { ast })
+}
+
+// runs `source` in the interpreter with `input` as everything , reads
+pub fn run(source: &str, input: &[u8], config: &Config) -> Result<RunOutput, Error> {
+    // the interpreter optimizes by itself, keeping errors at source positions
+    let ast = parse(source, config)?;
+    let mut interpreter = Interpreter::builder()
+        .tape_size(config.tape_size)
+        .optimize(config.optimize)
+        .partial_evaluation(config.partial_evaluation)
+        .cell_width(config.cell_width)
+        .eof_behavior(config.eof_behavior)
+        .tape_policy(config.tape_policy)
+        .max_instructions(config.max_instructions)
+        .max_output(config.max_output)
+        .input(input)
+        .build()
+        .map_err(Error::Config)?;
+    let (bytes, memory, pointer) = interpreter.run_and_capture_bytes(&ast)?;
+    let output = config.output_encoding.decode(&bytes);
+    Ok(RunOutput { output, bytes, memory, pointer, stats: interpreter.statistics() })
+}
+
+// every unmatched bracket is reported, not just the first
+fn parse(source: &str, config: &Config) -> Result<AstNode, Error> {
+    let mut lexer = Lexer::new(source);
+    lexer.set_dialect(config.dialect);
+    lexer.set_debug_command(config.debug_command);
+    let tokens = lexer.tokenize_spanned();
+    if let Err(errors) = lexer::validate(&tokens) {
+        return Err(Error::Syntax(errors.into_iter().map(|error| Diagnostic::from(error).render(source)).collect()));
+    }
+    Parser::with_spans(tokens).parse().map_err(|e| Error::Syntax(vec![e.render(source)]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile() {
+        let ast = compile(">+++[-]", &Config::default()).unwrap();
+        assert_eq!(ast, AstNode::Program(vec![AstNode::MoveRight, AstNode::Add(3), AstNode::Set(0)]));
+        let config = Config { optimize: false, ..Config::default() };
+        assert_eq!(compile("+", &config).unwrap(), AstNode::Program(vec![AstNode::Increment]));
+        let Err(Error::Syntax(messages)) = compile("[[]", &Config::default()) else { panic!("expected a syntax error") };
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn test_run() {
+        let result = run(",[.,]", b"hi", &Config { eof_behavior: EofBehavior::Zero, ..Config::default() }).unwrap();
+        assert_eq!(result.output, "hi");
+        let result = run("-.", b"", &Config::default()).unwrap();
+        assert_eq!((result.output.as_str(), &result.bytes[..]), ("\u{ff}", &[255][..]));
+        let utf8 = Config { output_encoding: OutputEncoding::Utf8, ..Config::default() };
+        assert_eq!(run(",.,.", "é".as_bytes(), &utf8).unwrap().output, "é");
+        assert_eq!(result.pointer, 0);
+        assert!(result.stats.total_instructions.is_some_and(|total| total > 0));
+        let limited = Config { max_instructions: Some(10), ..Config::default() };
+        assert!(matches!(run("+[]", b"", &limited), Err(Error::Runtime(_))));
+        assert!(matches!(run("+", b"", &Config { tape_size: 0, ..Config::default() }), Err(Error::Config(_))));
+    }
+}
@@ -0,0 +1,183 @@
+//! common interface over the different ways of executing a program
+
+use crate::interpreter::{ExecutedInstruction, Interpreter, RuntimeError};
+use crate::parser::AstNode;
+use crate::profile::Profile;
+use crate::stats::ExecutionStats;
+
+// anything that can execute a parsed program and expose the resulting state,
+// so the CLI, debugger and stats code don't care which engine is running
+pub trait Engine {
+    fn name(&self) -> &'static str;
+    fn run(&mut self, ast: &AstNode) -> Result<(), RuntimeError>;
+    // begins a session on `ast` that step() advances
+    fn start(&mut self, ast: &AstNode) -> Result<(), RuntimeError>;
+    // executes the next instruction, returns false once the program has finished
+    fn step(&mut self) -> Result<bool, RuntimeError>;
+    fn memory(&self) -> &[u32];
+    fn pointer(&self) -> usize;
+    fn instruction_count(&self) -> usize;
+    fn set_observer(&mut self, observer: Option<Box<dyn Observer>>);
+    // what the engine recorded about its last run
+    fn statistics(&self) -> ExecutionStats;
+    // the last instructions executed, oldest first, for engines that keep them
+    fn history(&self) -> Vec<ExecutedInstruction> {
+        Vec::new()
+    }
+    // how often each command was reached, by position, for engines that count them
+    fn command_counts(&self) -> Vec<usize> {
+        Vec::new()
+    }
+    // what each instruction cost, empty for engines that don't time them
+    fn profile(&self) -> Profile {
+        Profile::default()
+    }
+
+    fn print_statistics(&self) {
+        print!("{}", self.statistics().to_text());
+    }
+}
+
+// called by an engine before every instruction it executes
+pub trait Observer: Send {
+    fn on_instruction(&mut self, instruction: &AstNode, pointer: usize, memory: &[u32]);
+}
+
+// the available engines, selected with --engine on the command line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EngineKind {
+    Vm,
+    TreeWalker,
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+impl EngineKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "vm" => Some(EngineKind::Vm),
+            "tree" => Some(EngineKind::TreeWalker),
+            #[cfg(feature = "jit")]
+            "jit" => Some(EngineKind::Jit),
+            _ => None,
+        }
+    }
+
+    pub fn names() -> &'static [&'static str] {
+        if cfg!(feature = "jit") {
+            &["vm", "tree", "jit"]
+        } else {
+            &["vm", "tree"]
+        }
+    }
+}
+
+impl Engine for Interpreter {
+    fn name(&self) -> &'static str {
+        "tree"
+    }
+
+    fn run(&mut self, ast: &AstNode) -> Result<(), RuntimeError> {
+        Interpreter::run(self, ast)
+    }
+
+    fn start(&mut self, ast: &AstNode) -> Result<(), RuntimeError> {
+        Interpreter::start(self, ast)
+    }
+
+    fn step(&mut self) -> Result<bool, RuntimeError> {
+        Interpreter::step(self)
+    }
+
+    fn memory(&self) -> &[u32] {
+        Interpreter::memory(self)
+    }
+
+    fn pointer(&self) -> usize {
+        Interpreter::pointer(self)
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    fn set_observer(&mut self, observer: Option<Box<dyn Observer>>) {
+        Interpreter::set_observer(self, observer)
+    }
+
+    fn statistics(&self) -> ExecutionStats {
+        Interpreter::statistics(self)
+    }
+
+    fn history(&self) -> Vec<ExecutedInstruction> {
+        Interpreter::history(self)
+    }
+
+    fn command_counts(&self) -> Vec<usize> {
+        Interpreter::command_counts(self).to_vec()
+    }
+
+    fn profile(&self) -> Profile {
+        Interpreter::profile(self).clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    // records (pointer, cell) before each instruction
+    struct Recorder(Arc<Mutex<Vec<(usize, u32)>>>);
+
+    impl Observer for Recorder {
+        fn on_instruction(&mut self, _instruction: &AstNode, pointer: usize, memory: &[u32]) {
+            self.0.lock().unwrap().push((pointer, memory[pointer]));
+        }
+    }
+
+    #[test]
+    fn test_engine_selection() {
+        assert_eq!(EngineKind::from_name("tree"), Some(EngineKind::TreeWalker));
+        assert_eq!(EngineKind::from_name("vm"), Some(EngineKind::Vm));
+        assert_eq!(EngineKind::from_name("warp"), None);
+    }
+
+    #[test]
+    fn test_observer_sees_every_instruction() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut engine: Box<dyn Engine> = Box::new(Interpreter::new());
+        engine.set_observer(Some(Box::new(Recorder(seen.clone()))));
+
+        engine.run(&AstNode::Program(vec![AstNode::Increment, AstNode::MoveRight, AstNode::Increment])).unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![(0, 0), (0, 1), (1, 0)]);
+    }
+
+    #[test]
+    fn test_run_through_trait() {
+        let mut engine: Box<dyn Engine> = Box::new(Interpreter::new());
+        let program = AstNode::Program(vec![AstNode::Add(2), AstNode::MoveRight, AstNode::Increment]);
+        engine.run(&program).unwrap();
+        assert_eq!(engine.name(), "tree");
+        assert_eq!(engine.pointer(), 1);
+        assert_eq!(&engine.memory()[..2], &[2, 1]);
+        assert_eq!(engine.instruction_count(), 3);
+    }
+
+    #[test]
+    fn test_step_through_trait() {
+        // +[-] is +, [, - and the loop test: three counted instructions
+        let mut engine: Box<dyn Engine> = Box::new(Interpreter::new());
+        let program = AstNode::Program(vec![AstNode::Increment, AstNode::Loop(vec![AstNode::Decrement])]);
+        engine.start(&program).unwrap();
+        assert!(engine.step().unwrap());
+        assert_eq!(engine.memory()[0], 1);
+        assert!(engine.step().unwrap());
+        assert!(engine.step().unwrap());
+        assert_eq!(engine.memory()[0], 0);
+        assert!(!engine.step().unwrap());
+        assert!(!engine.step().unwrap());
+        assert_eq!(engine.instruction_count(), 3);
+    }
+}
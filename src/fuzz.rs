@@ -0,0 +1,124 @@
+//! the properties the fuzz targets in fuzz/ check, on any input: the parser
+//! never panics, optimized programs print as source that parses again, and
+//! optimizing never changes what a program does. with the arbitrary feature,
+//! tokens and syntax trees implement Arbitrary
+
+use crate::interpreter::{EofBehavior, Interpreter, RuntimeError};
+use crate::optimizer::Optimizer;
+use crate::parser::{self, AstNode};
+use crate::lexer::{self, Token};
+
+// instructions a run may take before it is cut off, so programs that never
+// halt don't stall the fuzzer
+pub const FUEL: usize = 100_000;
+
+// bad token streams are rejected with an error
+pub fn check_parse(tokens: &[Token]) {
+    let _ = parser::parse(tokens.to_vec());
+}
+
+// the optimized program, printed as Brainfuck, is a valid program
+pub fn check_reparse(ast: &AstNode) {
+    let source = Optimizer::new().optimize(ast).to_source();
+    let reparsed = lexer::tokenize(&source).and_then(parser::parse);
+    assert!(reparsed.is_ok(), "optimized program doesn't parse: {:?}", source);
+}
+
+// a run that finishes within FUEL leaves the same output, tape and pointer
+// optimized as not. runs that fail or are cut off aren't compared, as the
+// optimizer may drop moves that would have left the tape
+pub fn check_optimize(ast: &AstNode, input: &[u8]) {
+    let Ok(expected) = run(ast, input) else {
+        return;
+    };
+    let optimized = Optimizer::new().optimize(ast);
+    assert_eq!(run(&optimized, input), Ok(expected), "optimizing changed what {} does", ast);
+}
+
+fn run(ast: &AstNode, input: &[u8]) -> Result<(String, Vec<u32>, usize), RuntimeError> {
+    let mut interpreter = Interpreter::builder()
+        .eof_behavior(EofBehavior::Zero)
+        .max_instructions(Some(FUEL))
+        .input(input)
+        .build()
+        .expect("the default tape fits the memory limit");
+    interpreter.run_and_capture_output(ast)
+}
+
+// programs of up to this many instructions in a body, nested this deep
+#[cfg(feature = "arbitrary")]
+const MAX_BODY: u32 = 64;
+#[cfg(feature = "arbitrary")]
+const MAX_DEPTH: usize = 8;
+
+// an arbitrary program as the parser would produce it, made of the eight
+// standard commands
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for AstNode {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(AstNode::Program(arbitrary_body(u, 0)?))
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+fn arbitrary_body(u: &mut arbitrary::Unstructured, depth: usize) -> arbitrary::Result<Vec<AstNode>> {
+    use std::ops::ControlFlow;
+
+    let mut body = Vec::new();
+    u.arbitrary_loop(None, Some(MAX_BODY), |u| {
+        let kinds = if depth < MAX_DEPTH { 7 } else { 6 };
+        body.push(match u.choose_index(kinds)? {
+            0 => AstNode::Increment,
+            1 => AstNode::Decrement,
+            2 => AstNode::MoveRight,
+            3 => AstNode::MoveLeft,
+            4 => AstNode::Input,
+            5 => AstNode::Output,
+            _ => AstNode::Loop(arbitrary_body(u, depth + 1)?),
+        });
+        Ok(ControlFlow::Continue(()))
+    })?;
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> AstNode {
+        parser::parse(lexer::tokenize(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_checks() {
+        check_parse(&[Token::LoopEnd, Token::LoopStart]);
+        for source in ["++++[->+++<]>.", "+[>>+<<-]>>[-]<,[.,]", ">+[<]>.", "+[]", "<"] {
+            let ast = parse(source);
+            check_reparse(&ast);
+            check_optimize(&ast, b"ab");
+        }
+    }
+
+    // what the fuzz targets do, on a fixed set of inputs
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn test_arbitrary() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        let mut state = 0x2545_f491_u32;
+        for _ in 0..200 {
+            let bytes: Vec<u8> = (0..256)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                })
+                .collect();
+            let ast = AstNode::arbitrary(&mut Unstructured::new(&bytes)).unwrap();
+            check_reparse(&ast);
+            check_optimize(&ast, &bytes);
+            check_parse(&Vec::<Token>::arbitrary(&mut Unstructured::new(&bytes)).unwrap());
+        }
+    }
+}
@@ -3,344 +3,2046 @@
 // use std::hash::Hash;
 use std::vec::Vec;
 use crate::parser::AstNode;
-use std::collections::HashMap;
+use crate::engine::Observer;
+use crate::io::BfIo;
+use crate::linear::{self, Op};
+use crate::cost::CostModel;
+use crate::profile::{instruction_name, LoopEvent, Profile};
+use crate::stats::{CellAccess, ExecutionStats, LoopStats, Sample};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::time::{Instant, Duration};
+use std::io::Write;
+use std::fmt;
+use serde::{Serialize, Deserialize};
 
-pub fn interpret_with_state(ast: &AstNode) -> Result<(String, Vec<u8>, usize), String> {
-    let mut interpreter = Interpreter::new();
-    interpreter.run_and_capture_output(ast)
+// the classic 30k cells
+pub const DEFAULT_TAPE_SIZE: usize = 30000;
+
+// how many bytes block buffering collects before writing to stdout
+pub(crate) const OUTPUT_BLOCK_SIZE: usize = 8192;
+// default cap for a growable tape, 64 MiB
+pub(crate) const DEFAULT_MAX_MEMORY: usize = 64 * 1024 * 1024;
+// cells a lazy tape allocates at a time, see set_lazy_tape
+pub const TAPE_PAGE_SIZE: usize = 4096;
+// how deeply pbrain procedures may call each other before the run fails,
+// so unbounded recursion ends in an error instead of exhausting memory
+const MAX_CALL_DEPTH: usize = 100_000;
+// how many cells from the start of the tape the # debug command shows
+pub const DEBUG_DUMP_CELLS: usize = 10;
+// how many instructions run between looks at the cancel flag and control
+const CANCEL_CHECK_INTERVAL: usize = 1024;
+// how often a paused run looks whether it may go on
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// captured runs have no stdin behind them, so , reads 0 at EOF
+pub fn interpret_with_state(ast: &AstNode, tape_size: usize) -> Result<(String, Vec<u32>, usize), String> {
+    interpret_with_input(ast, &[], tape_size)
+}
+
+// like interpret_with_state, with , reading from `input`
+pub fn interpret_with_input(ast: &AstNode, input: &[u8], tape_size: usize) -> Result<(String, Vec<u32>, usize), String> {
+    let mut interpreter = Interpreter::with_tape_size(tape_size)?;
+    interpreter.set_eof_behavior(EofBehavior::Zero);
+    interpreter.provide_input(input);
+    Ok(interpreter.run_and_capture_output(ast)?)
 }
 
 pub struct Interpreter {
-    memory: Vec<u8>,     // Memory tape
+    memory: Vec<u32>,    // Memory tape, cells masked to cell_width
+    cell_width: CellWidth,
     pointer: usize,     // Data pointer
     tape_size: usize,    // 30k cells
+    peak_tape_size: usize, // the most cells the tape has had, see statistics
+    lazy_tape: bool, // memory only reaches as far as the pointer has, see set_lazy_tape
+    origin: usize,   // index of the cell runs start on, past 0 once the tape grew to the left
     debug: bool,
     pub instruction_count: usize, // # instructions executed
-    loop_depth: usize,        
+    loop_stack: Vec<LoopFrame>, // currently active loops, outermost first
+    position: usize,            // command index of the instruction being executed
     step_by_step: bool,
-    instruction_times: HashMap<String, Duration>,
-    instruction_counts: HashMap<String, usize>,
+    pause_below: Option<usize>, // stepping doesn't stop while the loop depth is at least this
+    profile: Profile, // count and time of every instruction, see set_record_timeline
     loop_iterations: HashMap<usize, usize>, // loop_depth -> iteration count
+    loop_totals: HashMap<usize, LoopTotals>, // position of a loop's [ -> what it cost
+    loop_entered: Vec<(usize, Option<Instant>)>, // instruction count and time each active loop began at
     start_time: Option<Instant>,
     breakpoints: Breakpoints,
+    output_buffering: OutputBuffering,
+    pending_output: Vec<u8>, // bytes written by . but not yet flushed
+    pending_count: usize,   // number of . executions in pending_output
+    warn_on_eof: bool,
+    persistent: bool, // keep tape and pointer between runs
+    tape_policy: TapePolicy,
+    max_memory: usize, // upper bound in bytes for a growable tape
+    observer: Option<Box<dyn Observer>>,
+    input_buffer: VecDeque<u8>, // bytes , reads before falling back to stdin
+    eof_behavior: EofBehavior,
+    output_sink: Option<Box<dyn Write + Send>>, // where run() sends output, stdout if None
+    io: Option<Box<dyn BfIo>>, // handles , and . in run() instead of stdin/stdout
+    session: Option<Session>,  // program being stepped through, see start()
+    optimize: bool,            // run the optimizer on programs before executing them
+    partial_evaluation: bool,  // and let it fold their input-free start, see set_partial_evaluation
+    procedures: HashMap<u32, usize>, // pbrain procedure number -> first instruction of its body
+    call_stack: Vec<usize>,          // where each running procedure returns to
+    dumps: Vec<TapeDump>,            // taken by # in captured runs
+    max_instructions: Option<usize>, // per run, see set_max_instructions
+    max_output: Option<usize>,       // bytes per run, see set_max_output
+    run_instructions: usize,         // instructions executed by the current run
+    run_output: usize,               // bytes written by the current run
+    partial_output: Vec<u8>,         // what a failed captured run wrote, see partial_output
+    cancel: Option<Arc<AtomicBool>>, // stops the run when set, see set_cancel_flag
+    control: Option<RunControl>,     // see set_control
+    progress: Option<Progress>,      // see set_on_progress
+    trace: Option<Box<dyn Write + Send>>, // gets a line per instruction, see set_trace
+    heatmap: Option<Vec<(usize, usize)>>, // reads and writes per cell, see set_track_heatmap
+    history: VecDeque<ExecutedInstruction>, // the last history_size instructions executed
+    history_size: usize,
+    sample_interval: Option<usize>, // see set_sample_interval
+    samples: HashMap<usize, (String, usize)>, // position -> instruction and times sampled
+    command_counts: Option<Vec<usize>>, // times each position was reached, see set_count_commands
+    cost_model: Option<CostModel>, // see set_cost_model
+    cycles: usize,                 // what the instructions so far cost by cost_model
+    max_pointer: usize,            // the highest cell the pointer has been at
+    written: Vec<bool>,            // which cells have been stored to, up to the last one
+}
+
+// called with the instructions executed so far and the time since the
+// first run started, see set_on_progress
+pub type ProgressHook = Box<dyn FnMut(usize, Duration) + Send>;
+
+struct Progress {
+    every: usize, // instructions of a run between calls
+    hook: ProgressHook,
+}
+
+// lets a host application pause, resume or cancel a run from another
+// thread, see set_control. clones share one control word, an AtomicU8
+#[derive(Debug, Clone, Default)]
+pub struct RunControl(Arc<AtomicU8>);
+
+const RUNNING: u8 = 0;
+const PAUSED: u8 = 1;
+const CANCELLED: u8 = 2;
+
+impl RunControl {
+    pub fn new() -> Self {
+        RunControl::default()
+    }
+
+    // a run that is cancelled stays cancelled, pausing it does nothing
+    pub fn pause(&self) {
+        let _ = self.0.compare_exchange(RUNNING, PAUSED, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        let _ = self.0.compare_exchange(PAUSED, RUNNING, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(CANCELLED, Ordering::Relaxed);
+    }
+
+    // back to running, e.g. to use the control again after a cancel
+    pub fn reset(&self) {
+        self.0.store(RUNNING, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == PAUSED
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == CANCELLED
+    }
+}
+
+// flattened code and the next instruction for start()/step()
+struct Session {
+    code: Vec<linear::Instruction>,
+    pc: usize,
+}
+
+// adapts a closure to io::Write, e.g. to stream output to a UI
+pub struct CallbackWriter<F: FnMut(&[u8])>(pub F);
+
+impl<F: FnMut(&[u8])> Write for CallbackWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        (self.0)(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// what , stores when there is no input left
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EofBehavior {
+    Unchanged, // leave the cell as it was
+    Zero,
+    MaxValue, // -1 in the cell width, 255 for 8-bit cells
+}
+
+impl EofBehavior {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unchanged" => Some(EofBehavior::Unchanged),
+            "zero" | "0" => Some(EofBehavior::Zero),
+            "max" | "255" | "-1" => Some(EofBehavior::MaxValue),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for EofBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EofBehavior::Unchanged => write!(f, "cell left unchanged"),
+            EofBehavior::Zero => write!(f, "cell set to 0"),
+            EofBehavior::MaxValue => write!(f, "cell set to -1"),
+        }
+    }
+}
+
+// what happens when the pointer moves off either end of the tape
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TapePolicy {
+    Abort, // fail with "Pointer out of bounds"
+    Wrap,  // continue from the other end
+    Grow,  // extend the tape to the right, up to the memory limit. < at 0 still fails
+    // extend it in both directions, so < at 0 adds cells before the first.
+    // memory() then starts with them, see Interpreter::origin
+    GrowBoth,
+}
+
+// how many bits each tape cell holds, arithmetic wraps at this width
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CellWidth {
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            8 => Some(CellWidth::U8),
+            16 => Some(CellWidth::U16),
+            32 => Some(CellWidth::U32),
+            _ => None,
+        }
+    }
+
+    pub fn mask(self) -> u32 {
+        match self {
+            CellWidth::U8 => 0xFF,
+            CellWidth::U16 => 0xFFFF,
+            CellWidth::U32 => 0xFFFF_FFFF,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            CellWidth::U8 => 1,
+            CellWidth::U16 => 2,
+            CellWidth::U32 => 4,
+        }
+    }
+}
+
+// what went wrong during execution
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeErrorKind {
+    PointerOutOfBounds,
+    MemoryLimitExceeded(usize), // the configured limit in bytes
+    InvalidInstruction,
+    ExpectedProgram,
+    Input(String),
+    Output(String),
+    Compile(String), // the jit engine failed to generate code
+    UnknownProcedure(u32), // : called a procedure number that was never defined
+    CallDepthExceeded(usize), // the limit on nested procedure calls
+    InstructionLimitExceeded(usize), // the limit set with set_max_instructions
+    OutputLimitExceeded(usize), // the limit in bytes set with set_max_output
+    Cancelled,       // the flag from set_cancel_flag was raised, or the control cancelled
+    Trace(String),   // writing to the trace from set_trace failed
+    Terminated,      // the user quit at a breakpoint
+}
+
+// a runtime error together with where in the program it happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub position: usize,          // command index of the failing instruction
+    pub instruction_count: usize, // instructions executed, including the failing one
+    pub pointer: usize,
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeErrorKind::PointerOutOfBounds => write!(f, "Pointer out of bounds"),
+            RuntimeErrorKind::MemoryLimitExceeded(limit) => {
+                write!(f, "Memory limit exceeded: tape cannot grow past {} bytes", limit)
+            }
+            RuntimeErrorKind::InvalidInstruction => write!(f, "Invalid instruction"),
+            RuntimeErrorKind::ExpectedProgram => write!(f, "Expected program node"),
+            RuntimeErrorKind::Input(e) => write!(f, "Failed to read input: {}", e),
+            RuntimeErrorKind::Output(e) => write!(f, "Failed to write output: {}", e),
+            RuntimeErrorKind::Compile(e) => write!(f, "Failed to compile: {}", e),
+            RuntimeErrorKind::UnknownProcedure(number) => write!(f, "Unknown procedure {}", number),
+            RuntimeErrorKind::CallDepthExceeded(limit) => {
+                write!(f, "Procedure calls nested more than {} deep", limit)
+            }
+            RuntimeErrorKind::InstructionLimitExceeded(limit) => {
+                write!(f, "Instruction limit exceeded: stopped after {} instructions", limit)
+            }
+            RuntimeErrorKind::OutputLimitExceeded(limit) => {
+                write!(f, "Output limit exceeded: stopped after {} bytes", limit)
+            }
+            RuntimeErrorKind::Cancelled => write!(f, "Execution cancelled"),
+            RuntimeErrorKind::Trace(e) => write!(f, "Failed to write trace: {}", e),
+            RuntimeErrorKind::Terminated => write!(f, "Execution terminated by user"),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at command {} (instruction {}, pointer {})",
+            self.kind, self.position, self.instruction_count, self.pointer
+        )
+    }
+}
+
+impl From<RuntimeError> for String {
+    fn from(error: RuntimeError) -> String {
+        error.to_string()
+    }
+}
+
+// controls when output from . reaches stdout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputBuffering {
+    Unbuffered,   // flush after every byte, for interactive programs
+    Line,         // flush on newline
+    Block,        // flush when the buffer fills or the program ends
+    Every(usize), // flush after every n bytes
+}
+
+// one entry in the loop "call stack"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoopFrame {
+    pub start: usize,      // command index of the loop's [ in the source
+    pub iterations: usize, // iterations entered so far
+    pub cell: usize,       // cell the loop condition was last tested on
+}
+
+// what the # debug command saw: the pointer and the first cells of the tape
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TapeDump {
+    pub pointer: usize,
+    pub cells: Vec<u32>,
+}
+
+impl fmt::Display for TapeDump {
+    // the same format generated programs print
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pointer {}: {:?}", self.pointer, self.cells)
+    }
+}
+
+// what one loop has cost so far, see hot_loops
+#[derive(Debug, Clone, Copy, Default)]
+struct LoopTotals {
+    entries: usize,
+    iterations: usize,
+    instructions: usize, // executed from its [ to leaving it, nested loops included
+    time: Duration,
+}
+
+// busiest first, ties in program order
+fn sorted_samples(samples: &HashMap<usize, (String, usize)>) -> Vec<Sample> {
+    let mut sorted: Vec<Sample> = samples
+        .iter()
+        .map(|(&position, (instruction, count))| Sample { position, instruction: instruction.clone(), count: *count })
+        .collect();
+    sorted.sort_by_key(|sample| (std::cmp::Reverse(sample.count), sample.position));
+    sorted
+}
+
+// an instruction in the history of recent ones, see set_history_size
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExecutedInstruction {
+    pub position: usize,     // command index in the source
+    pub instruction: String, // named like stats and traces do
+    pub pointer: usize,      // before it ran
+    pub value: u32,          // the cell at the pointer before it ran
+}
+
+// where Interpreter::run_for left the program
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunStatus {
+    // it used up its instructions, call run_for again to go on
+    Running,
+    Done,
+    // it failed, the next run_for starts it over
+    Error(RuntimeError),
+}
+
+// one instruction executed by steps(), with what it did to the cell under
+// the pointer
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepEvent {
+    pub pc: usize,       // index of the instruction in the flattened code
+    pub position: usize, // command index in the source
+    // the instruction, a loop or procedure definition without its body and
+    // a call as ProcCall
+    pub node: AstNode,
+    pub pointer: usize,     // before it ran, a move's target is the next event's
+    pub cell_before: u32,   // the cell at `pointer`
+    pub cell_after: u32,
+    pub output: Option<u8>, // what . wrote
+}
+
+// the execution of a program one instruction at a time, see
+// Interpreter::steps. it ends after the last instruction or the first error
+pub struct Steps<'a> {
+    interpreter: &'a mut Interpreter,
+}
+
+impl Iterator for Steps<'_> {
+    type Item = Result<StepEvent, RuntimeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.interpreter.step_event()
+    }
+}
+
+// everything needed to put an interpreter back where it was, see snapshot()
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InterpreterState {
+    pub memory: Vec<u32>,
+    pub pointer: usize,
+    pub instruction_count: usize,
+    pub position: usize,
+    pub cell_width: CellWidth,
+    pub loop_stack: Vec<LoopFrame>,
+}
+
+impl InterpreterState {
+    pub fn loop_depth(&self) -> usize {
+        self.loop_stack.len()
+    }
 }
 
 pub struct Breakpoints {
     instruction_count: Option<usize>,
-    memory_value: Option<u8>,
+    memory_value: Option<u32>,
     loop_depth: Option<usize>,
+    positions: BTreeSet<usize>, // commands to stop before
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// collects an interpreter's configuration up front, e.g.
+// Interpreter::builder().tape_size(1000).eof_behavior(EofBehavior::Zero).build().
+// anything left out keeps the default of Interpreter::new, and the setters
+// are still there for what changes between runs
+pub struct InterpreterBuilder {
+    interpreter: Interpreter,
+    // applied last, as it is checked against the memory limit
+    tape_size: Option<usize>,
+}
+
+impl InterpreterBuilder {
+    pub fn tape_size(mut self, cells: usize) -> Self {
+        self.tape_size = Some(cells);
+        self
+    }
+
+    pub fn cell_width(mut self, cell_width: CellWidth) -> Self {
+        self.interpreter.set_cell_width(cell_width);
+        self
+    }
+
+    pub fn eof_behavior(mut self, eof_behavior: EofBehavior) -> Self {
+        self.interpreter.set_eof_behavior(eof_behavior);
+        self
+    }
+
+    pub fn tape_policy(mut self, tape_policy: TapePolicy) -> Self {
+        self.interpreter.set_tape_policy(tape_policy);
+        self
+    }
+
+    pub fn lazy_tape(mut self, lazy: bool) -> Self {
+        self.interpreter.set_lazy_tape(lazy);
+        self
+    }
+
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.interpreter.set_max_memory(bytes);
+        self
+    }
+
+    pub fn max_instructions(mut self, limit: Option<usize>) -> Self {
+        self.interpreter.set_max_instructions(limit);
+        self
+    }
+
+    pub fn max_output(mut self, limit: Option<usize>) -> Self {
+        self.interpreter.set_max_output(limit);
+        self
+    }
+
+    pub fn cancel_flag(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.interpreter.set_cancel_flag(Some(cancel));
+        self
+    }
+
+    pub fn control(mut self, control: RunControl) -> Self {
+        self.interpreter.set_control(Some(control));
+        self
+    }
+
+    pub fn optimize(mut self, optimize: bool) -> Self {
+        self.interpreter.set_optimize(optimize);
+        self
+    }
+
+    pub fn partial_evaluation(mut self, enabled: bool) -> Self {
+        self.interpreter.set_partial_evaluation(enabled);
+        self
+    }
+
+    // bytes for , to read before the I/O or stdin
+    pub fn input(mut self, bytes: &[u8]) -> Self {
+        self.interpreter.provide_input(bytes);
+        self
+    }
+
+    pub fn io(mut self, io: Box<dyn BfIo>) -> Self {
+        self.interpreter.set_io(Some(io));
+        self
+    }
+
+    pub fn output_sink(mut self, sink: Box<dyn Write + Send>) -> Self {
+        self.interpreter.set_output_sink(Some(sink));
+        self
+    }
+
+    pub fn output_buffering(mut self, buffering: OutputBuffering) -> Self {
+        self.interpreter.set_output_buffering(buffering);
+        self
+    }
+
+    pub fn warn_on_eof(mut self, warn: bool) -> Self {
+        self.interpreter.set_warn_on_eof(warn);
+        self
+    }
+
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.interpreter.set_debug(debug);
+        self
+    }
+
+    pub fn step_by_step(mut self, enabled: bool) -> Self {
+        self.interpreter.set_step_by_step(enabled);
+        self
+    }
+
+    pub fn trace(mut self, trace: Box<dyn Write + Send>) -> Self {
+        self.interpreter.set_trace(Some(trace));
+        self
+    }
+
+    pub fn history_size(mut self, size: usize) -> Self {
+        self.interpreter.set_history_size(size);
+        self
+    }
+
+    // fails if the tape size is 0 or over the memory limit
+    pub fn build(mut self) -> Result<Interpreter, String> {
+        if let Some(cells) = self.tape_size {
+            self.interpreter.set_tape_size(cells)?;
+        }
+        Ok(self.interpreter)
+    }
 }
 
 impl Interpreter {
 
     pub fn new() -> Self {
-        const DEFAULT_TAPE_SIZE: usize = 30000;
         Interpreter {
             memory: vec![0; DEFAULT_TAPE_SIZE],
+            cell_width: CellWidth::U8,
             pointer: 0,
             tape_size: DEFAULT_TAPE_SIZE,
+            peak_tape_size: 0,
+            lazy_tape: false,
+            origin: 0,
             debug: false, 
             instruction_count: 0,
-            loop_depth: 0,
+            loop_stack: Vec::new(),
+            position: 0,
             step_by_step: false,
-            instruction_times: HashMap::new(),
-            instruction_counts: HashMap::new(),
+            pause_below: None,
+            profile: Profile::default(),
             loop_iterations: HashMap::new(),
+            loop_totals: HashMap::new(),
+            loop_entered: Vec::new(),
             start_time: None,
             breakpoints: Breakpoints {
                 instruction_count: None,
                 memory_value: None,
                 loop_depth: None,
+                positions: BTreeSet::new(),
             },
+            output_buffering: OutputBuffering::Line,
+            pending_output: Vec::new(),
+            pending_count: 0,
+            warn_on_eof: false,
+            persistent: false,
+            tape_policy: TapePolicy::Abort,
+            max_memory: DEFAULT_MAX_MEMORY,
+            observer: None,
+            input_buffer: VecDeque::new(),
+            eof_behavior: EofBehavior::Unchanged,
+            output_sink: None,
+            io: None,
+            session: None,
+            optimize: false,
+            partial_evaluation: false,
+            procedures: HashMap::new(),
+            call_stack: Vec::new(),
+            dumps: Vec::new(),
+            max_instructions: None,
+            max_output: None,
+            run_instructions: 0,
+            run_output: 0,
+            partial_output: Vec::new(),
+            cancel: None,
+            control: None,
+            progress: None,
+            trace: None,
+            heatmap: None,
+            history: VecDeque::new(),
+            history_size: 0,
+            sample_interval: None,
+            samples: HashMap::new(),
+            command_counts: None,
+            cost_model: None,
+            cycles: 0,
+            max_pointer: 0,
+            written: Vec::new(),
+        }
+    }
+
+    pub fn builder() -> InterpreterBuilder {
+        InterpreterBuilder { interpreter: Interpreter::new(), tape_size: None }
+    }
+
+    pub fn with_tape_size(tape_size: usize) -> Result<Self, String> {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_tape_size(tape_size)?;
+        Ok(interpreter)
+    }
+
+    // resizes the tape, keeping existing cells that still fit
+    pub fn set_tape_size(&mut self, tape_size: usize) -> Result<(), String> {
+        if tape_size == 0 {
+            return Err("Tape size must be at least 1 cell".to_string());
+        }
+        if tape_size > self.max_cells() {
+            return Err(format!("Tape size {} exceeds the memory limit of {} bytes", tape_size, self.max_memory));
+        }
+        self.tape_size = tape_size;
+        self.memory.truncate(tape_size);
+        self.pointer = self.pointer.min(tape_size - 1);
+        if !self.lazy_tape {
+            self.memory.resize(tape_size, 0);
+        }
+        Ok(())
+    }
+
+    pub fn tape_size(&self) -> usize {
+        self.tape_size
+    }
+
+    // allocates the tape TAPE_PAGE_SIZE cells at a time as the pointer gets
+    // to them, instead of all at once, so a huge tape costs only what the
+    // program uses. memory() then ends after the last page allocated, and
+    // the cells past it are all zero. a program that moves far to the right
+    // still pays for every page up to there
+    pub fn set_lazy_tape(&mut self, lazy: bool) {
+        self.lazy_tape = lazy;
+        if lazy {
+            // zeros at the end are the same as pages never allocated
+            let used = self.memory.iter().rposition(|&cell| cell != 0).map_or(0, |last| last + 1);
+            self.memory.truncate(used.max(self.pointer + 1).next_multiple_of(TAPE_PAGE_SIZE).min(self.tape_size));
+        } else {
+            self.memory.resize(self.tape_size, 0);
+        }
+    }
+
+    // makes sure the cell at `index`, which is on the tape, is allocated
+    fn allocate(&mut self, index: usize) {
+        if index >= self.memory.len() {
+            let size = (index + 1).next_multiple_of(TAPE_PAGE_SIZE).min(self.tape_size);
+            self.memory.resize(size, 0);
+            self.peak_tape_size = self.peak_tape_size.max(size);
         }
     }
 
+    // switching to a narrower width truncates existing cells
+    pub fn set_cell_width(&mut self, cell_width: CellWidth) {
+        self.cell_width = cell_width;
+        let mask = cell_width.mask();
+        self.memory.iter_mut().for_each(|cell| *cell &= mask);
+    }
+
+    pub fn cell_width(&self) -> CellWidth {
+        self.cell_width
+    }
+
+    // how many cells of the current width fit in the memory limit
+    fn max_cells(&self) -> usize {
+        self.max_memory / self.cell_width.bytes()
+    }
+
+    fn add_to_cell(&mut self, n: u32) {
+        let cell = &mut self.memory[self.pointer];
+        *cell = cell.wrapping_add(n) & self.cell_width.mask();
+    }
+
+    fn sub_from_cell(&mut self, n: u32) {
+        let cell = &mut self.memory[self.pointer];
+        *cell = cell.wrapping_sub(n) & self.cell_width.mask();
+    }
+
+    // . writes the low 8 bits of the current cell, whatever the cell width
+    fn output_byte(&self) -> u8 {
+        (self.memory[self.pointer] & 0xFF) as u8
+    }
+
     // ==================== WEBASSEMBLY IMPLEMENTATIONS ============================
 
-    pub fn run_and_capture_output(&mut self, ast: &crate::parser::AstNode) -> Result<(String, Vec<u8>, usize), String> {
-        let mut output = String::new();
-        
+    // the output as text, each byte taken as the char with that code point
+    // (Latin-1), so a byte is never lost but UTF-8 text isn't decoded. see
+    // run_and_capture_bytes for the bytes themselves
+    pub fn run_and_capture_output(&mut self, ast: &crate::parser::AstNode) -> Result<(String, Vec<u32>, usize), RuntimeError> {
+        let (output, memory, pointer) = self.run_and_capture_bytes(ast)?;
+        Ok((crate::io::OutputEncoding::Latin1.decode(&output), memory, pointer))
+    }
+
+    // the output exactly as . wrote it, for programs that write binary data
+    pub fn run_and_capture_bytes(&mut self, ast: &crate::parser::AstNode) -> Result<(Vec<u8>, Vec<u32>, usize), RuntimeError> {
+        let mut output = Vec::new();
+
         match ast {
             crate::parser::AstNode::Program(instructions) => {
-                for inst in instructions {
-                    self.execute_instruction_capture(&mut output, inst)?;
+                self.begin_run();
+                let code = self.compile(instructions);
+                if let Err(e) = self.execute(&code, Some(&mut output)) {
+                    self.partial_output = output;
+                    return Err(e);
                 }
                 Ok((output, self.memory.clone(), self.pointer))
             },
-            _ => Err("Expected program node".to_string())
+            _ => Err(self.error(RuntimeErrorKind::ExpectedProgram))
         }
     }
 
     // New execute method that captures output
-    fn execute_instruction_capture(&mut self, output: &mut String, instruction: &AstNode) -> Result<(), String> {
+    fn execute_instruction_capture(&mut self, output: &mut Vec<u8>, instruction: &AstNode) -> Result<(), RuntimeError> {
         self.instruction_count += 1;
+        self.notify_observer(instruction);
         self.debug_step(instruction);
         
-        let start = Instant::now();
+        let start = self.sample_interval.is_none().then(Instant::now);
     
         let result = match instruction {
             AstNode::Output => {
-                output.push(self.memory[self.pointer] as char);
+                output.push(self.output_byte());
                 Ok(())
             },
-            AstNode::Loop(instructions) => {
-                self.loop_depth += 1;
-                let mut loop_count = 0;
-                
-                while self.memory[self.pointer] != 0 {
-                    loop_count += 1;
-                    for instruction in instructions {
-                        self.execute_instruction_capture(output, instruction)?;
-                    }
-                }
-                
-                *self.loop_iterations.entry(self.loop_depth).or_insert(0) += loop_count;
-                self.loop_depth -= 1;
+            AstNode::Debug => {
+                let dump = self.dump();
+                self.dumps.push(dump);
+                Ok(())
+            },
+            AstNode::Loop(_) => {
+                self.enter_loop();
                 Ok(())
             },
             AstNode::Increment => {
-                self.memory[self.pointer] = self.memory[self.pointer].wrapping_add(1);
+                self.add_to_cell(1);
                 Ok(())
             },
             AstNode::Decrement => {
-                self.memory[self.pointer] = self.memory[self.pointer].wrapping_sub(1);
+                self.sub_from_cell(1);
                 Ok(())
             },
             AstNode::Add(n) => {
-                self.memory[self.pointer] = self.memory[self.pointer].wrapping_add(*n as u8);
+                self.add_to_cell(*n as u32);
                 Ok(())
             },
             AstNode::Sub(n) => {
-                self.memory[self.pointer] = self.memory[self.pointer].wrapping_sub(*n as u8);
-                Ok(())
-            },
-            AstNode::MoveRight => {
-                if self.pointer + 1 >= self.tape_size {
-                    return Err("Pointer out of bounds".to_string());
-                }
-                self.pointer += 1;
+                self.sub_from_cell(*n as u32);
                 Ok(())
             },
-            AstNode::MoveLeft => {
-                if self.pointer == 0 {
-                    return Err("Pointer out of bounds".to_string());
-                }
-                self.pointer -= 1;
+            AstNode::Set(value) => {
+                self.memory[self.pointer] = *value as u32 & self.cell_width.mask();
                 Ok(())
             },
+            AstNode::MoveRight => self.move_right(),
+            AstNode::MoveLeft => self.move_left(),
+            AstNode::Move(n) => self.move_by(*n),
+            AstNode::MulCopy { targets } => self.mul_copy(targets),
+            AstNode::Swap { offset, temp } => self.swap(*offset, *temp),
+            AstNode::ScanRight(stride) => self.scan(*stride as isize),
+            AstNode::ScanLeft(stride) => self.scan(-(*stride as isize)),
             AstNode::Input => {
-                self.memory[self.pointer] = 0;
+                // captured runs have no stdin, only input queued with provide_input
+                match self.input_buffer.pop_front() {
+                    Some(byte) => self.memory[self.pointer] = byte as u32,
+                    None => self.apply_eof(),
+                }
                 Ok(())
             },
-            _ => Err("Invalid instruction".to_string()),
+            // the jumps are done by step_at
+            AstNode::ProcDef(_) | AstNode::ProcCall | AstNode::Comment(_) => Ok(()),
+            _ => Err(self.error(RuntimeErrorKind::InvalidInstruction)),
         };
 
-        let duration = start.elapsed();
-        self.record_instruction(instruction, duration);
-        
+        self.record_instruction(instruction, start);
         result
     }
 
-    pub fn interpret_with_state(ast: &AstNode) -> Result<(String, Vec<u8>, usize), String> {
-        let mut interpreter = Interpreter::new();
-        interpreter.run_and_capture_output(ast)
+    pub fn interpret_with_state(ast: &AstNode, tape_size: usize) -> Result<(String, Vec<u32>, usize), String> {
+        interpret_with_state(ast, tape_size)
     }
 
-    // ==================== BREAKPOINT IMPLEMENTATION FUNCTIONS ====================
+    // ==================== SESSION STATE ==========================================
 
-    pub fn set_instruction_breakpoint(&mut self, count: usize) {
-        self.breakpoints.instruction_count = Some(count);
+    pub fn set_observer(&mut self, observer: Option<Box<dyn Observer>>) {
+        self.observer = observer;
     }
 
-    pub fn set_memory_breakpoint(&mut self, value: u8) {
-        self.breakpoints.memory_value = Some(value);
+    fn notify_observer(&mut self, instruction: &AstNode) {
+        if let Some(observer) = self.observer.as_mut() {
+            observer.on_instruction(instruction, self.pointer, &self.memory);
+        }
     }
 
-    pub fn set_loop_breakpoint(&mut self, depth: usize) {
-        self.breakpoints.loop_depth = Some(depth);
+    // builds an error describing the instruction currently executing
+    fn error(&self, kind: RuntimeErrorKind) -> RuntimeError {
+        RuntimeError {
+            kind,
+            position: self.position,
+            instruction_count: self.instruction_count,
+            pointer: self.pointer,
+        }
     }
 
-    fn check_breakpoints(&self) -> bool {
-        // check if any breakpoint condition is met
-        if let Some(count) = self.breakpoints.instruction_count {
-            if self.instruction_count == count {
-                println!("\nBreakpoint hit: Instruction count = {}", count);
-                return true;
-            }
-        }
+    // when enabled, successive runs continue on the same tape and pointer
+    // instead of starting fresh, e.g. for a REPL or notebook
+    pub fn set_persistent(&mut self, persistent: bool) {
+        self.persistent = persistent;
+    }
 
-        if let Some(value) = self.breakpoints.memory_value {
-            if self.memory[self.pointer] == value {
-                println!("\nBreakpoint hit: Memory value = {}", value);
-                return true;
-            }
+    // clears the tape and moves the pointer back to cell 0
+    pub fn reset(&mut self) {
+        self.memory.iter_mut().for_each(|cell| *cell = 0);
+        self.pointer = 0;
+        self.origin = 0;
+    }
+
+    // captures the tape, pointer and progress, e.g. for undo. a persistent
+    // interpreter restored from it continues on the same tape and pointer, but
+    // every run starts its program from the top, so position and loop_stack
+    // only describe where the snapshot was taken
+    pub fn snapshot(&self) -> InterpreterState {
+        InterpreterState {
+            memory: self.memory.clone(),
+            pointer: self.pointer,
+            instruction_count: self.instruction_count,
+            position: self.position,
+            cell_width: self.cell_width,
+            loop_stack: self.loop_stack.clone(),
         }
+    }
 
-        if let Some(depth) = self.breakpoints.loop_depth {
-            if self.loop_depth == depth {
-                println!("\nBreakpoint hit: Loop depth = {}", depth);
-                return true;
-            }
+    // puts the interpreter back to `state`. runs start from it only when persistent.
+    // states that couldn't come from snapshot(), e.g. edited or deserialized
+    // ones with the pointer off the tape, are rejected and nothing changes
+    pub fn restore(&mut self, state: &InterpreterState) -> Result<(), String> {
+        let tape_size = state.memory.len();
+        if tape_size == 0 {
+            return Err("Invalid state: the tape is empty".to_string());
+        }
+        if state.pointer >= tape_size {
+            return Err(format!("Invalid state: pointer {} is outside the tape of {} cells", state.pointer, tape_size));
+        }
+        if let Some(frame) = state.loop_stack.iter().find(|frame| frame.cell >= tape_size) {
+            return Err(format!("Invalid state: loop cell {} is outside the tape of {} cells", frame.cell, tape_size));
+        }
+        if let Some(cell) = state.memory.iter().find(|&&cell| cell > state.cell_width.mask()) {
+            return Err(format!("Invalid state: cell value {} does not fit in {} bits", cell, state.cell_width.bytes() * 8));
         }
 
-        false
+        self.memory = state.memory.clone();
+        // a lazy tape's snapshot stops at its last page
+        self.tape_size = if self.lazy_tape { self.tape_size.max(tape_size) } else { tape_size };
+        self.pointer = state.pointer;
+        self.instruction_count = state.instruction_count;
+        self.position = state.position;
+        self.cell_width = state.cell_width;
+        self.loop_stack = state.loop_stack.clone();
+        Ok(())
     }
 
-    // ================================== Stats Implementations ===========================================
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
 
-    fn record_instruction(&mut self, instruction: &AstNode, duration: Duration) {
-        let instruction_type = format!("{:?}", instruction);
-        *self.instruction_counts.entry(instruction_type.clone()).or_insert(0) += 1;
-        *self.instruction_times.entry(instruction_type).or_insert(Duration::new(0, 0)) += duration;
+    // the dumps taken by # during run_and_capture_output, oldest first. run()
+    // prints them to stderr instead
+    pub fn dumps(&self) -> &[TapeDump] {
+        &self.dumps
     }
 
-    pub fn print_statistics(&self) {
-        println!("\nExecution Statistics:");
-        println!("Total instructions executed: {}", self.instruction_count);
-        
-        println!("\nInstruction counts:");
-        for (instruction, count) in &self.instruction_counts {
-            println!("{}: {} times", instruction, count);
-        }
+    fn dump(&self) -> TapeDump {
+        let end = DEBUG_DUMP_CELLS.min(self.memory.len());
+        TapeDump { pointer: self.pointer, cells: self.memory[..end].to_vec() }
+    }
 
-        println!("\nInstruction times:");
-        for (instruction, time) in &self.instruction_times {
-            println!("{}: {:?}", instruction, time);
-        }
+    pub fn memory(&self) -> &[u32] {
+        &self.memory
+    }
 
-        println!("\nLoop statistics:");
-        for (depth, iterations) in &self.loop_iterations {
-            println!("Loop at depth {}: {} iterations", depth, iterations);
-        }
+    // the index in memory() of the cell the run started on. only more than
+    // 0 once a TapePolicy::GrowBoth tape has grown to the left, which moves
+    // every cell, and the pointer, along by the cells added
+    pub fn origin(&self) -> usize {
+        self.origin
     }
 
-    pub fn set_step_by_step(&mut self, enabled: bool) {
-        self.step_by_step = enabled;
+    pub fn set_tape_policy(&mut self, tape_policy: TapePolicy) {
+        self.tape_policy = tape_policy;
     }
 
-    fn debug_step(&self, instruction: &AstNode) {
-        if self.debug {
-            println!("\nStep {}:", self.instruction_count);
-            println!("Loop depth: {}", self.loop_depth);
-            println!("Executing: {:?}", instruction);
-            println!("Pointer: {}", self.pointer);
-            println!("Memory around pointer: {:?}", self.get_memory_window());
-            
-            if self.step_by_step {
-                println!("\nPress Enter to continue...");
-                let mut input = String::new();
-                std::io::stdin().read_line(&mut input).unwrap();
-            }
-        }
+    pub fn set_max_memory(&mut self, bytes: usize) {
+        self.max_memory = bytes;
+    }
+
+    fn move_right(&mut self) -> Result<(), RuntimeError> {
+        if self.pointer + 1 >= self.tape_size {
+            match self.tape_policy {
+                TapePolicy::Abort => return Err(self.error(RuntimeErrorKind::PointerOutOfBounds)),
+                TapePolicy::Wrap => {
+                    self.pointer = 0;
+                    return Ok(());
+                }
+                TapePolicy::Grow | TapePolicy::GrowBoth => self.grow_tape()?,
+            }
+        }
+        self.pointer += 1;
+        self.allocate(self.pointer);
+        Ok(())
+    }
+
+    fn move_left(&mut self) -> Result<(), RuntimeError> {
+        if self.pointer == 0 {
+            match self.tape_policy {
+                TapePolicy::Wrap => {
+                    self.pointer = self.tape_size;
+                    self.allocate(self.tape_size - 1);
+                }
+                TapePolicy::GrowBoth => {
+                    self.grow_left(1)?;
+                }
+                _ => return Err(self.error(RuntimeErrorKind::PointerOutOfBounds)),
+            }
+        }
+        self.pointer -= 1;
+        Ok(())
+    }
+
+    // moves n cells in one step, following the tape policy past either end
+    fn move_by(&mut self, n: isize) -> Result<(), RuntimeError> {
+        self.pointer = self.cell_at(n)?;
+        Ok(())
+    }
+
+    // index of the cell `offset` away from the pointer, following the tape policy
+    fn cell_at(&mut self, offset: isize) -> Result<usize, RuntimeError> {
+        let target = self.pointer as isize + offset;
+        if target >= 0 && (target as usize) < self.memory.len() {
+            return Ok(target as usize);
+        }
+        let index = match self.tape_policy {
+            _ if target >= 0 && (target as usize) < self.tape_size => target as usize,
+            TapePolicy::Wrap => target.rem_euclid(self.tape_size as isize) as usize,
+            TapePolicy::GrowBoth if target < 0 => {
+                let added = self.grow_left(target.unsigned_abs())?;
+                (target + added as isize) as usize
+            }
+            TapePolicy::Grow | TapePolicy::GrowBoth if target > 0 => {
+                while target as usize >= self.tape_size {
+                    self.grow_tape()?;
+                }
+                target as usize
+            }
+            _ => return Err(self.error(RuntimeErrorKind::PointerOutOfBounds)),
+        };
+        self.allocate(index);
+        Ok(index)
+    }
+
+    // does what [>] or [<] would: moves by `stride` until the cell is zero
+    fn scan(&mut self, stride: isize) -> Result<(), RuntimeError> {
+        let step = stride.unsigned_abs();
+        let found = if stride > 0 {
+            self.memory[self.pointer..].iter().step_by(step).position(|&cell| cell == 0)
+        } else {
+            self.memory[..=self.pointer].iter().rev().step_by(step).position(|&cell| cell == 0)
+        };
+        if let Some(steps) = found {
+            self.pointer = (self.pointer as isize + stride * steps as isize) as usize;
+            return Ok(());
+        }
+        // no zero before the end of the tape, step so the tape policy applies
+        while self.memory[self.pointer] != 0 {
+            self.move_by(stride)?;
+        }
+        Ok(())
+    }
+
+    // does what a copy loop would in one step: adds cell * factor at each
+    // target, then clears the counter
+    fn mul_copy(&mut self, targets: &[(isize, i32)]) -> Result<(), RuntimeError> {
+        let value = self.memory[self.pointer];
+        if value == 0 {
+            return Ok(());
+        }
+        for &(offset, factor) in targets {
+            let index = self.cell_at(offset)?;
+            let cell = &mut self.memory[index];
+            *cell = cell.wrapping_add(value.wrapping_mul(factor as u32)) & self.cell_width.mask();
+        }
+        self.memory[self.pointer] = 0;
+        Ok(())
+    }
+
+    fn swap(&mut self, offset: isize, temp: isize) -> Result<(), RuntimeError> {
+        let other = self.cell_at(offset)?;
+        // growing the tape at the start for temp moves the others along,
+        // `other` already is where it is after its own lookup
+        let pointer = self.pointer;
+        let temp = self.cell_at(temp)?;
+        let other = other + (self.pointer - pointer);
+        let value = self.memory[self.pointer];
+        self.memory[self.pointer] = self.memory[other];
+        self.memory[other] = self.memory[temp].wrapping_add(value) & self.cell_width.mask();
+        self.memory[temp] = 0;
+        Ok(())
+    }
+
+    // doubles the tape, clamped to the memory limit
+    fn grow_tape(&mut self) -> Result<(), RuntimeError> {
+        if self.tape_size >= self.max_cells() {
+            return Err(self.error(RuntimeErrorKind::MemoryLimitExceeded(self.max_memory)));
+        }
+        let new_size = (self.tape_size * 2).clamp(1, self.max_cells());
+        self.tape_size = new_size;
+        if !self.lazy_tape {
+            self.memory.resize(new_size, 0);
+            self.peak_tape_size = self.peak_tape_size.max(new_size);
+        }
+        Ok(())
+    }
+
+    // adds at least `needed` cells before the first one, as many as are
+    // allocated if that's more, up to the memory limit. everything that
+    // refers to cells by index moves along with them. returns how many were
+    // added
+    fn grow_left(&mut self, needed: usize) -> Result<usize, RuntimeError> {
+        let room = self.max_cells().saturating_sub(self.tape_size);
+        if needed > room {
+            return Err(self.error(RuntimeErrorKind::MemoryLimitExceeded(self.max_memory)));
+        }
+        let added = self.memory.len().max(needed).min(room);
+        self.memory.splice(0..0, std::iter::repeat_n(0, added));
+        self.tape_size += added;
+        self.peak_tape_size = self.peak_tape_size.max(self.memory.len());
+        self.pointer += added;
+        self.origin += added;
+        self.max_pointer += added;
+        for frame in &mut self.loop_stack {
+            frame.cell += added;
+        }
+        for executed in &mut self.history {
+            executed.pointer += added;
+        }
+        if !self.written.is_empty() {
+            self.written.splice(0..0, std::iter::repeat_n(false, added));
+        }
+        if let Some(heatmap) = self.heatmap.as_mut().filter(|heatmap| !heatmap.is_empty()) {
+            heatmap.splice(0..0, std::iter::repeat_n((0, 0), added));
+        }
+        Ok(added)
+    }
+
+    fn begin_run(&mut self) {
+        if !self.persistent {
+            self.reset();
+        }
+        // positions are relative to the program being run, and so are the
+        // procedure bodies
+        self.loop_stack.clear();
+        self.procedures.clear();
+        self.call_stack.clear();
+        self.dumps.clear();
+        self.history.clear();
+        self.loop_entered.clear();
+        self.run_instructions = 0;
+        self.run_output = 0;
+        self.partial_output.clear();
+        self.position = 0;
+        self.start_time.get_or_insert_with(Instant::now);
+    }
+
+    // ==================== BREAKPOINT IMPLEMENTATION FUNCTIONS ====================
+
+    pub fn set_instruction_breakpoint(&mut self, count: usize) {
+        self.breakpoints.instruction_count = Some(count);
+    }
+
+    pub fn set_memory_breakpoint(&mut self, value: u32) {
+        self.breakpoints.memory_value = Some(value);
+    }
+
+    pub fn set_loop_breakpoint(&mut self, depth: usize) {
+        self.breakpoints.loop_depth = Some(depth);
+    }
+
+    // stops before the command at `position` each time it runs. ] and ) are
+    // never stopped at, they only jump
+    pub fn add_position_breakpoint(&mut self, position: usize) {
+        self.breakpoints.positions.insert(position);
+    }
+
+    fn check_breakpoints(&self) -> bool {
+        // check if any breakpoint condition is met
+        if let Some(count) = self.breakpoints.instruction_count {
+            if self.instruction_count == count {
+                println!("\nBreakpoint hit: Instruction count = {}", count);
+                return true;
+            }
+        }
+
+        if let Some(value) = self.breakpoints.memory_value {
+            if self.memory[self.pointer] == value {
+                println!("\nBreakpoint hit: Memory value = {}", value);
+                return true;
+            }
+        }
+
+        if let Some(depth) = self.breakpoints.loop_depth {
+            if self.loop_depth() == depth {
+                println!("\nBreakpoint hit: Loop depth = {}", depth);
+                return true;
+            }
+        }
+
+        if self.breakpoints.positions.contains(&self.position) {
+            println!("\nBreakpoint hit: Command {}", self.position);
+            return true;
+        }
+
+        false
+    }
+
+    // ================================== Loop Stack Implementations ===================================
+
+    pub fn loop_depth(&self) -> usize {
+        self.loop_stack.len()
+    }
+
+    // active loops, outermost first
+    pub fn loop_stack(&self) -> &[LoopFrame] {
+        &self.loop_stack
+    }
+
+    // renders the active loops innermost first, like a stack trace
+    pub fn format_loop_stack(&self) -> String {
+        if self.loop_stack.is_empty() {
+            return "  (not inside a loop)\n".to_string();
+        }
+        let mut trace = String::new();
+        for (i, frame) in self.loop_stack.iter().rev().enumerate() {
+            trace.push_str(&format!(
+                "  #{} loop at command {}, iteration {}, cell[{}] = {}\n",
+                i, frame.start, frame.iterations, frame.cell, self.memory[frame.cell]
+            ));
+        }
+        trace
+    }
+
+    fn enter_loop(&mut self) {
+        self.loop_stack.push(LoopFrame {
+            start: self.position,
+            iterations: 0,
+            cell: self.pointer,
+        });
+        // sampling is meant to be cheap, so it goes without loop times
+        self.loop_entered.push((self.instruction_count, self.sample_interval.is_none().then(Instant::now)));
+    }
+
+    fn next_iteration(&mut self) {
+        let frame = self.loop_stack.last_mut().unwrap();
+        frame.iterations += 1;
+        frame.cell = self.pointer;
+    }
+
+    fn exit_loop(&mut self) {
+        let frame = self.loop_stack.pop().unwrap();
+        *self.loop_iterations.entry(self.loop_depth() + 1).or_insert(0) += frame.iterations;
+        let entered = self.loop_entered.pop();
+        let totals = self.loop_totals.entry(frame.start).or_default();
+        totals.entries += 1;
+        totals.iterations += frame.iterations;
+        let Some((count, started)) = entered else {
+            return;
+        };
+        totals.instructions += self.instruction_count - count;
+        if let Some(started) = started {
+            let duration = started.elapsed();
+            totals.time += duration;
+            let start = started.duration_since(self.start_time.unwrap_or(started));
+            self.profile.record_loop(LoopEvent { position: frame.start, start, duration, iterations: frame.iterations });
+        }
+    }
+
+    // ================================== Stats Implementations ===========================================
+
+    // `start` is when the instruction began, None when sampling
+    fn record_instruction(&mut self, instruction: &AstNode, start: Option<Instant>) {
+        let Some(start) = start else {
+            self.sample(instruction);
+            return;
+        };
+        self.profile.record(self.position, instruction, start.elapsed());
+    }
+
+    // counts every sample_interval-th instruction by where it is in the source
+    fn sample(&mut self, instruction: &AstNode) {
+        let interval = self.sample_interval.unwrap_or(1);
+        if self.instruction_count.is_multiple_of(interval) {
+            let (_, count) = self.samples.entry(self.position).or_insert_with(|| (instruction_name(instruction), 0));
+            *count += 1;
+        }
+    }
+
+    // instead of timing and counting every instruction by kind, which slows
+    // a run down a lot, look at every `interval`-th instruction and count
+    // where in the program it is. the counts then say where the time goes,
+    // and statistics() has samples instead of instruction counts and times
+    pub fn set_sample_interval(&mut self, interval: Option<usize>) {
+        self.sample_interval = interval.map(|interval| interval.max(1));
+    }
+
+    // adds the cells `instruction` read and wrote to the heatmap, and notes
+    // the written ones for statistics(). `pointer` and `value` are the
+    // pointer and its cell before it ran
+    fn record_accesses(&mut self, instruction: &AstNode, pointer: usize, value: u32) {
+        match instruction {
+            AstNode::Increment | AstNode::Decrement | AstNode::Add(_) | AstNode::Sub(_) | AstNode::Set(_) | AstNode::Input => {
+                self.record_access(pointer, 0, 1)
+            }
+            AstNode::Output | AstNode::Loop(_) | AstNode::ProcDef(_) | AstNode::ProcCall => self.record_access(pointer, 1, 0),
+            AstNode::MulCopy { targets } if value != 0 => {
+                self.record_access(pointer, 1, 1);
+                for &(offset, _) in targets {
+                    // the pointer hasn't moved, and the copy reached every target
+                    if let Ok(cell) = self.cell_at(offset) {
+                        self.record_access(cell, 1, 1);
+                    }
+                }
+            }
+            AstNode::MulCopy { .. } => self.record_access(pointer, 1, 0),
+            AstNode::Swap { offset, temp } => {
+                self.record_access(pointer, 1, 1);
+                for offset in [*offset, *temp] {
+                    if let Ok(cell) = self.cell_at(offset) {
+                        self.record_access(cell, 1, 1);
+                    }
+                }
+            }
+            // every cell the scan stopped at was tested
+            AstNode::ScanRight(stride) | AstNode::ScanLeft(stride) if self.heatmap.is_some() => {
+                let (low, high) = (pointer.min(self.pointer), pointer.max(self.pointer));
+                for cell in (low..=high).step_by(*stride) {
+                    self.record_access(cell, 1, 0);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn record_access(&mut self, cell: usize, reads: usize, writes: usize) {
+        if writes > 0 {
+            if cell >= self.written.len() {
+                self.written.resize(cell + 1, false);
+            }
+            self.written[cell] = true;
+        }
+        if let Some(heatmap) = self.heatmap.as_mut() {
+            if cell >= heatmap.len() {
+                heatmap.resize(cell + 1, (0, 0));
+            }
+            heatmap[cell].0 += reads;
+            heatmap[cell].1 += writes;
+        }
+    }
+
+    // time since the first run started
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.start_time.map(|start_time| start_time.elapsed())
+    }
+
+    // everything above in one serializable struct
+    pub fn statistics(&self) -> ExecutionStats {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let totals = self.profile.totals();
+        ExecutionStats {
+            total_instructions: Some(self.instruction_count),
+            elapsed_ms: self.elapsed().map(millis),
+            cycles: self.cost_model.map(|_| self.cycles),
+            max_pointer: Some(self.max_pointer),
+            cells_written: Some(self.written.iter().filter(|&&written| written).count()),
+            nonzero_cells: Some(self.memory.iter().filter(|&&cell| cell != 0).count()),
+            peak_memory_bytes: Some(self.peak_tape_size.max(self.memory.len()) * self.cell_width.bytes()),
+            instruction_counts: totals.iter().map(|(name, &(count, _))| (name.clone(), count)).collect(),
+            instruction_times_ms: totals.iter().map(|(name, &(_, time))| (name.clone(), millis(time))).collect(),
+            loop_iterations: self.loop_iterations.iter().map(|(&depth, &iterations)| (depth, iterations)).collect(),
+            heatmap: self
+                .heatmap
+                .iter()
+                .flatten()
+                .enumerate()
+                .filter(|(_, &(reads, writes))| reads + writes > 0)
+                .map(|(cell, &(reads, writes))| CellAccess { cell, reads, writes })
+                .collect(),
+            sample_interval: self.sample_interval,
+            samples: sorted_samples(&self.samples),
+            loops: self.hot_loops(),
+        }
+    }
+
+    // every loop that ran, the most iterations first
+    fn hot_loops(&self) -> Vec<LoopStats> {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let mut loops: Vec<LoopStats> = self
+            .loop_totals
+            .iter()
+            .map(|(&position, totals)| LoopStats {
+                position,
+                span: None,
+                entries: totals.entries,
+                iterations: totals.iterations,
+                instructions: totals.instructions,
+                time_ms: self.sample_interval.is_none().then(|| millis(totals.time)),
+            })
+            .collect();
+        loops.sort_by_key(|stats| (std::cmp::Reverse((stats.iterations, stats.instructions)), stats.position));
+        loops
+    }
+
+    pub fn print_statistics(&self) {
+        print!("{}", self.statistics().to_text());
+    }
+
+    pub fn set_step_by_step(&mut self, enabled: bool) {
+        self.step_by_step = enabled;
+    }
+
+    fn debug_step(&mut self, instruction: &AstNode) {
+        if self.debug && self.should_pause() {
+            println!("\nStep {}:", self.instruction_count);
+            println!("Loop depth: {}", self.loop_depth());
+            println!("Executing: {:?}", instruction);
+            println!("Pointer: {}", self.pointer);
+            println!("Memory around pointer: {:?}", self.get_memory_window());
+            
+            if self.step_by_step {
+                println!("\nPress Enter to step, n to step over this loop or u to run until the current loop exits...");
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).unwrap();
+                self.apply_step_command(input.trim(), instruction);
+            }
+        }
+    }
+
+    // n on a [ runs its whole loop without stopping, u runs until the loop
+    // the instruction is in has exited (or to the end outside of loops), and
+    // anything else steps into the next instruction
+    fn apply_step_command(&mut self, command: &str, instruction: &AstNode) {
+        let depth = self.loop_depth();
+        self.pause_below = match command {
+            "n" if matches!(instruction, AstNode::Loop(_)) => Some(depth + 1),
+            "u" => Some(depth),
+            _ => None,
+        };
+    }
+
+    // whether stepping stops before the next instruction, false while it
+    // is stepping over or out of a loop
+    fn should_pause(&mut self) -> bool {
+        match self.pause_below {
+            Some(below) if self.loop_depth() >= below => false,
+            _ => {
+                self.pause_below = None;
+                true
+            }
+        }
+    }
+
+    pub fn set_output_buffering(&mut self, buffering: OutputBuffering) {
+        self.output_buffering = buffering;
+    }
+
+    fn write_output(&mut self, byte: u8) -> Result<(), RuntimeError> {
+        self.pending_output.push(byte);
+        self.pending_count += 1;
+        let flush = match self.output_buffering {
+            OutputBuffering::Unbuffered => true,
+            OutputBuffering::Line => byte == b'\n',
+            OutputBuffering::Block => self.pending_output.len() >= OUTPUT_BLOCK_SIZE,
+            OutputBuffering::Every(n) => self.pending_count >= n,
+        };
+        if flush {
+            self.flush_output()?;
+        }
+        Ok(())
+    }
+
+    // sends output from . to `sink` instead of stdout, as it is produced
+    pub fn set_output_sink(&mut self, sink: Option<Box<dyn Write + Send>>) {
+        self.output_sink = sink;
+    }
+
+    // writes any pending output to the sink, or stdout if there is none
+    pub fn flush_output(&mut self) -> Result<(), RuntimeError> {
+        if let Some(trace) = self.trace.as_mut() {
+            let flushed = trace.flush();
+            flushed.map_err(|e| self.error(RuntimeErrorKind::Trace(e.to_string())))?;
+        }
+        if let Some(io) = self.io.as_mut() {
+            let flushed = io.flush();
+            flushed.map_err(|e| self.error(RuntimeErrorKind::Output(e.to_string())))?;
+        }
+        if self.pending_output.is_empty() {
+            return Ok(());
+        }
+        let bytes = &self.pending_output;
+        let written = match self.output_sink.as_mut() {
+            Some(sink) => sink.write_all(bytes).and_then(|_| sink.flush()),
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(bytes).and_then(|_| stdout.flush())
+            }
+        };
+        written.map_err(|e| self.error(RuntimeErrorKind::Output(e.to_string())))?;
+        self.pending_output.clear();
+        self.pending_count = 0;
+        Ok(())
+    }
+
+    // routes , and . in run() through `io` instead of stdin and stdout
+    pub fn set_io(&mut self, io: Option<Box<dyn BfIo>>) {
+        self.io = io;
+    }
+
+    // the installed I/O if it is a `T`, e.g. to collect a MemoryIo's output
+    pub fn io<T: BfIo>(&self) -> Option<&T> {
+        let io: &dyn std::any::Any = self.io.as_deref()?;
+        io.downcast_ref()
+    }
+
+    pub fn take_io(&mut self) -> Option<Box<dyn BfIo>> {
+        self.io.take()
+    }
+
+    // next byte for , from provided input, then the I/O or stdin. None at EOF
+    fn read_input(&mut self) -> Result<Option<u8>, RuntimeError> {
+        if let Some(byte) = self.input_buffer.pop_front() {
+            return Ok(Some(byte));
+        }
+        let read = match self.io.as_mut() {
+            Some(io) => io.read_byte(),
+            None => {
+                use std::io::Read;
+                let mut input = [0];
+                Ok(std::io::stdin().read_exact(&mut input).ok().map(|_| input[0]))
+            }
+        };
+        read.map_err(|e| self.error(RuntimeErrorKind::Input(e.to_string())))
+    }
+
+    // queues bytes for , to read before it falls back to stdin
+    pub fn provide_input(&mut self, bytes: &[u8]) {
+        self.input_buffer.extend(bytes);
+    }
+
+    pub fn set_eof_behavior(&mut self, eof_behavior: EofBehavior) {
+        self.eof_behavior = eof_behavior;
+    }
+
+    fn apply_eof(&mut self) {
+        match self.eof_behavior {
+            EofBehavior::Unchanged => {}
+            EofBehavior::Zero => self.memory[self.pointer] = 0,
+            EofBehavior::MaxValue => self.memory[self.pointer] = self.cell_width.mask(),
+        }
+    }
+
+    // print a one-time warning when , finds stdin exhausted
+    pub fn set_warn_on_eof(&mut self, warn: bool) {
+        self.warn_on_eof = warn;
     }
 
     pub fn set_debug(&mut self, debug: bool) {
         self.debug = debug;
     }
 
-    pub fn run(&mut self, ast: &crate::parser::AstNode) -> Result<(), String> {
+    // ends each run with an error once it has executed `limit` instructions,
+    // so programs that never halt can't hang the caller. every ] that jumps
+    // back counts too. None means no limit
+    pub fn set_max_instructions(&mut self, limit: Option<usize>) {
+        self.max_instructions = limit;
+    }
+
+    // like set_max_instructions, for the number of bytes written by .
+    pub fn set_max_output(&mut self, limit: Option<usize>) {
+        self.max_output = limit;
+    }
+
+    // the output a captured run wrote before it failed, e.g. up to the
+    // output limit. empty after a run that succeeded
+    pub fn partial_output(&self) -> &[u8] {
+        &self.partial_output
+    }
+
+    // lets another thread stop a run by setting `cancel`. the run ends with a
+    // Cancelled error within a few instructions, and the tape and any output
+    // produced so far are kept
+    pub fn set_cancel_flag(&mut self, cancel: Option<Arc<AtomicBool>>) {
+        self.cancel = cancel;
+    }
+
+    // like set_cancel_flag, with pausing too: a paused run waits where it
+    // is, without using the CPU, until it is resumed or cancelled. the time
+    // it waits counts towards the elapsed time
+    pub fn set_control(&mut self, control: Option<RunControl>) {
+        self.control = control;
+    }
+
+    // calls `hook` every `every` instructions of a run, e.g. to show that a
+    // long run is still going and how fast. like the instruction limit,
+    // ] testing its loop again counts towards `every`
+    pub fn set_on_progress(&mut self, every: usize, hook: Option<ProgressHook>) {
+        self.progress = hook.map(|hook| Progress { every: every.max(1), hook });
+    }
+
+    // writes a line to `trace` before every instruction: the command's
+    // position in the source, the instruction, the pointer and the cell's
+    // value, separated by tabs. positions refer to the original program, so
+    // traces with and without the optimizer line up. ] re-testing a loop
+    // isn't traced
+    pub fn set_trace(&mut self, trace: Option<Box<dyn Write + Send>>) {
+        self.trace = trace;
+    }
+
+    // counts how often each cell is read and written, reported as the
+    // heatmap of statistics(). a read is a test or output of the cell, a
+    // write anything that stores to it, and a copy loop does both to every
+    // cell it touches
+    pub fn set_track_heatmap(&mut self, enabled: bool) {
+        self.heatmap = enabled.then(Vec::new);
+    }
+
+    // what every instruction cost, and with set_record_timeline the loops.
+    // empty when sampling
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    // also keeps when each loop ran, for exporting the profile as a trace
+    pub fn set_record_timeline(&mut self, enabled: bool) {
+        self.profile.set_record_timeline(enabled);
+    }
+
+    // adds up what every instruction costs by `model`, reported as the
+    // cycles of statistics(). unlike times, cycles are the same on every
+    // run, so they can be compared in tests or used to score programs
+    pub fn set_cost_model(&mut self, model: Option<CostModel>) {
+        self.cost_model = model;
+    }
+
+    // counts how often each command of the program is reached, for
+    // annotating the source. unlike the instruction count, ] re-testing its
+    // loop counts too. with the optimizer on, a folded run of commands is
+    // counted at its first command
+    pub fn set_count_commands(&mut self, enabled: bool) {
+        self.command_counts = enabled.then(Vec::new);
+    }
+
+    // indexed by position, empty unless set_count_commands is on. commands
+    // after the last one reached are left out
+    pub fn command_counts(&self) -> &[usize] {
+        self.command_counts.as_deref().unwrap_or(&[])
+    }
+
+    // keeps the last `size` instructions executed, e.g. for a core dump.
+    // 0, the default, keeps none. like traces, ] re-testing a loop isn't kept
+    pub fn set_history_size(&mut self, size: usize) {
+        self.history_size = size;
+        while self.history.len() > size {
+            self.history.pop_front();
+        }
+    }
+
+    // oldest first. after an error the last one is the instruction that
+    // failed, unless that was a ]
+    pub fn history(&self) -> Vec<ExecutedInstruction> {
+        self.history.iter().cloned().collect()
+    }
+
+    // optimizes programs before running them. better than passing in an
+    // optimized AST, because error positions still refer to the commands of
+    // the original program
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    // lets the optimizer run the start of each program ahead of time, see
+    // Optimizer::set_partial_evaluation. only with set_optimize, and left
+    // out of persistent runs, which don't start on a clear tape
+    pub fn set_partial_evaluation(&mut self, enabled: bool) {
+        self.partial_evaluation = enabled;
+    }
+
+    // flattens a program body, optimizing it first when asked to
+    fn compile(&self, instructions: &[AstNode]) -> Vec<linear::Instruction> {
+        if !self.optimize {
+            return linear::flatten(instructions);
+        }
+        let program = AstNode::Program(instructions.to_vec());
+        let mut optimizer = crate::optimizer::Optimizer::new();
+        optimizer.set_partial_evaluation(self.partial_evaluation && !self.persistent);
+        optimizer.set_tape(self.tape_size, self.tape_policy);
+        let (optimized, positions) = optimizer.optimize_with_positions(&program);
+        match optimized {
+            AstNode::Program(optimized) => linear::flatten_with_positions(&optimized, &positions),
+            _ => linear::flatten(instructions),
+        }
+    }
+
+    pub fn run(&mut self, ast: &crate::parser::AstNode) -> Result<(), RuntimeError> {
         match ast {
             AstNode::Program(instructions) => {
-                for instruction in instructions {
-                    self.execute_instruction(instruction)?;
-                }
-                Ok(())
+                self.begin_run();
+                let code = self.compile(instructions);
+                let result = self.execute(&code, None);
+                // flush even on error so output produced so far isn't lost
+                self.flush_output()?;
+                result
             }
-            _=> Err("Expected program node".to_string()),
+            _=> Err(self.error(RuntimeErrorKind::ExpectedProgram)),
         }   
     }
 
-    fn execute_instruction(&mut self, instruction: &AstNode) -> Result<(), String> {
+    // prepares `ast` to be executed one instruction at a time with step()
+    pub fn start(&mut self, ast: &AstNode) -> Result<(), RuntimeError> {
+        let AstNode::Program(instructions) = ast else {
+            return Err(self.error(RuntimeErrorKind::ExpectedProgram));
+        };
+        self.begin_run();
+        self.session = Some(Session { code: self.compile(instructions), pc: 0 });
+        Ok(())
+    }
+
+    // command index of the instruction executed last
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    // command index of the instruction step() executes next, None before
+    // start() and once the program has finished
+    pub fn next_position(&self) -> Option<usize> {
+        let session = self.session.as_ref()?;
+        session.code.get(session.pc).map(|instruction| instruction.position)
+    }
+
+    // executes the next instruction of the started program, returns false
+    // once the program has finished. output is flushed at the end
+    pub fn step(&mut self) -> Result<bool, RuntimeError> {
+        let Some(mut session) = self.session.take() else {
+            return Ok(false);
+        };
+        loop {
+            if session.pc >= session.code.len() {
+                self.flush_output()?;
+                return Ok(false);
+            }
+            // ] only re-tests the loop and ) only returns, so carry on to a real instruction
+            let counted = !matches!(session.code[session.pc].op, Op::LoopEnd { .. } | Op::ProcEnd);
+            match self.step_at(&session.code, session.pc, None) {
+                Ok(pc) => session.pc = pc,
+                Err(e) => {
+                    self.flush_output()?;
+                    return Err(e);
+                }
+            }
+            if counted {
+                break;
+            }
+        }
+        self.session = Some(session);
+        Ok(true)
+    }
+
+    // executes at most `instructions` instructions of `ast`, then returns so
+    // the caller can do something else, e.g. keep a UI responsive or give
+    // other programs a turn. the next call picks up where this one stopped,
+    // so pass the same `ast` until it is Done or an Error; after that, a call
+    // starts it over. counts instructions like step()
+    pub fn run_for(&mut self, ast: &AstNode, instructions: usize) -> RunStatus {
+        if self.session.is_none() {
+            if let Err(e) = self.start(ast) {
+                return RunStatus::Error(e);
+            }
+        }
+        for _ in 0..instructions {
+            match self.step() {
+                Ok(true) => {}
+                Ok(false) => return RunStatus::Done,
+                Err(e) => return RunStatus::Error(e),
+            }
+        }
+        // a program that ran its last instruction is done now, not a call later
+        match &self.session {
+            Some(session) if session.pc < session.code.len() => RunStatus::Running,
+            _ => match self.step() {
+                Ok(_) => RunStatus::Done,
+                Err(e) => RunStatus::Error(e),
+            },
+        }
+    }
+
+    // starts `ast` and executes it as the returned iterator is advanced, an
+    // event per instruction. output goes into the events instead of stdout,
+    // the I/O or the sink, and like step() the ] of a loop that repeats
+    // is executed along with the instruction after it, with no event
+    pub fn steps(&mut self, ast: &AstNode) -> Result<Steps<'_>, RuntimeError> {
+        self.start(ast)?;
+        Ok(Steps { interpreter: self })
+    }
+
+    fn step_event(&mut self) -> Option<Result<StepEvent, RuntimeError>> {
+        let mut session = self.session.take()?;
+        loop {
+            let Some(instruction) = session.code.get(session.pc) else {
+                return self.flush_output().err().map(Err);
+            };
+            let (pc, position, pointer) = (session.pc, instruction.position, self.pointer);
+            let node = match &instruction.op {
+                Op::Basic(node) | Op::LoopStart { node, .. } | Op::ProcStart { node, .. } => Some(node.clone()),
+                Op::Call => Some(AstNode::ProcCall),
+                Op::LoopEnd { .. } | Op::ProcEnd => None,
+            };
+            let cell_before = self.memory[pointer];
+            let mut output = Vec::new();
+            match self.step_at(&session.code, pc, Some(&mut output)) {
+                Ok(next) => session.pc = next,
+                Err(e) => return Some(Err(e)),
+            }
+            let Some(node) = node else {
+                continue;
+            };
+            self.session = Some(session);
+            let output = output.first().copied();
+            return Some(Ok(StepEvent { pc, position, node, pointer, cell_before, cell_after: self.memory[pointer], output }));
+        }
+    }
+
+    // runs flattened code with a program counter, so loop nesting doesn't
+    // use the Rust stack. output is captured into `output` when given
+    fn execute(&mut self, code: &[linear::Instruction], mut output: Option<&mut Vec<u8>>) -> Result<(), RuntimeError> {
+        let mut pc = 0;
+        while pc < code.len() {
+            pc = self.step_at(code, pc, output.as_deref_mut())?;
+        }
+        Ok(())
+    }
+
+    // executes code[pc] and returns the index of the next instruction
+    fn step_at(&mut self, code: &[linear::Instruction], pc: usize, output: Option<&mut Vec<u8>>) -> Result<usize, RuntimeError> {
+        let instruction = &code[pc];
+        self.position = instruction.position;
+        if let Some(counts) = self.command_counts.as_mut() {
+            if instruction.position >= counts.len() {
+                counts.resize(instruction.position + 1, 0);
+            }
+            counts[instruction.position] += 1;
+        }
+        Ok(match &instruction.op {
+            Op::Basic(node) => {
+                self.dispatch(node, output)?;
+                pc + 1
+            }
+            Op::LoopStart { node, end } => {
+                self.dispatch(node, output)?;
+                self.branch(pc + 1, *end)
+            }
+            // re-testing the condition at ] isn't counted as an instruction,
+            // but does count towards the limit so that [] can't spin forever
+            Op::LoopEnd { start } => {
+                self.count_towards_limit()?;
+                if let Some(model) = &self.cost_model {
+                    self.cycles += model.loops;
+                }
+                self.record_access(self.pointer, 1, 0);
+                self.loop_stack.last_mut().unwrap().cell = self.pointer;
+                self.branch(start + 1, pc + 1)
+            }
+            Op::ProcStart { node, end } => {
+                self.dispatch(node, output)?;
+                self.procedures.insert(self.memory[self.pointer], pc + 1);
+                *end
+            }
+            Op::ProcEnd => self.call_stack.pop().unwrap_or(pc + 1),
+            Op::Call => {
+                self.dispatch(&AstNode::ProcCall, output)?;
+                let number = self.memory[self.pointer];
+                let Some(&body) = self.procedures.get(&number) else {
+                    return Err(self.error(RuntimeErrorKind::UnknownProcedure(number)));
+                };
+                if self.call_stack.len() >= MAX_CALL_DEPTH {
+                    return Err(self.error(RuntimeErrorKind::CallDepthExceeded(MAX_CALL_DEPTH)));
+                }
+                self.call_stack.push(pc + 1);
+                body
+            }
+        })
+    }
+
+    fn dispatch(&mut self, instruction: &AstNode, output: Option<&mut Vec<u8>>) -> Result<(), RuntimeError> {
+        // before the limits, so an instruction they stop is the last one
+        if self.history_size > 0 {
+            if self.history.len() == self.history_size {
+                self.history.pop_front();
+            }
+            self.history.push_back(ExecutedInstruction {
+                position: self.position,
+                instruction: instruction_name(instruction),
+                pointer: self.pointer,
+                value: self.memory[self.pointer],
+            });
+        }
+        self.check_limits(instruction)?;
+        if let Some(model) = &self.cost_model {
+            self.cycles += model.cost(instruction);
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            let name = instruction_name(instruction);
+            let line = format!("{}\t{}\t{}\t{}", self.position, name, self.pointer, self.memory[self.pointer]);
+            let written = writeln!(trace, "{}", line);
+            written.map_err(|e| self.error(RuntimeErrorKind::Trace(e.to_string())))?;
+        }
+        let (pointer, value) = (self.pointer, self.memory[self.pointer]);
+        let result = match output {
+            Some(output) => self.execute_instruction_capture(output, instruction),
+            None => self.execute_instruction(instruction),
+        };
+        if result.is_ok() {
+            self.record_accesses(instruction, pointer, value);
+            self.max_pointer = self.max_pointer.max(self.pointer);
+        }
+        result
+    }
+
+    // waits while the run is paused, and fails once it's cancelled
+    fn check_control(&self) -> Result<(), RuntimeError> {
+        if let Some(control) = &self.control {
+            while control.is_paused() {
+                std::thread::sleep(PAUSE_POLL_INTERVAL);
+            }
+            if control.is_cancelled() {
+                return Err(self.error(RuntimeErrorKind::Cancelled));
+            }
+        }
+        if self.cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+            return Err(self.error(RuntimeErrorKind::Cancelled));
+        }
+        Ok(())
+    }
+
+    // fails before `instruction` would go over the instruction or output limit
+    fn check_limits(&mut self, instruction: &AstNode) -> Result<(), RuntimeError> {
+        if let (AstNode::Output, Some(limit)) = (instruction, self.max_output) {
+            if self.run_output >= limit {
+                return Err(self.error(RuntimeErrorKind::OutputLimitExceeded(limit)));
+            }
+            self.run_output += 1;
+        }
+        self.count_towards_limit()
+    }
+
+    // also where the cancel flag and control are looked at, so [] can be
+    // paused and cancelled
+    fn count_towards_limit(&mut self) -> Result<(), RuntimeError> {
+        if (self.cancel.is_some() || self.control.is_some()) && self.run_instructions.is_multiple_of(CANCEL_CHECK_INTERVAL) {
+            self.check_control()?;
+        }
+        if let Some(limit) = self.max_instructions {
+            if self.run_instructions >= limit {
+                return Err(self.error(RuntimeErrorKind::InstructionLimitExceeded(limit)));
+            }
+        }
+        self.run_instructions += 1;
+        if let Some(progress) = self.progress.as_mut() {
+            if self.run_instructions.is_multiple_of(progress.every) {
+                let elapsed = self.start_time.map_or(Duration::ZERO, |start| start.elapsed());
+                (progress.hook)(self.instruction_count, elapsed);
+            }
+        }
+        Ok(())
+    }
+
+    // continues the innermost loop at `body` while the cell is non-zero,
+    // otherwise leaves it and continues at `exit`
+    fn branch(&mut self, body: usize, exit: usize) -> usize {
+        if self.memory[self.pointer] != 0 {
+            self.next_iteration();
+            body
+        } else {
+            self.exit_loop();
+            exit
+        }
+    }
+
+    fn execute_instruction(&mut self, instruction: &AstNode) -> Result<(), RuntimeError> {
         self.instruction_count += 1;
     
         // Check breakpoints before executing
         if self.check_breakpoints() {
+            // and stepping stops there too
+            self.pause_below = None;
             println!("Program paused at breakpoint.");
             println!("Current state:");
             println!("  Instruction: {:?}", instruction);
             println!("  Memory at pointer: {}", self.memory[self.pointer]);
-            println!("  Loop depth: {}", self.loop_depth);
+            println!("  Loop depth: {}", self.loop_depth());
+            println!("Loop stack:");
+            print!("{}", self.format_loop_stack());
             
             println!("\nPress Enter to continue or 'q' to quit...");
             let mut input = String::new();
             std::io::stdin().read_line(&mut input).unwrap();
             
             if input.trim() == "q" {
-                return Err("Execution terminated by user".to_string());
+                return Err(self.error(RuntimeErrorKind::Terminated));
             }
         }
     
+        self.notify_observer(instruction);
         self.debug_step(instruction);
         //start timing
-        let start = Instant::now();
+        let start = self.sample_interval.is_none().then(Instant::now);
     
         let result = match instruction {
             AstNode::Increment => {
-                self.memory[self.pointer] = self.memory[self.pointer].wrapping_add(1);
+                self.add_to_cell(1);
                 Ok(())
             },
             AstNode::Decrement => {
-                self.memory[self.pointer] = self.memory[self.pointer].wrapping_sub(1);
+                self.sub_from_cell(1);
                 Ok(())
             },
             AstNode::Add(n) => {
-                self.memory[self.pointer] = self.memory[self.pointer].wrapping_add(*n as u8);
+                self.add_to_cell(*n as u32);
                 Ok(())
             },
             AstNode::Sub(n) => {
-                self.memory[self.pointer] = self.memory[self.pointer].wrapping_sub(*n as u8);
+                self.sub_from_cell(*n as u32);
                 Ok(())
             },
-            AstNode::MoveRight => {
-                if self.pointer + 1 >= self.tape_size {
-                    return Err("Pointer out of bounds".to_string());
-                }
-                self.pointer += 1;
+            AstNode::Set(value) => {
+                self.memory[self.pointer] = *value as u32 & self.cell_width.mask();
                 Ok(())
             },
-            AstNode::MoveLeft => {
-                if self.pointer == 0 {
-                    return Err("Pointer out of bounds".to_string());
+            AstNode::MoveRight => self.move_right(),
+            AstNode::MoveLeft => self.move_left(),
+            AstNode::Move(n) => self.move_by(*n),
+            AstNode::MulCopy { targets } => self.mul_copy(targets),
+            AstNode::Swap { offset, temp } => self.swap(*offset, *temp),
+            AstNode::ScanRight(stride) => self.scan(*stride as isize),
+            AstNode::ScanLeft(stride) => self.scan(-(*stride as isize)),
+            AstNode::Output => {
+                let byte = self.output_byte();
+                match self.io.as_mut() {
+                    Some(io) => {
+                        let written = io.write_byte(byte);
+                        written.map_err(|e| self.error(RuntimeErrorKind::Output(e.to_string())))
+                    }
+                    None => self.write_output(byte),
                 }
-                self.pointer -= 1;
-                Ok(())
             },
-            AstNode::Output => {
-                print!("{}", self.memory[self.pointer] as char);
+            AstNode::Debug => {
+                // output so far goes first, so the dump shows up where it happened
+                self.flush_output()?;
+                eprintln!("{}", self.dump());
                 Ok(())
             },
             AstNode::Input => {
-                use std::io::{stdin, Read};
-                let mut input = [0];
-                if stdin().read_exact(&mut input).is_ok() {
-                    self.memory[self.pointer] = input[0];
+                // make sure any prompt is visible before blocking on input
+                self.flush_output()?;
+                if let Some(byte) = self.read_input()? {
+                    self.memory[self.pointer] = byte as u32;
+                } else {
+                    if self.warn_on_eof {
+                        // only warn once, a cat-style program hits EOF on every read
+                        self.warn_on_eof = false;
+                        eprintln!("Warning: , reached the end of a non-interactive input ({})", self.eof_behavior);
+                    }
+                    self.apply_eof();
                 }
                 Ok(())
             },
-            AstNode::Loop(instructions) => {
-                self.loop_depth += 1;
-                let mut loop_count = 0;
-                
-                while self.memory[self.pointer] != 0 {
-                    loop_count += 1;
-                    for instruction in instructions {
-                        self.execute_instruction(instruction)?;
-                    }
-                }
-
-                // record loop iterations
-                *self.loop_iterations.entry(self.loop_depth).or_insert(0) += loop_count;
-                
-                self.loop_depth -= 1;
+            AstNode::Loop(_) => {
+                self.enter_loop();
                 Ok(())
             },
-            _ => Err("Invalid instruction".to_string()),
+            // the jumps are done by step_at
+            AstNode::ProcDef(_) | AstNode::ProcCall | AstNode::Comment(_) => Ok(()),
+            _ => Err(self.error(RuntimeErrorKind::InvalidInstruction)),
         };
 
         // record timing and stats
-        let duration = start.elapsed();
-        self.record_instruction(instruction, duration);
+        self.record_instruction(instruction, start);
 
         if self.debug {
             // Show any changes after instruction execution
@@ -352,10 +2054,10 @@ impl Interpreter {
     }
 
     // helper method for debug
-    fn get_memory_window(&self) -> Vec<(usize, u8)> {
+    fn get_memory_window(&self) -> Vec<(usize, u32)> {
         // show 5 cells before and after pointer
         let start = self.pointer.saturating_sub(5);
-        let end = self.pointer + 5.min(self.tape_size - 1);
+        let end = (self.pointer + 5).min(self.memory.len() - 1);
 
         (start..=end)
         .map(|i| (i, self.memory[i]))
@@ -365,6 +2067,7 @@ impl Interpreter {
 
 
 
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -434,5 +2137,787 @@ mod tests {
         assert_eq!(interpreter.memory[0], 1);
         assert_eq!(interpreter.memory[1], 3);
     }
+
+    #[test]
+    fn test_loop_stack_on_error() {
+        // +[>+[<<]] fails inside the inner loop, leaving both frames active
+        let mut interpreter = Interpreter::new();
+        let program = AstNode::Program(vec![
+            AstNode::Increment,
+            AstNode::Loop(vec![
+                AstNode::MoveRight,
+                AstNode::Increment,
+                AstNode::Loop(vec![
+                    AstNode::MoveLeft,
+                    AstNode::MoveLeft,
+                ]),
+            ]),
+        ]);
+        assert!(interpreter.run(&program).is_err());
+        assert_eq!(interpreter.loop_stack(), &[
+            LoopFrame { start: 1, iterations: 1, cell: 0 },
+            LoopFrame { start: 4, iterations: 1, cell: 1 },
+        ]);
+        assert!(interpreter.format_loop_stack().starts_with("  #0 loop at command 4"));
+    }
+
+    #[test]
+    fn test_loop_stack_positions_after_finished_loop() {
+        // ++[-]>+[<<] - the second loop starts at command 7
+        let mut interpreter = Interpreter::new();
+        let program = AstNode::Program(vec![
+            AstNode::Increment,
+            AstNode::Increment,
+            AstNode::Loop(vec![AstNode::Decrement]),
+            AstNode::MoveRight,
+            AstNode::Increment,
+            AstNode::Loop(vec![AstNode::MoveLeft, AstNode::MoveLeft]),
+        ]);
+        assert!(interpreter.run(&program).is_err());
+        assert_eq!(interpreter.loop_stack(), &[
+            LoopFrame { start: 7, iterations: 1, cell: 1 },
+        ]);
+    }
+
+    #[test]
+    fn test_set() {
+        let mut interpreter = Interpreter::new();
+        let program = AstNode::Program(vec![AstNode::Add(9), AstNode::Set(0), AstNode::MoveRight, AstNode::Set(7)]);
+        interpreter.run(&program).unwrap();
+        assert_eq!(&interpreter.memory()[..2], &[0, 7]);
+    }
+
+    #[test]
+    fn test_move() {
+        let mut interpreter = Interpreter::with_tape_size(10).unwrap();
+        interpreter.run(&AstNode::Program(vec![AstNode::Move(5), AstNode::Increment, AstNode::Move(-2)])).unwrap();
+        assert_eq!(interpreter.pointer(), 3);
+        assert_eq!(interpreter.memory()[5], 1);
+
+        let err = interpreter.run(&AstNode::Program(vec![AstNode::Move(10)])).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::PointerOutOfBounds);
+
+        interpreter.set_tape_policy(TapePolicy::Wrap);
+        interpreter.run(&AstNode::Program(vec![AstNode::Move(-3)])).unwrap();
+        assert_eq!(interpreter.pointer(), 7);
+
+        interpreter.set_tape_policy(TapePolicy::Grow);
+        interpreter.run(&AstNode::Program(vec![AstNode::Move(25)])).unwrap();
+        assert_eq!(interpreter.pointer(), 25);
+        assert_eq!(interpreter.tape_size(), 40);
+    }
+
+    #[test]
+    fn test_mul_copy() {
+        // 3 * [->++>-<<] leaves 0, 6, 253
+        let mut interpreter = Interpreter::new();
+        let program = AstNode::Program(vec![
+            AstNode::Add(3),
+            AstNode::MulCopy { targets: vec![(1, 2), (2, -1)] },
+        ]);
+        interpreter.run(&program).unwrap();
+        assert_eq!(&interpreter.memory()[..3], &[0, 6, 253]);
+
+        // the loop never runs on a zero cell, so nothing is touched
+        let program = AstNode::Program(vec![AstNode::MulCopy { targets: vec![(-1, 1)] }]);
+        interpreter.run(&program).unwrap();
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut interpreter = Interpreter::with_tape_size(8).unwrap();
+        interpreter.set_persistent(true);
+        interpreter.memory = vec![1, 1, 0, 1, 1, 1, 0, 1];
+        interpreter.run(&AstNode::Program(vec![AstNode::ScanRight(1)])).unwrap();
+        assert_eq!(interpreter.pointer(), 2);
+        interpreter.run(&AstNode::Program(vec![AstNode::Increment, AstNode::ScanRight(2)])).unwrap();
+        assert_eq!(interpreter.pointer(), 6);
+        interpreter.memory[0] = 0;
+        interpreter.run(&AstNode::Program(vec![AstNode::Increment, AstNode::ScanLeft(3)])).unwrap();
+        assert_eq!(interpreter.pointer(), 0);
+
+        // running off the end still follows the tape policy
+        interpreter.memory = vec![1; 8];
+        let err = interpreter.run(&AstNode::Program(vec![AstNode::ScanRight(1)])).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::PointerOutOfBounds);
+    }
+
+    #[test]
+    fn test_deeply_nested_loops() {
+        // +[[[...[-]...]]] nested far deeper than recursion would allow
+        let mut body = vec![AstNode::Decrement];
+        for _ in 0..10_000 {
+            body = vec![AstNode::Loop(body)];
+        }
+        let mut interpreter = Interpreter::new();
+        interpreter.run(&AstNode::Program(vec![AstNode::Increment, AstNode::Loop(body)])).unwrap();
+        assert_eq!(interpreter.memory()[0], 0);
+        assert_eq!(interpreter.instruction_count, 10_003);
+    }
+
+    #[test]
+    fn test_persistent_runs() {
+        let program = AstNode::Program(vec![AstNode::Increment, AstNode::MoveRight]);
+
+        let mut interpreter = Interpreter::new();
+        interpreter.run(&program).unwrap();
+        interpreter.run(&program).unwrap();
+        assert_eq!(interpreter.pointer(), 1);
+        assert_eq!(interpreter.memory()[0], 1);
+
+        interpreter.set_persistent(true);
+        interpreter.run(&program).unwrap();
+        interpreter.run(&program).unwrap();
+        assert_eq!(interpreter.pointer(), 3);
+        assert_eq!(&interpreter.memory()[..3], &[1, 1, 1]);
+    }
+
+    #[test]
+    fn test_grow_both() {
+        // the first < doubles the tape to the left, Move(-2) stays on it
+        let mut interpreter = Interpreter::builder().tape_size(4).tape_policy(TapePolicy::GrowBoth).build().unwrap();
+        interpreter.set_persistent(true);
+        let program = AstNode::Program(vec![AstNode::Increment, AstNode::MoveLeft, AstNode::Add(2), AstNode::Move(-2), AstNode::Add(3)]);
+        interpreter.run(&program).unwrap();
+        assert_eq!(interpreter.memory(), &[0, 3, 0, 2, 1, 0, 0, 0]);
+        assert_eq!((interpreter.pointer(), interpreter.origin(), interpreter.tape_size()), (1, 4, 8));
+
+        // a [<] scan off the start, from inside a loop that keeps its cell
+        let program = AstNode::Program(vec![AstNode::Loop(vec![AstNode::ScanLeft(2), AstNode::Increment, AstNode::MoveRight])]);
+        interpreter.run(&program).unwrap();
+        assert_eq!((interpreter.pointer(), interpreter.origin(), interpreter.tape_size()), (8, 12, 16));
+        assert_eq!(interpreter.memory()[7..10], [1, 0, 3]);
+
+        // a swap with a cell off the start, which grows the tape before the
+        // temp cell is looked up
+        let mut interpreter = Interpreter::builder().tape_size(4).tape_policy(TapePolicy::GrowBoth).build().unwrap();
+        let swap = AstNode::Swap { offset: -1, temp: 1 };
+        let program = AstNode::Program(vec![AstNode::Increment, AstNode::MoveRight, AstNode::Add(2), AstNode::MoveLeft, swap]);
+        interpreter.run(&program).unwrap();
+        assert_eq!(interpreter.memory(), &[0, 0, 0, 3, 0, 0, 0, 0]);
+        assert_eq!(interpreter.pointer(), 4);
+
+        // a tape that only grows right still fails
+        let mut interpreter = Interpreter::new();
+        interpreter.set_tape_policy(TapePolicy::Grow);
+        let err = interpreter.run(&AstNode::Program(vec![AstNode::MoveLeft])).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::PointerOutOfBounds);
+    }
+
+    #[test]
+    fn test_lazy_tape() {
+        let mut interpreter = Interpreter::builder().lazy_tape(true).tape_size(50_000_000).build().unwrap();
+        assert_eq!(interpreter.memory().len(), TAPE_PAGE_SIZE);
+        let program = AstNode::Program(vec![AstNode::Move(TAPE_PAGE_SIZE as isize + 1), AstNode::Increment, AstNode::MoveRight]);
+        let (_, memory, pointer) = interpreter.run_and_capture_output(&program).unwrap();
+        assert_eq!((memory.len(), memory[TAPE_PAGE_SIZE + 1], pointer), (2 * TAPE_PAGE_SIZE, 1, TAPE_PAGE_SIZE + 2));
+        assert_eq!(interpreter.statistics().peak_memory_bytes, Some(2 * TAPE_PAGE_SIZE));
+
+        // wrapping to the end allocates the last page, which may be a short one
+        let mut interpreter = Interpreter::builder().lazy_tape(true).tape_size(5000).tape_policy(TapePolicy::Wrap).build().unwrap();
+        interpreter.run(&AstNode::Program(vec![AstNode::MoveLeft, AstNode::Increment])).unwrap();
+        assert_eq!((interpreter.memory().len(), interpreter.memory()[4999]), (5000, 1));
+
+        // turning it off allocates the rest
+        interpreter.set_lazy_tape(false);
+        assert_eq!(interpreter.memory().len(), 5000);
+    }
+
+    #[test]
+    fn test_growable_tape_limit() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_tape_policy(TapePolicy::Grow);
+        interpreter.set_max_memory(40000);
+        interpreter.pointer = 29999;
+        interpreter.set_persistent(true);
+
+        interpreter.run(&AstNode::Program(vec![AstNode::MoveRight, AstNode::Increment])).unwrap();
+        assert_eq!(interpreter.memory().len(), 40000);
+        assert_eq!(interpreter.memory()[30000], 1);
+
+        let runaway = AstNode::Program(vec![AstNode::Add(1), AstNode::Loop(vec![AstNode::MoveRight, AstNode::Increment])]);
+        let err = interpreter.run(&runaway).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::MemoryLimitExceeded(40000));
+        assert_eq!(interpreter.memory().len(), 40000);
+    }
+
+    #[test]
+    fn test_runtime_error_position() {
+        // +[-]><< fails on the last <
+        let mut interpreter = Interpreter::new();
+        let program = AstNode::Program(vec![
+            AstNode::Increment,
+            AstNode::Loop(vec![AstNode::Decrement]),
+            AstNode::MoveRight,
+            AstNode::MoveLeft,
+            AstNode::MoveLeft,
+        ]);
+        let err = interpreter.run(&program).unwrap_err();
+        assert_eq!(err, RuntimeError {
+            kind: RuntimeErrorKind::PointerOutOfBounds,
+            position: 6,
+            instruction_count: 6,
+            pointer: 0,
+        });
+        assert_eq!(err.to_string(), "Pointer out of bounds at command 6 (instruction 6, pointer 0)");
+
+        // optimized, the failing Move(-2) still points into the source: at
+        // the first < it was folded from
+        let mut interpreter = Interpreter::new();
+        interpreter.set_optimize(true);
+        let err = interpreter.run(&program).unwrap_err();
+        assert_eq!((err.position, err.instruction_count, err.pointer), (5, 4, 1));
+    }
+
+    #[test]
+    fn test_provided_input() {
+        let mut interpreter = Interpreter::new();
+        interpreter.provide_input(b"hi");
+        let program = AstNode::Program(vec![AstNode::Input, AstNode::MoveRight, AstNode::Input]);
+        interpreter.run(&program).unwrap();
+        assert_eq!(&interpreter.memory()[..2], &[b'h' as u32, b'i' as u32]);
+    }
+
+    #[test]
+    fn test_interpret_with_input() {
+        // ,[.,] echoes its input, reading 0 once it runs out
+        let program = AstNode::Program(vec![
+            AstNode::Input,
+            AstNode::Loop(vec![AstNode::Output, AstNode::Input]),
+        ]);
+        let (output, _, _) = interpret_with_input(&program, b"abc", DEFAULT_TAPE_SIZE).unwrap();
+        assert_eq!(output, "abc");
+    }
+
+    #[test]
+    fn test_tape_size() {
+        assert!(Interpreter::with_tape_size(0).is_err());
+        assert!(Interpreter::with_tape_size(DEFAULT_MAX_MEMORY + 1).is_err());
+
+        // a 2 cell tape fails on the second >
+        let mut interpreter = Interpreter::with_tape_size(2).unwrap();
+        let program = AstNode::Program(vec![AstNode::MoveRight, AstNode::MoveRight]);
+        let err = interpreter.run(&program).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::PointerOutOfBounds);
+        assert_eq!(err.position, 1);
+
+        let (_, memory, _) = interpret_with_state(&AstNode::Program(vec![]), 100000).unwrap();
+        assert_eq!(memory.len(), 100000);
+    }
+
+    #[test]
+    fn test_builder() {
+        // the tape size is checked against the memory limit however they're ordered
+        let cells = DEFAULT_MAX_MEMORY + 1;
+        let interpreter = Interpreter::builder().tape_size(cells).max_memory(cells * 4).build().unwrap();
+        assert_eq!(interpreter.tape_size(), cells);
+        assert!(Interpreter::builder().tape_size(0).build().is_err());
+
+        let mut interpreter = Interpreter::builder()
+            .tape_size(2)
+            .cell_width(CellWidth::U16)
+            .eof_behavior(EofBehavior::MaxValue)
+            .input(b"a")
+            .build()
+            .unwrap();
+        let program = AstNode::Program(vec![AstNode::Input, AstNode::MoveRight, AstNode::Input]);
+        let (_, memory, _) = interpreter.run_and_capture_output(&program).unwrap();
+        assert_eq!(memory, [97, 0xFFFF]);
+    }
+
+    #[test]
+    fn test_cell_widths() {
+        // - on a zero cell wraps to the maximum value for the width
+        let program = AstNode::Program(vec![AstNode::Decrement, AstNode::MoveRight, AstNode::Add(300)]);
+        let expected = [(CellWidth::U8, 0xFF, 44), (CellWidth::U16, 0xFFFF, 300), (CellWidth::U32, 0xFFFF_FFFF, 300)];
+        for (width, wrapped, added) in expected {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_cell_width(width);
+            interpreter.run(&program).unwrap();
+            assert_eq!(&interpreter.memory()[..2], &[wrapped, added]);
+        }
+
+        // output keeps the low byte of wide cells
+        let mut interpreter = Interpreter::new();
+        interpreter.set_cell_width(CellWidth::U16);
+        let program = AstNode::Program(vec![AstNode::Add(0x141), AstNode::Output]);
+        let (output, _, _) = interpreter.run_and_capture_output(&program).unwrap();
+        assert_eq!(output, "A");
+    }
+
+    #[test]
+    fn test_wrapping_tape() {
+        let mut interpreter = Interpreter::with_tape_size(3).unwrap();
+        interpreter.set_tape_policy(TapePolicy::Wrap);
+        // < from cell 0 lands on the last cell, > from there comes back
+        let program = AstNode::Program(vec![
+            AstNode::MoveLeft,
+            AstNode::Increment,
+            AstNode::MoveRight,
+            AstNode::Add(2),
+        ]);
+        interpreter.run(&program).unwrap();
+        assert_eq!(interpreter.pointer(), 0);
+        assert_eq!(interpreter.memory(), &[2, 0, 1]);
+    }
+
+    #[test]
+    fn test_eof_behavior() {
+        // ,>+, reads past the end of an empty input twice
+        let program = AstNode::Program(vec![AstNode::Add(7), AstNode::Input, AstNode::MoveRight, AstNode::Add(7), AstNode::Input]);
+        let expected = [(EofBehavior::Unchanged, 7), (EofBehavior::Zero, 0), (EofBehavior::MaxValue, 0xFFFF)];
+        for (eof_behavior, cell) in expected {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_cell_width(CellWidth::U16);
+            interpreter.set_eof_behavior(eof_behavior);
+            let (_, memory, _) = interpreter.run_and_capture_output(&program).unwrap();
+            assert_eq!(&memory[..2], &[cell, cell]);
+        }
+        assert_eq!(EofBehavior::from_name("-1"), Some(EofBehavior::MaxValue));
+    }
+
+    #[test]
+    fn test_output_sink_streams() {
+        use std::sync::Mutex;
+
+        // each flush reaches the sink separately while the program runs
+        let chunks = Arc::new(Mutex::new(Vec::new()));
+        let sink = chunks.clone();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_output_buffering(OutputBuffering::Unbuffered);
+        interpreter.set_output_sink(Some(Box::new(CallbackWriter(move |bytes: &[u8]| {
+            sink.lock().unwrap().push(bytes.to_vec());
+        }))));
+        let program = AstNode::Program(vec![AstNode::Add(65), AstNode::Output, AstNode::Increment, AstNode::Output]);
+        interpreter.run(&program).unwrap();
+        assert_eq!(*chunks.lock().unwrap(), vec![b"A".to_vec(), b"B".to_vec()]);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_persistent(true);
+        interpreter.run(&AstNode::Program(vec![AstNode::Add(3), AstNode::MoveRight])).unwrap();
+        let checkpoint = interpreter.snapshot();
+
+        interpreter.run(&AstNode::Program(vec![AstNode::Add(5), AstNode::MoveRight])).unwrap();
+        assert_eq!(interpreter.pointer(), 2);
+
+        interpreter.restore(&checkpoint).unwrap();
+        assert_eq!(interpreter.snapshot(), checkpoint);
+        assert_eq!(interpreter.pointer(), 1);
+        assert_eq!(interpreter.instruction_count, 2);
+        assert_eq!(&interpreter.memory()[..2], &[3, 0]);
+        assert_eq!(checkpoint.loop_depth(), 0);
+    }
+
+    #[test]
+    fn test_restore_rejects_invalid_state() {
+        let mut interpreter = Interpreter::new();
+        interpreter.run(&AstNode::Program(vec![AstNode::Increment])).unwrap();
+        let valid = interpreter.snapshot();
+
+        let empty = InterpreterState { memory: Vec::new(), pointer: 0, ..valid.clone() };
+        assert!(interpreter.restore(&empty).is_err());
+        let off_tape = InterpreterState { pointer: valid.memory.len(), ..valid.clone() };
+        assert!(interpreter.restore(&off_tape).is_err());
+        let mut too_wide = valid.clone();
+        too_wide.memory[1] = 256;
+        assert!(interpreter.restore(&too_wide).is_err());
+
+        // a rejected state leaves the interpreter as it was
+        assert_eq!(interpreter.snapshot(), valid);
+    }
+
+    #[test]
+    fn test_pluggable_io() {
+        use crate::io::MemoryIo;
+
+        // , reads provided input first, then the I/O, then hits EOF
+        let mut interpreter = Interpreter::new();
+        interpreter.set_eof_behavior(EofBehavior::Zero);
+        interpreter.set_io(Some(Box::new(MemoryIo::new(b"b"))));
+        interpreter.provide_input(b"a");
+        let program = AstNode::Program(vec![
+            AstNode::Input, AstNode::Output,
+            AstNode::Input, AstNode::Output,
+            AstNode::Input, AstNode::Add(33), AstNode::Output,
+        ]);
+        interpreter.run(&program).unwrap();
+        assert_eq!(interpreter.io::<MemoryIo>().unwrap().output, b"ab!");
+    }
+
+    #[test]
+    fn test_output_buffering() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_output_buffering(OutputBuffering::Every(3));
+        interpreter.write_output(b'a').unwrap();
+        interpreter.write_output(b'b').unwrap();
+        assert_eq!(interpreter.pending_output, b"ab");
+        interpreter.write_output(b'c').unwrap();
+        assert!(interpreter.pending_output.is_empty());
+
+        interpreter.set_output_buffering(OutputBuffering::Line);
+        interpreter.write_output(b'd').unwrap();
+        assert_eq!(interpreter.pending_output, b"d");
+        interpreter.write_output(b'\n').unwrap();
+        assert!(interpreter.pending_output.is_empty());
+    }
+
+    #[test]
+    fn test_output_sink_raw_bytes() {
+        use std::sync::Mutex;
+
+        // bytes above 127 reach the sink as they are, not UTF-8 encoded
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let sink = output.clone();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_output_sink(Some(Box::new(CallbackWriter(move |bytes: &[u8]| {
+            sink.lock().unwrap().extend_from_slice(bytes);
+        }))));
+        interpreter.run(&AstNode::Program(vec![AstNode::Decrement, AstNode::Output])).unwrap();
+        assert_eq!(*output.lock().unwrap(), [0xff]);
+    }
+
+    #[test]
+    fn test_procedures() {
+        let parse_pbrain = |source: &str| {
+            let mut lexer = crate::lexer::Lexer::new(source);
+            lexer.set_dialect(crate::lexer::Dialect::Pbrain);
+            crate::parser::parse(lexer.tokenize()).unwrap()
+        };
+
+        // procedure 0 bumps and prints the next cell, procedure 1 calls it twice
+        let program = parse_pbrain("(>+.<)+(-::+)-++++++++[>++++++++<-]+:-:");
+        let (output, _, _) = interpret_with_state(&program, 10).unwrap();
+        assert_eq!(output, "ABC");
+        let mut interpreter = Interpreter::new();
+        interpreter.set_optimize(true);
+        assert_eq!(interpreter.run_and_capture_output(&program).unwrap().0, "ABC");
+
+        let error = Interpreter::new().run(&parse_pbrain("(+)+:")).unwrap_err();
+        assert_eq!((error.kind, error.position), (RuntimeErrorKind::UnknownProcedure(1), 4));
+        let error = Interpreter::new().run(&parse_pbrain("(:):")).unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::CallDepthExceeded(MAX_CALL_DEPTH));
+    }
+
+    #[test]
+    fn test_debug_dumps() {
+        let mut lexer = crate::lexer::Lexer::new("+>++#>#");
+        lexer.set_debug_command(true);
+        let program = crate::parser::parse(lexer.tokenize()).unwrap();
+        let mut interpreter = Interpreter::with_tape_size(3).unwrap();
+        interpreter.set_optimize(true);
+        interpreter.run_and_capture_output(&program).unwrap();
+        let dumps: Vec<_> = interpreter.dumps().iter().map(TapeDump::to_string).collect();
+        // a tape shorter than DEBUG_DUMP_CELLS is shown whole
+        assert_eq!(dumps, ["pointer 1: [1, 2, 0]", "pointer 2: [1, 2, 0]"]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let program = crate::parser::parse(crate::lexer::tokenize("+[.]").unwrap()).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_max_instructions(Some(100));
+        let error = interpreter.run_and_capture_output(&program).unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::InstructionLimitExceeded(100));
+        // + [ and 49 times . with the ] that jumps back
+        assert_eq!(error.instruction_count, 51);
+
+        // the limits count each run separately
+        interpreter.set_max_instructions(None);
+        interpreter.set_max_output(Some(3));
+        let error = interpreter.run_and_capture_output(&program).unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::OutputLimitExceeded(3));
+        assert_eq!(interpreter.partial_output().len(), 3);
+        let error = interpreter.run_and_capture_output(&program).unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::OutputLimitExceeded(3));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let program = crate::parser::parse(crate::lexer::tokenize("+[>+<]").unwrap()).unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut interpreter = Interpreter::new();
+        interpreter.set_cancel_flag(Some(cancel.clone()));
+        let raise = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            cancel.store(true, Ordering::Relaxed);
+        });
+        let error = interpreter.run_and_capture_output(&program).unwrap_err();
+        raise.join().unwrap();
+        assert_eq!(error.kind, RuntimeErrorKind::Cancelled);
+        // the work done before the flag went up is still there
+        assert!(error.instruction_count > CANCEL_CHECK_INTERVAL);
+        assert_eq!(interpreter.memory()[0], 1);
+    }
+
+    #[test]
+    fn test_control() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Interpreter>();
+
+        // a paused run waits in another thread until it is resumed
+        let program = crate::parser::parse(crate::lexer::tokenize("++++[-]").unwrap()).unwrap();
+        let control = RunControl::new();
+        control.pause();
+        let mut interpreter = Interpreter::builder().control(control.clone()).build().unwrap();
+        let worker = {
+            let program = program.clone();
+            std::thread::spawn(move || interpreter.run_and_capture_output(&program).map(|_| interpreter))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!worker.is_finished());
+        control.resume();
+        let mut interpreter = worker.join().unwrap().unwrap();
+
+        // cancelling wins over pausing, and a cancelled control stays that way
+        control.cancel();
+        control.pause();
+        assert!(control.is_cancelled());
+        let error = interpreter.run_and_capture_output(&program).unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::Cancelled);
+        control.reset();
+        assert!(interpreter.run_and_capture_output(&program).is_ok());
+    }
+
+    #[test]
+    fn test_trace() {
+        struct Shared(Arc<std::sync::Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(bytes);
+                Ok(bytes.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let trace = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let program = crate::parser::parse(crate::lexer::tokenize("++ [-]").unwrap()).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_optimize(true);
+        interpreter.set_trace(Some(Box::new(Shared(trace.clone()))));
+        interpreter.run_and_capture_output(&program).unwrap();
+        assert_eq!(String::from_utf8(trace.lock().unwrap().clone()).unwrap(), "0\tAdd(2)\t0\t0\n2\tSet(0)\t0\t2\n");
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let program = crate::parser::parse(crate::lexer::tokenize("++[>+<-]>.").unwrap()).unwrap();
+        let mut interpreter = Interpreter::new();
+        assert!(interpreter.statistics().heatmap.is_empty());
+        interpreter.set_track_heatmap(true);
+        interpreter.run_and_capture_output(&program).unwrap();
+        // cell 0 is tested by [ and by ] each round, cell 1 is only read by .
+        assert_eq!(
+            interpreter.statistics().heatmap,
+            [CellAccess { cell: 0, reads: 3, writes: 4 }, CellAccess { cell: 1, reads: 1, writes: 2 }]
+        );
+
+        // the copy loop reads and writes both of its cells
+        let mut interpreter = Interpreter::new();
+        interpreter.set_optimize(true);
+        interpreter.set_track_heatmap(true);
+        interpreter.run_and_capture_output(&program).unwrap();
+        assert_eq!(
+            interpreter.statistics().heatmap,
+            [CellAccess { cell: 0, reads: 1, writes: 2 }, CellAccess { cell: 1, reads: 2, writes: 1 }]
+        );
+    }
+
+    #[test]
+    fn test_step_commands() {
+        let mut interpreter = Interpreter::new();
+        let frame = LoopFrame { start: 0, iterations: 0, cell: 0 };
+        interpreter.loop_stack.push(frame.clone());
+        // n at a [ doesn't stop inside the loop it enters
+        interpreter.apply_step_command("n", &AstNode::Loop(Vec::new()));
+        interpreter.loop_stack.push(frame.clone());
+        assert!(!interpreter.should_pause());
+        interpreter.loop_stack.pop();
+        assert!(interpreter.should_pause());
+        // and once stopped, the next loop stops again
+        interpreter.loop_stack.push(frame.clone());
+        assert!(interpreter.should_pause());
+        interpreter.loop_stack.pop();
+        // n anywhere else is a plain step
+        interpreter.apply_step_command("n", &AstNode::Increment);
+        assert!(interpreter.should_pause());
+
+        // u runs until the loop it's in has exited
+        interpreter.apply_step_command("u", &AstNode::Increment);
+        interpreter.loop_stack.push(frame.clone());
+        assert!(!interpreter.should_pause());
+        interpreter.loop_stack.truncate(0);
+        assert!(interpreter.should_pause());
+        // outside of loops that's the end of the program
+        interpreter.apply_step_command("u", &AstNode::Increment);
+        assert!(!interpreter.should_pause());
+    }
+
+    #[test]
+    fn test_steps() {
+        let program = crate::parser::parse(crate::lexer::tokenize("++[>+<-]>.").unwrap()).unwrap();
+        let mut interpreter = Interpreter::new();
+        let events: Vec<_> = interpreter.steps(&program).unwrap().map(Result::unwrap).collect();
+        // both +, the [, twice the 4 commands of the body, > and .
+        assert_eq!(events.len(), 13);
+        assert_eq!(
+            events[2],
+            StepEvent { pc: 2, position: 2, node: AstNode::Loop(Vec::new()), pointer: 0, cell_before: 2, cell_after: 2, output: None }
+        );
+        assert_eq!((events[4].pointer, events[4].cell_before, events[4].cell_after), (1, 0, 1));
+        // the ] at position 7 has no event of its own
+        assert_eq!(events.iter().map(|event| event.position).collect::<Vec<_>>(), [0, 1, 2, 3, 4, 5, 6, 3, 4, 5, 6, 8, 9]);
+        assert_eq!(events.last().unwrap().output, Some(2));
+
+        // an error is the last event
+        let program = AstNode::Program(vec![AstNode::MoveLeft, AstNode::Increment]);
+        let mut steps = interpreter.steps(&program).unwrap();
+        assert_eq!(steps.next().unwrap().unwrap_err().kind, RuntimeErrorKind::PointerOutOfBounds);
+        assert!(steps.next().is_none());
+    }
+
+    #[test]
+    fn test_run_for() {
+        let program = crate::parser::parse(crate::lexer::tokenize("++[>+<-]>.").unwrap()).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_output_sink(Some(Box::new(std::io::sink())));
+        assert_eq!(interpreter.run_for(&program, 5), RunStatus::Running);
+        assert_eq!((interpreter.pointer(), interpreter.memory()[1]), (1, 1));
+        assert_eq!(interpreter.run_for(&program, 7), RunStatus::Running);
+        // the last instruction finishes the run
+        assert_eq!(interpreter.run_for(&program, 1), RunStatus::Done);
+        assert_eq!((interpreter.pointer(), interpreter.memory()[1]), (1, 2));
+        // and the next call runs it again from the start
+        assert_eq!(interpreter.run_for(&program, 100), RunStatus::Done);
+        assert_eq!(interpreter.memory()[1], 2);
+
+        let program = AstNode::Program(vec![AstNode::Increment, AstNode::MoveLeft]);
+        let RunStatus::Error(error) = interpreter.run_for(&program, 100) else { panic!("expected an error") };
+        assert_eq!(error.kind, RuntimeErrorKind::PointerOutOfBounds);
+    }
+
+    #[test]
+    fn test_sampling() {
+        let program = crate::parser::parse(crate::lexer::tokenize("++++++++[>+<-]").unwrap()).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_sample_interval(Some(4));
+        interpreter.run_and_capture_output(&program).unwrap();
+        let stats = interpreter.statistics();
+        assert!(stats.instruction_counts.is_empty() && stats.instruction_times_ms.is_empty());
+        assert_eq!(stats.total_instructions, Some(41));
+        // the loop body is 4 instructions long, so it's sampled at the same command every time
+        assert_eq!(stats.samples.iter().map(|sample| sample.count).sum::<usize>(), 10);
+        let first = &stats.samples[0];
+        assert_eq!((first.position, first.instruction.as_str(), first.count), (11, "MoveLeft", 8));
+    }
+
+    #[test]
+    fn test_hot_loops() {
+        let program = crate::parser::parse(crate::lexer::tokenize("++[>+++[>+<-]<-]").unwrap()).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.run_and_capture_output(&program).unwrap();
+        let loops = interpreter.statistics().loops;
+        let summary: Vec<_> = loops.iter().map(|stats| (stats.position, stats.entries, stats.iterations, stats.instructions)).collect();
+        // the inner loop runs 3 times for each of the outer loop's 2 iterations
+        assert_eq!(summary, [(7, 2, 6, 24), (2, 1, 2, 38)]);
+        assert!(loops.iter().all(|stats| stats.time_ms.is_some()));
+    }
+
+    #[test]
+    fn test_command_counts() {
+        let program = crate::parser::parse(crate::lexer::tokenize("++[>+<-]").unwrap()).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_count_commands(true);
+        interpreter.run_and_capture_output(&program).unwrap();
+        // the body runs twice and ] tests the cell after each round
+        assert_eq!(interpreter.command_counts(), [1, 1, 1, 2, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_profile() {
+        let program = crate::parser::parse(crate::lexer::tokenize("++[>+[-]<-]").unwrap()).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_record_timeline(true);
+        interpreter.run_and_capture_output(&program).unwrap();
+        let instructions: Vec<_> = interpreter.profile().instructions().iter().map(|profile| (profile.position, profile.count)).collect();
+        assert_eq!(instructions, [(0, 1), (1, 1), (2, 1), (3, 2), (4, 2), (5, 2), (6, 2), (8, 2), (9, 2)]);
+        // the inner loop ends before the outer one, inside it
+        let timeline: Vec<_> = interpreter.profile().timeline().iter().map(|event| (event.position, event.iterations)).collect();
+        assert_eq!(timeline, [(5, 1), (5, 1), (2, 2)]);
+        let (inner, outer) = (&interpreter.profile().timeline()[1], &interpreter.profile().timeline()[2]);
+        assert!(outer.start <= inner.start && inner.start + inner.duration <= outer.start + outer.duration);
+    }
+
+    #[test]
+    fn test_peak_memory() {
+        let program = crate::parser::parse(crate::lexer::tokenize(">>>>>").unwrap()).unwrap();
+        let mut interpreter = Interpreter::with_tape_size(4).unwrap();
+        interpreter.set_cell_width(CellWidth::U16);
+        assert_eq!(interpreter.statistics().peak_memory_bytes, Some(8));
+        interpreter.set_tape_policy(TapePolicy::Grow);
+        interpreter.run_and_capture_output(&program).unwrap();
+        // the tape doubled to 8 cells of 2 bytes
+        assert_eq!(interpreter.statistics().peak_memory_bytes, Some(16));
+    }
+
+    #[test]
+    fn test_cycles() {
+        let program = crate::parser::parse(crate::lexer::tokenize("++[>+<-]>.").unwrap()).unwrap();
+        let cycles = |optimize: bool| {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_optimize(optimize);
+            interpreter.set_cost_model(Some(CostModel::default()));
+            interpreter.run_and_capture_output(&program).unwrap();
+            interpreter.statistics().cycles.unwrap()
+        };
+        // 2 adds, 3 loop tests, 8 in the body, a move and an output
+        assert_eq!(cycles(false), 2 + 3 + 8 + 1 + 10);
+        // Add(2), MulCopy, a move and an output
+        assert_eq!(cycles(true), 1 + 1 + 1 + 10);
+        assert_eq!(Interpreter::new().statistics().cycles, None);
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        // the copy writes cell 2 without the pointer getting there
+        let program = crate::parser::parse(crate::lexer::tokenize("++[->>+<<]>+>>>+[-]<<<<").unwrap()).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_optimize(true);
+        interpreter.run_and_capture_output(&program).unwrap();
+        let stats = interpreter.statistics();
+        assert_eq!((stats.max_pointer, stats.cells_written, stats.nonzero_cells), (Some(4), Some(4), Some(2)));
+    }
+
+    #[test]
+    fn test_partial_evaluation() {
+        let program = crate::parser::parse(crate::lexer::tokenize("++++++++[>++++++++<-]>+.,.").unwrap()).unwrap();
+        let run = |partial_evaluation: bool| {
+            let mut interpreter = Interpreter::builder().optimize(true).partial_evaluation(partial_evaluation).input(b"z").build().unwrap();
+            let result = interpreter.run_and_capture_output(&program).unwrap();
+            (result, interpreter.statistics().total_instructions)
+        };
+        let (folded, instructions) = run(true);
+        assert_eq!(folded, run(false).0);
+        assert_eq!(folded.0, "Az");
+        // Move(1), Set(65), Output, Input, Output
+        assert_eq!(instructions, Some(5));
+    }
+
+    #[test]
+    fn test_on_progress() {
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = calls.clone();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_on_progress(10, Some(Box::new(move |instructions, _| seen.lock().unwrap().push(instructions))));
+        // 26 instructions and 5 ] tests
+        let program = crate::parser::parse(crate::lexer::tokenize("+++++[>-<-]").unwrap()).unwrap();
+        interpreter.run_and_capture_output(&program).unwrap();
+        // the ] tests count towards the interval but aren't instructions, and
+        // the hook runs before the 10th, 20th and 30th
+        assert_eq!(*calls.lock().unwrap(), [9, 17, 25]);
+    }
 }
-    
\ No newline at end of file
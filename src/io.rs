@@ -0,0 +1,307 @@
+//! pluggable byte I/O for the , and . instructions
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+use std::process::Command;
+
+// where , reads from and . writes to. implement this to connect programs to
+// sockets, test fixtures or GUI widgets
+pub trait BfIo: Any + Send {
+    // Ok(None) means end of input
+    fn read_byte(&mut self) -> io::Result<Option<u8>>;
+    fn write_byte(&mut self, byte: u8) -> io::Result<()>;
+
+    // called before blocking on input and when a run ends
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// reads stdin and writes stdout directly, one byte at a time
+pub struct StdIo;
+
+impl BfIo for StdIo {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match io::stdin().read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        io::stdout().write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+// how output captured as bytes is turned into a String
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputEncoding {
+    // each byte becomes the char with that code point, so none is lost
+    #[default]
+    Latin1,
+    // the bytes are decoded as UTF-8, with U+FFFD for invalid sequences
+    Utf8,
+}
+
+impl OutputEncoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "latin1" => Some(OutputEncoding::Latin1),
+            "utf8" => Some(OutputEncoding::Utf8),
+            _ => None,
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            OutputEncoding::Latin1 => bytes.iter().copied().map(char::from).collect(),
+            OutputEncoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+        }
+    }
+
+    // decode() for output that is still being written: a UTF-8 sequence cut
+    // off at the end is left for when the rest of it arrives. returns the
+    // text and the number of bytes it came from
+    pub fn decode_complete(self, bytes: &[u8]) -> (String, usize) {
+        if self == OutputEncoding::Latin1 {
+            return (self.decode(bytes), bytes.len());
+        }
+        let mut text = String::new();
+        let mut rest = bytes;
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    return (text, bytes.len());
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    // checked by from_utf8
+                    text.push_str(std::str::from_utf8(valid).unwrap());
+                    match e.error_len() {
+                        Some(invalid) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &after[invalid..];
+                        }
+                        None => return (text, bytes.len() - after.len()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+// what PromptIo shows on stderr when , needs input
+pub const PROMPT: &str = "input> ";
+
+// how much of what is typed at a prompt , gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptMode {
+    // the whole line, newline included, one byte per , until it runs out
+    Line,
+    // only its first byte, a newline for an empty line, and the rest dropped
+    Byte,
+}
+
+impl PromptMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "line" => Some(PromptMode::Line),
+            "byte" => Some(PromptMode::Byte),
+            _ => None,
+        }
+    }
+}
+
+// reads a line from the terminal after showing PROMPT whenever , runs out of
+// input, so a program waiting for it doesn't look like it hung. writes
+// stdout like StdIo
+pub struct PromptIo {
+    mode: PromptMode,
+    echo: bool,
+    input: Box<dyn BufRead + Send>,
+    prompts: Box<dyn Write + Send>,
+    line: VecDeque<u8>,
+}
+
+impl PromptIo {
+    pub fn new(mode: PromptMode) -> Self {
+        Self::with_streams(mode, Box::new(io::BufReader::new(io::stdin())), Box::new(io::stderr()))
+    }
+
+    // reads lines from `input` and shows the prompts on `prompts`
+    pub fn with_streams(mode: PromptMode, input: Box<dyn BufRead + Send>, prompts: Box<dyn Write + Send>) -> Self {
+        PromptIo { mode, echo: true, input, prompts, line: VecDeque::new() }
+    }
+
+    // whether the terminal shows what is typed, turned off with stty while
+    // reading, e.g. for passwords
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        io::stdout().flush()?;
+        self.prompts.write_all(PROMPT.as_bytes())?;
+        self.prompts.flush()?;
+        if !self.echo {
+            set_terminal_echo(false)?;
+        }
+        let mut line = Vec::new();
+        let read = self.input.read_until(b'\n', &mut line);
+        if !self.echo {
+            set_terminal_echo(true)?;
+            // the newline that was typed wasn't shown either
+            self.prompts.write_all(b"\n")?;
+        }
+        read.map(|_| line)
+    }
+}
+
+fn set_terminal_echo(echo: bool) -> io::Result<()> {
+    let status = Command::new("stty").arg(if echo { "echo" } else { "-echo" }).status()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(io::Error::other("stty could not change the terminal's echo")),
+    }
+}
+
+impl BfIo for PromptIo {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.line.pop_front() {
+            return Ok(Some(byte));
+        }
+        let line = self.read_line()?;
+        // nothing at all, not even a newline, is the end of input
+        if line.is_empty() {
+            return Ok(None);
+        }
+        match self.mode {
+            PromptMode::Line => {
+                self.line.extend(line);
+                Ok(self.line.pop_front())
+            }
+            PromptMode::Byte => Ok(line.first().copied()),
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        io::stdout().write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+// serves input from a buffer and collects output, for tests and embedding
+#[derive(Debug, Default)]
+pub struct MemoryIo {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+impl MemoryIo {
+    pub fn new(input: &[u8]) -> Self {
+        MemoryIo {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl BfIo for MemoryIo {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop_front())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.push(byte);
+        Ok(())
+    }
+}
+
+// passes everything through to another BfIo and keeps a copy of the bytes
+// read and written, e.g. to save a run and play it back later
+pub struct TeeIo {
+    inner: Box<dyn BfIo>,
+    pub input: Vec<u8>,
+    pub output: Vec<u8>,
+}
+
+impl TeeIo {
+    pub fn new(inner: Box<dyn BfIo>) -> Self {
+        TeeIo { inner, input: Vec::new(), output: Vec::new() }
+    }
+}
+
+impl BfIo for TeeIo {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.inner.read_byte()?;
+        self.input.extend(byte);
+        Ok(byte)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.inner.write_byte(byte)?;
+        self.output.push(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_io() {
+        let mut io = MemoryIo::new(b"a");
+        assert_eq!(io.read_byte().unwrap(), Some(b'a'));
+        assert_eq!(io.read_byte().unwrap(), None);
+        io.write_byte(b'z').unwrap();
+        assert_eq!(io.output, b"z");
+    }
+
+    #[test]
+    fn test_output_encoding() {
+        let bytes = "é!".as_bytes();
+        assert_eq!(OutputEncoding::Latin1.decode(bytes), "\u{c3}\u{a9}!");
+        assert_eq!(OutputEncoding::Utf8.decode(bytes), "é!");
+        assert_eq!(OutputEncoding::Utf8.decode(b"a\xffb"), "a\u{fffd}b");
+        assert_eq!(OutputEncoding::Utf8.decode_complete(b"a\xff\xc3"), ("a\u{fffd}".to_string(), 2));
+        assert_eq!(OutputEncoding::Latin1.decode_complete(b"a\xc3"), ("a\u{c3}".to_string(), 2));
+        assert_eq!(OutputEncoding::from_name("utf8"), Some(OutputEncoding::Utf8));
+        assert_eq!(OutputEncoding::from_name("ascii"), None);
+    }
+
+    #[test]
+    fn test_prompt_io() {
+        let prompted = |mode, typed: &'static [u8], reads| {
+            let mut io = PromptIo::with_streams(mode, Box::new(typed), Box::new(io::sink()));
+            (0..reads).map(|_| io.read_byte().unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(prompted(PromptMode::Line, b"hi\nx", 5), [Some(b'h'), Some(b'i'), Some(b'\n'), Some(b'x'), None]);
+        assert_eq!(prompted(PromptMode::Byte, b"hi\n\nx", 4), [Some(b'h'), Some(b'\n'), Some(b'x'), None]);
+        assert_eq!(PromptMode::from_name("byte"), Some(PromptMode::Byte));
+        assert_eq!(PromptMode::from_name("char"), None);
+    }
+
+    #[test]
+    fn test_tee_io() {
+        let mut io = TeeIo::new(Box::new(MemoryIo::new(b"a")));
+        assert_eq!(io.read_byte().unwrap(), Some(b'a'));
+        assert_eq!(io.read_byte().unwrap(), None);
+        io.write_byte(b'z').unwrap();
+        assert_eq!((&io.input[..], &io.output[..]), (&b"a"[..], &b"z"[..]));
+    }
+}
@@ -0,0 +1,190 @@
+//! a flat, typed instruction list between the optimized tree and the
+//! backends. the tree's many spellings of the same thing (Increment, Add and
+//! Sub, MoveRight and Move, ScanLeft and ScanRight) become one instruction
+//! each, loops become jumps with their targets worked out once, and cell
+//! changes can be at an offset from the pointer instead of a move there and
+//! back
+
+use crate::linear::{self, Instruction};
+use crate::parser::AstNode;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    // adds to the cell `offset` away from the pointer, wrapping
+    Add { offset: isize, amount: i32 },
+    Set { offset: isize, value: u8 },
+    Move(isize),
+    // adds the cell times each factor to the cell at each offset, then clears it
+    MulCopy { targets: Vec<(isize, i32)> },
+    Swap { offset: isize, temp: isize },
+    Scan(isize), // moves by the stride until a zero cell
+    In,
+    Out,
+    Debug,
+    // [ jumps to `end`, the instruction after the matching ], when the cell is zero
+    LoopStart { end: usize },
+    // ] jumps back to `start`, the first instruction of the body, when it isn't
+    LoopEnd { start: usize },
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    pub instrs: Vec<Instr>,
+    pub positions: Vec<usize>, // source command index of each instruction
+}
+
+impl Program {
+    // lowers an optimized program body, see
+    // Optimizer::optimize_with_positions. pbrain procedures have no IR, the
+    // position of the first one is the error
+    pub fn lower(instructions: &[AstNode], positions: &[usize]) -> Result<Self, usize> {
+        let code = linear::flatten_with_positions(instructions, positions);
+        let mut program = Program::default();
+        // index of each open [
+        let mut open = Vec::new();
+        // the cells around the pointer the code has been to since the last
+        // jump, which a move can reach without going out of bounds
+        let mut visited = (0, 0);
+        let mut i = 0;
+        while i < code.len() {
+            let Instruction { op, position } = &code[i];
+            let instr = match op {
+                linear::Op::LoopStart { .. } => {
+                    open.push(program.instrs.len());
+                    visited = (0, 0);
+                    Instr::LoopStart { end: 0 }
+                }
+                linear::Op::LoopEnd { .. } => {
+                    let start = open.pop().expect("flatten balances loops");
+                    program.instrs[start] = Instr::LoopStart { end: program.instrs.len() + 1 };
+                    visited = (0, 0);
+                    Instr::LoopEnd { start: start + 1 }
+                }
+                linear::Op::Basic(node) => match (node, moved(node)) {
+                    (_, Some(offset)) => {
+                        // >+< over cells already visited becomes an Add at
+                        // offset 1, which can't go out of bounds either
+                        let body = code[i + 1..].iter().take_while(|next| at_offset(&next.op, 0).is_some()).count();
+                        let back = code.get(i + body + 1).and_then(|next| match &next.op {
+                            linear::Op::Basic(node) => moved(node),
+                            _ => None,
+                        });
+                        if body > 0 && back == Some(-offset) && visited.0 <= offset && offset <= visited.1 {
+                            for next in &code[i + 1..=i + body] {
+                                program.instrs.push(at_offset(&next.op, offset).unwrap());
+                                program.positions.push(next.position);
+                            }
+                            i += body + 2;
+                            continue;
+                        }
+                        visited = (visited.0.min(offset) - offset, visited.1.max(offset) - offset);
+                        Instr::Move(offset)
+                    }
+                    (AstNode::MulCopy { targets }, _) => Instr::MulCopy { targets: targets.clone() },
+                    (&AstNode::Swap { offset, temp }, _) => Instr::Swap { offset, temp },
+                    (&AstNode::ScanRight(stride), _) => {
+                        visited = (0, 0);
+                        Instr::Scan(stride as isize)
+                    }
+                    (&AstNode::ScanLeft(stride), _) => {
+                        visited = (0, 0);
+                        Instr::Scan(-(stride as isize))
+                    }
+                    (AstNode::Input, _) => Instr::In,
+                    (AstNode::Output, _) => Instr::Out,
+                    (AstNode::Debug, _) => Instr::Debug,
+                    _ => at_offset(op, 0).ok_or(*position)?,
+                },
+                _ => return Err(*position),
+            };
+            program.instrs.push(instr);
+            program.positions.push(*position);
+            i += 1;
+        }
+        Ok(program)
+    }
+}
+
+// how far a move instruction moves the pointer
+fn moved(node: &AstNode) -> Option<isize> {
+    match node {
+        AstNode::MoveRight => Some(1),
+        AstNode::MoveLeft => Some(-1),
+        AstNode::Move(n) => Some(*n),
+        _ => None,
+    }
+}
+
+// a change to a single cell, made to the one `offset` away from the pointer
+fn at_offset(op: &linear::Op, offset: isize) -> Option<Instr> {
+    let linear::Op::Basic(node) = op else { return None };
+    Some(match *node {
+        AstNode::Increment => Instr::Add { offset, amount: 1 },
+        AstNode::Decrement => Instr::Add { offset, amount: -1 },
+        AstNode::Add(n) => Instr::Add { offset, amount: n as i32 },
+        AstNode::Sub(n) => Instr::Add { offset, amount: (n as i32).wrapping_neg() },
+        AstNode::Set(value) => Instr::Set { offset, value },
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimizer::Optimizer;
+
+    fn lower(source: &str) -> Program {
+        let ast = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+        let AstNode::Program(instructions) = ast else { unreachable!() };
+        let positions = (0..source.len()).collect::<Vec<_>>();
+        Program::lower(&instructions, &positions).unwrap()
+    }
+
+    #[test]
+    fn test_lower() {
+        let program = lower("+-[>.<]<");
+        assert_eq!(program.instrs, [
+            Instr::Add { offset: 0, amount: 1 },
+            Instr::Add { offset: 0, amount: -1 },
+            Instr::LoopStart { end: 7 },
+            Instr::Move(1),
+            Instr::Out,
+            Instr::Move(-1),
+            Instr::LoopEnd { start: 3 },
+            Instr::Move(-1),
+        ]);
+        assert_eq!(program.positions, [0, 1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_offsets() {
+        // the first > could be the tape's first step right, so it stays a
+        // move, but the <+> back over the cell it left doesn't
+        let program = lower(">+<+>-<");
+        assert_eq!(program.instrs, [
+            Instr::Move(1),
+            Instr::Add { offset: 0, amount: 1 },
+            Instr::Add { offset: -1, amount: 1 },
+            Instr::Add { offset: 0, amount: -1 },
+            Instr::Move(-1),
+        ]);
+        assert_eq!(program.positions, [0, 1, 3, 5, 6]);
+        // a loop could have been anywhere, so its body starts over
+        let program = lower("><[>+<]");
+        assert_eq!(program.instrs[3..6], [Instr::Move(1), Instr::Add { offset: 0, amount: 1 }, Instr::Move(-1)]);
+    }
+
+    #[test]
+    fn test_lower_optimized() {
+        let optimizer = Optimizer::new();
+        let ast = crate::parser::parse(crate::lexer::tokenize(">>+<<[->+<]>[-]<[<]").unwrap()).unwrap();
+        let (AstNode::Program(instructions), positions) = optimizer.optimize_with_positions(&ast) else {
+            unreachable!()
+        };
+        let program = Program::lower(&instructions, &positions).unwrap();
+        assert_eq!(program.instrs.last(), Some(&Instr::Scan(-1)));
+        assert!(program.instrs.contains(&Instr::MulCopy { targets: vec![(1, 1)] }));
+        assert!(program.instrs.contains(&Instr::Set { offset: 1, value: 0 }));
+        assert!(Program::lower(&[AstNode::ProcDef(Vec::new())], &[4, 5]).is_err());
+    }
+}
@@ -0,0 +1,641 @@
+//! compiles programs to native code in-process with Cranelift
+
+use crate::engine::{Engine, Observer};
+use crate::interpreter::{EofBehavior, RuntimeError, RuntimeErrorKind, DEFAULT_TAPE_SIZE};
+use crate::optimizer::Optimizer;
+use crate::parser::AstNode;
+use crate::stats::ExecutionStats;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, FuncRef, InstBuilder, MemFlags, Value};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+// the generated function: (tape, context, pointer out) -> 0, or the command
+// position + 1 of a move that left the tape
+type CompiledProgram = unsafe extern "C" fn(*mut u8, *mut Context, *mut i64) -> i64;
+
+// runs the optimized program as native code. much faster than interpreting,
+// but only supports 8 bit cells on a fixed size tape, and doesn't count
+// instructions or call observers
+pub struct JitEngine {
+    memory: Vec<u32>,
+    pointer: usize,
+    tape_size: usize,
+    input: VecDeque<u8>,
+    eof_behavior: EofBehavior,
+    output: Option<Box<dyn Write>>,
+    elapsed: Option<Duration>,
+    started: Option<AstNode>, // program waiting for step()
+    unroll: HashMap<usize, usize>, // position of a loop's [ -> copies of its body, see set_unroll_factors
+    partial_evaluation: bool,
+}
+
+// what the compiled code reaches through its , and . callbacks
+struct Context<'a> {
+    input: &'a mut VecDeque<u8>,
+    eof_behavior: EofBehavior,
+    output: &'a mut dyn Write,
+    error: Option<io::Error>, // the first failed write, reported after the run
+}
+
+impl Default for JitEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JitEngine {
+    pub fn new() -> Self {
+        JitEngine {
+            memory: vec![0; DEFAULT_TAPE_SIZE],
+            pointer: 0,
+            tape_size: DEFAULT_TAPE_SIZE,
+            input: VecDeque::new(),
+            eof_behavior: EofBehavior::Unchanged,
+            output: None,
+            elapsed: None,
+            started: None,
+            unroll: HashMap::new(),
+            partial_evaluation: false,
+        }
+    }
+
+    pub fn set_tape_size(&mut self, tape_size: usize) -> Result<(), String> {
+        if tape_size == 0 {
+            return Err("Tape size must be at least 1 cell".to_string());
+        }
+        self.tape_size = tape_size;
+        self.memory = vec![0; tape_size];
+        Ok(())
+    }
+
+    pub fn set_eof_behavior(&mut self, eof_behavior: EofBehavior) {
+        self.eof_behavior = eof_behavior;
+    }
+
+    // queues bytes for , to read before it falls back to stdin
+    pub fn provide_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    // sends output from . to `sink` instead of stdout
+    pub fn set_output_sink(&mut self, sink: Option<Box<dyn Write>>) {
+        self.output = sink;
+    }
+
+    // compiles the loops whose [ is at one of the positions with that many
+    // copies of their body, each after the first behind its own test of the
+    // cell, so the hot loops of a pgo::LoopProfile jump back less often.
+    // only innermost loops are unrolled, which keeps the code small
+    pub fn set_unroll_factors(&mut self, unroll: HashMap<usize, usize>) {
+        self.unroll = unroll;
+    }
+
+    // runs the input-free start of each program before compiling it, see
+    // Optimizer::set_partial_evaluation
+    pub fn set_partial_evaluation(&mut self, enabled: bool) {
+        self.partial_evaluation = enabled;
+    }
+
+    fn error(&self, kind: RuntimeErrorKind, position: usize) -> RuntimeError {
+        RuntimeError {
+            kind,
+            position,
+            instruction_count: 0,
+            pointer: self.pointer,
+        }
+    }
+}
+
+impl Engine for JitEngine {
+    fn name(&self) -> &'static str {
+        "jit"
+    }
+
+    fn run(&mut self, ast: &AstNode) -> Result<(), RuntimeError> {
+        let mut optimizer = Optimizer::new();
+        optimizer.set_partial_evaluation(self.partial_evaluation);
+        let (AstNode::Program(instructions), positions) = optimizer.optimize_with_positions(ast) else {
+            return Err(self.error(RuntimeErrorKind::ExpectedProgram, 0));
+        };
+        let (module, program) = compile(&instructions, &positions, self.tape_size, &self.unroll)
+            .map_err(|e| self.error(RuntimeErrorKind::Compile(e), 0))?;
+
+        let start = Instant::now();
+        let mut tape = vec![0u8; self.tape_size];
+        let mut pointer = 0i64;
+        let mut stdout = io::stdout().lock();
+        let mut context = Context {
+            input: &mut self.input,
+            eof_behavior: self.eof_behavior,
+            output: match self.output.as_mut() {
+                Some(sink) => sink.as_mut(),
+                None => &mut stdout,
+            },
+            error: None,
+        };
+        // safe because the code bounds checks every access against tape_size
+        // and context outlives the call
+        let status = unsafe { program(tape.as_mut_ptr(), &mut context, &mut pointer) };
+        let flushed = context.output.flush();
+        let write_error = context.error.take();
+        drop(stdout);
+        // the code is freed with the module, so nothing may call it after this
+        unsafe { module.free_memory() };
+
+        self.elapsed = Some(start.elapsed());
+        self.memory = tape.into_iter().map(u32::from).collect();
+        self.pointer = pointer as usize;
+        if let Some(e) = write_error.map_or(flushed.err(), Some) {
+            return Err(self.error(RuntimeErrorKind::Output(e.to_string()), 0));
+        }
+        match status {
+            0 => Ok(()),
+            position => Err(self.error(RuntimeErrorKind::PointerOutOfBounds, position as usize - 1)),
+        }
+    }
+
+    fn start(&mut self, ast: &AstNode) -> Result<(), RuntimeError> {
+        self.started = Some(ast.clone());
+        Ok(())
+    }
+
+    // compiled code can't pause, so the first step runs the whole program
+    fn step(&mut self) -> Result<bool, RuntimeError> {
+        match self.started.take() {
+            Some(ast) => self.run(&ast).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    fn memory(&self) -> &[u32] {
+        &self.memory
+    }
+
+    fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    // compiled code doesn't count instructions
+    fn instruction_count(&self) -> usize {
+        0
+    }
+
+    // compiled code has no hook to call observers from
+    fn set_observer(&mut self, _observer: Option<Box<dyn Observer>>) {}
+
+    // compiled code only knows how long it took
+    fn statistics(&self) -> ExecutionStats {
+        ExecutionStats {
+            elapsed_ms: self.elapsed.map(|elapsed| elapsed.as_secs_f64() * 1000.0),
+            // a fixed tape of bytes
+            peak_memory_bytes: Some(self.tape_size),
+            nonzero_cells: Some(self.memory.iter().filter(|&&cell| cell != 0).count()),
+            ..ExecutionStats::default()
+        }
+    }
+}
+
+extern "C" fn bf_write(context: *mut Context, byte: i32) {
+    let context = unsafe { &mut *context };
+    if context.error.is_none() {
+        if let Err(e) = context.output.write_all(&[byte as u8]) {
+            context.error = Some(e);
+        }
+    }
+}
+
+// returns the new value of the cell, which is `old` at end of input unless
+// the EOF behavior says otherwise
+extern "C" fn bf_read(context: *mut Context, old: i32) -> i32 {
+    let context = unsafe { &mut *context };
+    if let Some(byte) = context.input.pop_front() {
+        return byte as i32;
+    }
+    // make sure any prompt is visible before blocking on input
+    let _ = context.output.flush();
+    let mut byte = [0];
+    match io::stdin().read_exact(&mut byte) {
+        Ok(()) => byte[0] as i32,
+        Err(_) => match context.eof_behavior {
+            EofBehavior::Unchanged => old,
+            EofBehavior::Zero => 0,
+            EofBehavior::MaxValue => 0xFF,
+        },
+    }
+}
+
+fn compile(
+    instructions: &[AstNode],
+    positions: &[usize],
+    tape_size: usize,
+    unroll: &HashMap<usize, usize>,
+) -> Result<(JITModule, CompiledProgram), String> {
+    if instructions.iter().any(AstNode::has_procedures) {
+        return Err("pbrain procedures are not supported".to_string());
+    }
+    if instructions.iter().any(|instruction| instruction.contains(&|node| *node == AstNode::Debug)) {
+        return Err("the # debug command is not supported".to_string());
+    }
+    let mut builder = JITBuilder::with_flags(&[("opt_level", "speed")], default_libcall_names())
+        .map_err(|e| e.to_string())?;
+    builder.symbol("bf_write", bf_write as *const u8);
+    builder.symbol("bf_read", bf_read as *const u8);
+    let mut module = JITModule::new(builder);
+    let pointer_type = module.target_config().pointer_type();
+
+    let mut write_signature = module.make_signature();
+    write_signature.params.push(AbiParam::new(pointer_type));
+    write_signature.params.push(AbiParam::new(types::I32));
+    let mut read_signature = module.make_signature();
+    read_signature.params.push(AbiParam::new(pointer_type));
+    read_signature.params.push(AbiParam::new(types::I32));
+    read_signature.returns.push(AbiParam::new(types::I32));
+    let write = module
+        .declare_function("bf_write", Linkage::Import, &write_signature)
+        .map_err(|e| e.to_string())?;
+    let read = module
+        .declare_function("bf_read", Linkage::Import, &read_signature)
+        .map_err(|e| e.to_string())?;
+
+    let mut context = module.make_context();
+    for _ in 0..3 {
+        context.func.signature.params.push(AbiParam::new(pointer_type));
+    }
+    context.func.signature.returns.push(AbiParam::new(types::I64));
+
+    let mut builder_context = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    let params = builder.block_params(entry).to_vec();
+
+    let pointer = Variable::from_u32(0);
+    builder.declare_var(pointer, pointer_type);
+    let zero = builder.ins().iconst(pointer_type, 0);
+    builder.def_var(pointer, zero);
+
+    let write = module.declare_func_in_func(write, builder.func);
+    let read = module.declare_func_in_func(read, builder.func);
+    let mut lowering = Lowering {
+        builder,
+        tape: params[0],
+        context: params[1],
+        pointer_out: params[2],
+        pointer,
+        write,
+        read,
+        tape_size,
+        positions,
+        next: 0,
+        position: 0,
+        unroll,
+    };
+    for instruction in instructions {
+        lowering.lower(instruction);
+    }
+    lowering.exit(0);
+    lowering.builder.seal_all_blocks();
+    lowering.builder.finalize();
+
+    let id = module
+        .declare_function("run", Linkage::Export, &context.func.signature)
+        .map_err(|e| e.to_string())?;
+    module.define_function(id, &mut context).map_err(|e| e.to_string())?;
+    module.clear_context(&mut context);
+    module.finalize_definitions().map_err(|e| e.to_string())?;
+
+    let code = module.get_finalized_function(id);
+    let program = unsafe { std::mem::transmute::<*const u8, CompiledProgram>(code) };
+    Ok((module, program))
+}
+
+// translates AST nodes into Cranelift IR for the function being built
+struct Lowering<'a> {
+    builder: FunctionBuilder<'a>,
+    tape: Value,
+    context: Value,
+    pointer_out: Value,
+    pointer: Variable,
+    write: FuncRef,
+    read: FuncRef,
+    tape_size: usize,
+    positions: &'a [usize], // source command of each instruction, in linear::flatten order
+    next: usize,            // index into positions of the next instruction
+    position: usize,        // command index of the node being lowered
+    unroll: &'a HashMap<usize, usize>,
+}
+
+impl Lowering<'_> {
+    fn lower(&mut self, instruction: &AstNode) {
+        if !matches!(instruction, AstNode::Program(_) | AstNode::Comment(_)) {
+            self.position = self.next_position();
+        }
+        match instruction {
+            AstNode::Increment => self.add(1),
+            AstNode::Decrement => self.add(-1),
+            AstNode::Add(n) => self.add(*n as i64),
+            AstNode::Sub(n) => self.add(-(*n as i64)),
+            AstNode::Set(value) => {
+                let index = self.builder.use_var(self.pointer);
+                let value = self.builder.ins().iconst(types::I8, *value as i64);
+                self.store(index, value);
+            }
+            AstNode::MoveRight => self.move_by(1),
+            AstNode::MoveLeft => self.move_by(-1),
+            AstNode::Move(n) => self.move_by(*n),
+            AstNode::Output => {
+                let value = self.load_current();
+                let byte = self.builder.ins().uextend(types::I32, value);
+                self.builder.ins().call(self.write, &[self.context, byte]);
+            }
+            AstNode::Input => {
+                let index = self.builder.use_var(self.pointer);
+                let old = self.load(index);
+                let old = self.builder.ins().uextend(types::I32, old);
+                let call = self.builder.ins().call(self.read, &[self.context, old]);
+                let new = self.builder.inst_results(call)[0];
+                let new = self.builder.ins().ireduce(types::I8, new);
+                self.store(index, new);
+            }
+            AstNode::Loop(body) => {
+                let innermost = !body.iter().any(|instruction| matches!(instruction, AstNode::Loop(_)));
+                let copies = self.unroll.get(&self.position).copied().filter(|_| innermost).unwrap_or(1);
+                // every copy of the body has the same positions
+                let first = self.next;
+                self.while_nonzero_unrolled(copies.max(1), |lowering| {
+                    lowering.next = first;
+                    for instruction in body {
+                        lowering.lower(instruction);
+                    }
+                });
+                // the ]
+                self.next_position();
+            }
+            AstNode::MulCopy { targets } => {
+                let counter = self.load_current();
+                let copy = self.builder.create_block();
+                let done = self.builder.create_block();
+                self.builder.ins().brif(counter, copy, &[], done, &[]);
+                self.builder.switch_to_block(copy);
+                let pointer = self.builder.use_var(self.pointer);
+                for (offset, factor) in targets {
+                    let index = self.builder.ins().iadd_imm(pointer, *offset as i64);
+                    self.check_bounds(index);
+                    let old = self.load(index);
+                    let product = self.builder.ins().imul_imm(counter, *factor as i64);
+                    let new = self.builder.ins().iadd(old, product);
+                    self.store(index, new);
+                }
+                let zero = self.builder.ins().iconst(types::I8, 0);
+                self.store(pointer, zero);
+                self.builder.ins().jump(done, &[]);
+                self.builder.switch_to_block(done);
+            }
+            AstNode::Swap { offset, temp } => {
+                let pointer = self.builder.use_var(self.pointer);
+                let other = self.builder.ins().iadd_imm(pointer, *offset as i64);
+                self.check_bounds(other);
+                let temp = self.builder.ins().iadd_imm(pointer, *temp as i64);
+                self.check_bounds(temp);
+                let value = self.load(pointer);
+                let moved = self.load(other);
+                let kept = self.load(temp);
+                self.store(pointer, moved);
+                let sum = self.builder.ins().iadd(kept, value);
+                self.store(other, sum);
+                let zero = self.builder.ins().iconst(types::I8, 0);
+                self.store(temp, zero);
+            }
+            AstNode::ScanRight(stride) => {
+                let stride = *stride as isize;
+                self.while_nonzero(|lowering| lowering.move_by(stride));
+            }
+            AstNode::ScanLeft(stride) => {
+                let stride = -(*stride as isize);
+                self.while_nonzero(|lowering| lowering.move_by(stride));
+            }
+            AstNode::Program(instructions) => {
+                for instruction in instructions {
+                    self.lower(instruction);
+                }
+            }
+            AstNode::Comment(_) => {}
+            AstNode::ProcDef(_) | AstNode::ProcCall | AstNode::Debug => unreachable!("rejected by compile"),
+        }
+    }
+
+    fn next_position(&mut self) -> usize {
+        let position = self.positions.get(self.next).copied().unwrap_or(0);
+        self.next += 1;
+        position
+    }
+
+    // header: if cell == 0 goto exit; body; goto header
+    fn while_nonzero(&mut self, body: impl FnOnce(&mut Self)) {
+        let header = self.builder.create_block();
+        let inner = self.builder.create_block();
+        let exit = self.builder.create_block();
+        self.builder.ins().jump(header, &[]);
+        self.builder.switch_to_block(header);
+        let value = self.load_current();
+        self.builder.ins().brif(value, inner, &[], exit, &[]);
+        self.builder.switch_to_block(inner);
+        body(self);
+        self.builder.ins().jump(header, &[]);
+        self.builder.switch_to_block(exit);
+    }
+
+    // the same with `copies` copies of the body, each after the first
+    // behind another test: header: if cell == 0 goto exit; body; if cell ==
+    // 0 goto exit; body; ...; goto header
+    fn while_nonzero_unrolled(&mut self, copies: usize, body: impl Fn(&mut Self)) {
+        let header = self.builder.create_block();
+        let inner = self.builder.create_block();
+        let exit = self.builder.create_block();
+        self.builder.ins().jump(header, &[]);
+        self.builder.switch_to_block(header);
+        let value = self.load_current();
+        self.builder.ins().brif(value, inner, &[], exit, &[]);
+        self.builder.switch_to_block(inner);
+        body(self);
+        for _ in 1..copies {
+            let next = self.builder.create_block();
+            let value = self.load_current();
+            self.builder.ins().brif(value, next, &[], exit, &[]);
+            self.builder.switch_to_block(next);
+            body(self);
+        }
+        self.builder.ins().jump(header, &[]);
+        self.builder.switch_to_block(exit);
+    }
+
+    fn add(&mut self, n: i64) {
+        let index = self.builder.use_var(self.pointer);
+        let old = self.load(index);
+        let new = self.builder.ins().iadd_imm(old, n);
+        self.store(index, new);
+    }
+
+    fn move_by(&mut self, n: isize) {
+        let old = self.builder.use_var(self.pointer);
+        let new = self.builder.ins().iadd_imm(old, n as i64);
+        self.check_bounds(new);
+        self.builder.def_var(self.pointer, new);
+    }
+
+    // leaves the function with this node's position if index is off the tape
+    fn check_bounds(&mut self, index: Value) {
+        let in_bounds = self.builder.ins().icmp_imm(IntCC::UnsignedLessThan, index, self.tape_size as i64);
+        let ok = self.builder.create_block();
+        let fail = self.builder.create_block();
+        self.builder.ins().brif(in_bounds, ok, &[], fail, &[]);
+        self.builder.switch_to_block(fail);
+        self.exit(self.position as i64 + 1);
+        self.builder.switch_to_block(ok);
+    }
+
+    // stores the pointer for the caller and returns `status`
+    fn exit(&mut self, status: i64) {
+        let pointer = self.builder.use_var(self.pointer);
+        self.builder.ins().store(MemFlags::trusted(), pointer, self.pointer_out, 0);
+        let status = self.builder.ins().iconst(types::I64, status);
+        self.builder.ins().return_(&[status]);
+    }
+
+    fn address(&mut self, index: Value) -> Value {
+        self.builder.ins().iadd(self.tape, index)
+    }
+
+    fn load(&mut self, index: Value) -> Value {
+        let address = self.address(index);
+        self.builder.ins().load(types::I8, MemFlags::trusted(), address, 0)
+    }
+
+    fn load_current(&mut self) -> Value {
+        let index = self.builder.use_var(self.pointer);
+        self.load(index)
+    }
+
+    fn store(&mut self, index: Value, value: Value) {
+        let address = self.address(index);
+        self.builder.ins().store(MemFlags::trusted(), value, address, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // collects output into a shared buffer
+    struct Capture(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(source: &str, input: &[u8]) -> (Result<(), RuntimeError>, Vec<u8>, JitEngine) {
+        let ast = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+        let output = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = JitEngine::new();
+        engine.set_eof_behavior(EofBehavior::Zero);
+        engine.set_output_sink(Some(Box::new(Capture(output.clone()))));
+        engine.provide_input(input);
+        let result = engine.run(&ast);
+        let output = output.lock().unwrap().clone();
+        (result, output, engine)
+    }
+
+    #[test]
+    fn test_hello_world() {
+        let (result, output, _) = run(
+            "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+            b"",
+        );
+        result.unwrap();
+        assert_eq!(output, b"Hello World!\n");
+    }
+
+    #[test]
+    fn test_input_and_tape() {
+        // ,[.,] echoes up to the zero byte, so stdin is never read
+        let (result, output, engine) = run(",[.,]>>+++[->++<]", b"abc\0");
+        result.unwrap();
+        assert_eq!(output, b"abc");
+        assert_eq!(engine.pointer(), 2);
+        assert_eq!(&engine.memory()[..4], &[0, 0, 0, 6]);
+
+        // the copies through the third cell are a swap
+        let (result, _, engine) = run("+++>+++++>[-]<<[->>+<<]>[-<+>]>[-<+>]", b"");
+        result.unwrap();
+        assert_eq!((engine.pointer(), &engine.memory()[..3]), (2, &[5, 3, 0][..]));
+    }
+
+    #[test]
+    fn test_out_of_bounds() {
+        let (result, _, _) = run("+[-]<", b"");
+        let err = result.unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::PointerOutOfBounds);
+        assert_eq!(err.position, 4);
+        assert_eq!(err.pointer, 0);
+
+        // >< is folded into Move(1), Move(-2), which fails at the first < like
+        // the optimizing interpreter does
+        let (result, _, _) = run("+[-]><<", b"");
+        let err = result.unwrap_err();
+        let mut interpreter = crate::interpreter::Interpreter::new();
+        interpreter.set_optimize(true);
+        let expected = interpreter.run(&crate::parser::parse(crate::lexer::tokenize("+[-]><<").unwrap()).unwrap()).unwrap_err();
+        assert_eq!((err.kind, err.position, err.pointer), (expected.kind, expected.position, expected.pointer));
+        assert_eq!(err.position, 5);
+    }
+
+    #[test]
+    fn test_output_matches_interpreter() {
+        // both engines write bytes above 127 raw, without UTF-8 encoding them
+        let source = "-.+.>++++++++[<++++++++>-]<+.>+++[<+++++++++++++++++++++++++++++>-]<.";
+        let (result, output, _) = run(source, b"");
+        result.unwrap();
+
+        let expected = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut interpreter = crate::interpreter::Interpreter::new();
+        interpreter.set_output_sink(Some(Box::new(Capture(expected.clone()))));
+        interpreter.run(&crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap()).unwrap();
+        assert_eq!(output, *expected.lock().unwrap());
+        assert_eq!(output, [0xff, 0x00, 0x41, 0x98]);
+    }
+
+    #[test]
+    fn test_unrolled_loops() {
+        // unrolling every loop, an odd number of iterations leaves the body
+        // part way through the copies
+        let source = "+++++++[>++++++++++[>+.<-]<-]>>.<<,[>+<-]";
+        let ast = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+        let (expected, expected_output, _) = run(source, b"\x05");
+        expected.unwrap();
+
+        let output = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = JitEngine::new();
+        engine.set_eof_behavior(EofBehavior::Zero);
+        engine.set_output_sink(Some(Box::new(Capture(output.clone()))));
+        engine.provide_input(b"\x05");
+        engine.set_unroll_factors(HashMap::from([(3, 3), (15, 3)]));
+        engine.run(&ast).unwrap();
+        assert_eq!(*output.lock().unwrap(), expected_output);
+        assert_eq!(&engine.memory()[..3], &[0, 5, 70]);
+    }
+}
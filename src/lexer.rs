@@ -1,6 +1,7 @@
 //! module for performing lexical analysis on BrainFuck source code
 
 use std::str;
+use std::fmt;
 use std::iter::Peekable;
 use std::str::Chars;
 use serde::{Serialize, Deserialize};
@@ -10,9 +11,74 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>, String> {
     Ok(lexer.tokenize())
 }
 
+// like tokenize, keeping where each token was found
+pub fn tokenize_spanned(input: &str) -> Result<Vec<Spanned<Token>>, String> {
+    let mut lexer = Lexer::new(input);
+    Ok(lexer.tokenize_spanned())
+}
+
+// a bracket without a partner: an opening [ or ( that is never closed, or
+// a closing ] or ) with nothing open for it to close
+#[derive(Debug, Clone, PartialEq)]
+pub struct BracketError {
+   pub token: Token,
+   pub span: Span,
+}
+
+impl fmt::Display for BracketError {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+       match self.token {
+           Token::LoopStart => write!(f, "Unclosed loop - missing ]"),
+           Token::ProcStart => write!(f, "Unclosed procedure - missing )"),
+           Token::ProcEnd => write!(f, "Unexpected ) - no procedure to close"),
+           _ => write!(f, "Unexpected ] - no loop to close"),
+       }
+   }
+}
+
+// checks that brackets pair up before parsing, reporting every unmatched one
+// in source order. a closing bracket of the wrong kind, like the ) in [), is
+// unmatched and leaves the [ open
+pub fn validate(tokens: &[Spanned<Token>]) -> Result<(), Vec<BracketError>> {
+   let mut errors = Vec::new();
+   let mut open: Vec<&Spanned<Token>> = Vec::new();
+   for token in tokens {
+       let opener = match token.node {
+           Token::LoopStart | Token::ProcStart => {
+               open.push(token);
+               continue;
+           }
+           Token::LoopEnd => Token::LoopStart,
+           Token::ProcEnd => Token::ProcStart,
+           _ => continue,
+       };
+       if open.last().map(|last| &last.node) == Some(&opener) {
+           open.pop();
+       } else {
+           errors.push(BracketError { token: token.node.clone(), span: token.span });
+       }
+   }
+   errors.extend(open.into_iter().map(|token| BracketError { token: token.node.clone(), span: token.span }));
+   errors.sort_by_key(|error| error.span.offset);
+
+   if errors.is_empty() {
+       Ok(())
+   } else {
+       Err(errors)
+   }
+}
+
+// the index of the first token at or after `line` and `column`, e.g. to turn
+// a place in the source into a breakpoint. with only commands in `tokens`
+// the index is the command's position, as in traces and runtime errors
+pub fn command_at(tokens: &[Spanned<Token>], line: usize, column: usize) -> Option<usize> {
+   tokens.iter().position(|token| (token.span.line, token.span.column) >= (line, column))
+}
+
 // tokenizer
 // represents any valid token in the BrainFuck programming language.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[allow(missing_docs)]
 #[serde(tag = "type")]
 pub enum Token {
@@ -24,27 +90,108 @@ pub enum Token {
    LoopEnd,      // ]
    Input,        // ,
    Output,       // .
+   ProcStart,    // ( in pbrain
+   ProcEnd,      // ) in pbrain
+   ProcCall,     // : in pbrain
+   Debug,        // #, only when enabled with Lexer::set_debug_command
+   Comment { text: String }, // the text between commands, only kept when asked for
+}
+
+// where a token starts in the source. offset is in bytes, line and column
+// count from 1 and the column counts characters, not bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+   pub offset: usize,
+   pub line: usize,
+   pub column: usize,
+}
+
+// a token (or anything else) along with its position in the source
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Spanned<T> {
+   pub node: T,
+   pub span: Span,
+}
+
+// which commands the lexer recognizes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+   Brainfuck, // the eight standard commands
+   Pbrain,    // adds ( ) to define a procedure numbered by the cell and : to call one
+}
+
+impl Dialect {
+   pub fn from_name(name: &str) -> Option<Self> {
+       match name {
+           "brainfuck" | "bf" => Some(Dialect::Brainfuck),
+           "pbrain" => Some(Dialect::Pbrain),
+           _ => None,
+       }
+   }
 }
 
-pub struct Lexer<'a> {
-   input: Peekable<Chars<'a>>, // peekable iterator
-   position: usize,            // tracks current position in the input
+// comment directives that take up a whole line, see the doctest module
+pub const DIRECTIVES: [&str; 2] = ["expect", "input"];
+
+// reads tokens from any source of characters, so a large program can be
+// lexed as it is read instead of being held in memory. it is also an
+// iterator over the tokens with their spans
+pub struct Lexer<I: Iterator<Item = char>> {
+   input: Peekable<I>,  // peekable iterator
+   position: Span,      // where the next character is
+   at_line_start: bool, // true when the next character starts a line
+   keep_comments: bool, // emit Token::Comment instead of skipping comments
+   dialect: Dialect,
+   debug_command: bool, // read # as Token::Debug
+   comment: String,     // comment text read since the last token, when kept
+   comment_start: Span, // where that text starts
 }
 
-impl<'a> Lexer<'a> {
+impl<'a> Lexer<Chars<'a>> {
    // creates a new lexer instance from input string
    pub fn new(input: &'a str) -> Self {
+       Lexer::from_chars(input.chars())
+   }
+}
+
+impl<I: Iterator<Item = char>> Lexer<I> {
+   pub fn from_chars(input: I) -> Self {
        Lexer {
-           // convert input string into peekable character iterator
-           input: input.chars().peekable(),
-           position: 0,
+           input: input.peekable(),
+           position: Span { offset: 0, line: 1, column: 1 },
+           at_line_start: true,
+           keep_comments: false,
+           dialect: Dialect::Brainfuck,
+           debug_command: false,
+           comment: String::new(),
+           comment_start: Span { offset: 0, line: 1, column: 1 },
        }
    }
 
+   // when set, each run of non-command characters (directive lines and the
+   // #! line included) comes out as a Token::Comment holding its exact text,
+   // so the source can be printed back unchanged
+   pub fn set_keep_comments(&mut self, keep: bool) {
+       self.keep_comments = keep;
+   }
+
+   pub fn set_dialect(&mut self, dialect: Dialect) {
+       self.dialect = dialect;
+   }
+
+   // when set, # is the debugger command that dumps the start of the tape.
+   // a #! line and directive lines like #expect: are still comments
+   pub fn set_debug_command(&mut self, enabled: bool) {
+       self.debug_command = enabled;
+   }
+
    pub fn next_token(&mut self) -> Option<Token> {
-       while let Some(ch) = self.input.next() {
-           self.position += 1;
+       self.next_spanned().map(|token| token.node)
+   }
 
+   // the next token and where it starts
+   pub fn next_spanned(&mut self) -> Option<Spanned<Token>> {
+       while let Some(&ch) = self.input.peek() {
            // match only valid BrainFuck commands
            let token = match ch {
                '+' => Some(Token::Increment),
@@ -55,16 +202,118 @@ impl<'a> Lexer<'a> {
                ']' => Some(Token::LoopEnd),
                ',' => Some(Token::Input),
                '.' => Some(Token::Output),
+               '(' if self.dialect == Dialect::Pbrain => Some(Token::ProcStart),
+               ')' if self.dialect == Dialect::Pbrain => Some(Token::ProcEnd),
+               ':' if self.dialect == Dialect::Pbrain => Some(Token::ProcCall),
+               '#' if self.debug_command => Some(Token::Debug),
                // ignore any other character
                _ => None,
            };
 
-           if token.is_some() {
-               return token;
+           if let Some(node) = token {
+               if !self.comment.is_empty() {
+                   // hand out the comment before the command
+                   break;
+               }
+               let span = self.position;
+               self.next_char();
+               if node == Token::Debug && self.at_line_start {
+                   if self.keep_comments {
+                       self.comment.push('#');
+                       self.comment_start = span;
+                   }
+                   if self.skip_special_line() {
+                       continue;
+                   }
+                   // any letters read looking for a directive are a comment after the #
+                   if self.keep_comments {
+                       self.comment.remove(0);
+                       self.comment_start = Span { offset: span.offset + 1, column: span.column + 1, ..span };
+                   }
+               }
+               self.at_line_start = false;
+               return Some(Spanned { node, span });
            }
+
            // continue to next character if current char is a comment
+           self.skip_char();
+           if ch == '#' && self.at_line_start && self.skip_special_line() {
+               continue;
+           }
+           self.at_line_start = ch == '\n';
+       }
+
+       if self.comment.is_empty() {
+           return None;
+       }
+       let text = std::mem::take(&mut self.comment);
+       Some(Spanned { node: Token::Comment { text }, span: self.comment_start })
+   }
+
+   // called after a # at the start of a line, skips the rest of the line if
+   // it is the #! line or a directive
+   fn skip_special_line(&mut self) -> bool {
+       // skip a #! line so executable scripts don't lex the interpreter path
+       if self.position.offset == 1 && self.input.peek() == Some(&'!') {
+           self.skip_line();
+           return true;
+       }
+       self.skip_directive()
+   }
+
+   // consumes a character, keeping track of the position
+   fn next_char(&mut self) -> Option<char> {
+       let ch = self.input.next()?;
+       self.position.offset += ch.len_utf8();
+       if ch == '\n' {
+           self.position.line += 1;
+           self.position.column = 1;
+       } else {
+           self.position.column += 1;
+       }
+       Some(ch)
+   }
+
+   // consumes a character that is not a command
+   fn skip_char(&mut self) -> Option<char> {
+       let start = self.position;
+       let ch = self.next_char()?;
+       if self.keep_comments {
+           if self.comment.is_empty() {
+               self.comment_start = start;
+           }
+           self.comment.push(ch);
+       }
+       Some(ch)
+   }
+
+   // consumes the rest of the line, including its newline
+   fn skip_line(&mut self) {
+       while let Some(ch) = self.skip_char() {
+           if ch == '\n' {
+               break;
+           }
+       }
+       self.at_line_start = true;
+   }
+
+   // if the line is a directive like #expect: its text may contain
+   // commands, so skip all of it
+   fn skip_directive(&mut self) -> bool {
+       let mut name = String::new();
+       while let Some(&ch) = self.input.peek() {
+           if !ch.is_ascii_alphabetic() {
+               break;
+           }
+           name.push(ch);
+           self.skip_char();
+       }
+       if self.input.peek() != Some(&':') || !DIRECTIVES.contains(&name.as_str()) {
+           // the letters we consumed are plain comment text
+           return false;
        }
-       None
+       self.skip_line();
+       true
    }
 
    // collect all tokens into a Vec
@@ -75,6 +324,19 @@ impl<'a> Lexer<'a> {
        }
        tokens
    }
+
+   // collect all tokens with their positions
+   pub fn tokenize_spanned(&mut self) -> Vec<Spanned<Token>> {
+       self.collect()
+   }
+}
+
+impl<I: Iterator<Item = char>> Iterator for Lexer<I> {
+   type Item = Spanned<Token>;
+
+   fn next(&mut self) -> Option<Spanned<Token>> {
+       self.next_spanned()
+   }
 }
 
 #[cfg(test)]
@@ -108,10 +370,136 @@ mod tests {
        ]);
    }
 
+   #[test]
+   fn test_shebang_line_skipped() {
+       let mut lexer = Lexer::new("#!/usr/local/bin/bfc --quiet\n+.");
+       let tokens = lexer.tokenize();
+       assert_eq!(tokens, vec![Token::Increment, Token::Output]);
+
+       // only a leading shebang is special
+       let mut lexer = Lexer::new("+\n#!-");
+       assert_eq!(lexer.tokenize().len(), 2);
+   }
+
+   #[test]
+   fn test_directive_lines_skipped() {
+       let mut lexer = Lexer::new("#expect: a, b.\n+\n#input: <>\n#note: -\n x#input: .");
+       let tokens = lexer.tokenize();
+       // the directive lines are skipped, other # lines are ordinary comments
+       assert_eq!(tokens, vec![Token::Increment, Token::Decrement, Token::Output]);
+   }
+
+   #[test]
+   fn test_spans() {
+       let tokens = tokenize_spanned("#!/bin/bfc\n+ é-\n#input: ]\n  [\n]").unwrap();
+       let spans: Vec<_> = tokens.iter().map(|token| (token.span.offset, token.span.line, token.span.column)).collect();
+       // offsets are in bytes, columns in characters, é takes two bytes
+       assert_eq!(spans, vec![(11, 2, 1), (15, 2, 4), (29, 4, 3), (31, 5, 1)]);
+       assert_eq!(tokens[3].node, Token::LoopEnd);
+   }
+
    #[test]
    fn test_empty_input() {
        let mut lexer = Lexer::new("");
        let tokens = lexer.tokenize();
        assert_eq!(tokens.len(), 0);
    }
-}
\ No newline at end of file
+
+   #[test]
+   fn test_keep_comments() {
+       let source = "#!/bin/bfc\n+ add\n#expect: a.\n[-]done";
+       let mut lexer = Lexer::new(source);
+       lexer.set_keep_comments(true);
+       let tokens = lexer.tokenize_spanned();
+       let comment = |text: &str| Token::Comment { text: text.to_string() };
+       assert_eq!(tokens.iter().map(|token| token.node.clone()).collect::<Vec<_>>(), vec![
+           comment("#!/bin/bfc\n"),
+           Token::Increment,
+           comment(" add\n#expect: a.\n"),
+           Token::LoopStart,
+           Token::Decrement,
+           Token::LoopEnd,
+           comment("done"),
+       ]);
+       assert_eq!((tokens[2].span.line, tokens[2].span.column), (2, 2));
+   }
+
+   #[test]
+   fn test_streaming() {
+       // a program far larger than anything worth collecting, only ever
+       // looked at one character at a time
+       let chars = "+>".chars().cycle().take(2_000_000).chain("comment.".chars());
+       let mut lexer = Lexer::from_chars(chars);
+       assert_eq!(lexer.by_ref().filter(|token| token.node == Token::Increment).count(), 1_000_000);
+       assert_eq!(lexer.next(), None);
+
+       let last = Lexer::from_chars("#!bfc\n ]".chars()).last().unwrap();
+       assert_eq!((last.node, last.span.offset), (Token::LoopEnd, 7));
+   }
+
+   #[test]
+   fn test_pbrain_tokens() {
+       // ( ) and : are comments unless the dialect is pbrain
+       assert_eq!(tokenize("(+):").unwrap(), vec![Token::Increment]);
+
+       let mut lexer = Lexer::new("(+):");
+       lexer.set_dialect(Dialect::Pbrain);
+       assert_eq!(lexer.tokenize(), vec![Token::ProcStart, Token::Increment, Token::ProcEnd, Token::ProcCall]);
+   }
+
+   #[test]
+   fn test_debug_command() {
+       assert_eq!(tokenize("+#").unwrap(), vec![Token::Increment]);
+
+       let source = "#!/bin/bfc\n+#\n#expect: a\n#note\n";
+       let mut lexer = Lexer::new(source);
+       lexer.set_debug_command(true);
+       assert_eq!(lexer.tokenize(), vec![Token::Increment, Token::Debug, Token::Debug]);
+
+       let mut lexer = Lexer::new(source);
+       lexer.set_debug_command(true);
+       lexer.set_keep_comments(true);
+       let tokens = lexer.tokenize_spanned();
+       let text: Vec<_> = tokens.iter().map(|token| match &token.node {
+           Token::Comment { text } => text.clone(),
+           Token::Increment => "+".to_string(),
+           Token::Debug => "#".to_string(),
+           other => panic!("unexpected {:?}", other),
+       }).collect();
+       // nothing is lost or reordered
+       assert_eq!(text.concat(), source);
+       assert_eq!(text, ["#!/bin/bfc\n", "+", "#", "\n", "#expect: a\n", "#", "note\n"]);
+       assert_eq!((tokens[6].span.line, tokens[6].span.column), (4, 2));
+   }
+
+   #[test]
+   fn test_validate() {
+       assert_eq!(validate(&tokenize_spanned("+[[-]>]").unwrap()), Ok(()));
+
+       let errors = validate(&tokenize_spanned("]\n [-]]\n[[").unwrap()).unwrap_err();
+       let found: Vec<_> = errors.iter().map(|error| (error.to_string(), error.span.line, error.span.column)).collect();
+       assert_eq!(found, vec![
+           ("Unexpected ] - no loop to close".to_string(), 1, 1),
+           ("Unexpected ] - no loop to close".to_string(), 2, 5),
+           ("Unclosed loop - missing ]".to_string(), 3, 1),
+           ("Unclosed loop - missing ]".to_string(), 3, 2),
+       ]);
+
+       // a ) can't close a [
+       let mut lexer = Lexer::new("[)]");
+       lexer.set_dialect(Dialect::Pbrain);
+       let errors = validate(&lexer.tokenize_spanned()).unwrap_err();
+       assert_eq!(errors, vec![BracketError { token: Token::ProcEnd, span: Span { offset: 1, line: 1, column: 2 } }]);
+   }
+
+   #[test]
+   fn test_command_at() {
+       let tokens = tokenize_spanned("++ add two\n  [-]\n").unwrap();
+       assert_eq!(command_at(&tokens, 1, 2), Some(1));
+       // a comment or indent moves on to the next command
+       assert_eq!(command_at(&tokens, 1, 4), Some(2));
+       assert_eq!(command_at(&tokens, 2, 1), Some(2));
+       assert_eq!(command_at(&tokens, 2, 4), Some(3));
+       assert_eq!(command_at(&tokens, 3, 1), None);
+   }
+}
@@ -1,30 +1,197 @@
+//! a Brainfuck compiler and interpreter. `compile` and `run`, set up by a
+//! `Config`, cover embedding it in another Rust project:
+//!
+//! ```
+//! let result = brainfuck_compiler::run(",.,.", b"hi", &brainfuck_compiler::Config::default()).unwrap();
+//! assert_eq!(result.output, "hi");
+//! ```
+//!
+//! the modules below are the pieces behind them: `lexer`, `parser`,
+//! `optimizer`, `interpreter` and the `engine`s that run a program, and
+//! `codegen` for the languages it compiles to. the wasm bindings further down
+//! are what the JavaScript package exports
+
 use wasm_bindgen::prelude::*;
 
-mod lexer;
-mod parser;
-mod interpreter;
-mod optimizer;
-mod codegen;
+pub mod lexer;
+pub mod parser;
+pub mod interpreter;
+pub mod optimizer;
+pub mod analysis;
+pub mod codegen;
+pub mod diagnostic;
+pub mod engine;
+pub mod doctest;
+pub mod io;
+pub mod linear;
+pub mod ir;
+pub mod stats;
+pub mod printer;
+pub mod bench;
+pub mod recording;
+pub mod coredump;
+pub mod annotate;
+pub mod profile;
+pub mod cost;
+pub mod pgo;
+pub mod embed;
+pub mod fuzz;
+pub mod vm;
+pub mod verify;
+#[cfg(feature = "jit")]
+pub mod jit;
+#[cfg(feature = "tui")]
+pub mod tui;
+
+pub use embed::{compile, run, Config, Error, RunOutput};
 
 // Struct to hold the execution state
 #[wasm_bindgen]
 pub struct ExecutionResult {
     output: String,
-    memory: Vec<u8>,  
+    output_bytes: Vec<u8>,
+    memory: Vec<u32>,
     pointer: usize,
     error: Option<String>,
-    //stats: ExecutionStats,
+    stats: ExecutionStats,
+}
+
+// counters collected while a program ran
+#[wasm_bindgen]
+#[derive(Clone, Debug, Default)]
+pub struct ExecutionStats {
+    total_instructions: usize,
+    // sorted by name
+    instruction_counts: Vec<(String, usize)>,
+    // index 0 is the outermost loops
+    loop_iterations: Vec<usize>,
+    elapsed_ms: f64,
+    peak_memory_bytes: usize,
+    // empty unless ExecutionOptions.heatmap was set
+    heatmap: Vec<stats::CellAccess>,
+}
+
+impl ExecutionStats {
+    fn to_json_value(&self) -> serde_json::Value {
+        let instruction_counts: serde_json::Map<String, serde_json::Value> = self
+            .instruction_counts
+            .iter()
+            .map(|(name, count)| (name.clone(), (*count).into()))
+            .collect();
+        let mut value = serde_json::json!({
+            "total_instructions": self.total_instructions,
+            "instruction_counts": instruction_counts,
+            "loop_iterations": self.loop_iterations,
+            "elapsed_ms": self.elapsed_ms,
+            "peak_memory_bytes": self.peak_memory_bytes,
+        });
+        if !self.heatmap.is_empty() {
+            value["heatmap"] = serde_json::json!(self.heatmap);
+        }
+        value
+    }
+
+    fn from_interpreter(interpreter: &interpreter::Interpreter) -> Self {
+        let stats = interpreter.statistics();
+        let depths = stats.loop_iterations.keys().copied().max().unwrap_or(0);
+        let loop_iterations = (1..=depths)
+            .map(|depth| stats.loop_iterations.get(&depth).copied().unwrap_or(0))
+            .collect();
+        ExecutionStats {
+            total_instructions: stats.total_instructions.unwrap_or(0),
+            instruction_counts: stats.instruction_counts.into_iter().collect(),
+            loop_iterations,
+            elapsed_ms: stats.elapsed_ms.unwrap_or(0.0),
+            peak_memory_bytes: stats.peak_memory_bytes.unwrap_or(0),
+            heatmap: stats.heatmap,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl ExecutionStats {
+    #[wasm_bindgen(getter)]
+    pub fn total_instructions(&self) -> usize {
+        self.total_instructions
+    }
+
+    // the kinds of instruction that ran, e.g. "Increment" or "Add(3)",
+    // matching instruction_counts index for index
+    #[wasm_bindgen(getter)]
+    pub fn instruction_names(&self) -> Vec<String> {
+        self.instruction_counts.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn instruction_counts(&self) -> Vec<usize> {
+        self.instruction_counts.iter().map(|&(_, count)| count).collect()
+    }
+
+    // iterations of finished loops per nesting depth, outermost first
+    #[wasm_bindgen(getter)]
+    pub fn loop_iterations(&self) -> Vec<usize> {
+        self.loop_iterations.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn total_loop_iterations(&self) -> usize {
+        self.loop_iterations.iter().sum()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn elapsed_ms(&self) -> f64 {
+        self.elapsed_ms
+    }
+
+    // the most bytes the tape took up, more than its starting size only
+    // when it grows
+    #[wasm_bindgen(getter)]
+    pub fn peak_memory_bytes(&self) -> usize {
+        self.peak_memory_bytes
+    }
+
+    // the cells the program used, in tape order, matching heatmap_reads and
+    // heatmap_writes index for index
+    #[wasm_bindgen(getter)]
+    pub fn heatmap_cells(&self) -> Vec<usize> {
+        self.heatmap.iter().map(|access| access.cell).collect()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn heatmap_reads(&self) -> Vec<usize> {
+        self.heatmap.iter().map(|access| access.reads).collect()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn heatmap_writes(&self) -> Vec<usize> {
+        self.heatmap.iter().map(|access| access.writes).collect()
+    }
 }
 
 #[wasm_bindgen]
 impl ExecutionResult {
+    // decoded as ExecutionOptions.output_encoding says
     #[wasm_bindgen(getter)]
     pub fn output(&self) -> String {
         self.output.clone()
     }
 
+    // the output as written, a Uint8Array in JS, for programs that produce
+    // binary data such as images
+    #[wasm_bindgen(getter)]
+    pub fn output_bytes(&self) -> Vec<u8> {
+        self.output_bytes.clone()
+    }
+
+    // the tape as bytes, cells wider than 8 bits are cut to their low byte
     #[wasm_bindgen(getter)]
     pub fn memory(&self) -> Vec<u8> {
+        self.memory.iter().map(|&cell| cell as u8).collect()
+    }
+
+    // the tape with full 16 or 32 bit cell values
+    #[wasm_bindgen(getter)]
+    pub fn wide_memory(&self) -> Vec<u32> {
         self.memory.clone()
     }
 
@@ -37,21 +204,444 @@ impl ExecutionResult {
     pub fn error(&self) -> Option<String> {
         self.error.clone()
     }
+
+    #[wasm_bindgen(getter)]
+    pub fn stats(&self) -> ExecutionStats {
+        self.stats.clone()
+    }
+
+    // everything above as one JSON object, for consumers that don't go
+    // through the getters:
+    //
+    // {"output": "A", "memory": [0, 65, ...], "pointer": 1, "error": null,
+    //  "stats": {"total_instructions": 7, "instruction_counts": {"Loop": 1, ...},
+    //            "loop_iterations": [8], "elapsed_ms": 0.01}}
+    //
+    // memory holds the full cell values, like wide_memory
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "output": self.output,
+            "memory": self.memory,
+            "pointer": self.pointer,
+            "error": self.error,
+            "stats": self.stats.to_json_value(),
+        })
+        .to_string()
+    }
+}
+
+// compiles a program to a JavaScript `function run(input, output)`, so it can
+// be evaluated once and called without going through the interpreter
+#[wasm_bindgen]
+pub fn compile_to_js(program: &str) -> Result<String, JsValue> {
+    lexer::tokenize(program)
+        .and_then(parser::parse)
+        .map(|ast| optimizer::Optimizer::new().optimize(&ast))
+        .and_then(|ast| codegen::js::JsGenerator::new().generate(&ast))
+        .map_err(|e| JsValue::from_str(&e))
+}
+
+// the program's syntax tree as JSON, e.g. for drawing it. every node is
+// {"type": ..., "value": ...}: "value" is the body of Program, Loop and
+// ProcDef and is left out for plain commands like {"type": "Increment"}
+#[wasm_bindgen]
+pub fn parse_to_json(program: &str) -> Result<String, String> {
+    let ast = parse_for_wasm(program).map_err(|e| format!("Error: {}", e))?;
+    serde_json::to_string(&ast).map_err(|e| format!("Error: {}", e))
+}
+
+// like parse_to_json, after the optimizer has run, so runs of + become
+// {"type": "Add", "value": 3} and [-] becomes {"type": "Set", "value": 0}
+#[wasm_bindgen]
+pub fn optimize_to_json(program: &str) -> Result<String, String> {
+    let ast = parse_for_wasm(program).map_err(|e| format!("Error: {}", e))?;
+    let optimized = optimizer::Optimizer::new().optimize(&ast);
+    serde_json::to_string(&optimized).map_err(|e| format!("Error: {}", e))
 }
 
 #[wasm_bindgen]
 pub fn compile_and_run(input: &str) -> ExecutionResult {
+    compile_and_run_with_input(input, "")
+}
+
+// interpreter settings for compile_and_run_with_options. in JS, create it
+// with `new ExecutionOptions()` and assign the properties that matter
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct ExecutionOptions {
+    cell_width: u32,
+    eof: String,
+    tape_size: usize,
+    lazy_tape: bool,
+    opt_level: u32,
+    max_instructions: Option<usize>,
+    max_output_bytes: Option<usize>,
+    output_encoding: String,
+    heatmap: bool,
+}
+
+impl Default for ExecutionOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl ExecutionOptions {
+    // 8 bit cells on a 30,000 cell tape, , stores 0 once input runs out, the
+    // program is optimized, nothing is limited and no heatmap is kept
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        ExecutionOptions {
+            cell_width: 8,
+            eof: "zero".to_string(),
+            tape_size: interpreter::DEFAULT_TAPE_SIZE,
+            lazy_tape: false,
+            opt_level: 1,
+            max_instructions: None,
+            max_output_bytes: None,
+            output_encoding: "latin1".to_string(),
+            heatmap: false,
+        }
+    }
+
+    // cell width in bits: 8, 16 or 32
+    #[wasm_bindgen(getter)]
+    pub fn cell_width(&self) -> u32 {
+        self.cell_width
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_cell_width(&mut self, bits: u32) {
+        self.cell_width = bits;
+    }
+
+    // what , stores once input runs out: "unchanged", "zero" or "max"
+    #[wasm_bindgen(getter)]
+    pub fn eof(&self) -> String {
+        self.eof.clone()
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_eof(&mut self, eof: String) {
+        self.eof = eof;
+    }
+
+    // number of cells on the tape
+    #[wasm_bindgen(getter)]
+    pub fn tape_size(&self) -> usize {
+        self.tape_size
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_tape_size(&mut self, cells: usize) {
+        self.tape_size = cells;
+    }
+
+    // allocate the tape a page at a time as the program reaches it, so a
+    // huge tape_size is cheap. ExecutionResult.memory then stops after the
+    // last page the program reached, the cells after it are all zero
+    #[wasm_bindgen(getter)]
+    pub fn lazy_tape(&self) -> bool {
+        self.lazy_tape
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_lazy_tape(&mut self, lazy: bool) {
+        self.lazy_tape = lazy;
+    }
+
+    // 0 runs the program as written, 1 optimizes it first
+    #[wasm_bindgen(getter)]
+    pub fn opt_level(&self) -> u32 {
+        self.opt_level
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_opt_level(&mut self, level: u32) {
+        self.opt_level = level;
+    }
+
+    // the run fails once it has executed this many instructions, which keeps
+    // a program that never halts from freezing the page. undefined for no limit
+    #[wasm_bindgen(getter)]
+    pub fn max_instructions(&self) -> Option<usize> {
+        self.max_instructions
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_max_instructions(&mut self, limit: Option<usize>) {
+        self.max_instructions = limit;
+    }
+
+    // the run fails when . would write more than this many bytes, which keeps
+    // a program stuck printing from using up the page's memory. the output
+    // up to the limit is still in ExecutionResult.output
+    #[wasm_bindgen(getter)]
+    pub fn max_output_bytes(&self) -> Option<usize> {
+        self.max_output_bytes
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_max_output_bytes(&mut self, limit: Option<usize>) {
+        self.max_output_bytes = limit;
+    }
+
+    // how ExecutionResult.output is made from the bytes written: "latin1"
+    // takes each byte as the character with that code point, "utf8" decodes
+    // them, so text in any language shows up right
+    #[wasm_bindgen(getter)]
+    pub fn output_encoding(&self) -> String {
+        self.output_encoding.clone()
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_output_encoding(&mut self, encoding: String) {
+        self.output_encoding = encoding;
+    }
+
+    // count reads and writes per cell, for ExecutionStats.heatmap_cells and
+    // friends. slows the run down a little
+    #[wasm_bindgen(getter)]
+    pub fn heatmap(&self) -> bool {
+        self.heatmap
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_heatmap(&mut self, enabled: bool) {
+        self.heatmap = enabled;
+    }
+}
+
+// how far BfSession.run_with_fuel got
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepResult {
+    // the fuel ran out, call again to continue
+    Paused,
+    Finished,
+}
+
+// a program run a few instructions at a time, for UIs that show the tape as
+// it changes. , reads as end of input
+#[wasm_bindgen]
+pub struct BfSession {
+    source: String,
+    ast: parser::AstNode,
+    interpreter: interpreter::Interpreter,
+    finished: bool,
+    cancelled: bool,
+    // how much of the output has been handed out
+    output_read: usize,
+    output_encoding: io::OutputEncoding,
+    // the tape as memory_changes last reported it
+    reported: Vec<u32>,
+    on_output: Option<js_sys::Function>,
+}
+
+#[wasm_bindgen]
+impl BfSession {
+    // parses `program` and stops before its first instruction. errors are
+    // thrown as strings, like ExecutionResult.error
+    #[wasm_bindgen(constructor)]
+    pub fn new(program: &str) -> Result<BfSession, String> {
+        let ast = parse_for_wasm(program).map_err(|e| format!("Error: {}", e))?;
+        let mut interpreter = interpreter::Interpreter::new();
+        interpreter.set_optimize(true);
+        interpreter.set_io(Some(Box::new(io::MemoryIo::default())));
+        interpreter.start(&ast).map_err(|e| format!("Error: {}", e))?;
+        let reported = interpreter.memory().to_vec();
+        Ok(BfSession {
+            source: program.to_string(),
+            ast,
+            interpreter,
+            finished: false,
+            cancelled: false,
+            output_read: 0,
+            output_encoding: io::OutputEncoding::Latin1,
+            reported,
+            on_output: None,
+        })
+    }
+
+    // executes up to `n` instructions and returns whether the program is
+    // still running. a runtime error ends the session
+    pub fn step(&mut self, n: u32) -> Result<bool, String> {
+        // run_for would start a finished program over
+        if self.finished {
+            return Ok(false);
+        }
+        let result = match self.interpreter.run_for(&self.ast, n as usize) {
+            interpreter::RunStatus::Running => Ok(()),
+            interpreter::RunStatus::Done => {
+                self.finished = true;
+                Ok(())
+            }
+            interpreter::RunStatus::Error(e) => {
+                self.finished = true;
+                let rendered = diagnostic::Diagnostic::from_runtime_error(&e, &self.source).render(&self.source);
+                Err(format!("Error: {}", rendered))
+            }
+        };
+        // output written before an error still reaches the page
+        self.deliver_output()?;
+        result.map(|_| !self.finished)
+    }
+
+    // step() for pages slicing up their own time: runs at most `fuel`
+    // instructions, e.g. one batch per animation frame
+    pub fn run_with_fuel(&mut self, fuel: u32) -> Result<StepResult, String> {
+        match self.step(fuel)? {
+            true => Ok(StepResult::Paused),
+            false => Ok(StepResult::Finished),
+        }
+    }
+
+    // calls `callback` with the output of every step() call that wrote any,
+    // so it streams to the page as it is produced. output passed to the
+    // callback isn't returned by output_since_last_call. undefined removes it
+    pub fn set_on_output(&mut self, callback: Option<js_sys::Function>) {
+        self.on_output = callback;
+    }
+
+    // the tape as bytes
+    pub fn memory(&self) -> Vec<u8> {
+        self.interpreter.memory().iter().map(|&cell| cell as u8).collect()
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.interpreter.pointer()
+    }
+
+    // the cells that changed since the previous call, or since the session
+    // started, as flat [index, old, new, index, old, new, ...] triples in tape
+    // order. lets a debugger redraw only those instead of copying the whole
+    // tape after every step
+    pub fn memory_changes(&mut self) -> Vec<u32> {
+        let mut changes = Vec::new();
+        for (index, (&new, old)) in self.interpreter.memory().iter().zip(self.reported.iter_mut()).enumerate() {
+            if new != *old {
+                changes.extend([index as u32, *old, new]);
+                *old = new;
+            }
+        }
+        changes
+    }
+
+    // "latin1" (the default) or "utf8", as for ExecutionOptions
+    pub fn set_output_encoding(&mut self, encoding: &str) -> Result<(), String> {
+        self.output_encoding =
+            io::OutputEncoding::from_name(encoding).ok_or_else(|| format!("Error: Unknown output encoding: {}", encoding))?;
+        Ok(())
+    }
+
+    // what . has written since the previous call. in UTF-8, a character
+    // whose bytes haven't all been written yet waits for the next call, or
+    // is replaced once the program has finished
+    pub fn output_since_last_call(&mut self) -> String {
+        let Some(io) = self.interpreter.io::<io::MemoryIo>() else {
+            return String::new();
+        };
+        let unread = &io.output[self.output_read..];
+        if self.finished {
+            self.output_read = io.output.len();
+            return self.output_encoding.decode(unread);
+        }
+        let (output, read) = self.output_encoding.decode_complete(unread);
+        self.output_read += read;
+        output
+    }
+
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    // stops the program for good, e.g. from a Stop button. the tape, pointer
+    // and output stay readable, and step() does nothing afterwards
+    pub fn cancel(&mut self) {
+        self.cancelled = true;
+        self.finished = true;
+    }
+
+    // whether cancel() ended the session rather than the program itself
+    pub fn cancelled(&self) -> bool {
+        self.cancelled
+    }
+}
+
+impl BfSession {
+    fn deliver_output(&mut self) -> Result<(), String> {
+        if self.on_output.is_none() {
+            return Ok(());
+        }
+        let output = self.output_since_last_call();
+        let Some(callback) = self.on_output.as_ref().filter(|_| !output.is_empty()) else {
+            return Ok(());
+        };
+        callback
+            .call1(&JsValue::NULL, &JsValue::from_str(&output))
+            .map(|_| ())
+            .map_err(|e| format!("Error: output callback failed: {:?}", e))
+    }
+}
+
+// lexes, checks brackets and parses, with errors rendered against `program`
+fn parse_for_wasm(program: &str) -> Result<parser::AstNode, String> {
+    let tokens = lexer::tokenize_spanned(program)?;
+    lexer::validate(&tokens).map_err(|errors| diagnostic::render_bracket_errors(errors, program))?;
+    parser::parse_spanned(tokens).map_err(|e| e.render(program))
+}
+
+// runs a program with `stdin` supplying the bytes read by ,
+#[wasm_bindgen]
+pub fn compile_and_run_with_input(program: &str, stdin: &str) -> ExecutionResult {
+    compile_and_run_with_options(program, stdin, &ExecutionOptions::new())
+}
+
+// like compile_and_run_with_input, with the interpreter configured by `options`
+#[wasm_bindgen]
+pub fn compile_and_run_with_options(program: &str, stdin: &str, options: &ExecutionOptions) -> ExecutionResult {
     let result: Result<ExecutionResult, String> = (|| {
-        let tokens = lexer::tokenize(input)?;
-        let ast = parser::parse(tokens)?;
-        let optimized = optimizer::Optimizer::new().optimize(&ast);
-        let (output, memory, pointer) = interpreter::interpret_with_state(&optimized)?;
-        
+        let cell_width = interpreter::CellWidth::from_bits(options.cell_width)
+            .ok_or_else(|| format!("Unsupported cell width: {} bits", options.cell_width))?;
+        let eof_behavior = interpreter::EofBehavior::from_name(&options.eof)
+            .ok_or_else(|| format!("Unknown EOF behavior: {}", options.eof))?;
+        let encoding = io::OutputEncoding::from_name(&options.output_encoding)
+            .ok_or_else(|| format!("Unknown output encoding: {}", options.output_encoding))?;
+        if options.opt_level > 1 {
+            return Err(format!("Unsupported opt level: {} (expected 0 or 1)", options.opt_level));
+        }
+        let ast = parse_for_wasm(program)?;
+
+        let mut interpreter = interpreter::Interpreter::builder()
+            .lazy_tape(options.lazy_tape)
+            .tape_size(options.tape_size)
+            .optimize(options.opt_level > 0)
+            .cell_width(cell_width)
+            .eof_behavior(eof_behavior)
+            .max_instructions(options.max_instructions)
+            .max_output(options.max_output_bytes)
+            .input(stdin.as_bytes())
+            .build()?;
+        interpreter.set_track_heatmap(options.heatmap);
+        // a failed run keeps what it wrote and the tape it stopped on, so a
+        // program cut off by max_output_bytes still shows its output
+        let (output_bytes, memory, pointer, error) = match interpreter.run_and_capture_bytes(&ast) {
+            Ok((output, memory, pointer)) => (output, memory, pointer, None),
+            Err(e) => {
+                let rendered = diagnostic::Diagnostic::from_runtime_error(&e, program).render(program);
+                let output = interpreter.partial_output().to_vec();
+                (output, interpreter.memory().to_vec(), interpreter.pointer(), Some(format!("Error: {}", rendered)))
+            }
+        };
+
         Ok(ExecutionResult {
-            output,
+            output: encoding.decode(&output_bytes),
+            output_bytes,
             memory,
             pointer,
-            error: None,
+            error,
+            stats: ExecutionStats::from_interpreter(&interpreter),
         })
     })();
 
@@ -60,9 +650,203 @@ pub fn compile_and_run(input: &str) -> ExecutionResult {
         Ok(execution_result) => execution_result,
         Err(e) => ExecutionResult {
             output: String::new(),
+            output_bytes: Vec::new(),
             memory: vec![0; 30],  
             pointer: 0,
             error: Some(format!("Error: {}", e)),
+            stats: ExecutionStats::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_getters() {
+        let mut options = ExecutionOptions::new();
+        options.set_cell_width(16);
+        let result = compile_and_run_with_options("->+", "", &options);
+        assert_eq!(&result.memory()[..2], &[255, 1]);
+        assert_eq!(&result.wide_memory()[..2], &[65535, 1]);
+
+        options.set_tape_size(20_000_000);
+        options.set_lazy_tape(true);
+        let result = compile_and_run_with_options("->+", "", &options);
+        assert_eq!(result.wide_memory().len(), interpreter::TAPE_PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_error_rendering() {
+        let result = compile_and_run("+\n[[-]");
+        assert_eq!(result.error(), Some("Error: Unclosed loop - missing ] at line 2, column 1\n  [[-]\n  ^".to_string()));
+
+        // every unmatched bracket is reported
+        let result = compile_and_run("]+[");
+        assert_eq!(
+            result.error(),
+            Some("Error: Unexpected ] - no loop to close at line 1, column 1\n  ]+[\n  ^\nUnclosed loop - missing ] at line 1, column 3\n  ]+[\n    ^".to_string())
+        );
+    }
+
+    #[test]
+    fn test_execution_options() {
+        let mut options = ExecutionOptions::new();
+        options.set_eof("max".to_string());
+        let result = compile_and_run_with_options(",", "", &options);
+        assert_eq!(result.memory()[0], 255);
+        let result = compile_and_run_with_options("-.", "", &ExecutionOptions::new());
+        assert_eq!((result.output(), result.output_bytes()), ("\u{ff}".to_string(), vec![255]));
+        let mut utf8 = ExecutionOptions::new();
+        utf8.set_output_encoding("utf8".to_string());
+        assert_eq!(compile_and_run_with_options(",.,.,.", "é", &utf8).output(), "é\u{0}");
+
+        options.set_eof("never".to_string());
+        let result = compile_and_run_with_options(",", "", &options);
+        assert_eq!(result.error(), Some("Error: Unknown EOF behavior: never".to_string()));
+    }
+
+    #[test]
+    fn test_execution_limits() {
+        let mut options = ExecutionOptions::new();
+        options.set_tape_size(2);
+        let result = compile_and_run_with_options(">>", "", &options);
+        assert!(result.error().unwrap().starts_with("Error: Pointer out of bounds"));
+
+        let mut options = ExecutionOptions::new();
+        options.set_max_instructions(Some(1000));
+        let result = compile_and_run_with_options("+[]", "", &options);
+        assert!(result.error().unwrap().starts_with("Error: Instruction limit exceeded: stopped after 1000 instructions"));
+
+        let mut options = ExecutionOptions::new();
+        options.set_max_output_bytes(Some(2));
+        let result = compile_and_run_with_options("+[.]", "", &options);
+        assert!(result.error().unwrap().starts_with("Error: Output limit exceeded: stopped after 2 bytes"));
+        assert_eq!(result.output(), "\u{1}\u{1}");
+
+        // without the optimizer [-] counts every decrement
+        let mut options = ExecutionOptions::new();
+        options.set_opt_level(0);
+        assert_eq!(compile_and_run_with_options("+++[-]", "", &options).stats().total_instructions(), 7);
+        options.set_opt_level(2);
+        assert!(compile_and_run_with_options("+", "", &options).error().is_some());
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let mut options = ExecutionOptions::new();
+        options.set_heatmap(true);
+        let result = compile_and_run_with_options(">>+.", "", &options);
+        let stats = result.stats();
+        assert_eq!((stats.heatmap_cells(), stats.heatmap_reads(), stats.heatmap_writes()), (vec![2], vec![1], vec![1]));
+        let value: serde_json::Value = serde_json::from_str(&result.to_json()).unwrap();
+        assert_eq!(value["stats"]["heatmap"], serde_json::json!([{"cell": 2, "reads": 1, "writes": 1}]));
+        assert!(compile_and_run(">>+.").stats().heatmap_cells().is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = compile_and_run("++[>+++[-]<-]").stats();
+        // the optimizer turns [-] into Set(0)
+        assert_eq!(stats.instruction_names(), ["Add(2)", "Add(3)", "Decrement", "Loop", "MoveLeft", "MoveRight", "Set(0)"]);
+        assert_eq!(stats.instruction_counts(), [1, 2, 2, 1, 2, 2, 2]);
+        assert_eq!(stats.total_instructions(), 12);
+        assert_eq!(stats.loop_iterations(), [2]);
+        assert_eq!(stats.total_loop_iterations(), 2);
+        assert_eq!(stats.peak_memory_bytes(), 30000);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut options = ExecutionOptions::new();
+        options.set_tape_size(2);
+        let json = compile_and_run_with_options("+[>+<-]>.", "", &options).to_json();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["output"], "\u{1}");
+        assert_eq!(value["memory"], serde_json::json!([0, 1]));
+        assert_eq!(value["pointer"], 1);
+        assert_eq!(value["error"], serde_json::Value::Null);
+        assert_eq!(value["stats"]["total_instructions"], 4);
+        assert_eq!(value["stats"]["instruction_counts"]["MulCopy { targets: [(1, 1)] }"], 1);
+
+        let value: serde_json::Value = serde_json::from_str(&compile_and_run("[").to_json()).unwrap();
+        assert!(value["error"].as_str().unwrap().starts_with("Error: Unclosed loop"));
+    }
+
+    #[test]
+    fn test_ast_json() {
+        assert_eq!(
+            parse_to_json("+[-]").unwrap(),
+            r#"{"type":"Program","value":[{"type":"Increment"},{"type":"Loop","value":[{"type":"Decrement"}]}]}"#
+        );
+        assert_eq!(
+            optimize_to_json("++[-]>[->+<]").unwrap(),
+            r#"{"type":"Program","value":[{"type":"Add","value":2},{"type":"Set","value":0},{"type":"MoveRight"},{"type":"MulCopy","value":{"targets":[[1,1]]}}]}"#
+        );
+        assert!(parse_to_json("]").unwrap_err().starts_with("Error: Unexpected ]"));
+    }
+
+    #[test]
+    fn test_session() {
+        let mut session = BfSession::new("++.>+.").unwrap();
+        assert!(session.step(3).unwrap());
+        assert_eq!(session.output_since_last_call(), "\u{2}");
+        assert_eq!(session.output_since_last_call(), "");
+        assert_eq!(&session.memory()[..2], &[2, 0]);
+
+        assert!(!session.step(10).unwrap());
+        assert!(session.finished());
+        assert_eq!(session.pointer(), 1);
+        assert_eq!(session.output_since_last_call(), "\u{1}");
+
+        // 0xc3 0xa9 is é in UTF-8
+        let mut session = BfSession::new(&format!("{}.{}.", "-".repeat(61), "-".repeat(26))).unwrap();
+        session.set_output_encoding("utf8").unwrap();
+        session.step(2).unwrap();
+        assert_eq!(session.output_since_last_call(), "");
+        session.step(2).unwrap();
+        assert_eq!(session.output_since_last_call(), "é");
+        assert!(session.set_output_encoding("utf16").is_err());
+    }
+
+    #[test]
+    fn test_run_with_fuel() {
+        let mut session = BfSession::new("+++[>+.<-]").unwrap();
+        assert_eq!(session.run_with_fuel(4), Ok(StepResult::Paused));
+        assert_eq!(&session.memory()[..2], &[3, 1]);
+        assert_eq!(session.run_with_fuel(100), Ok(StepResult::Finished));
+        assert_eq!(session.run_with_fuel(100), Ok(StepResult::Finished));
+    }
+
+    #[test]
+    fn test_cancel_session() {
+        let mut session = BfSession::new("+.[>+<]").unwrap();
+        assert!(session.step(100).unwrap());
+        session.cancel();
+        assert!(!session.step(100).unwrap());
+        assert!(session.finished() && session.cancelled());
+        assert_eq!(session.output_since_last_call(), "\u{1}");
+        assert_eq!(session.memory()[0], 1);
+    }
+
+    #[test]
+    fn test_memory_changes() {
+        let mut session = BfSession::new("++>+++>-<<-").unwrap();
+        assert!(session.memory_changes().is_empty());
+        session.step(4).unwrap();
+        assert_eq!(session.memory_changes(), [0, 0, 2, 1, 0, 3]);
+        assert!(session.memory_changes().is_empty());
+        session.step(10).unwrap();
+        assert_eq!(session.memory_changes(), [0, 2, 1, 2, 0, 255]);
+    }
+
+    #[test]
+    fn test_session_errors() {
+        assert!(BfSession::new("[").is_err());
+        let mut session = BfSession::new("+.<").unwrap();
+        assert!(session.step(5).unwrap_err().starts_with("Error: Pointer out of bounds"));
+        assert!(session.finished());
+        assert_eq!(session.output_since_last_call(), "\u{1}");
+    }
+}
@@ -0,0 +1,145 @@
+//! flattens the AST into a linear instruction list with explicit jumps
+
+use crate::parser::AstNode;
+
+// owns its nodes, so an interpreter can keep the code around between steps
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Basic(AstNode), // anything that isn't a loop
+    // [ jumps past the matching ] when the cell is zero. the body follows as
+    // separate instructions, so `node` is the loop with an empty body
+    LoopStart { node: AstNode, end: usize },
+    // ] jumps back to the first instruction of the body when the cell is non-zero
+    LoopEnd { start: usize },
+    // pbrain ( records the body that follows as a procedure and jumps past
+    // the matching ), like LoopStart `node` is the definition with no body
+    ProcStart { node: AstNode, end: usize },
+    // ) returns to the instruction after the : that called the procedure
+    ProcEnd,
+    // : jumps to the procedure numbered by the cell
+    Call,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub op: Op,
+    pub position: usize, // command index in the source
+}
+
+// turns a program body into flat code. uses an explicit stack instead of
+// recursion so deeply nested loops can't overflow the Rust stack. every
+// parsed command becomes one instruction and comments are left out, so for
+// an unoptimized program the position of each instruction is its index
+pub fn flatten(instructions: &[AstNode]) -> Vec<Instruction> {
+    let mut code = Vec::new();
+    // the remaining nodes of each open loop, with the index of its [
+    let mut open = vec![(instructions.iter(), None)];
+
+    while let Some((nodes, start)) = open.last_mut() {
+        match nodes.next() {
+            Some(AstNode::Loop(body)) => {
+                code.push(Instruction { op: Op::LoopStart { node: AstNode::Loop(Vec::new()), end: 0 }, position: code.len() });
+                open.push((body.iter(), Some(code.len() - 1)));
+            }
+            Some(AstNode::ProcDef(body)) => {
+                code.push(Instruction { op: Op::ProcStart { node: AstNode::ProcDef(Vec::new()), end: 0 }, position: code.len() });
+                open.push((body.iter(), Some(code.len() - 1)));
+            }
+            Some(AstNode::ProcCall) => {
+                code.push(Instruction { op: Op::Call, position: code.len() });
+            }
+            Some(AstNode::Comment(_)) => {}
+            Some(node) => {
+                code.push(Instruction { op: Op::Basic(node.clone()), position: code.len() });
+            }
+            None => {
+                if let Some(start) = *start {
+                    let close = match code[start].op {
+                        Op::ProcStart { .. } => Op::ProcEnd,
+                        _ => Op::LoopEnd { start },
+                    };
+                    code.push(Instruction { op: close, position: code.len() });
+                    let end = code.len();
+                    if let Op::LoopStart { end: target, .. } | Op::ProcStart { end: target, .. } = &mut code[start].op {
+                        *target = end;
+                    }
+                }
+                open.pop();
+            }
+        }
+    }
+    code
+}
+
+// like flatten, for an optimized program. `positions` holds the source
+// command index of each instruction, see Optimizer::optimize_with_positions
+pub fn flatten_with_positions(instructions: &[AstNode], positions: &[usize]) -> Vec<Instruction> {
+    let mut code = flatten(instructions);
+    for (instruction, &position) in code.iter_mut().zip(positions) {
+        instruction.position = position;
+    }
+    code
+}
+
+// number of instructions flatten turns a node into: none for a comment, one
+// for anything but a loop or procedure, which also has its [ and ] or ( and )
+pub fn flat_len(node: &AstNode) -> usize {
+    match node {
+        AstNode::Program(instructions) => instructions.iter().map(flat_len).sum(),
+        AstNode::Loop(instructions) | AstNode::ProcDef(instructions) => {
+            2 + instructions.iter().map(flat_len).sum::<usize>()
+        }
+        AstNode::Comment(_) => 0,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flatten_jumps() {
+        // ++[>[-]<]. with the ++ already folded, the outer loop spans 1..=7
+        let program = vec![
+            AstNode::Add(2),
+            AstNode::Loop(vec![
+                AstNode::MoveRight,
+                AstNode::Loop(vec![AstNode::Decrement]),
+                AstNode::MoveLeft,
+            ]),
+            AstNode::Output,
+        ];
+        let code = flatten(&program);
+        let ops: Vec<_> = code.iter().map(|instruction| match instruction.op {
+            Op::Basic(_) => "op".to_string(),
+            Op::LoopStart { end, .. } => format!("[ -> {}", end),
+            Op::LoopEnd { start } => format!("] -> {}", start),
+            _ => unreachable!(),
+        }).collect();
+        assert_eq!(ops, ["op", "[ -> 8", "op", "[ -> 6", "op", "] -> 3", "op", "] -> 1", "op"]);
+
+        let positions: Vec<_> = code.iter().map(|instruction| instruction.position).collect();
+        assert_eq!(positions, [0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(flat_len(&AstNode::Program(program.clone())), 9);
+
+        // the optimizer knows the ++ were two commands
+        let shifted: Vec<_> = (1..10).collect();
+        let code = flatten_with_positions(&program, &shifted);
+        assert_eq!(code.iter().map(|instruction| instruction.position).collect::<Vec<_>>(), shifted);
+    }
+
+    #[test]
+    fn test_flatten_procedures() {
+        // ([-]):
+        let program = vec![
+            AstNode::ProcDef(vec![AstNode::Loop(vec![AstNode::Decrement])]),
+            AstNode::ProcCall,
+        ];
+        let code = flatten(&program);
+        assert!(matches!(code[0].op, Op::ProcStart { end: 5, .. }));
+        assert_eq!(code[4].op, Op::ProcEnd);
+        assert_eq!(code[5].op, Op::Call);
+        assert_eq!(flat_len(&AstNode::Program(program)), 6);
+    }
+}
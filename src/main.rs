@@ -0,0 +1,1435 @@
+use brainfuck_compiler::{annotate, bench, coredump, doctest, interpreter, lexer, optimizer, parser, printer};
+use brainfuck_compiler::io::{BfIo, PromptIo, PromptMode, StdIo};
+use brainfuck_compiler::recording::{Recording, Session};
+use brainfuck_compiler::doctest::DocTestResult;
+use brainfuck_compiler::interpreter::{CellWidth, EofBehavior, OutputBuffering, TapePolicy};
+use brainfuck_compiler::diagnostic::{self, Diagnostic};
+use brainfuck_compiler::lexer::{Dialect, Token};
+use brainfuck_compiler::engine::{Engine, EngineKind};
+use brainfuck_compiler::codegen::{CodeGenerator, CodeStyle};
+use brainfuck_compiler::stats::StatsFormat;
+use brainfuck_compiler::cost::CostModel;
+use brainfuck_compiler::verify::{self, Verdict};
+#[cfg(feature = "tui")]
+use brainfuck_compiler::tui;
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// options that take a value, e.g. --flush-every 64
+const VALUE_OPTIONS: [&str; 30] = [
+    "--buffer", "--flush-every", "--max-memory", "--engine", "--cell-width", "--eof", "--opt-level", "--output",
+    "--dialect", "--tape-size", "--stats-format", "--stage", "--trace", "--width",
+    "--iterations", "--warmup", "--break", "--record", "--replay", "--seek", "--core-dump",
+    "--sample", "--annotate", "--profile", "--cost", "--prompt", "--input", "--input-file",
+    "--max-output", "--project",
+];
+
+// options that take no value, e.g. --quiet. any other --option is a mistake
+const FLAG_OPTIONS: [&str; 29] = [
+    "--quiet", "--raw", "--stats", "--progress", "--step", "--debug", "--debug-command", "--tui", "--heatmap", "--pgo",
+    "--no-opt", "--jit", "--lazy-tape", "--grow-tape", "--grow-both", "--wrap-pointer", "--verify-opt",
+    "--partial-eval", "--precompute", "--no-echo", "--emit-rust", "--compact", "--json", "--source", "--loops",
+    "--minify", "--compare", "--doc", "--repl",
+];
+
+// instructions between the lines --progress prints
+const PROGRESS_INTERVAL: usize = 10_000_000;
+
+// options that change what a program does, saved by --record so that
+// --replay runs it the same way
+const RECORDED_OPTIONS: [&str; 9] = [
+    "--cell-width", "--eof", "--tape-size", "--max-memory", "--grow-tape", "--grow-both", "--wrap-pointer", "--dialect",
+    "--debug-command",
+];
+
+// first arguments that name a subcommand rather than a program file
+const SUBCOMMANDS: [&str; 8] = ["run", "debug", "check", "fmt", "emit", "compile", "bench", "test"];
+
+// what made bfc fail, reported as its exit status so scripts can tell
+// mistakes in the command line and in the program apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    Other = 1,   // unreadable files, failed doc tests, rustc errors
+    Usage = 2,   // invalid options or arguments
+    Lex = 3,     // unmatched brackets
+    Parse = 4,   // any other error in the program's syntax
+    Runtime = 5, // the program failed while running
+}
+
+impl Failure {
+    fn exit(self) -> ! {
+        process::exit(self as i32)
+    }
+}
+
+fn main() {
+    // get arguments
+    let args: Vec<String> = env::args().collect();
+
+    // when piped, keep stdout to program output only and never prompt
+    let interactive_in = std::io::stdin().is_terminal();
+    let interactive_out = std::io::stdout().is_terminal();
+
+    // split positional arguments from --options. without a subcommand,
+    // options stop at the program, so everything after a #! script's path
+    // reaches the script untouched. subcommands take options anywhere, with
+    // -- ending them
+    let mut options = Vec::new();
+    let mut positional = Vec::new();
+    let mut subcommand = false;
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            positional.extend(iter.by_ref().cloned());
+        } else if VALUE_OPTIONS.contains(&arg.as_str()) {
+            options.push(arg.clone());
+            options.extend(iter.next().cloned());
+        } else if arg.starts_with("--") {
+            if !FLAG_OPTIONS.contains(&arg.as_str()) {
+                usage_error(&format!("Unknown option: {}", arg));
+            }
+            options.push(arg.clone());
+        } else {
+            // the program text given with -p may itself start with --
+            let program = arg == "-p";
+            positional.push(arg.clone());
+            if program {
+                positional.extend(iter.next().cloned());
+            }
+            if positional.len() == 1 || program {
+                subcommand |= SUBCOMMANDS.contains(&arg.as_str());
+            }
+            if !subcommand {
+                positional.extend(iter.by_ref().cloned());
+            }
+        }
+    }
+
+    // `bfc file.bf` is the same as `bfc run file.bf`
+    let command = if subcommand { positional.remove(0) } else { "run".to_string() };
+
+    if options.contains(&"--repl".to_string()) {
+        run_repl(interactive_out);
+        return;
+    }
+
+    if command == "test" {
+        if !options.contains(&"--doc".to_string()) || positional.is_empty() {
+            print_usage();
+            Failure::Usage.exit();
+        }
+        if !run_doc_tests(&positional) {
+            Failure::Other.exit();
+        }
+        return;
+    }
+
+    if command == "check" || command == "fmt" || command == "emit" {
+        if positional.is_empty() || (command != "check" && positional.len() != 1) {
+            print_usage();
+            Failure::Usage.exit();
+        }
+        let language = match LanguageOptions::from_args(&options) {
+            Ok(language) => language,
+            Err(e) => usage_error(&e),
+        };
+        let result = match command.as_str() {
+            "check" => check_files(&positional, language),
+            "fmt" => format_file(&positional[0], language, &options),
+            _ => emit_file(&positional[0], language, &options),
+        };
+        if let Err(failure) = result {
+            failure.exit();
+        }
+        return;
+    }
+
+    if command == "bench" {
+        let Some((path, program_args)) = positional.split_first() else {
+            print_usage();
+            Failure::Usage.exit();
+        };
+        let language = match LanguageOptions::from_args(&options) {
+            Ok(language) => language,
+            Err(e) => usage_error(&e),
+        };
+        if let Err(failure) = bench_file(path, program_args, language, &options) {
+            failure.exit();
+        }
+        return;
+    }
+
+    if command == "compile" {
+        if positional.len() != 1 {
+            print_usage();
+            Failure::Usage.exit();
+        }
+        if let Some(dir) = option_value(&options, "--project") {
+            match compile_project(&positional[0], dir, &options) {
+                Ok(()) => println!("Wrote {}, build it with cargo build --release", dir),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    Failure::Other.exit();
+                }
+            }
+            return;
+        }
+        match compile(&positional[0], &options) {
+            Ok(binary) => println!("Compiled {}", binary.display()),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                Failure::Other.exit();
+            }
+        }
+        return;
+    }
+
+    // a replayed program runs with the options it was recorded with
+    let replay = option_value(&options, "--replay").map(load_session);
+    if let Some(session) = &replay {
+        if !positional.is_empty() {
+            usage_error("--replay runs the program saved in the session, don't give another one");
+        }
+        let (_, mut rest) = split_recorded_options(&options);
+        rest.extend(session.options.iter().cloned());
+        options = rest;
+    }
+
+    // print generated Rust instead of running the program
+    let emit_rust = options.contains(&"--emit-rust".to_string());
+
+    // banners are only for people at a terminal, never for #! scripts
+    // --raw keeps stdout to the bytes the program wrote, e.g. for images
+    let raw = options.contains(&"--raw".to_string());
+    let quiet = options.contains(&"--quiet".to_string()) || !interactive_out || emit_rust || raw;
+
+    // anything after the program is handed to it as input
+    let source;
+    let (program, program_args) = match positional.as_slice() {
+        [] if replay.is_some() => (replay.as_ref().unwrap().source.as_str(), &[][..]),
+        // no arguments, use default hello world
+        [] => {
+            if !quiet {
+                println!("No input provided, running Hello World example:");
+            }
+            ("++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.", &[][..])
+        },
+        // program input
+        [flag] if flag == "-p" => {
+            print_usage();
+            Failure::Usage.exit();
+        },
+        [flag, text, rest @ ..] if flag == "-p" => {
+            if !quiet {
+                println!("Running program: {}", text);
+            }
+            (text.as_str(), rest)
+        },
+        // file input
+        [path, rest @ ..] => {
+            source = match fs::read_to_string(path) {
+                Ok(source) => source,
+                Err(e) => {
+                    eprintln!("Error: Could not read {}: {}", path, e);
+                    Failure::Other.exit();
+                }
+            };
+            if !quiet && !source.starts_with("#!") {
+                println!("Reading from file: {}", path);
+            }
+            (source.as_str(), rest)
+        },
+    };
+
+    // parse debug options. the debug subcommand is run with --debug --step
+    let debug = command == "debug" || options.contains(&"--debug".to_string());
+    let mut step = command == "debug" || options.contains(&"--step".to_string());
+    if step && !interactive_in {
+        eprintln!("Warning: stdin is not a terminal, ignoring --step");
+        step = false;
+    }
+    let stats_format = match option_value(&options, "--stats-format") {
+        None => None,
+        Some(name) => match StatsFormat::from_name(name) {
+            Some(format) => Some(format),
+            None => usage_error(&format!("Invalid --stats-format value: {} (expected text, json or csv)", name)),
+        },
+    };
+    let heatmap = options.contains(&"--heatmap".to_string());
+    let sample = match option_value(&options, "--sample").map(str::parse::<usize>) {
+        None => None,
+        Some(Ok(interval)) if interval > 0 => Some(interval),
+        Some(_) => usage_error("Invalid --sample value (expected a number of instructions above 0)"),
+    };
+    let cost_model = match option_value(&options, "--cost").map(CostModel::from_spec) {
+        None => None,
+        Some(Ok(model)) => Some(model),
+        Some(Err(e)) => usage_error(&e),
+    };
+    let stats = options.contains(&"--stats".to_string()) || stats_format.is_some() || heatmap || sample.is_some() || cost_model.is_some();
+    let trace = option_value(&options, "--trace");
+    let core_dump = option_value(&options, "--core-dump");
+    let annotate = option_value(&options, "--annotate");
+    let progress = options.contains(&"--progress".to_string());
+    let pgo = options.contains(&"--pgo".to_string());
+    let partial_eval = options.contains(&"--partial-eval".to_string());
+    let verify_opt = options.contains(&"--verify-opt".to_string());
+    let profile = match option_value(&options, "--profile") {
+        None => None,
+        Some(path) => match ProfileFormat::from_path(path) {
+            Some(format) => Some((path, format)),
+            None => usage_error(&format!("Invalid --profile file: {} (expected a .csv or .json file)", path)),
+        },
+    };
+    if profile.is_some() && sample.is_some() {
+        usage_error("--profile times every instruction and can't be combined with --sample");
+    }
+    let tui = options.contains(&"--tui".to_string());
+    if tui && !cfg!(feature = "tui") {
+        eprintln!("Error: --tui is not available, rebuild with --features tui");
+        Failure::Usage.exit();
+    }
+    if tui && !(interactive_in && interactive_out) {
+        eprintln!("Error: --tui needs a terminal");
+        Failure::Usage.exit();
+    }
+
+    // parse output options
+    let buffering = match output_buffering(&options, interactive_out) {
+        Ok(buffering) => buffering,
+        Err(e) => usage_error(&e),
+    };
+
+    let max_output = match option_value(&options, "--max-output").map(str::parse::<usize>) {
+        None => None,
+        Some(Ok(bytes)) => Some(bytes),
+        Some(Err(_)) => usage_error("Invalid --max-output value"),
+    };
+
+    // parse tape options
+    let grow_tape = options.contains(&"--grow-tape".to_string());
+    let grow_both = options.contains(&"--grow-both".to_string());
+    let lazy_tape = options.contains(&"--lazy-tape".to_string());
+    let wrap_pointer = options.contains(&"--wrap-pointer".to_string());
+    if (grow_tape || grow_both) && wrap_pointer {
+        usage_error("--grow-tape and --grow-both can't be combined with --wrap-pointer");
+    }
+    let tape_size = match option_value(&options, "--tape-size").map(str::parse::<usize>) {
+        None => None,
+        Some(Ok(cells)) => Some(cells),
+        Some(Err(_)) => usage_error("Invalid --tape-size value"),
+    };
+    let max_memory = match option_value(&options, "--max-memory").map(str::parse::<usize>) {
+        None => None,
+        Some(Ok(bytes)) => Some(bytes),
+        Some(Err(_)) => usage_error("Invalid --max-memory value"),
+    };
+
+    let cell_width = match option_value(&options, "--cell-width") {
+        None => CellWidth::U8,
+        Some(bits) => match bits.parse().ok().and_then(CellWidth::from_bits) {
+            Some(width) => width,
+            None => usage_error(&format!("Invalid --cell-width value: {} (expected 8, 16 or 32)", bits)),
+        },
+    };
+
+    let eof_behavior = match option_value(&options, "--eof") {
+        None => EofBehavior::Unchanged,
+        Some(name) => match EofBehavior::from_name(name) {
+            Some(eof_behavior) => eof_behavior,
+            None => usage_error(&format!("Invalid --eof value: {} (expected unchanged, zero or max)", name)),
+        },
+    };
+
+    // parse input options
+    let mut prompt = match option_value(&options, "--prompt") {
+        None => None,
+        Some(name) => match PromptMode::from_name(name) {
+            Some(mode) => Some(mode),
+            None => usage_error(&format!("Invalid --prompt value: {} (expected line or byte)", name)),
+        },
+    };
+    let echo = !options.contains(&"--no-echo".to_string());
+    if !echo && prompt.is_none() {
+        usage_error("--no-echo only works with --prompt");
+    }
+    if prompt.is_some() && (replay.is_some() || tui) {
+        usage_error("--prompt can't be combined with --replay or --tui");
+    }
+    if prompt.is_some() && !interactive_in {
+        eprintln!("Warning: stdin is not a terminal, ignoring --prompt");
+        prompt = None;
+    }
+    let prompt_io = || {
+        prompt.map(|mode| {
+            let mut io = PromptIo::new(mode);
+            io.set_echo(echo);
+            Box::new(io) as Box<dyn BfIo>
+        })
+    };
+
+    let language = match LanguageOptions::from_args(&options) {
+        Ok(language) => language,
+        Err(e) => usage_error(&e),
+    };
+
+    // pick the execution engine. the vm has none of the tree walker's hooks
+    // for debugging and profiling, or pbrain and #, so runs that need them
+    // get the tree walker unless --engine asks for something else
+    let extended = language.dialect != Dialect::Brainfuck || language.debug_command;
+    let profiling = stats || annotate.is_some() || profile.is_some() || progress;
+    let recording = replay.is_some() || options.contains(&"--record".to_string()) || core_dump.is_some();
+    let inspecting = debug || step || tui || trace.is_some() || options.contains(&"--break".to_string());
+    let tree_only = extended || profiling || recording || inspecting;
+    let default_engine = if options.contains(&"--jit".to_string()) {
+        "jit"
+    } else if tree_only {
+        "tree"
+    } else {
+        "vm"
+    };
+    let engine_name = option_value(&options, "--engine").unwrap_or(default_engine);
+    if engine_name == "vm" && tree_only {
+        eprintln!(
+            "Error: The vm engine does not support --debug, --step, --tui, --trace, --break, --stats, --annotate, --profile, --progress, --record, --replay, --core-dump, --dialect or --debug-command"
+        );
+        Failure::Usage.exit();
+    }
+    if engine_name == "jit" && !cfg!(feature = "jit") {
+        eprintln!("Error: The jit engine is not available, rebuild with --features jit");
+        Failure::Usage.exit();
+    }
+    let Some(engine_kind) = EngineKind::from_name(engine_name) else {
+        eprintln!("Error: Unknown engine: {} (available: {})", engine_name, EngineKind::names().join(", "));
+        Failure::Usage.exit();
+    };
+
+    // what , reads before stdin: the arguments, then --input and
+    // --input-file, or everything a replayed run read
+    let input_text = option_value(&options, "--input");
+    let input_file = option_value(&options, "--input-file");
+    if replay.is_some() && (input_text.is_some() || input_file.is_some()) {
+        usage_error("--input and --input-file can't be combined with --replay, which reads the session's input");
+    }
+    let provided = match &replay {
+        Some(session) => session.input.clone(),
+        None => {
+            let mut provided = Vec::new();
+            if !program_args.is_empty() {
+                provided.extend(format!("{}\n", program_args.join(" ")).into_bytes());
+            }
+            provided.extend(input_text.unwrap_or_default().as_bytes());
+            if let Some(path) = input_file {
+                match fs::read(path) {
+                    Ok(bytes) => provided.extend(bytes),
+                    Err(e) => {
+                        eprintln!("Error: Could not read {}: {}", path, e);
+                        Failure::Other.exit();
+                    }
+                }
+            }
+            provided
+        }
+    };
+    let tape_policy = if wrap_pointer {
+        TapePolicy::Wrap
+    } else if grow_both {
+        TapePolicy::GrowBoth
+    } else if grow_tape {
+        TapePolicy::Grow
+    } else {
+        TapePolicy::Abort
+    };
+    let new_vm = || {
+        let mut vm = brainfuck_compiler::vm::Vm::new();
+        vm.set_lazy_tape(lazy_tape);
+        if let Some(cells) = tape_size {
+            if let Err(e) = vm.set_tape_size(cells) {
+                eprintln!("Error: {}", e);
+                Failure::Usage.exit();
+            }
+        }
+        if let Some(bytes) = max_memory {
+            vm.set_max_memory(bytes);
+        }
+        vm.set_cell_width(cell_width);
+        vm.set_tape_policy(tape_policy);
+        vm.set_eof_behavior(eof_behavior);
+        vm.set_warn_on_eof(!interactive_in);
+        vm.set_output_buffering(buffering);
+        vm.set_max_output(max_output);
+        vm.set_partial_evaluation(partial_eval);
+        vm.set_io(prompt_io());
+        vm.provide_input(&provided);
+        vm
+    };
+
+    // --no-opt on the vm runs the commands as they are, without building a
+    // syntax tree at all, for the quickest start
+    let no_opt = options.contains(&"--no-opt".to_string());
+    if no_opt && engine_name == "jit" {
+        usage_error("The jit engine always optimizes, --no-opt only works with the vm and tree engines");
+    }
+    // the tree engine runs the program as written
+    if partial_eval && (no_opt || engine_kind == EngineKind::TreeWalker) {
+        usage_error("--partial-eval only works with the optimizer, on the vm and jit engines");
+    }
+    if verify_opt && (no_opt || extended || replay.is_some()) {
+        usage_error("--verify-opt can't be combined with --no-opt, --replay, --dialect or --debug-command");
+    }
+    if no_opt && engine_kind == EngineKind::Vm && !emit_rust {
+        let tokens = match language.lex(program) {
+            Ok(tokens) => tokens,
+            Err(errors) => errors.exit(),
+        };
+        let tokens: Vec<_> = tokens.into_iter().map(|token| token.node).collect();
+        if let Err(e) = new_vm().run_tokens(&tokens) {
+            eprintln!("Error: {}", Diagnostic::from_runtime_error_lexed(&e, language.lexer(program)).render(program));
+            Failure::Runtime.exit();
+        }
+        return;
+    }
+
+    // run the program
+    let ast = match language.parse(program) {
+        Ok(ast) => ast,
+        Err(errors) => errors.exit(),
+    };
+    let program_path = match positional.as_slice() {
+        [flag, ..] if flag == "-p" => None,
+        [path, ..] => Some(path.as_str()),
+        [] => None,
+    };
+    // checks the optimizer on the program and the input given ahead of
+    // stdin, and doesn't run it if they disagree
+    if verify_opt {
+        let config = brainfuck_compiler::Config {
+            tape_size: tape_size.unwrap_or(interpreter::DEFAULT_TAPE_SIZE),
+            cell_width,
+            eof_behavior,
+            tape_policy,
+            max_output,
+            partial_evaluation: partial_eval,
+            ..brainfuck_compiler::Config::default()
+        };
+        match verify::verify(&ast, &provided, &config) {
+            Ok(Verdict::Same) => {}
+            Ok(Verdict::Unverified(e)) => eprintln!("Warning: Could not verify the optimizer, the program as written stopped: {}", e),
+            Ok(Verdict::Diverged(divergence)) => {
+                eprintln!("Error: {}", divergence);
+                Failure::Other.exit();
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                Failure::Usage.exit();
+            }
+        }
+    }
+    let breakpoints = match breakpoint_positions(&options, program_path, program, language) {
+        Ok(breakpoints) => breakpoints,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            Failure::Usage.exit();
+        }
+    };
+
+    if emit_rust {
+        if ast.has_procedures() {
+            eprintln!("Error: --emit-rust does not support pbrain procedures");
+            Failure::Usage.exit();
+        }
+        let mut generator = CodeGenerator::new();
+        if options.contains(&"--compact".to_string()) {
+            generator.set_style(CodeStyle::Compact);
+        }
+        generator.set_precompute(options.contains(&"--precompute".to_string()));
+        print!("{}", generator.generate(&ast));
+        return;
+    }
+
+    let mut builder = interpreter::Interpreter::builder()
+        .debug(debug)
+        .step_by_step(step)
+        .output_buffering(buffering)
+        // a replay's input all comes from the session
+        .warn_on_eof(!interactive_in && replay.is_none())
+        .tape_policy(tape_policy)
+        .lazy_tape(lazy_tape)
+        .cell_width(cell_width)
+        .eof_behavior(eof_behavior)
+        .max_output(max_output)
+        .input(&provided);
+    if let Some(bytes) = max_memory {
+        builder = builder.max_memory(bytes);
+    }
+    if let Some(cells) = tape_size {
+        builder = builder.tape_size(cells);
+    }
+    if core_dump.is_some() {
+        builder = builder.history_size(coredump::DEFAULT_HISTORY);
+    }
+    if let Some(io) = prompt_io() {
+        builder = builder.io(io);
+    }
+    let mut interpreter = match builder.build() {
+        Ok(interpreter) => interpreter,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            Failure::Usage.exit();
+        }
+    };
+    if let Some(path) = trace {
+        match fs::File::create(path) {
+            Ok(file) => interpreter.set_trace(Some(Box::new(std::io::BufWriter::new(file)))),
+            Err(e) => {
+                eprintln!("Error: Could not create {}: {}", path, e);
+                Failure::Other.exit();
+            }
+        }
+    }
+    interpreter.set_track_heatmap(heatmap);
+    interpreter.set_sample_interval(sample);
+    interpreter.set_count_commands(annotate.is_some());
+    interpreter.set_cost_model(cost_model);
+    // one line on stderr, rewritten in place
+    let progress_shown = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    if progress {
+        let shown = progress_shown.clone();
+        interpreter.set_on_progress(
+            PROGRESS_INTERVAL,
+            Some(Box::new(move |instructions, elapsed| {
+                let millions = instructions as f64 / 1_000_000.0;
+                let seconds = elapsed.as_secs_f64();
+                eprint!("\r{:.0}M instructions in {:.1}s ({:.1}M/s)", millions, seconds, millions / seconds.max(0.001));
+                shown.store(true, std::sync::atomic::Ordering::Relaxed);
+            })),
+        );
+    }
+    interpreter.set_record_timeline(matches!(profile, Some((_, ProfileFormat::ChromeTrace))));
+
+    let record = option_value(&options, "--record");
+    let seek = match option_value(&options, "--seek").map(str::parse::<usize>) {
+        None => None,
+        Some(Ok(step)) => Some(step),
+        Some(Err(_)) => usage_error("Invalid --seek value"),
+    };
+    if record.is_some() && (replay.is_some() || tui) {
+        usage_error("--record can't be combined with --replay or --tui");
+    }
+    if seek.is_some() && (replay.is_none() || tui) {
+        usage_error("--seek needs --replay, and doesn't work with --tui");
+    }
+    if (record.is_some() || replay.is_some()) && engine_kind != EngineKind::TreeWalker {
+        eprintln!("Error: --record and --replay only work with the tree engine");
+        Failure::Usage.exit();
+    }
+
+    // the full-screen debugger steps through the program in place of --debug and --step
+    #[cfg(feature = "tui")]
+    if tui {
+        if engine_kind != EngineKind::TreeWalker {
+            eprintln!("Error: --tui only works with the tree engine");
+            Failure::Usage.exit();
+        }
+        let spans = language.lexer(program).map(|token| token.span).collect();
+        let mut debugger = match tui::Debugger::new(program, spans, interpreter, &ast) {
+            Ok(debugger) => debugger,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                Failure::Runtime.exit();
+            }
+        };
+        for &position in &breakpoints {
+            debugger.add_breakpoint(position);
+        }
+        if let Err(e) = tui::run(debugger) {
+            eprintln!("Error: {}", e);
+            Failure::Other.exit();
+        }
+        return;
+    }
+
+    // added after the tui has taken the interpreter, as they wait for Enter
+    for &position in &breakpoints {
+        interpreter.add_position_breakpoint(position);
+    }
+
+    if let Some(session) = &replay {
+        if let Err(failure) = replay_session(session, interpreter, &ast, seek, program, language) {
+            failure.exit();
+        }
+        return;
+    }
+    if let Some(path) = record {
+        let (recorded, _) = split_recorded_options(&options);
+        if let Err(failure) = record_session(path, program, recorded, &provided, interpreter, &ast, language) {
+            failure.exit();
+        }
+        return;
+    }
+
+    if pgo && engine_name != "jit" {
+        eprintln!("Error: --pgo only works with the jit engine");
+        Failure::Usage.exit();
+    }
+    let mut engine: Box<dyn Engine> = match engine_kind {
+        EngineKind::TreeWalker => Box::new(interpreter),
+        EngineKind::Vm => Box::new(new_vm()),
+        #[cfg(feature = "jit")]
+        EngineKind::Jit => {
+            // compiled code has no hooks for debugging and a fixed 8 bit tape
+            let tape = grow_tape || grow_both || wrap_pointer || lazy_tape || tape_size.is_some() || cell_width != CellWidth::U8;
+            let profiling = heatmap || sample.is_some() || annotate.is_some() || profile.is_some() || cost_model.is_some() || progress;
+            let limited = prompt.is_some() || max_output.is_some();
+            if debug || step || trace.is_some() || !breakpoints.is_empty() || profiling || tape || extended || limited {
+                eprintln!(
+                    "Error: The jit engine does not support --debug, --step, --trace, --break, --prompt, --max-output, --heatmap, --sample, --annotate, --profile, --cost, --progress, --grow-tape, --grow-both, --wrap-pointer, --lazy-tape, --tape-size, --cell-width, --dialect or --debug-command"
+                );
+                Failure::Usage.exit();
+            }
+            let mut jit = brainfuck_compiler::jit::JitEngine::new();
+            jit.set_eof_behavior(eof_behavior);
+            jit.provide_input(&provided);
+            jit.set_partial_evaluation(partial_eval);
+            if pgo {
+                // the profiling run only sees the arguments, never stdin
+                let profile = brainfuck_compiler::pgo::LoopProfile::collect(&ast, &provided, brainfuck_compiler::pgo::PROFILE_INSTRUCTIONS);
+                jit.set_unroll_factors(profile.unroll_factors());
+            }
+            Box::new(jit)
+        }
+    };
+
+    let result = engine.run(&ast);
+    if progress_shown.load(std::sync::atomic::Ordering::Relaxed) {
+        eprintln!();
+    }
+    // a failed run's counts still show where it went
+    if let Some(path) = annotate {
+        let spans: Vec<_> = language.lexer(program).map(|token| token.span).collect();
+        let annotation = annotate::Annotation::new(program, &spans, &engine.command_counts());
+        let html = Path::new(path).extension().is_some_and(|extension| extension == "html" || extension == "htm");
+        let report = if html { annotation.to_html() } else { annotation.to_text() };
+        if let Err(e) = fs::write(path, report) {
+            eprintln!("Error: Could not write {}: {}", path, e);
+            Failure::Other.exit();
+        }
+    }
+    if let Some((path, format)) = profile {
+        let report = match format {
+            ProfileFormat::Csv => engine.profile().to_csv(),
+            ProfileFormat::ChromeTrace => {
+                let spans: Vec<_> = language.lexer(program).map(|token| token.span).collect();
+                engine.profile().to_chrome_trace(&spans)
+            }
+        };
+        if let Err(e) = fs::write(path, report) {
+            eprintln!("Error: Could not write {}: {}", path, e);
+            Failure::Other.exit();
+        }
+    }
+    match result {
+        Ok(_) if stats => {
+            let mut statistics = engine.statistics();
+            let spans: Vec<_> = language.lexer(program).map(|token| token.span).collect();
+            statistics.add_spans(&spans);
+            match stats_format {
+                // machine-readable stats go to stderr, keeping stdout to program output
+                Some(format @ (StatsFormat::Json | StatsFormat::Csv)) => eprint!("{}", statistics.render(format)),
+                _ if raw => eprint!("{}", statistics.to_text()),
+                _ => print!("{}", statistics.to_text()),
+            }
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Error: {}", Diagnostic::from_runtime_error_lexed(&e, language.lexer(program)).render(program));
+            if let Some(path) = core_dump {
+                let span = language.lexer(program).nth(e.position).map(|token| token.span);
+                match fs::write(path, coredump::CoreDump::new(&e, engine.as_ref(), span).to_json()) {
+                    Ok(()) => eprintln!("Core dump written to {}", path),
+                    Err(write_error) => eprintln!("Error: Could not write {}: {}", path, write_error),
+                }
+            }
+            Failure::Runtime.exit();
+        }
+    }
+}
+
+// what --profile writes, picked by the file's extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProfileFormat {
+    Csv,         // a row per instruction of the program
+    ChromeTrace, // a timeline of the loops for chrome://tracing
+}
+
+impl ProfileFormat {
+    fn from_path(path: &str) -> Option<Self> {
+        match Path::new(path).extension()?.to_str()? {
+            "csv" => Some(ProfileFormat::Csv),
+            "json" => Some(ProfileFormat::ChromeTrace),
+            _ => None,
+        }
+    }
+}
+
+// the options that change which characters are commands
+#[derive(Debug, Clone, Copy)]
+struct LanguageOptions {
+    dialect: Dialect,
+    debug_command: bool,
+}
+
+impl LanguageOptions {
+    fn from_args(args: &[String]) -> Result<Self, String> {
+        let dialect = match option_value(args, "--dialect") {
+            None => Dialect::Brainfuck,
+            Some(name) => Dialect::from_name(name)
+                .ok_or_else(|| format!("Invalid --dialect value: {} (expected brainfuck or pbrain)", name))?,
+        };
+        let debug_command = args.iter().any(|arg| arg == "--debug-command");
+        Ok(LanguageOptions { dialect, debug_command })
+    }
+
+    fn lexer<'a>(&self, source: &'a str) -> lexer::Lexer<std::str::Chars<'a>> {
+        let mut lexer = lexer::Lexer::new(source);
+        lexer.set_dialect(self.dialect);
+        lexer.set_debug_command(self.debug_command);
+        lexer
+    }
+
+    // the program's tokens once its brackets are known to pair up. every
+    // unmatched bracket is reported at once rather than just the first.
+    // errors come rendered against `source`
+    fn lex(&self, source: &str) -> Result<Vec<lexer::Spanned<lexer::Token>>, SyntaxErrors> {
+        let tokens = self.lexer(source).tokenize_spanned();
+        if let Err(errors) = lexer::validate(&tokens) {
+            let messages = errors.into_iter().map(|error| Diagnostic::from(error).render(source)).collect();
+            return Err(SyntaxErrors { failure: Failure::Lex, messages });
+        }
+        Ok(tokens)
+    }
+
+    fn parse(&self, source: &str) -> Result<parser::AstNode, SyntaxErrors> {
+        let tokens = self.lex(source)?;
+        parser::Parser::with_spans(tokens)
+            .parse()
+            .map_err(|e| SyntaxErrors { failure: Failure::Parse, messages: vec![e.render(source)] })
+    }
+}
+
+// why a program didn't parse
+struct SyntaxErrors {
+    failure: Failure, // Lex or Parse
+    messages: Vec<String>,
+}
+
+impl SyntaxErrors {
+    fn report(self) -> Failure {
+        for message in self.messages {
+            eprintln!("Error: {}", message);
+        }
+        self.failure
+    }
+
+    fn exit(self) -> ! {
+        self.report().exit()
+    }
+}
+
+// parses each file without running it. fails with the first file's
+// failure when any of them don't parse
+fn check_files(paths: &[String], language: LanguageOptions) -> Result<(), Failure> {
+    let mut result = Ok(());
+    for path in paths {
+        let errors = match fs::read_to_string(path) {
+            Ok(source) => language.parse(&source).err(),
+            Err(e) => Some(SyntaxErrors { failure: Failure::Other, messages: vec![format!("Could not read {}: {}", path, e)] }),
+        };
+        match errors {
+            None => println!("{}: ok", path),
+            Some(errors) => {
+                for message in errors.messages {
+                    eprintln!("{}: Error: {}", path, message);
+                }
+                result = result.and(Err(errors.failure));
+            }
+        }
+    }
+    result
+}
+
+// prints the file laid out by the printer module: indented by loop depth
+// and wrapped at --width columns, or with --minify as bare commands
+fn format_file(path: &str, language: LanguageOptions, args: &[String]) -> Result<(), Failure> {
+    let width = match option_value(args, "--width") {
+        None => 80,
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("Error: Invalid --width value: {}", n);
+                return Err(Failure::Usage);
+            }
+        },
+    };
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Error: Could not read {}: {}", path, e);
+            return Err(Failure::Other);
+        }
+    };
+    if let Err(errors) = language.parse(&source) {
+        return Err(errors.report());
+    }
+    let mut lexer = language.lexer(&source);
+    lexer.set_keep_comments(true);
+    let tokens = lexer.tokenize_spanned();
+    if args.iter().any(|arg| arg == "--minify") {
+        print!("{}", printer::minify(&tokens));
+    } else {
+        print!("{}", printer::pretty(&tokens, width));
+    }
+    Ok(())
+}
+
+// times --iterations runs of the file after --warmup untimed ones, on the
+// engine picked by --engine or on every backend with --compare
+fn bench_file(path: &str, program_args: &[String], language: LanguageOptions, args: &[String]) -> Result<(), Failure> {
+    let count = |name: &str, default: usize, min: usize| match option_value(args, name) {
+        None => Ok(default),
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) if n >= min => Ok(n),
+            _ => Err(format!("Invalid {} value: {}", name, n)),
+        },
+    };
+    let (iterations, warmup) = match (count("--iterations", 10, 1), count("--warmup", 1, 0)) {
+        (Ok(iterations), Ok(warmup)) => (iterations, warmup),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Error: {}", e);
+            return Err(Failure::Usage);
+        }
+    };
+    let backends = if args.iter().any(|arg| arg == "--compare") {
+        bench::Backend::all()
+    } else {
+        let default_engine = if args.iter().any(|arg| arg == "--jit") { "jit" } else { "vm" };
+        let engine_name = option_value(args, "--engine").unwrap_or(default_engine);
+        match EngineKind::from_name(engine_name) {
+            Some(EngineKind::Vm) => vec![bench::Backend::Vm],
+            Some(EngineKind::TreeWalker) => vec![bench::Backend::Interpreter],
+            #[cfg(feature = "jit")]
+            Some(EngineKind::Jit) => vec![bench::Backend::Jit],
+            None => {
+                eprintln!("Error: Unknown engine: {} (available: {})", engine_name, EngineKind::names().join(", "));
+                return Err(Failure::Usage);
+            }
+        }
+    };
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Error: Could not read {}: {}", path, e);
+            return Err(Failure::Other);
+        }
+    };
+    let ast = match language.parse(&source) {
+        Ok(ast) => ast,
+        Err(errors) => return Err(errors.report()),
+    };
+    let input = if program_args.is_empty() { String::new() } else { format!("{}\n", program_args.join(" ")) };
+    let render = |e: interpreter::RuntimeError| {
+        eprintln!("Error: {}", Diagnostic::from_runtime_error_lexed(&e, language.lexer(&source)).render(&source));
+        Err(Failure::Runtime)
+    };
+    let instructions = match bench::count_instructions(&ast, input.as_bytes()) {
+        Ok(instructions) => instructions,
+        Err(e) => return render(e),
+    };
+    let mut results = Vec::new();
+    for backend in backends {
+        match bench::bench(&ast, backend, input.as_bytes(), warmup, iterations) {
+            Ok(result) => results.push(result),
+            Err(e) => return render(e),
+        }
+    }
+    println!("{} instructions, {} runs after {} warmup", instructions, iterations, warmup);
+    print!("{}", bench::render_table(&results, instructions));
+    Ok(())
+}
+
+// prints the program's syntax tree, as parsed or after the optimizer with
+// --stage optimized, and as JSON with --json, Brainfuck with --source or
+// with what each loop does with --loops
+fn emit_file(path: &str, language: LanguageOptions, args: &[String]) -> Result<(), Failure> {
+    let optimize = match option_value(args, "--stage") {
+        None | Some("ast") => false,
+        Some("optimized") => true,
+        Some(stage) => {
+            eprintln!("Error: Invalid --stage value: {} (expected ast or optimized)", stage);
+            return Err(Failure::Usage);
+        }
+    };
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Error: Could not read {}: {}", path, e);
+            return Err(Failure::Other);
+        }
+    };
+    let mut ast = match language.parse(&source) {
+        Ok(ast) => ast,
+        Err(errors) => return Err(errors.report()),
+    };
+    if optimize {
+        let mut optimizer = optimizer::Optimizer::new();
+        optimizer.set_partial_evaluation(args.iter().any(|arg| arg == "--partial-eval"));
+        ast = optimizer.optimize(&ast);
+    }
+    if args.iter().any(|arg| arg == "--json") {
+        match serde_json::to_string_pretty(&ast) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return Err(Failure::Other);
+            }
+        }
+    } else if args.iter().any(|arg| arg == "--source") {
+        println!("{}", ast);
+    } else if args.iter().any(|arg| arg == "--loops") {
+        print!("{}", brainfuck_compiler::analysis::annotated_tree(&ast));
+    } else {
+        print!("{}", ast.tree());
+    }
+    Ok(())
+}
+
+// runs the #expect:/#input: directives in each file, returns true if all pass
+fn run_doc_tests(paths: &[String]) -> bool {
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for path in paths {
+        let result = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path, e))
+            .and_then(|source| doctest::run_doc_test(&source));
+        match result {
+            Ok(DocTestResult::Passed) => {
+                println!("test {} ... ok", path);
+                passed += 1;
+            }
+            Ok(DocTestResult::NoDirectives) => {
+                println!("test {} ... skipped (no #expect: directive)", path);
+                skipped += 1;
+            }
+            Ok(DocTestResult::Failed { expected, actual }) => {
+                println!("test {} ... FAILED", path);
+                println!("  expected: {:?}", String::from_utf8_lossy(&expected));
+                println!("  actual:   {:?}", String::from_utf8_lossy(&actual));
+                failed += 1;
+            }
+            Err(e) => {
+                println!("test {} ... FAILED", path);
+                println!("  error: {}", e);
+                failed += 1;
+            }
+        }
+    }
+    println!("\ntest result: {} passed; {} failed; {} skipped", passed, failed, skipped);
+    failed == 0
+}
+
+// builds a native executable from a .bf file by generating Rust and running
+// rustc on it. the binary goes next to the source unless --output is given
+fn compile(path: &str, args: &[String]) -> Result<PathBuf, String> {
+    let opt_level = option_value(args, "--opt-level").unwrap_or("3");
+    if !["0", "1", "2", "3", "s", "z"].contains(&opt_level) {
+        return Err(format!("Invalid --opt-level value: {} (expected 0-3, s or z)", opt_level));
+    }
+    let binary = match option_value(args, "--output") {
+        Some(output) => PathBuf::from(output),
+        None => Path::new(path).with_extension(env::consts::EXE_EXTENSION),
+    };
+    if binary == Path::new(path) {
+        return Err(format!("Refusing to overwrite {}, pass --output", path));
+    }
+
+    let (mut generator, optimized) = compile_ast(path, args)?;
+    let code = generator.generate(&optimized);
+
+    // rustc wants a file, so stage the generated code in a scratch directory
+    let dir = scratch_dir()?;
+    let main_rs = dir.join("main.rs");
+    if let Err(e) = fs::write(&main_rs, code) {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(format!("Could not write {}: {}", main_rs.display(), e));
+    }
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let status = Command::new(&rustc)
+        .arg("-C")
+        .arg(format!("opt-level={}", opt_level))
+        .arg("-o")
+        .arg(&binary)
+        .arg(&main_rs)
+        .status();
+    let _ = fs::remove_dir_all(&dir);
+
+    match status {
+        Ok(status) if status.success() => Ok(binary),
+        Ok(status) => Err(format!("{} failed ({})", rustc, status)),
+        Err(e) => Err(format!("Could not run {}: {}", rustc, e)),
+    }
+}
+
+// writes the program at `path` as a Cargo project in `dir`, for compile --project
+fn compile_project(path: &str, dir: &str, args: &[String]) -> Result<(), String> {
+    // cargo picks the optimization level and where the executable goes
+    if let Some(flag) = ["--opt-level", "--output"].iter().find(|flag| args.iter().any(|arg| arg == *flag)) {
+        return Err(format!("compile --project does not support {}, set it in {}/Cargo.toml", flag, dir));
+    }
+    let (mut generator, optimized) = compile_ast(path, args)?;
+    generator.generate_project(&optimized, Path::new(dir)).map_err(|e| format!("Could not write {}: {}", dir, e))
+}
+
+// the optimized program at `path` and the generator for it, as compile's
+// flags set them up
+fn compile_ast(path: &str, args: &[String]) -> Result<(CodeGenerator, parser::AstNode), String> {
+    // the generated code always has 30,000 8 bit cells, leaves the cell
+    // unchanged at end of input and knows only the standard commands, so
+    // flags asking for anything else are errors
+    let unsupported = ["--eof", "--cell-width", "--grow-tape", "--grow-both", "--wrap-pointer", "--tape-size", "--max-memory", "--dialect"];
+    if let Some(flag) = unsupported.iter().find(|flag| args.iter().any(|arg| arg == *flag)) {
+        return Err(format!("compile does not support {}", flag));
+    }
+    let source = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let mut lexer = lexer::Lexer::new(&source);
+    lexer.set_debug_command(args.iter().any(|arg| arg == "--debug-command"));
+    let ast = parser::parse(lexer.tokenize())?;
+    let mut optimizer = optimizer::Optimizer::new();
+    optimizer.set_partial_evaluation(args.iter().any(|arg| arg == "--partial-eval"));
+    let mut generator = CodeGenerator::new();
+    generator.set_precompute(args.iter().any(|arg| arg == "--precompute"));
+    Ok((generator, optimizer.optimize(&ast)))
+}
+
+// creates a new, empty directory under the system temp dir. the name is hard
+// to guess and creation fails if it already exists, so nothing placed there
+// beforehand (like a symlink) is ever written through
+fn scratch_dir() -> Result<PathBuf, String> {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.subsec_nanos()).unwrap_or(0);
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    for attempt in 0..16u32 {
+        let dir = env::temp_dir().join(format!("bfc-{}-{:08x}", process::id(), nanos.wrapping_add(attempt)));
+        match builder.create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(format!("Could not create {}: {}", dir.display(), e)),
+        }
+    }
+    Err("Could not create a scratch directory".to_string())
+}
+
+// reads programs line by line, running each on the same tape
+fn run_repl(interactive_out: bool) {
+    let mut interpreter = interpreter::Interpreter::new();
+    interpreter.set_persistent(true);
+    interpreter.set_output_buffering(OutputBuffering::Unbuffered);
+
+    let stdin = std::io::stdin();
+    loop {
+        if interactive_out {
+            print!("bf> ");
+            std::io::stdout().flush().unwrap();
+        }
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 || line.trim() == "quit" {
+            break;
+        }
+
+        let result = lexer::tokenize_spanned(&line)
+            .and_then(|tokens| match lexer::validate(&tokens) {
+                Ok(()) => parser::parse_spanned(tokens).map_err(|e| e.render(&line)),
+                Err(errors) => Err(diagnostic::render_bracket_errors(errors, &line)),
+            })
+            .and_then(|ast| interpreter.run(&ast).map_err(|e| Diagnostic::from_runtime_error(&e, &line).render(&line)));
+        match result {
+            Ok(_) => println!(
+                "\n[pointer: {}, cell: {}]",
+                interpreter.pointer(),
+                interpreter.memory()[interpreter.pointer()]
+            ),
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+}
+
+// runs the program on a Recording, with , and . on the terminal, and saves
+// the session to `path`. a failed run is saved too, it's the one worth replaying
+fn record_session(
+    path: &str,
+    program: &str,
+    options: Vec<String>,
+    provided: &[u8],
+    mut interpreter: interpreter::Interpreter,
+    ast: &parser::AstNode,
+    language: LanguageOptions,
+) -> Result<(), Failure> {
+    // a --prompt terminal, if the interpreter was given one
+    let io = interpreter.take_io().unwrap_or_else(|| Box::new(StdIo));
+    let mut recording = Recording::with_io(interpreter, ast, io).map_err(|e| {
+        eprintln!("Error: {}", e);
+        Failure::Runtime
+    })?;
+    while recording.step() {}
+    let _ = std::io::stdout().flush();
+
+    let session = Session::new(program, options, provided, &recording);
+    let written = fs::File::create(path).and_then(|file| session.write_to(std::io::BufWriter::new(file)));
+    if let Err(e) = written {
+        eprintln!("Error: Could not write {}: {}", path, e);
+        return Err(Failure::Other);
+    }
+    if let Some(e) = recording.error() {
+        eprintln!("Error: {}", Diagnostic::from_runtime_error_lexed(e, language.lexer(program)).render(program));
+        return Err(Failure::Runtime);
+    }
+    Ok(())
+}
+
+// runs a recorded program again, checking it does what the journal says,
+// and prints its output. with `seek` it stops at that step instead and
+// describes the state there on stderr
+fn replay_session(
+    session: &Session,
+    interpreter: interpreter::Interpreter,
+    ast: &parser::AstNode,
+    seek: Option<usize>,
+    program: &str,
+    language: LanguageOptions,
+) -> Result<(), Failure> {
+    let mut recording = Recording::new(interpreter, ast).map_err(|e| {
+        eprintln!("Error: {}", e);
+        Failure::Runtime
+    })?;
+    if let Err(step) = session.replay(&mut recording) {
+        eprintln!("Error: The replay went differently from the recording at step {}", step);
+        return Err(Failure::Other);
+    }
+    if let Some(step) = seek {
+        recording.seek(step);
+    }
+    let mut stdout = std::io::stdout();
+    let _ = stdout.write_all(recording.output()).and_then(|_| stdout.flush());
+
+    if seek.is_none() {
+        if let Some(e) = recording.error() {
+            eprintln!("Error: {}", Diagnostic::from_runtime_error_lexed(e, language.lexer(program)).render(program));
+            return Err(Failure::Runtime);
+        }
+        return Ok(());
+    }
+    let location = recording
+        .position()
+        .and_then(|position| language.lexer(program).nth(position).map(|token| (position, token.span)))
+        .map_or("the end".to_string(), |(position, span)| {
+            format!("command {} at line {}, column {}", position, span.line, span.column)
+        });
+    if !recording.output().is_empty() && !recording.output().ends_with(b"\n") {
+        eprintln!();
+    }
+    eprintln!("Step {} of {}, before {}", recording.at(), recording.steps(), location);
+    let pointer = recording.pointer();
+    let first = pointer.saturating_sub(5);
+    let cells: Vec<String> = (first..recording.memory().len().min(first + 10))
+        .map(|cell| match recording.memory()[cell] {
+            value if cell == pointer => format!("[{}]", value),
+            value => value.to_string(),
+        })
+        .collect();
+    eprintln!("Pointer: {}", pointer);
+    eprintln!("Cells {}-{}: {}", first, first + cells.len().saturating_sub(1), cells.join(" "));
+    Ok(())
+}
+
+fn load_session(path: &str) -> Session {
+    match fs::File::open(path).and_then(|file| Session::read_from(std::io::BufReader::new(file))) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Error: Could not read {}: {}", path, e);
+            Failure::Other.exit();
+        }
+    }
+}
+
+// the options in `args` that --record saves, and the others
+fn split_recorded_options(args: &[String]) -> (Vec<String>, Vec<String>) {
+    let (mut recorded, mut rest) = (Vec::new(), Vec::new());
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let target = if RECORDED_OPTIONS.contains(&arg.as_str()) { &mut recorded } else { &mut rest };
+        target.push(arg.clone());
+        if VALUE_OPTIONS.contains(&arg.as_str()) {
+            target.extend(iter.next().cloned());
+        }
+    }
+    (recorded, rest)
+}
+
+// the commands --break stops at. each value is N for the command at
+// position N (counting from 0, as traces and errors do), LINE:COLUMN for the
+// first command at or after that place, or FILE:LINE:COLUMN where FILE is
+// the program's path
+fn breakpoint_positions(args: &[String], path: Option<&str>, program: &str, language: LanguageOptions) -> Result<Vec<usize>, String> {
+    let tokens = language.lexer(program).tokenize_spanned();
+    let mut positions = Vec::new();
+    for location in option_values(args, "--break") {
+        let invalid = || format!("Invalid --break value: {} (expected N, LINE:COLUMN or FILE:LINE:COLUMN)", location);
+        let parts: Vec<&str> = location.rsplitn(3, ':').collect();
+        let position = match parts.as_slice() {
+            [n] => n.parse().map_err(|_| invalid())?,
+            [column, line, file @ ..] => {
+                if let [file] = file {
+                    if path.is_none_or(|path| Path::new(path) != Path::new(file)) {
+                        return Err(format!("Breakpoint {} is not in the program being run", location));
+                    }
+                }
+                let line = line.parse().map_err(|_| invalid())?;
+                let column = column.parse().map_err(|_| invalid())?;
+                lexer::command_at(&tokens, line, column).ok_or_else(|| format!("No command at or after {}", location))?
+            }
+            [] => return Err(invalid()),
+        };
+        match tokens.get(position).map(|token| &token.node) {
+            None => return Err(format!("Breakpoint {} is past the last command", location)),
+            Some(Token::LoopEnd | Token::ProcEnd) => {
+                return Err(format!("Breakpoint {} is on a ] or ), which only jump and can't be stopped at", location))
+            }
+            Some(_) => positions.push(position),
+        }
+    }
+    Ok(positions)
+}
+
+// every value given for an option that may be repeated
+fn option_values<'a>(args: &'a [String], name: &str) -> Vec<&'a str> {
+    args.windows(2).filter(|pair| pair[0] == name).map(|pair| pair[1].as_str()).collect()
+}
+
+// returns the value following an option, if present
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .map(|value| value.as_str())
+}
+
+fn output_buffering(args: &[String], interactive_out: bool) -> Result<OutputBuffering, String> {
+    if let Some(n) = option_value(args, "--flush-every") {
+        return match n.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(OutputBuffering::Every(n)),
+            _ => Err(format!("Invalid --flush-every value: {}", n)),
+        };
+    }
+    match option_value(args, "--buffer") {
+        // block buffer by default when writing to a pipe or file
+        None if !interactive_out => Ok(OutputBuffering::Block),
+        None | Some("line") => Ok(OutputBuffering::Line),
+        Some("none") => Ok(OutputBuffering::Unbuffered),
+        Some("block") => Ok(OutputBuffering::Block),
+        Some(mode) => Err(format!("Unknown --buffer mode: {}", mode)),
+    }
+}
+
+// reports invalid options or arguments, with the usage text, and exits
+fn usage_error(message: &str) -> ! {
+    eprintln!("Error: {}", message);
+    print_usage();
+    Failure::Usage.exit()
+}
+
+fn print_usage() {
+    eprintln!("Usage:");
+    eprintln!("  cargo run              # Run Hello World example");
+    eprintln!("  cargo run file.bf      # Run program from file");
+    eprintln!("  cargo run run file.bf  # Same, as an explicit subcommand");
+    eprintln!("  cargo run debug file.bf     # Run with --debug --step");
+    eprintln!("  cargo run check FILES       # Report syntax errors without running anything");
+    eprintln!("  cargo run fmt file.bf       # Print file.bf indented by loop depth, or add --minify");
+    eprintln!("  cargo run emit file.bf      # Print the syntax tree, add --stage optimized, --json, --source or --loops");
+    eprintln!("  cargo run file.bf ARGS # Run program with ARGS as its input line");
+    eprintln!("  cargo run -p '++++.'   # Run program directly");
+    eprintln!("  cargo run --repl       # Run lines interactively on a shared tape");
+    eprintln!("  cargo run test --doc FILES  # Check #expect:/#input: directives in FILES");
+    eprintln!("  cargo run compile file.bf   # Build a native executable next to file.bf with rustc");
+    eprintln!("  cargo run bench file.bf     # Time repeated runs, add --compare to try every backend");
+    eprintln!("  Add --quiet            # Don't print banners");
+    eprintln!("  Add --max-output BYTES # Stop the program with an error once it has written this much");
+    eprintln!("  Add --raw              # Only the program's bytes on stdout, statistics go to stderr");
+    eprintln!("  cargo run --emit-rust file.bf  # Print the program as Rust source");
+    eprintln!("  Options go before file.bf, the rest is ARGS. After a subcommand they may go");
+    eprintln!("  anywhere, and -- passes everything after it as ARGS");
+    eprintln!("\nDebug options:");
+    eprintln!("  Add --debug            # Enable debug mode");
+    eprintln!("  Add --step             # Enable step-by-step");
+    eprintln!("  Add --tui              # Full-screen debugger with breakpoints (needs the tui feature)");
+    eprintln!("  Add --stats            # Show execution statistics");
+    eprintln!("  Add --stats-format F   # Statistics as text (default), or json or csv on stderr");
+    eprintln!("  Add --heatmap          # Include how often each cell was read and written in the statistics");
+    eprintln!("  Add --sample N         # Statistics from sampling every Nth instruction instead of timing each one");
+    eprintln!("  Add --cost SPEC        # Statistics with the run's cycles by a cost per instruction, e.g. default or io=100");
+    eprintln!("  Add --annotate FILE    # Write the source with how often each command ran to FILE, HTML for .html");
+    eprintln!("  Add --profile FILE     # Write per-instruction times as .csv, or a loop timeline as Chrome trace .json");
+    eprintln!("  Add --progress         # Show instructions executed and speed on stderr during long runs");
+    eprintln!("  Add --trace PATH       # Write position, instruction, pointer and cell of each step to PATH");
+    eprintln!("  Add --debug-command    # Make # print the pointer and the first 10 cells to stderr");
+    eprintln!("  Add --break LOCATION   # Pause before a command: N (its position), LINE:COL or FILE:LINE:COL");
+    eprintln!("  Add --core-dump FILE   # On a runtime error, save the tape and the last instructions to FILE");
+    eprintln!("  Add --record FILE      # Save the input and a journal of the run to FILE");
+    eprintln!("  Add --replay FILE      # Run a recorded session again instead of a program, checking it matches");
+    eprintln!("  Add --seek N           # With --replay, stop at step N and show the state there");
+    eprintln!("\nEngine options:");
+    eprintln!("  Add --engine NAME      # Execution engine: vm (default), tree or jit");
+    eprintln!("  Add --jit              # Same as --engine jit (needs the jit feature)");
+    eprintln!("  Add --no-opt           # Run the vm on the commands as written, without the optimizer");
+    eprintln!("  Add --pgo              # Profile in the interpreter first, then unroll the jit's hottest loops");
+    eprintln!("  Add --partial-eval     # Run the program's start up to its first input ahead of time");
+    eprintln!("  Add --verify-opt       # First check that optimizing doesn't change what the program does");
+    eprintln!("\nTape options:");
+    eprintln!("  Add --grow-tape        # Grow the tape instead of failing at the end");
+    eprintln!("  Add --grow-both        # Grow it at the start too, so < at cell 0 works");
+    eprintln!("  Add --max-memory N     # Cap a growing tape at N bytes (default 64 MiB)");
+    eprintln!("  Add --tape-size N      # Start with N cells instead of 30,000");
+    eprintln!("  Add --lazy-tape        # Allocate the tape a page at a time as the program reaches it");
+    eprintln!("  Add --wrap-pointer     # Moving off one end of the tape continues at the other");
+    eprintln!("  Add --cell-width BITS  # 8 (default), 16 or 32 bit cells");
+    eprintln!("\nInput options:");
+    eprintln!("  Add --eof MODE         # What , stores at end of input: unchanged (default), zero or max");
+    eprintln!("  Add --input TEXT       # Bytes for , to read before stdin");
+    eprintln!("  Add --input-file FILE  # The same, from a file");
+    eprintln!("  Add --prompt MODE      # Prompt on stderr when , waits for the terminal: line or byte");
+    eprintln!("  Add --no-echo          # Don't show what is typed at the prompt");
+    eprintln!("\nLanguage options:");
+    eprintln!("  Add --dialect NAME     # brainfuck (default) or pbrain, which adds ( ) procedures and : calls");
+    eprintln!("\nFormatting options:");
+    eprintln!("  Add --minify           # fmt prints only the commands, on one line");
+    eprintln!("  Add --width N          # fmt wraps runs of commands at N columns (default 80)");
+    eprintln!("\nOutput options:");
+    eprintln!("  Add --buffer MODE      # none, line or block (default when piped)");
+    eprintln!("  Add --flush-every N    # Flush output every N bytes");
+    eprintln!("\nBenchmark options:");
+    eprintln!("  Add --iterations N     # bench times N runs (default 10)");
+    eprintln!("  Add --warmup N         # bench first runs N times untimed (default 1)");
+    eprintln!("  Add --compare          # bench the interpreter, optimized interpreter and jit (when built) in one table");
+    eprintln!("\nCode generation options:");
+    eprintln!("  Add --opt-level N      # rustc optimization level for compile: 0-3, s or z (default 3)");
+    eprintln!("  Add --output PATH      # Where compile writes the executable");
+    eprintln!("  Add --project DIR      # compile writes a Cargo project to DIR instead of running rustc");
+    eprintln!("  Add --pretty           # Indent generated code by loop depth (default)");
+    eprintln!("  Add --compact          # Emit generated code without indentation");
+    eprintln!("  Add --precompute       # Run a program without input now and only emit what it prints");
+}
@@ -1,86 +1,452 @@
+mod peephole;
+
+use crate::analysis::LoopClass;
+use crate::interpreter::{CellWidth, Interpreter, TapePolicy, DEFAULT_TAPE_SIZE};
+use crate::linear::flat_len;
 use crate::parser::AstNode;
+use peephole::{peephole, Peephole};
+
+// upper bound on optimization sweeps, in case rewrites keep feeding each other
+const MAX_PASSES: usize = 16;
+// instructions partial evaluation runs at compile time, at each cell width,
+// before it gives up and leaves the program as it is
+pub const PARTIAL_EVALUATION_FUEL: usize = 10_000_000;
+// and the output it folds at most, as each byte becomes an instruction
+pub const PARTIAL_EVALUATION_MAX_OUTPUT: usize = 65_536;
 
+// the rewrites run over every block after the loops in it are optimized,
+// first match wins
+const PEEPHOLES: &[Peephole] = &[
+   // runs of + and of -, including ones folded by an earlier pass
+   peephole!("increments": [AstNode::Increment, AstNode::Increment] => [AstNode::Add(2)]),
+   peephole!("increments": [AstNode::Increment, AstNode::Add(n)] => [AstNode::Add(n + 1)]),
+   peephole!("increments": [AstNode::Add(n), AstNode::Increment] => [AstNode::Add(n + 1)]),
+   peephole!("increments": [AstNode::Add(a), AstNode::Add(b)] => [AstNode::Add(a + b)]),
+   peephole!("decrements": [AstNode::Decrement, AstNode::Decrement] => [AstNode::Sub(2)]),
+   peephole!("decrements": [AstNode::Decrement, AstNode::Sub(n)] => [AstNode::Sub(n + 1)]),
+   peephole!("decrements": [AstNode::Sub(n), AstNode::Decrement] => [AstNode::Sub(n + 1)]),
+   peephole!("decrements": [AstNode::Sub(a), AstNode::Sub(b)] => [AstNode::Sub(a + b)]),
+   // and mixed ones, down to their net change, or nothing when it is 0.
+   // cells wrap, so this holds at any cell width
+   peephole!("net change": [a @ (AstNode::Increment | AstNode::Add(_)), b @ (AstNode::Decrement | AstNode::Sub(_))] => net_change(delta(a) + delta(b))),
+   peephole!("net change": [a @ (AstNode::Decrement | AstNode::Sub(_)), b @ (AstNode::Increment | AstNode::Add(_))] => net_change(delta(a) + delta(b))),
+   // x[t+x-] y[x+y-] t[y+t-], once the loops are copies, swaps x and y when
+   // t is clear. the pointer ends up on t
+   peephole!(
+      "swap": [
+         AstNode::MulCopy { targets: first },
+         to_other @ (AstNode::MoveRight | AstNode::MoveLeft | AstNode::Move(_)),
+         AstNode::MulCopy { targets: second },
+         to_temp @ (AstNode::MoveRight | AstNode::MoveLeft | AstNode::Move(_)),
+         AstNode::MulCopy { targets: third },
+      ] if is_swap(first, moved(to_other), second, moved(to_temp), third)
+      => [AstNode::Swap { offset: moved(to_other), temp: first[0].0 }, AstNode::Move(first[0].0)]
+   ),
+];
 
+// what a +, -, Add or Sub adds to the cell
+fn delta(node: &AstNode) -> isize {
+   match node {
+       AstNode::Increment => 1,
+       AstNode::Decrement => -1,
+       AstNode::Add(n) => *n as isize,
+       AstNode::Sub(n) => -(*n as isize),
+       _ => 0,
+   }
+}
 
-pub struct Optimizer;
+// how far a >, < or Move moves the pointer
+fn moved(node: &AstNode) -> isize {
+   match node {
+       AstNode::MoveRight => 1,
+       AstNode::MoveLeft => -1,
+       AstNode::Move(n) => *n,
+       _ => 0,
+   }
+}
+
+// copies from the cell to temp, then from the cell at offset back to it and
+// from temp on to offset, with the moves between them
+fn is_swap(first: &[(isize, i32)], offset: isize, second: &[(isize, i32)], to_temp: isize, third: &[(isize, i32)]) -> bool {
+   let [(temp, 1)] = *first else { return false };
+   offset + to_temp == temp && second == [(-offset, 1)] && third == [(offset - temp, 1)]
+}
+
+// the instruction that adds `amount`, if any
+fn net_change(amount: isize) -> Vec<AstNode> {
+   match amount {
+       0 => Vec::new(),
+       1 => vec![AstNode::Increment],
+       -1 => vec![AstNode::Decrement],
+       n if n > 0 => vec![AstNode::Add(n as usize)],
+       n => vec![AstNode::Sub(n.unsigned_abs())],
+   }
+}
+
+// prints what the optimizer is doing, only when verbose
+macro_rules! trace {
+   ($optimizer:expr, $($arg:tt)*) => {
+       if $optimizer.verbose {
+           println!($($arg)*);
+       }
+   };
+}
+
+pub struct Optimizer {
+   verbose: bool,
+   partial_evaluation: bool,
+   tape: (usize, TapePolicy), // size and policy of the tape the program runs on
+}
+
+impl Default for Optimizer {
+   fn default() -> Self {
+       Self::new()
+   }
+}
 
 impl Optimizer {
    pub fn new() -> Self {
-       Optimizer
+       Optimizer { verbose: false, partial_evaluation: false, tape: (DEFAULT_TAPE_SIZE, TapePolicy::Abort) }
+   }
+
+   pub fn set_verbose(&mut self, verbose: bool) {
+       self.verbose = verbose;
+   }
+
+   // runs the start of the program, up to the first instruction that could
+   // read input, at compile time and replaces it with what it did, see
+   // fold_prefix. only right for programs that start on a clear tape
+   pub fn set_partial_evaluation(&mut self, enabled: bool) {
+       self.partial_evaluation = enabled;
+   }
+
+   // the tape partial evaluation runs the start on, the default 30,000 cells
+   // that end in an error unless set
+   pub fn set_tape(&mut self, tape_size: usize, tape_policy: TapePolicy) {
+       self.tape = (tape_size, tape_policy);
    }
 
    pub fn optimize(&self, ast: &AstNode) -> AstNode {
-       println!("Starting optimization...");
+       self.optimize_with_positions(ast).0
+   }
+
+   // also returns the source command each instruction of the result starts
+   // at, in the order linear::flatten lays them out. an instruction made from
+   // several commands gets the position of the first one
+   pub fn optimize_with_positions(&self, ast: &AstNode) -> (AstNode, Vec<usize>) {
+       trace!(self, "Starting optimization...");
+       let mut positions: Vec<usize> = (0..flat_len(ast)).collect();
        let result = match ast {
            AstNode::Program(instructions) => {
-               println!("Optimizing program with {} instructions", instructions.len());
-               AstNode::Program(self.optimize_instructions(instructions))
+               trace!(self, "Optimizing program with {} instructions", instructions.len());
+               // one sweep can expose new patterns, so repeat until nothing changes
+               let mut current = instructions.clone();
+               for pass in 1..=MAX_PASSES {
+                   let (next, next_positions) = self.optimize_instructions(&current, &positions);
+                   let changed = next != current;
+                   current = next;
+                   positions = next_positions;
+                   if !changed {
+                       trace!(self, "Reached fixpoint after {} passes", pass);
+                       break;
+                   }
+               }
+               if self.partial_evaluation {
+                   if let Some((folded, folded_positions)) = fold_prefix(&current, &positions, self.tape) {
+                       trace!(self, "Folded the input-free start of the program into {} instructions", folded.len());
+                       current = folded;
+                       positions = folded_positions;
+                   }
+               }
+               AstNode::Program(current)
            }
            _ => ast.clone(),
        };
-       println!("Optimization complete.");
-       result
+       trace!(self, "Optimization complete.");
+       (result, positions)
    }
 
-   fn optimize_instructions(&self, instructions: &[AstNode]) -> Vec<AstNode> {
-       println!("Optimizing instruction block..."); 
+   // `positions` are those of the flattened instructions, and the ones of
+   // the optimized block are returned with it
+   fn optimize_instructions(&self, instructions: &[AstNode], positions: &[usize]) -> (Vec<AstNode>, Vec<usize>) {
+       trace!(self, "Optimizing instruction block..."); 
        let mut optimized = Vec::new();
+       let mut optimized_positions = Vec::new();
+       // index into `positions` of instructions[i]
+       let mut first = 0;
        let mut i = 0;
+       // the cells around the pointer the block has already been to, as the
+       // offsets of the leftmost and rightmost, so moving among them again
+       // can't leave the tape
+       let mut visited = (0isize, 0isize);
 
        while i < instructions.len() {
-           println!("Processing instruction {}/{}", i, instructions.len());
+           trace!(self, "Processing instruction {}/{}", i, instructions.len());
            match &instructions[i] {
-               AstNode::Increment => {
-                   println!("Found increment at position {}", i);
-                   // Count consecutive increments
-                   let mut count = 1;
-                   while i + count < instructions.len() {
-                       if let AstNode::Increment = instructions[i + count] {
-                           count += 1;
-                       } else {
-                           break;
+               AstNode::MoveRight | AstNode::MoveLeft | AstNode::Move(_) => {
+                   trace!(self, "Found move at position {}", i);
+                   // fold a run of > and < into as few moves as possible
+                   let mut count = 0;
+                   let mut offsets = Vec::new();
+                   let mut offset = 0isize;
+                   while let Some(instruction) = instructions.get(i + count) {
+                       match instruction {
+                           AstNode::MoveRight => offset += 1,
+                           AstNode::MoveLeft => offset -= 1,
+                           AstNode::Move(n) => offset += n,
+                           _ => break,
                        }
+                       offsets.push(offset);
+                       count += 1;
                    }
+                   let (left, right) = (offsets.iter().copied().min().unwrap_or(0), offsets.iter().copied().max().unwrap_or(0));
                    if count > 1 {
-                       println!("Optimizing {} increments into Add({})", count, count);
-                       // create an optimized increment
-                       optimized.push(AstNode::Add(count));
-                       i += count;
+                       let moves = fold_moves(&offsets, visited);
+                       trace!(self, "Optimizing {} moves into {:?}", count, moves);
+                       for (start, node) in moves {
+                           optimized.push(node);
+                           optimized_positions.push(positions[first + start]);
+                       }
                    } else {
                        optimized.push(instructions[i].clone());
-                       i += 1;
+                       optimized_positions.push(positions[first]);
                    }
+                   visited = (visited.0.min(left) - offset, visited.1.max(right) - offset);
+                   first += count;
+                   i += count;
                },
-               AstNode::Decrement => {
-                   println!("Found decrement at position {}", i);
-                   // same for decrements
-                   let mut count = 1;
-                   while i + count < instructions.len() {
-                       if let AstNode::Decrement = instructions[i + count] {
-                           count += 1;
-                       } else {
-                           break;
-                       }
+               AstNode::Loop(body) => {
+                   trace!(self, "Found loop at position {}", i);
+                   let width = flat_len(&instructions[i]);
+                   if optimized.last().is_some_and(leaves_zero) {
+                       // the cell is zero, so the loop never runs
+                       trace!(self, "Dropping dead loop");
+                       first += width;
+                       i += 1;
+                       continue;
                    }
-                   if count > 1 {
-                       println!("Optimizing {} decrements into Sub({})", count, count);
-                       optimized.push(AstNode::Sub(count));
-                       i += count;
+                   if !LoopClass::of(body).is_balanced() {
+                       visited = (0, 0);
+                   }
+                   let (body, body_positions) = self.optimize_instructions(body, &positions[first + 1..first + width - 1]);
+                   // anything a loop turns into points at its [
+                   optimized_positions.push(positions[first]);
+                   if is_clear_loop(&body) {
+                       // [-] and [+] run until the cell wraps to zero
+                       trace!(self, "Optimizing clear loop into Set(0)");
+                       optimized.push(AstNode::Set(0));
+                   } else if let Some(scan) = scan_loop(&body) {
+                       trace!(self, "Optimizing scan loop into {:?}", scan);
+                       optimized.push(scan);
+                   } else if let Some(targets) = mul_copy_targets(&body) {
+                       trace!(self, "Optimizing copy loop into MulCopy with {} targets", targets.len());
+                       optimized.push(AstNode::MulCopy { targets });
                    } else {
-                       optimized.push(instructions[i].clone());
-                       i += 1;
+                       optimized.push(AstNode::Loop(body));
+                       optimized_positions.extend(body_positions);
+                       optimized_positions.push(positions[first + width - 1]);
                    }
+                   first += width;
+                   i += 1;
+               },
+               AstNode::ProcDef(body) => {
+                   // a procedure body is optimized on its own, like a loop body
+                   let width = flat_len(&instructions[i]);
+                   let (body, body_positions) = self.optimize_instructions(body, &positions[first + 1..first + width - 1]);
+                   optimized.push(AstNode::ProcDef(body));
+                   optimized_positions.push(positions[first]);
+                   optimized_positions.extend(body_positions);
+                   optimized_positions.push(positions[first + width - 1]);
+                   first += width;
+                   i += 1;
+               },
+               AstNode::Comment(_) => {
+                   // comments don't run, and dropping them lets the commands
+                   // around them fold together on the next pass
+                   i += 1;
                },
                _ => {
-                   println!("Found other instruction at position {}", i);
+                   trace!(self, "Found other instruction at position {}", i);
+                   if matches!(instructions[i], AstNode::ScanRight(_) | AstNode::ScanLeft(_) | AstNode::ProcCall) {
+                       visited = (0, 0);
+                   }
                    optimized.push(instructions[i].clone());
+                   optimized_positions.push(positions[first]);
+                   first += 1;
                    i += 1;
                }
            }
        }
-       println!("Block optimization complete");
-       optimized
+       let result = peephole::apply(PEEPHOLES, optimized, optimized_positions, &|rule| trace!(self, "Rewrote {}", rule.name));
+       trace!(self, "Block optimization complete");
+       result
+   }
+}
+
+// the moves for a run that goes through `offsets`. only the net movement
+// matters to the program, but running off either end of the tape has to fail
+// the same way, so the leftmost and rightmost cells the run visits are still
+// visited, in the order it reaches them, unless they are within `visited`.
+// e.g. >>< becomes Move(2), Move(-1), and >< stays as it is unless the cell
+// to the right was visited, when it goes. each move comes with the index of
+// the first command of the run it replaces
+fn fold_moves(offsets: &[isize], visited: (isize, isize)) -> Vec<(usize, AstNode)> {
+   let first_visit = |target: isize| offsets.iter().position(|&offset| offset == target);
+   let mut stops = Vec::new();
+   if let Some(left) = offsets.iter().copied().min().filter(|&left| left < visited.0) {
+       stops.extend(first_visit(left).map(|index| (index, left)));
+   }
+   if let Some(right) = offsets.iter().copied().max().filter(|&right| right > visited.1) {
+       stops.extend(first_visit(right).map(|index| (index, right)));
+   }
+   stops.sort();
+   stops.push((offsets.len() - 1, offsets.last().copied().unwrap_or(0)));
+
+   let mut moves = Vec::new();
+   let mut current = 0;
+   let mut start = 0;
+   for (index, stop) in stops {
+       if stop != current {
+           moves.push((start, AstNode::Move(stop - current)));
+           current = stop;
+           start = index + 1;
+       }
+   }
+   moves
+}
+
+// runs the instructions before the first one that could read input, or do
+// anything else whose effect can't be known here, and returns the program
+// with them replaced by Set, Output and Move instructions that write the
+// same output and leave the same tape. the pointer still goes as far right
+// as it did, so a tape that is too small still fails. only done when every
+// cell width gets the same result within the fuel, and the start has a loop
+// to save
+fn fold_prefix(instructions: &[AstNode], positions: &[usize], tape: (usize, TapePolicy)) -> Option<(Vec<AstNode>, Vec<usize>)> {
+   let opaque = |node: &AstNode| matches!(node, AstNode::Input | AstNode::ProcDef(_) | AstNode::ProcCall | AstNode::Debug);
+   let end = instructions.iter().position(|node| node.contains(&opaque)).unwrap_or(instructions.len());
+   let prefix = &instructions[..end];
+   let looping = |node: &AstNode| matches!(node, AstNode::Loop(_) | AstNode::MulCopy { .. } | AstNode::ScanRight(_) | AstNode::ScanLeft(_));
+   if !prefix.iter().any(|node| node.contains(&looping)) {
+       return None;
+   }
+
+   // a start that would grow the tape is left alone, so it fails here
+   // instead, and the folded moves stay on the tape it starts with
+   let (tape_size, tape_policy) = tape;
+   let tape_policy = if tape_policy == TapePolicy::Wrap { TapePolicy::Wrap } else { TapePolicy::Abort };
+   let program = AstNode::Program(prefix.to_vec());
+   let mut result = None;
+   for width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+       let mut interpreter = Interpreter::builder().tape_size(tape_size).tape_policy(tape_policy).build().ok()?;
+       interpreter.set_cell_width(width);
+       interpreter.set_max_instructions(Some(PARTIAL_EVALUATION_FUEL));
+       interpreter.set_max_output(Some(PARTIAL_EVALUATION_MAX_OUTPUT));
+       let (output, memory, pointer) = interpreter.run_and_capture_bytes(&program).ok()?;
+       let run = (output, memory, pointer, interpreter.statistics().max_pointer?);
+       if result.as_ref().is_some_and(|first| *first != run) {
+           return None;
+       }
+       result = Some(run);
+   }
+   let (output, memory, pointer, reach) = result?;
+
+   // goes as far as the start did first, so a tape too short for it fails
+   // before anything is written. the output is written from there, then
+   // every cell that doesn't hold its value yet is set
+   let mut folded = Vec::new();
+   if reach > 0 {
+       folded.push(AstNode::Move(reach as isize));
+   }
+   let mut written = 0;
+   let mut wrote = false;
+   for byte in output {
+       if !wrote || byte != written {
+           folded.push(AstNode::Set(byte));
+           written = byte;
+           wrote = true;
+       }
+       folded.push(AstNode::Output);
+   }
+   let mut at = reach;
+   for (index, &value) in memory.iter().enumerate() {
+       let holds = if index == reach { written } else { 0 };
+       if value == holds as u32 {
+           continue;
+       }
+       if index != at {
+           folded.push(AstNode::Move(index as isize - at as isize));
+           at = index;
+       }
+       folded.push(AstNode::Set(u8::try_from(value).ok()?));
+   }
+   if pointer != at {
+       folded.push(AstNode::Move(pointer as isize - at as isize));
+   }
+
+   // everything folded points at the first command of the start
+   let width: usize = prefix.iter().map(flat_len).sum();
+   let mut folded_positions = vec![positions.first().copied().unwrap_or(0); folded.len()];
+   folded_positions.extend_from_slice(&positions[width..]);
+   folded.extend_from_slice(&instructions[end..]);
+   Some((folded, folded_positions))
+}
+
+// whether the cell is zero after `node`: a loop only ends on a zero cell,
+// and copies, scans and Set(0) leave one too
+fn leaves_zero(node: &AstNode) -> bool {
+   matches!(node, AstNode::Loop(_) | AstNode::MulCopy { .. } | AstNode::ScanRight(_) | AstNode::ScanLeft(_) | AstNode::Set(0))
+}
+
+// a loop body that only steps the current cell by one, like [-] or [+]
+fn is_clear_loop(body: &[AstNode]) -> bool {
+   matches!(body, [AstNode::Decrement] | [AstNode::Increment])
+}
+
+// a loop body that only moves the pointer, like [>] or [<<], searches for a zero cell
+fn scan_loop(body: &[AstNode]) -> Option<AstNode> {
+   match body {
+       [AstNode::MoveRight] => Some(AstNode::ScanRight(1)),
+       [AstNode::MoveLeft] => Some(AstNode::ScanLeft(1)),
+       [AstNode::Move(n)] if *n > 0 => Some(AstNode::ScanRight(n.unsigned_abs())),
+       [AstNode::Move(n)] if *n < 0 => Some(AstNode::ScanLeft(n.unsigned_abs())),
+       _ => None,
+   }
+}
+
+// for a loop like [->++>+++<<] that only adds to cells around the counter,
+// returns (offset, amount added per iteration) for each cell it changes.
+// the loop must return to the counter and subtract exactly 1 from it, so it
+// runs counter times
+fn mul_copy_targets(body: &[AstNode]) -> Option<Vec<(isize, i32)>> {
+   let class = LoopClass::of(body);
+   if !class.simple || !class.is_counted() {
+       return None;
+   }
+   let mut offset = 0isize;
+   let mut deltas: Vec<(isize, i32)> = Vec::new();
+   for instruction in body {
+       let delta = match instruction {
+           AstNode::Increment => 1,
+           AstNode::Decrement => -1,
+           AstNode::Add(n) => i32::try_from(*n).ok()?,
+           AstNode::Sub(n) => -i32::try_from(*n).ok()?,
+           AstNode::MoveRight => { offset += 1; continue; }
+           AstNode::MoveLeft => { offset -= 1; continue; }
+           AstNode::Move(n) => { offset += n; continue; }
+           _ => return None,
+       };
+       match deltas.iter_mut().find(|(target, _)| *target == offset) {
+           Some((_, total)) => *total = total.wrapping_add(delta),
+           None => deltas.push((offset, delta)),
+       }
    }
+
+   // LoopClass made sure the counter is there and only goes down by 1
+   let counter = deltas.iter().position(|(target, _)| *target == 0)?;
+   deltas.remove(counter);
+   deltas.retain(|(_, factor)| *factor != 0);
+   Some(deltas)
 }
 
 #[cfg(test)]
@@ -128,4 +494,256 @@ mod tests {
            panic!("Expected Program node");
        }
    }
-}
\ No newline at end of file
+
+   #[test]
+   fn test_net_change() {
+       let parse = |source: &str| crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+       let optimizer = Optimizer::new();
+       assert_eq!(optimizer.optimize(&parse("++-+")), AstNode::Program(vec![AstNode::Add(2)]));
+       assert_eq!(optimizer.optimize(&parse("+++----.")), AstNode::Program(vec![AstNode::Decrement, AstNode::Output]));
+       // +- cancels out, and so does a loop's whole body
+       assert_eq!(optimizer.optimize(&parse(">+-<.[-+]")), AstNode::Program(vec![
+           AstNode::Move(1),
+           AstNode::Move(-1),
+           AstNode::Output,
+           AstNode::Loop(vec![]),
+       ]));
+       let (optimized, positions) = optimizer.optimize_with_positions(&parse("--+.-+++"));
+       assert_eq!(optimized, AstNode::Program(vec![AstNode::Decrement, AstNode::Output, AstNode::Add(2)]));
+       assert_eq!(positions, [0, 3, 6]);
+   }
+
+   #[test]
+   fn test_clear_loops() {
+       // [-] and [+] become Set(0), also inside other loops
+       let program = AstNode::Program(vec![
+           AstNode::Loop(vec![AstNode::Decrement]),
+           AstNode::Input,
+           AstNode::Loop(vec![
+               AstNode::MoveRight,
+               AstNode::Loop(vec![AstNode::Increment]),
+           ]),
+       ]);
+
+       let optimized = Optimizer::new().optimize(&program);
+       assert_eq!(optimized, AstNode::Program(vec![
+           AstNode::Set(0),
+           AstNode::Input,
+           AstNode::Loop(vec![AstNode::MoveRight, AstNode::Set(0)]),
+       ]));
+   }
+
+   #[test]
+   fn test_partial_evaluation() {
+       let parse = |source: &str| crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+       let mut optimizer = Optimizer::new();
+       optimizer.set_partial_evaluation(true);
+       // prints A and a zero byte, leaves 0 65 0 with the pointer on cell 1
+       // after visiting cell 3, which it goes to before writing anything
+       let (optimized, positions) = optimizer.optimize_with_positions(&parse("+++++[->+++++++++++++<]>.>><.<,."));
+       assert_eq!(optimized, AstNode::Program(vec![
+           AstNode::Move(3),
+           AstNode::Set(65),
+           AstNode::Output,
+           AstNode::Set(0),
+           AstNode::Output,
+           AstNode::Move(-2),
+           AstNode::Set(65),
+           AstNode::Input,
+           AstNode::Output,
+       ]));
+       assert_eq!(positions, [0, 0, 0, 0, 0, 0, 0, 30, 31]);
+
+       // runs that depend on the cell width are left alone, and so are
+       // programs that start with input
+       assert_eq!(optimizer.optimize(&parse("-[>+<-]>.")), Optimizer::new().optimize(&parse("-[>+<-]>.")));
+       assert_eq!(optimizer.optimize(&parse(",[>+<-]")), Optimizer::new().optimize(&parse(",[>+<-]")));
+
+       // on a tape of 2 cells, a start that goes off the end is left alone,
+       // and one that wraps around is run wrapping: >> is back on cell 0,
+       // which the copy clears
+       let source = parse("+++[>>+<<-]>>.");
+       optimizer.set_tape(2, TapePolicy::Abort);
+       assert_eq!(optimizer.optimize(&source), Optimizer::new().optimize(&source));
+       optimizer.set_tape(2, TapePolicy::Wrap);
+       assert_eq!(optimizer.optimize(&source), AstNode::Program(vec![AstNode::Set(0), AstNode::Output]));
+   }
+
+   #[test]
+   fn test_fold_moves() {
+       // the last >< only goes back to a cell the program has been to, so it
+       // goes
+       let program = AstNode::Program(vec![
+           AstNode::MoveRight,
+           AstNode::MoveRight,
+           AstNode::MoveLeft,
+           AstNode::MoveRight,
+           AstNode::Increment,
+           AstNode::MoveLeft,
+           AstNode::MoveLeft,
+           AstNode::Decrement,
+           AstNode::MoveRight,
+           AstNode::MoveLeft,
+       ]);
+
+       let optimized = Optimizer::new().optimize(&program);
+       assert_eq!(optimized, AstNode::Program(vec![
+           AstNode::Move(2),
+           AstNode::Increment,
+           AstNode::Move(-2),
+           AstNode::Decrement,
+       ]));
+       // while one to a cell it hasn't been to could step off the end of
+       // the tape, so it is kept
+       let program = AstNode::Program(vec![AstNode::Increment, AstNode::MoveRight, AstNode::MoveLeft, AstNode::Output]);
+       assert_eq!(Optimizer::new().optimize(&program), AstNode::Program(vec![
+           AstNode::Increment,
+           AstNode::Move(1),
+           AstNode::Move(-1),
+           AstNode::Output,
+       ]));
+
+       // runs that turn around keep the leftmost and rightmost cells they visit
+       assert_eq!(fold_moves(&[1, 2, 1, 0, -1, 0], (0, 0)), vec![(0, AstNode::Move(2)), (2, AstNode::Move(-3)), (5, AstNode::Move(1))]);
+       assert_eq!(fold_moves(&[-1, 0, 1, 2], (0, 0)), vec![(0, AstNode::Move(-1)), (1, AstNode::Move(3))]);
+       assert_eq!(fold_moves(&[1, 0, 1], (0, 0)), vec![(0, AstNode::Move(1))]);
+       assert_eq!(fold_moves(&[1, 2, 1, 0, -1, 0], (-1, 1)), vec![(0, AstNode::Move(2)), (2, AstNode::Move(-2))]);
+       assert_eq!(fold_moves(&[-1, 0], (-1, 0)), vec![]);
+   }
+
+   #[test]
+   fn test_dead_loops() {
+       let parse = |source: &str| crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+       // a loop only ends on a zero cell, so one right after it never runs
+       let (optimized, positions) = Optimizer::new().optimize_with_positions(&parse("+[>+<-][-],[.,][.]>[-]"));
+       assert_eq!(optimized, AstNode::Program(vec![
+           AstNode::Increment,
+           AstNode::MulCopy { targets: vec![(1, 1)] },
+           AstNode::Input,
+           AstNode::Loop(vec![AstNode::Output, AstNode::Input]),
+           AstNode::MoveRight,
+           AstNode::Set(0),
+       ]));
+       assert_eq!(positions, [0, 1, 10, 11, 12, 13, 14, 18, 19]);
+       // but an empty one that does run never ends, so it stays
+       assert_eq!(Optimizer::new().optimize(&parse("+[+-]")), AstNode::Program(vec![AstNode::Increment, AstNode::Loop(vec![])]));
+   }
+
+   #[test]
+   fn test_swap() {
+       let parse = |source: &str| crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+       // swaps 3 and 5 through the cell after them
+       let source = "+++>+++++>[-]<<[->>+<<]>[-<+>]>[-<+>]<<.";
+       let optimized = Optimizer::new().optimize(&parse(source));
+       assert_eq!(optimized, AstNode::Program(vec![
+           AstNode::Add(3),
+           AstNode::MoveRight,
+           AstNode::Add(5),
+           AstNode::MoveRight,
+           AstNode::Set(0),
+           AstNode::Move(-2),
+           AstNode::Swap { offset: 1, temp: 2 },
+           AstNode::Output,
+       ]));
+       let run = |ast: &AstNode| crate::interpreter::interpret_with_state(ast, 10).unwrap();
+       assert_eq!(run(&optimized), run(&parse(source)));
+       assert_eq!(run(&optimized).1[..3], [5, 3, 0]);
+
+       // with the copies going elsewhere it's no swap
+       let optimized = Optimizer::new().optimize(&parse("[->>+<<]>[-<+>]>[-<<+>>]"));
+       assert!(!optimized.contains(&|node| matches!(node, AstNode::Swap { .. })));
+   }
+
+   #[test]
+   fn test_mul_copy_loops() {
+       // [->++>+++<<] adds 2x and 3x the counter to the next two cells
+       let program = AstNode::Program(vec![AstNode::Loop(vec![
+           AstNode::Decrement,
+           AstNode::MoveRight,
+           AstNode::Increment,
+           AstNode::Increment,
+           AstNode::MoveRight,
+           AstNode::Increment,
+           AstNode::Increment,
+           AstNode::Increment,
+           AstNode::MoveLeft,
+           AstNode::MoveLeft,
+       ])]);
+       let optimized = Optimizer::new().optimize(&program);
+       assert_eq!(optimized, AstNode::Program(vec![AstNode::MulCopy { targets: vec![(1, 2), (2, 3)] }]));
+
+       // unbalanced loops and counters changed by more than 1 are left alone
+       assert_eq!(mul_copy_targets(&[AstNode::Decrement, AstNode::MoveRight]), None);
+       assert_eq!(mul_copy_targets(&[AstNode::Sub(2), AstNode::MoveRight, AstNode::Increment, AstNode::MoveLeft]), None);
+       assert_eq!(mul_copy_targets(&[AstNode::MoveRight, AstNode::Decrement, AstNode::MoveLeft, AstNode::Decrement]), Some(vec![(1, -1)]));
+   }
+
+   #[test]
+   fn test_scan_loops() {
+       let program = AstNode::Program(vec![
+           AstNode::Loop(vec![AstNode::MoveRight]),
+           AstNode::Input,
+           AstNode::Loop(vec![AstNode::MoveLeft, AstNode::MoveLeft]),
+           AstNode::Input,
+           AstNode::Loop(vec![AstNode::MoveLeft, AstNode::Increment]),
+       ]);
+       let optimized = Optimizer::new().optimize(&program);
+       assert_eq!(optimized, AstNode::Program(vec![
+           AstNode::ScanRight(1),
+           AstNode::Input,
+           AstNode::ScanLeft(2),
+           AstNode::Input,
+           AstNode::Loop(vec![AstNode::MoveLeft, AstNode::Increment]),
+       ]));
+   }
+
+   #[test]
+   fn test_fixpoint() {
+       // >< is not dropped, so the two + around it stay apart
+       let program = AstNode::Program(vec![
+           AstNode::Increment,
+           AstNode::MoveRight,
+           AstNode::MoveLeft,
+           AstNode::Increment,
+       ]);
+       let optimizer = Optimizer::new();
+       let optimized = optimizer.optimize(&program);
+       assert_eq!(optimized, AstNode::Program(vec![
+           AstNode::Increment,
+           AstNode::Move(1),
+           AstNode::Move(-1),
+           AstNode::Increment,
+       ]));
+
+       // already optimized code is left as it is
+       assert_eq!(optimizer.optimize(&optimized), optimized);
+   }
+
+   #[test]
+   fn test_positions() {
+       let program = crate::parser::parse(crate::lexer::tokenize("++[-]>[>]<<<-[.-]").unwrap()).unwrap();
+       let (optimized, positions) = Optimizer::new().optimize_with_positions(&program);
+       assert_eq!(optimized, AstNode::Program(vec![
+           AstNode::Add(2),
+           AstNode::Set(0),
+           AstNode::MoveRight,
+           AstNode::ScanRight(1),
+           AstNode::Move(-3),
+           AstNode::Decrement,
+           AstNode::Loop(vec![AstNode::Output, AstNode::Decrement]),
+       ]));
+       // each instruction points at the first command it was made from
+       assert_eq!(positions, vec![0, 2, 5, 6, 9, 12, 13, 14, 15, 16]);
+   }
+
+   #[test]
+   fn test_comments_dropped() {
+       let mut lexer = crate::lexer::Lexer::new("+ one\n+ two [ clear - ] > three");
+       lexer.set_keep_comments(true);
+       let program = crate::parser::Parser::new(lexer.tokenize()).parse().unwrap();
+       let (optimized, positions) = Optimizer::new().optimize_with_positions(&program);
+       // the increments fold across the comment between them
+       assert_eq!(optimized, AstNode::Program(vec![AstNode::Add(2), AstNode::Set(0), AstNode::MoveRight]));
+       assert_eq!(positions, vec![0, 2, 5]);
+   }
+}
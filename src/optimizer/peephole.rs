@@ -0,0 +1,112 @@
+//! rewrites of short runs of instructions, written as patterns. a rule is a
+//! slice pattern over a window of instructions, which can bind what's inside
+//! them and have a guard, and the instructions the window becomes, e.g.
+//! `peephole!("add add": [AstNode::Add(a), AstNode::Add(b)] => [AstNode::Add(a + b)])`.
+//! a replacement that isn't a list of instructions is an expression for the
+//! Vec of them
+
+use crate::linear::flat_len;
+use crate::parser::AstNode;
+
+pub struct Peephole {
+   pub name: &'static str, // shown by a verbose optimizer
+   pub width: usize,       // instructions in the window
+   pub rewrite: fn(&[AstNode]) -> Option<Vec<AstNode>>,
+}
+
+macro_rules! peephole {
+   ($name:literal: [$($pattern:pat),+ $(,)?] $(if $guard:expr)? => [$($replacement:expr),*]) => {
+      $crate::optimizer::peephole::Peephole {
+         name: $name,
+         width: [$(stringify!($pattern)),+].len(),
+         rewrite: |window| match window {
+            [$($pattern),+] $(if $guard)? => Some(vec![$($replacement),*]),
+            _ => None,
+         },
+      }
+   };
+   ($name:literal: [$($pattern:pat),+ $(,)?] $(if $guard:expr)? => $replacement:expr) => {
+      $crate::optimizer::peephole::Peephole {
+         name: $name,
+         width: [$(stringify!($pattern)),+].len(),
+         rewrite: |window| match window {
+            [$($pattern),+] $(if $guard)? => Some($replacement),
+            _ => None,
+         },
+      }
+   };
+}
+pub(crate) use peephole;
+
+// rewrites the block with the first rule that matches at each instruction,
+// then tries again at the same place, so +++ becomes Add(2) and then Add(3).
+// a replacement has to be shorter than its window, or something no rule
+// matches, or this never ends. `positions` are those of the flattened
+// instructions, as in Optimizer::optimize_with_positions, and every
+// instruction of a replacement gets the first one of its window, so
+// replacements can't contain loops. `on_rewrite` sees each rule that fires
+pub fn apply(
+   rules: &[Peephole],
+   mut instructions: Vec<AstNode>,
+   mut positions: Vec<usize>,
+   on_rewrite: &dyn Fn(&Peephole),
+) -> (Vec<AstNode>, Vec<usize>) {
+   // index into `positions` of instructions[i]
+   let mut first = 0;
+   let mut i = 0;
+   while i < instructions.len() {
+      let rewrite = rules.iter().find_map(|rule| {
+         let window = instructions.get(i..i + rule.width)?;
+         Some((rule, (rule.rewrite)(window)?))
+      });
+      let Some((rule, replacement)) = rewrite else {
+         first += flat_len(&instructions[i]);
+         i += 1;
+         continue;
+      };
+      debug_assert!(replacement.iter().all(|node| flat_len(node) == 1), "{} replaces with a loop", rule.name);
+      on_rewrite(rule);
+      let width: usize = instructions[i..i + rule.width].iter().map(flat_len).sum();
+      let position = positions[first];
+      positions.splice(first..first + width, std::iter::repeat_n(position, replacement.len()));
+      instructions.splice(i..i + rule.width, replacement);
+   }
+   (instructions, positions)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_apply() {
+      let rules = [
+         peephole!("add add": [AstNode::Add(a), AstNode::Add(b)] => [AstNode::Add(a + b)]),
+         peephole!("small set": [AstNode::Set(0), AstNode::Add(n)] if *n < 256 => [AstNode::Set(*n as u8)]),
+      ];
+      assert_eq!(rules[1].width, 2);
+      // the loop keeps its positions, and the rewrites around it take their
+      // window's first
+      let instructions = vec![
+         AstNode::Add(1),
+         AstNode::Add(2),
+         AstNode::Add(3),
+         AstNode::Loop(vec![AstNode::Output]),
+         AstNode::Set(0),
+         AstNode::Add(300),
+         AstNode::Set(0),
+         AstNode::Add(4),
+         AstNode::Add(5),
+      ];
+      let (rewritten, positions) = apply(&rules, instructions, (0..11).collect(), &|_| {});
+      assert_eq!(rewritten, [
+         AstNode::Add(6),
+         AstNode::Loop(vec![AstNode::Output]),
+         AstNode::Set(0),
+         AstNode::Add(300),
+         AstNode::Set(4),
+         AstNode::Add(5),
+      ]);
+      assert_eq!(positions, [0, 3, 4, 5, 6, 7, 8, 10]);
+   }
+}
@@ -1,15 +1,32 @@
-use crate::lexer::Token;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::{Span, Spanned, Token};
+use serde::{Serialize, Deserialize};
+use std::fmt;
 
 pub fn parse(tokens: Vec<Token>) -> Result<AstNode, String> {
     let mut parser = Parser::new(tokens);
+    parser.parse().map_err(String::from)
+}
+
+// like parse, errors point at where in the source they are
+pub fn parse_spanned(tokens: Vec<Spanned<Token>>) -> Result<AstNode, Diagnostic> {
+    let mut parser = Parser::with_spans(tokens);
     parser.parse()
 }
 
-// Define AST node types 
-#[derive(Debug, Clone, PartialEq)]
+// like parse_spanned, taking each token as it comes instead of all of them
+// up front, e.g. straight from a Lexer reading a large file
+pub fn parse_streaming<I: Iterator<Item = Spanned<Token>>>(tokens: I) -> Result<AstNode, Diagnostic> {
+    let mut parser = Parser::from_spanned_iter(tokens);
+    parser.parse()
+}
 
+// Define AST node types 
 // defines what our abstract syntax tree looks like 
 // each node can be a basic instruciton or a container
+// serialized as {"type": "Loop", "value": [...]}, with no value for plain commands
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value")]
 pub enum AstNode {
    Program(Vec<AstNode>), // root node containing all instrutions
    Loop(Vec<AstNode>),    // loop with its body instructions
@@ -21,123 +38,251 @@ pub enum AstNode {
    Output,                // .
    Add(usize),    // optimized multiple increments
    Sub(usize),    // optimized multiple decrements
+   Set(u8),       // optimized clear loop, stores a constant in the cell
+   Move(isize),   // optimized pointer movement, positive is right
+   MulCopy { targets: Vec<(isize, i32)> }, // optimized copy/multiply loop, adds cell * factor at each offset then clears it
+   // optimized swap through a clear cell at temp, moving the cell there, the
+   // one at offset into the cell and temp on to offset. so offset ends up
+   // with the cell plus temp, and temp with 0
+   Swap { offset: isize, temp: isize },
+   ScanRight(usize), // optimized [>], moves right by the stride until a zero cell
+   ScanLeft(usize),  // optimized [<], moves left by the stride until a zero cell
+   ProcDef(Vec<AstNode>), // pbrain (...), defines the body as the procedure numbered by the cell
+   ProcCall,              // pbrain :, runs the procedure numbered by the cell
+   Debug,                 // #, dumps the start of the tape when the debug command is enabled
+   Comment(String),  // source text between commands, see Lexer::set_keep_comments. does nothing
 }
 
-pub struct Parser {
-   tokens: Vec<Token>, // input tokens from lexer 
-   position: usize,    // current position in token stream 
-}
-
-impl Parser {
-   pub fn new(tokens: Vec<Token>) -> Self {
-       Parser {
-           tokens,
-           position: 0,
+impl AstNode {
+   // whether the node or anything in its body matches `predicate`
+   pub fn contains(&self, predicate: &dyn Fn(&AstNode) -> bool) -> bool {
+       predicate(self) || match self {
+           AstNode::Program(instructions) | AstNode::Loop(instructions) | AstNode::ProcDef(instructions) => {
+               instructions.iter().any(|instruction| instruction.contains(predicate))
+           }
+           _ => false,
        }
    }
 
-   // entry point for parsing
-   pub fn parse(&mut self) -> Result<AstNode, String> {
-       self.parse_program()
+   // whether the node is or contains a pbrain procedure definition or call
+   pub fn has_procedures(&self) -> bool {
+       self.contains(&|node| matches!(node, AstNode::ProcDef(_) | AstNode::ProcCall))
    }
 
-   // parses entire program
-   fn parse_program(&mut self) -> Result<AstNode, String> {
-       let mut instructions = Vec::new();
-       
-       while !self.is_at_end() {
-           match self.peek() {
-               None => {
-                   if self.looking_for_loop_end() {
-                       return Err("Unexpected end of input - unclosed loop".to_string());
-                   }
-                   return Err("Unexpected end of input".to_string());
+   // one node per line, bodies indented under their Program, Loop or ProcDef
+   // and everything else in its Debug form, e.g.
+   //
+   // Program
+   //   Add(2)
+   //   Loop
+   //     Sub(1)
+   pub fn tree(&self) -> String {
+       self.tree_with(&|_| String::new())
+   }
+
+   // tree() with `label(body)` after each Loop, when it isn't empty
+   pub fn tree_with(&self, label: &dyn Fn(&[AstNode]) -> String) -> String {
+       let mut tree = String::new();
+       self.write_tree(&mut tree, 0, label);
+       tree
+   }
+
+   // the node as Brainfuck source, with optimized instructions written out
+   // as the commands they stand for: Add(3) as +++, Set(2) as [-]++ and
+   // MulCopy and Swap as their copy loops. runs the same as the node, and comments come
+   // out unchanged, so it re-parses to the unoptimized program
+   pub fn to_source(&self) -> String {
+       let mut source = String::new();
+       self.write_source(&mut source);
+       source
+   }
+
+   fn write_source(&self, source: &mut String) {
+       let moves = |offset: isize| if offset < 0 { "<".repeat(offset.unsigned_abs()) } else { ">".repeat(offset as usize) };
+       match self {
+           AstNode::Program(body) => body.iter().for_each(|node| node.write_source(source)),
+           AstNode::Loop(body) | AstNode::ProcDef(body) => {
+               let (open, close) = if matches!(self, AstNode::Loop(_)) { ('[', ']') } else { ('(', ')') };
+               source.push(open);
+               body.iter().for_each(|node| node.write_source(source));
+               source.push(close);
+           }
+           AstNode::Increment => source.push('+'),
+           AstNode::Decrement => source.push('-'),
+           AstNode::MoveRight => source.push('>'),
+           AstNode::MoveLeft => source.push('<'),
+           AstNode::Input => source.push(','),
+           AstNode::Output => source.push('.'),
+           AstNode::ProcCall => source.push(':'),
+           AstNode::Debug => source.push('#'),
+           AstNode::Add(n) => source.push_str(&"+".repeat(*n)),
+           AstNode::Sub(n) => source.push_str(&"-".repeat(*n)),
+           AstNode::Set(value) => {
+               source.push_str("[-]");
+               source.push_str(&"+".repeat(*value as usize));
+           }
+           AstNode::Move(offset) => source.push_str(&moves(*offset)),
+           AstNode::MulCopy { targets } => {
+               source.push_str("[-");
+               let mut at = 0;
+               for &(offset, factor) in targets {
+                   source.push_str(&moves(offset - at));
+                   let command = if factor < 0 { "-" } else { "+" };
+                   source.push_str(&command.repeat(factor.unsigned_abs() as usize));
+                   at = offset;
                }
-               Some(token) => {
-                   match *token {
-                       Token::Increment => {
-                           instructions.push(AstNode::Increment);
-                           self.advance();
-                       },
-                       Token::Decrement => {
-                           instructions.push(AstNode::Decrement);
-                           self.advance();
-                       },
-                       Token::IncrementPtr => {
-                           instructions.push(AstNode::MoveRight);
-                           self.advance();
-                       },
-                       Token::DecrementPtr => {
-                           instructions.push(AstNode::MoveLeft);
-                           self.advance();
-                       },
-                       Token::Input => {
-                           instructions.push(AstNode::Input);
-                           self.advance();
-                       },
-                       Token::Output => {
-                           instructions.push(AstNode::Output);
-                           self.advance();
-                       },
-                       Token::LoopStart => {
-                        self.advance(); // move past [ character
-                        let loop_body = self.parse_program()?;
-                        let body_instructions = match loop_body {
-                            AstNode::Program(nodes) => {
-                                if nodes.is_empty() {
-                                    Vec::new()
-                                } else {
-                                    nodes
-                                }
-                            },
-                            _ => return Err("Expected program node from loop body".to_string())
-                        };
-                        instructions.push(AstNode::Loop(body_instructions));
-                    },
-                       Token::LoopEnd => {
-                           self.advance(); // move past ] character
-                           return Ok(AstNode::Program(instructions));
-                       }
-                   }
+               source.push_str(&moves(-at));
+               source.push(']');
+           }
+           AstNode::Swap { offset, temp } => {
+               // cell to temp, offset to cell, temp to offset and back
+               for (from, to) in [(0, *temp), (*offset, 0), (*temp, *offset)] {
+                   source.push_str(&moves(from));
+                   source.push_str(&format!("[-{}+{}]", moves(to - from), moves(from - to)));
+                   source.push_str(&moves(-from));
                }
            }
+           AstNode::ScanRight(stride) => source.push_str(&format!("[{}]", ">".repeat(*stride))),
+           AstNode::ScanLeft(stride) => source.push_str(&format!("[{}]", "<".repeat(*stride))),
+           AstNode::Comment(text) => source.push_str(text),
        }
-       
-       if self.looking_for_loop_end() {
-           return Err("Unclosed loop - missing ]".to_string());
+   }
+
+   fn write_tree(&self, tree: &mut String, depth: usize, label: &dyn Fn(&[AstNode]) -> String) {
+       tree.push_str(&"  ".repeat(depth));
+       let body = match self {
+           AstNode::Program(body) => Some(("Program", body)),
+           AstNode::Loop(body) => Some(("Loop", body)),
+           AstNode::ProcDef(body) => Some(("ProcDef", body)),
+           _ => None,
+       };
+       match body {
+           Some((name, body)) => {
+               tree.push_str(name);
+               let annotation = if matches!(self, AstNode::Loop(_)) { label(body) } else { String::new() };
+               if !annotation.is_empty() {
+                   tree.push(' ');
+                   tree.push_str(&annotation);
+               }
+               tree.push('\n');
+               for node in body {
+                   node.write_tree(tree, depth + 1, label);
+               }
+           }
+           None => tree.push_str(&format!("{:?}\n", self)),
        }
-       Ok(AstNode::Program(instructions))
    }
+}
 
-   // helper to check if we are at the end
-   fn is_at_end(&self) -> bool {
-       self.position >= self.tokens.len()
+// a token with where it is in the source, when that is known
+type Item = (Token, Option<Span>);
+
+// formats as Brainfuck source, see to_source
+impl fmt::Display for AstNode {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+       f.write_str(&self.to_source())
    }
+}
 
-   // helper to peek at current token 
-   fn peek(&self) -> Option<&Token> {
-       self.tokens.get(self.position)
+pub struct Parser<'a> {
+   tokens: Box<dyn Iterator<Item = Item> + 'a>, // input tokens, read as the parser goes
+   max_nesting: usize, // deepest allowed loop or procedure nesting
+}
+
+// other passes over the AST still recurse once per level, so this is deep
+// enough for any real program while keeping them well within the stack
+pub const DEFAULT_MAX_NESTING: usize = 1000;
+
+impl Parser<'static> {
+   pub fn new(tokens: Vec<Token>) -> Self {
+       Parser::from_iter(tokens)
+   }
+
+   // a parser whose errors carry the span of the token they are about
+   pub fn with_spans(tokens: Vec<Spanned<Token>>) -> Self {
+       Parser::from_spanned_iter(tokens.into_iter())
    }
+}
 
-   // helper to advance to next token
-   fn advance(&mut self) -> Option<&Token> {
-       if !self.is_at_end() {
-           self.position += 1;
+impl<'a> Parser<'a> {
+   // reads the tokens one at a time as it parses, so they never all have
+   // to be in memory. not FromIterator, which would have to collect them
+   #[allow(clippy::should_implement_trait)]
+   pub fn from_iter<I: IntoIterator<Item = Token> + 'a>(tokens: I) -> Self {
+       Parser::from_items(tokens.into_iter().map(|token| (token, None)))
+   }
+
+   // like from_iter, with the spans errors point at, e.g. from a Lexer
+   pub fn from_spanned_iter<I: Iterator<Item = Spanned<Token>> + 'a>(tokens: I) -> Self {
+       Parser::from_items(tokens.map(|token| (token.node, Some(token.span))))
+   }
+
+   fn from_items<I: Iterator<Item = Item> + 'a>(tokens: I) -> Self {
+       Parser {
+           tokens: Box::new(tokens),
+           max_nesting: DEFAULT_MAX_NESTING,
        }
-       self.tokens.get(self.position - 1)
    }
 
-   // helper to check if we're in a loop
-   fn looking_for_loop_end(&self) -> bool {
-       let mut depth = 0;
-       for i in 0..self.position {
-           match self.tokens[i] {
-               Token::LoopStart => depth += 1,
-               Token::LoopEnd => depth -= 1,
-               _ => {}
-           }
+   // how deeply loops and procedures may nest, see DEFAULT_MAX_NESTING
+   pub fn set_max_nesting(&mut self, depth: usize) {
+       self.max_nesting = depth;
+   }
+
+   // entry point for parsing, which uses up the tokens. works through them
+   // with an explicit stack of unfinished bodies instead of recursing, so
+   // deep nesting can't overflow the Rust stack
+   pub fn parse(&mut self) -> Result<AstNode, Diagnostic> {
+       // each open loop or procedure with the token that opened it and its
+       // span, innermost last. the program itself is at the bottom
+       let mut open: Vec<(Option<Item>, Vec<AstNode>)> = vec![(None, Vec::new())];
+
+       for (token, span) in self.tokens.by_ref() {
+           let node = match token {
+               Token::Increment => AstNode::Increment,
+               Token::Decrement => AstNode::Decrement,
+               Token::IncrementPtr => AstNode::MoveRight,
+               Token::DecrementPtr => AstNode::MoveLeft,
+               Token::Input => AstNode::Input,
+               Token::Output => AstNode::Output,
+               Token::ProcCall => AstNode::ProcCall,
+               Token::Debug => AstNode::Debug,
+               Token::Comment { text } => AstNode::Comment(text),
+               Token::LoopStart | Token::ProcStart => {
+                   if open.len() > self.max_nesting {
+                       let message = format!("Nested too deeply - more than {} levels", self.max_nesting);
+                       return Err(Diagnostic::new(&message, span));
+                   }
+                   open.push((Some((token, span)), Vec::new()));
+                   continue;
+               }
+               Token::LoopEnd | Token::ProcEnd => {
+                   let procedure = token == Token::ProcEnd;
+                   let opener = open.last().and_then(|(start, _)| start.as_ref()).map(|(opener, _)| opener);
+                   match opener {
+                       Some(Token::LoopStart) if !procedure => {}
+                       Some(Token::ProcStart) if procedure => {}
+                       _ if procedure => return Err(Diagnostic::new("Unexpected ) - no procedure to close", span)),
+                       _ => return Err(Diagnostic::new("Unexpected ] - no loop to close", span)),
+                   }
+                   let (_, body) = open.pop().unwrap();
+                   if procedure {
+                       AstNode::ProcDef(body)
+                   } else {
+                       AstNode::Loop(body)
+                   }
+               }
+           };
+           open.last_mut().unwrap().1.push(node);
+       }
+
+       match open.pop() {
+           Some((None, instructions)) => Ok(AstNode::Program(instructions)),
+           // the innermost body that never got closed
+           Some((Some((Token::ProcStart, span)), _)) => Err(Diagnostic::new("Unclosed procedure - missing )", span)),
+           Some((Some((_, span)), _)) => Err(Diagnostic::new("Unclosed loop - missing ]", span)),
+           None => unreachable!("the program is never popped"),
        }
-       depth > 0
    }
 }
 
@@ -199,6 +344,16 @@ fn test_nested_loops() {
        
        let result = parser.parse();
        assert!(result.is_err());
+
+       // with spans, the error points at the [ that is never closed
+       let tokens = crate::lexer::tokenize_spanned("+\n [[-]").unwrap();
+       let error = parse_spanned(tokens).unwrap_err();
+       assert_eq!(error.to_string(), "Unclosed loop - missing ] at line 2, column 2");
+
+       // a stray ] used to end the program early, dropping the rest
+       let tokens = crate::lexer::tokenize_spanned("+.]+.").unwrap();
+       let error = parse_spanned(tokens).unwrap_err();
+       assert_eq!(error.to_string(), "Unexpected ] - no loop to close at line 1, column 3");
    }
 
    #[test]
@@ -232,4 +387,104 @@ fn test_nested_loops() {
            assert_eq!(instructions[5], AstNode::Input);
        }
    }
-}
\ No newline at end of file
+
+   #[test]
+   fn test_comment_nodes() {
+       let mut lexer = Lexer::new("copy: [->+<] done");
+       lexer.set_keep_comments(true);
+       let ast = Parser::new(lexer.tokenize()).parse().unwrap();
+       let comment = |text: &str| AstNode::Comment(text.to_string());
+       assert_eq!(ast, AstNode::Program(vec![
+           comment("copy: "),
+           AstNode::Loop(vec![
+               AstNode::Decrement,
+               AstNode::MoveRight,
+               AstNode::Increment,
+               AstNode::MoveLeft,
+           ]),
+           comment(" done"),
+       ]));
+   }
+
+   #[test]
+   fn test_to_source() {
+       let ast = AstNode::Program(vec![
+           AstNode::Add(3),
+           AstNode::Move(-2),
+           AstNode::Set(0),
+           AstNode::Set(2),
+           AstNode::MulCopy { targets: vec![(1, 2), (-1, -1)] },
+           AstNode::ScanRight(2),
+           AstNode::Loop(vec![AstNode::Sub(2), AstNode::Output]),
+       ]);
+       assert_eq!(ast.to_source(), "+++<<[-][-]++[->++<<->][>>][--.]");
+       assert_eq!(ast.to_string(), ast.to_source());
+       assert_eq!(AstNode::Swap { offset: 1, temp: 2 }.to_source(), "[->>+<<]>[-<+>]<>>[-<+>]<<");
+
+       // optimizing and printing keeps what the program does
+       let source = "++++[->+++<]>.[-]+[>>]";
+       let optimized = crate::optimizer::Optimizer::new().optimize(&parse(crate::lexer::tokenize(source).unwrap()).unwrap());
+       let printed = optimized.to_source();
+       let run = |source: &str| crate::interpreter::interpret_with_state(&parse(crate::lexer::tokenize(source).unwrap()).unwrap(), 100).unwrap();
+       assert_eq!(run(&printed), run(source));
+   }
+
+   #[test]
+   fn test_streaming() {
+       // the lexer feeds the parser directly, with no Vec of tokens between
+       let source = "+[->+<]\n>.";
+       let ast = parse_streaming(Lexer::from_chars(source.chars())).unwrap();
+       assert_eq!(ast, parse(crate::lexer::tokenize(source).unwrap()).unwrap());
+       let error = parse_streaming(Lexer::new("+\n[[-]")).unwrap_err();
+       assert_eq!(error.to_string(), "Unclosed loop - missing ] at line 2, column 1");
+
+       // tokens made on the fly, which are never collected
+       let tokens = (0..1_000_000).map(|i| if i % 2 == 0 { Token::Increment } else { Token::Output });
+       let AstNode::Program(instructions) = Parser::from_iter(tokens).parse().unwrap() else { unreachable!() };
+       assert_eq!((instructions.len(), &instructions[..2]), (1_000_000, &[AstNode::Increment, AstNode::Output][..]));
+   }
+
+   #[test]
+   fn test_procedures() {
+       let parse_pbrain = |source: &str| {
+           let mut lexer = Lexer::new(source);
+           lexer.set_dialect(crate::lexer::Dialect::Pbrain);
+           parse_spanned(lexer.tokenize_spanned())
+       };
+       assert_eq!(parse_pbrain("([-]):").unwrap(), AstNode::Program(vec![
+           AstNode::ProcDef(vec![AstNode::Loop(vec![AstNode::Decrement])]),
+           AstNode::ProcCall,
+       ]));
+
+       let error = parse_pbrain("+(\n[)]").unwrap_err();
+       assert_eq!(error.to_string(), "Unexpected ) - no procedure to close at line 2, column 2");
+       let error = parse_pbrain("(]").unwrap_err();
+       assert_eq!(error.to_string(), "Unexpected ] - no loop to close at line 1, column 2");
+       let error = parse_pbrain("+ (:").unwrap_err();
+       assert_eq!(error.to_string(), "Unclosed procedure - missing ) at line 1, column 3");
+   }
+
+   #[test]
+   fn test_deep_nesting() {
+       // far deeper than recursion per level would survive
+       let source = format!("+{}-{}", "[".repeat(100_000), "]".repeat(100_000));
+       let mut parser = Parser::new(crate::lexer::tokenize(&source).unwrap());
+       parser.set_max_nesting(100_000);
+       let mut node = parser.parse().unwrap();
+       let mut depth = 0;
+       while let AstNode::Program(mut body) | AstNode::Loop(mut body) = node {
+           node = body.pop().unwrap();
+           depth += 1;
+       }
+       assert_eq!((depth, node), (100_001, AstNode::Decrement));
+
+       let error = parse_spanned(crate::lexer::tokenize_spanned(&source).unwrap()).unwrap_err();
+       assert_eq!(error.to_string(), "Nested too deeply - more than 1000 levels at line 1, column 1002");
+   }
+
+   #[test]
+   fn test_tree() {
+       let ast = parse(crate::lexer::tokenize("+[-[>]]").unwrap()).unwrap();
+       assert_eq!(ast.tree(), "Program\n  Increment\n  Loop\n    Decrement\n    Loop\n      MoveRight\n");
+   }
+}
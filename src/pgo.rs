@@ -0,0 +1,68 @@
+//! profile-guided optimization: a profiling run in the interpreter finds
+//! the loops a program spends its iterations in, and the jit then unrolls
+//! the hottest of them
+
+use crate::interpreter::{EofBehavior, Interpreter};
+use crate::parser::AstNode;
+use crate::stats::LoopStats;
+
+use std::collections::HashMap;
+
+// copies of the body an unrolled loop gets
+pub const UNROLL_FACTOR: usize = 4;
+// at most this many loops are unrolled, the ones with the most iterations
+pub const HOT_LOOPS: usize = 8;
+// loops that run fewer iterations per entry than this gain nothing from it
+const MIN_ITERATIONS: usize = UNROLL_FACTOR;
+// where the profiling run stops, keeping what it saw up to there
+pub const PROFILE_INSTRUCTIONS: usize = 100_000_000;
+
+// the loops that ran during a profiling run, the most iterations first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoopProfile {
+    pub loops: Vec<LoopStats>,
+}
+
+impl LoopProfile {
+    // runs `ast` optimized in the interpreter on `input`, with , reading 0
+    // once that runs out, for at most `max_instructions` instructions. a
+    // failed or cut off run still leaves a profile of what came before, so
+    // that isn't an error
+    pub fn collect(ast: &AstNode, input: &[u8], max_instructions: usize) -> Self {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_optimize(true);
+        interpreter.set_eof_behavior(EofBehavior::Zero);
+        interpreter.set_max_instructions(Some(max_instructions));
+        interpreter.provide_input(input);
+        let _ = interpreter.run_and_capture_output(ast);
+        LoopProfile { loops: interpreter.statistics().loops }
+    }
+
+    // how many copies of its body each loop worth unrolling should get, by
+    // the position of its [
+    pub fn unroll_factors(&self) -> HashMap<usize, usize> {
+        self.loops
+            .iter()
+            .filter(|stats| stats.iterations >= MIN_ITERATIONS * stats.entries.max(1))
+            .take(HOT_LOOPS)
+            .map(|stats| (stats.position, UNROLL_FACTOR))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unroll_factors() {
+        // the inner loop runs 10 times for each of the outer loop's 3
+        // iterations, and only the inner one is worth unrolling. the copy
+        // loop at the end is optimized into a MulCopy, so isn't a loop at all
+        let ast = crate::parser::parse(crate::lexer::tokenize("+++[>++++++++++[>+.<-]<-]>[->+<]").unwrap()).unwrap();
+        let profile = LoopProfile::collect(&ast, b"", PROFILE_INSTRUCTIONS);
+        let loops: Vec<_> = profile.loops.iter().map(|stats| (stats.position, stats.iterations)).collect();
+        assert_eq!(loops, [(15, 30), (3, 3)]);
+        assert_eq!(profile.unroll_factors(), HashMap::from([(15, UNROLL_FACTOR)]));
+    }
+}
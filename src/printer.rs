@@ -0,0 +1,147 @@
+//! prints lexed programs back out as source, for bfc fmt
+//!
+//! both layouts keep every command in order, so the printed program runs
+//! exactly like the original. they expect tokens from a lexer with
+//! set_keep_comments on, and brackets that lexer::validate accepted
+
+use crate::lexer::{Spanned, Token};
+
+// the character a command token was lexed from, None for comments
+pub fn command_char(token: &Token) -> Option<char> {
+    match token {
+        Token::IncrementPtr => Some('>'),
+        Token::DecrementPtr => Some('<'),
+        Token::Increment => Some('+'),
+        Token::Decrement => Some('-'),
+        Token::LoopStart => Some('['),
+        Token::LoopEnd => Some(']'),
+        Token::Input => Some(','),
+        Token::Output => Some('.'),
+        Token::ProcStart => Some('('),
+        Token::ProcEnd => Some(')'),
+        Token::ProcCall => Some(':'),
+        Token::Debug => Some('#'),
+        Token::Comment { .. } => None,
+    }
+}
+
+// the commands alone on one line, without comments or whitespace
+pub fn minify(tokens: &[Spanned<Token>]) -> String {
+    let mut source: String = tokens.iter().filter_map(|token| command_char(&token.node)).collect();
+    source.push('\n');
+    source
+}
+
+// one line per bracket with the body indented by two spaces a level, runs of
+// other commands wrapped once a line would pass `width` columns, and each
+// comment line on a line of its own. directive and #! lines are printed
+// unchanged at the start of their line so they keep working
+pub fn pretty(tokens: &[Spanned<Token>], width: usize) -> String {
+    let mut printer = PrettyPrinter { source: String::new(), line: String::new(), depth: 0, width };
+    for token in tokens {
+        match &token.node {
+            Token::LoopStart | Token::ProcStart => {
+                printer.bracket(&token.node);
+                printer.depth += 1;
+            }
+            Token::LoopEnd | Token::ProcEnd => {
+                printer.end_line();
+                printer.depth = printer.depth.saturating_sub(1);
+                printer.bracket(&token.node);
+            }
+            // whitespace between commands doesn't break up a line
+            Token::Comment { text } if text.trim().is_empty() => {}
+            Token::Comment { text } => {
+                printer.end_line();
+                for (i, line) in text.lines().enumerate() {
+                    // only the first line can start partway through a source line
+                    printer.comment(line, i > 0 || token.span.column == 1);
+                }
+            }
+            command => printer.command(command),
+        }
+    }
+    printer.end_line();
+    printer.source
+}
+
+struct PrettyPrinter {
+    source: String,
+    line: String, // commands waiting to be printed at the current depth
+    depth: usize,
+    width: usize,
+}
+
+impl PrettyPrinter {
+    fn indent(&self) -> String {
+        "  ".repeat(self.depth)
+    }
+
+    fn end_line(&mut self) {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.push_line(&line);
+        }
+    }
+
+    fn push_line(&mut self, line: &str) {
+        self.source.push_str(&self.indent());
+        self.source.push_str(line);
+        self.source.push('\n');
+    }
+
+    fn command(&mut self, token: &Token) {
+        if !self.line.is_empty() && self.indent().len() + self.line.len() >= self.width {
+            self.end_line();
+        }
+        self.line.extend(command_char(token));
+    }
+
+    fn bracket(&mut self, token: &Token) {
+        self.end_line();
+        self.push_line(&command_char(token).unwrap().to_string());
+    }
+
+    fn comment(&mut self, line: &str, at_line_start: bool) {
+        if at_line_start && line.starts_with('#') {
+            self.source.push_str(line);
+            self.source.push('\n');
+            return;
+        }
+        let text = line.trim();
+        if text.is_empty() {
+            return;
+        }
+        // a # moved to the start of a line could turn into a directive
+        if text.starts_with('#') && self.depth == 0 {
+            self.source.push(' ');
+        }
+        self.push_line(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn lex(source: &str) -> Vec<Spanned<Token>> {
+        let mut lexer = Lexer::new(source);
+        lexer.set_keep_comments(true);
+        lexer.tokenize_spanned()
+    }
+
+    #[test]
+    fn test_minify() {
+        assert_eq!(minify(&lex("#!/bin/bfc\n+ add\n#expect: a.\n[-]done")), "+[-]\n");
+    }
+
+    #[test]
+    fn test_pretty() {
+        let source = "#expect: [.]\nset up +++++ +++++[>++ ++ ++<-] one#two\n>.";
+        let printed = pretty(&lex(source), 6);
+        assert_eq!(printed, "#expect: [.]\nset up\n++++++\n++++\n[\n  >+++\n  +++<\n  -\n]\none#two\n>.\n");
+        // the same commands in the same order
+        assert_eq!(minify(&lex(&printed)), minify(&lex(source)));
+    }
+}
@@ -0,0 +1,197 @@
+//! what a run spent its time on: totals for every instruction of the
+//! program and, when asked for, a timeline of its loops. bfc --profile
+//! exports them as CSV for spreadsheets or as Chrome trace_event JSON for
+//! chrome://tracing and Perfetto
+
+use crate::lexer::Span;
+use crate::parser::AstNode;
+use crate::stats::csv_field;
+
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::mem::Discriminant;
+use std::time::Duration;
+
+// loop runs kept on the timeline, later ones are only counted
+pub const MAX_EVENTS: usize = 100_000;
+
+// how stats and traces name an instruction: its Debug form, without the
+// body that a loop or procedure's Debug output would include
+pub fn instruction_name(instruction: &AstNode) -> String {
+    match instruction {
+        AstNode::Loop(_) => "Loop".to_string(),
+        AstNode::ProcDef(_) => "ProcDef".to_string(),
+        _ => format!("{:?}", instruction),
+    }
+}
+
+// how often one instruction of the program ran and how long it took
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstructionProfile {
+    pub position: usize, // command index in the source
+    pub name: String,
+    pub count: usize,
+    pub time: Duration,
+}
+
+// one run of a loop, from reaching its [ to leaving it
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoopEvent {
+    pub position: usize,   // command index of the [
+    pub start: Duration,   // since the first run started
+    pub duration: Duration,
+    pub iterations: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    // keyed by position and kind of instruction, as a persistent interpreter
+    // runs different programs with the same positions. the name is only
+    // worked out the first time an instruction runs
+    instructions: HashMap<(usize, Discriminant<AstNode>), InstructionProfile>,
+    timeline: Option<Vec<LoopEvent>>, // in the order the loops ended
+    dropped_events: usize,
+}
+
+impl Profile {
+    pub fn record(&mut self, position: usize, instruction: &AstNode, time: Duration) {
+        let profile = self
+            .instructions
+            .entry((position, std::mem::discriminant(instruction)))
+            .or_insert_with(|| InstructionProfile { position, name: instruction_name(instruction), count: 0, time: Duration::ZERO });
+        profile.count += 1;
+        profile.time += time;
+    }
+
+    // loop runs are only kept once this is on, as there can be a lot of them
+    pub fn set_record_timeline(&mut self, enabled: bool) {
+        self.timeline = enabled.then(Vec::new);
+    }
+
+    pub fn record_loop(&mut self, event: LoopEvent) {
+        if let Some(timeline) = self.timeline.as_mut() {
+            if timeline.len() < MAX_EVENTS {
+                timeline.push(event);
+            } else {
+                self.dropped_events += 1;
+            }
+        }
+    }
+
+    // in program order
+    pub fn instructions(&self) -> Vec<&InstructionProfile> {
+        let mut instructions: Vec<_> = self.instructions.values().collect();
+        instructions.sort_by(|a, b| (a.position, &a.name).cmp(&(b.position, &b.name)));
+        instructions
+    }
+
+    pub fn timeline(&self) -> &[LoopEvent] {
+        self.timeline.as_deref().unwrap_or(&[])
+    }
+
+    // loop runs left off the timeline after MAX_EVENTS
+    pub fn dropped_events(&self) -> usize {
+        self.dropped_events
+    }
+
+    // count and time per instruction name, what the statistics report
+    pub fn totals(&self) -> BTreeMap<String, (usize, Duration)> {
+        let mut totals = BTreeMap::new();
+        for profile in self.instructions.values() {
+            let (count, time) = totals.entry(profile.name.clone()).or_insert((0, Duration::ZERO));
+            *count += profile.count;
+            *time += profile.time;
+        }
+        totals
+    }
+
+    // a row per instruction of the program
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("position,instruction,count,time_ms\n");
+        for profile in self.instructions() {
+            let time = profile.time.as_secs_f64() * 1000.0;
+            writeln!(csv, "{},{},{},{}", profile.position, csv_field(&profile.name), profile.count, time).unwrap();
+        }
+        csv
+    }
+
+    // the timeline as complete ("X") events, which nest by time so loops
+    // show up inside the loops around them. `spans` are those of the
+    // program's commands, indexed by position, to name each loop by where
+    // its [ is
+    pub fn to_chrome_trace(&self, spans: &[Span]) -> String {
+        let micros = |duration: Duration| duration.as_secs_f64() * 1_000_000.0;
+        let events: Vec<_> = self
+            .timeline()
+            .iter()
+            .map(|event| {
+                let name = match spans.get(event.position) {
+                    Some(span) => format!("[ at line {}, column {}", span.line, span.column),
+                    None => format!("[ at command {}", event.position),
+                };
+                json!({
+                    "name": name,
+                    "cat": "loop",
+                    "ph": "X",
+                    "ts": micros(event.start),
+                    "dur": micros(event.duration),
+                    "pid": 1,
+                    "tid": 1,
+                    "args": {"position": event.position, "iterations": event.iterations},
+                })
+            })
+            .collect();
+        let trace = json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ms",
+            "otherData": {"dropped_events": self.dropped_events},
+        });
+        // only plain values, which can't fail
+        serde_json::to_string(&trace).unwrap() + "\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv() {
+        let mut profile = Profile::default();
+        profile.record(4, &AstNode::MulCopy { targets: vec![(1, 1)] }, Duration::from_millis(2));
+        profile.record(0, &AstNode::Add(3), Duration::from_millis(1));
+        profile.record(4, &AstNode::MulCopy { targets: vec![(1, 1)] }, Duration::from_millis(2));
+        assert_eq!(
+            profile.to_csv(),
+            "position,instruction,count,time_ms\n0,Add(3),1,1\n4,\"MulCopy { targets: [(1, 1)] }\",2,4\n"
+        );
+        assert_eq!(profile.totals()["Add(3)"], (1, Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn test_chrome_trace() {
+        let mut profile = Profile::default();
+        let event = LoopEvent { position: 2, start: Duration::from_micros(5), duration: Duration::from_micros(20), iterations: 3 };
+        profile.record_loop(event.clone());
+        assert!(profile.timeline().is_empty());
+
+        profile.set_record_timeline(true);
+        profile.record_loop(event);
+        let spans = [Span { offset: 0, line: 1, column: 1 }, Span { offset: 1, line: 1, column: 2 }, Span { offset: 3, line: 2, column: 1 }];
+        let trace: serde_json::Value = serde_json::from_str(&profile.to_chrome_trace(&spans)).unwrap();
+        assert_eq!(
+            trace["traceEvents"][0],
+            json!({
+                "name": "[ at line 2, column 1",
+                "cat": "loop",
+                "ph": "X",
+                "ts": 5.0,
+                "dur": 20.0,
+                "pid": 1,
+                "tid": 1,
+                "args": {"position": 2, "iterations": 3},
+            })
+        );
+    }
+}
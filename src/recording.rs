@@ -0,0 +1,457 @@
+//! runs a program one step at a time while keeping enough history to go
+//! back, for reverse debugging
+//!
+//! every step gets a journal entry with the command that ran, the pointer
+//! before and after, and the cell at the pointer before and after. programs
+//! run unoptimized, where that cell is the only one an instruction can
+//! change. every CHECKPOINT_INTERVAL steps a copy of the tape is kept as well,
+//! so seeking to any step replays at most that many entries.
+//!
+//! the recording has a view of the tape at some step that moves back and
+//! forth over the journal. only stepping forward from the newest step runs
+//! the interpreter.
+//!
+//! a Session saves a finished recording with the program and its input, so
+//! the run can be played back later and checked against the journal
+
+use crate::interpreter::{Interpreter, RuntimeError};
+use crate::io::{BfIo, MemoryIo, TeeIo};
+use crate::parser::AstNode;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+pub const CHECKPOINT_INTERVAL: usize = 4096;
+
+// how many steps are kept by default, about 80 MB with the checkpoints of
+// a 30,000 cell tape
+pub const DEFAULT_MAX_STEPS: usize = 1_000_000;
+
+// one executed instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub position: usize, // command index of the instruction
+    pub pointer: usize,  // before it ran
+    pub old: u32,        // the cell at `pointer` before it ran
+    pub new: u32,        // and after
+    pub next_pointer: usize,
+    pub output_len: usize, // bytes of output once it ran
+}
+
+// the tape before step `first + i * CHECKPOINT_INTERVAL`
+struct Checkpoint {
+    memory: Vec<u32>,
+    pointer: usize,
+}
+
+pub struct Recording {
+    interpreter: Interpreter,
+    journal: VecDeque<JournalEntry>,
+    checkpoints: VecDeque<Checkpoint>,
+    first: usize,     // step number of journal[0], older ones have been dropped
+    first_output: usize, // bytes of output written before step `first`
+    max_steps: usize, // journal entries kept before the oldest are dropped
+    at: usize,        // the view shows the state before this step
+    memory: Vec<u32>, // the tape at `at`
+    pointer: usize,
+    error: Option<RuntimeError>,
+    finished: bool,
+}
+
+impl Recording {
+    // starts `ast` on `interpreter`, switching its optimizer off. output is
+    // kept so it can be shown as of any step, and , reads what was given
+    // with provide_input and then sees end of input
+    pub fn new(interpreter: Interpreter, ast: &AstNode) -> Result<Self, RuntimeError> {
+        Self::with_io(interpreter, ast, Box::new(MemoryIo::new(&[])))
+    }
+
+    // like new, with , and . going through `io` once the provided input is
+    // used up, e.g. to record a program talking to the terminal
+    pub fn with_io(mut interpreter: Interpreter, ast: &AstNode, io: Box<dyn BfIo>) -> Result<Self, RuntimeError> {
+        interpreter.set_optimize(false);
+        interpreter.set_io(Some(Box::new(TeeIo::new(io))));
+        interpreter.start(ast)?;
+        let memory = interpreter.memory().to_vec();
+        let pointer = interpreter.pointer();
+        Ok(Recording {
+            interpreter,
+            journal: VecDeque::new(),
+            checkpoints: VecDeque::from([Checkpoint { memory: memory.clone(), pointer }]),
+            first: 0,
+            first_output: 0,
+            max_steps: DEFAULT_MAX_STEPS,
+            at: 0,
+            memory,
+            pointer,
+            error: None,
+            finished: false,
+        })
+    }
+
+    // caps the history. once more steps have run, the oldest are forgotten
+    // a checkpoint interval at a time and can't be gone back to
+    pub fn set_max_steps(&mut self, max_steps: usize) {
+        self.max_steps = max_steps.max(CHECKPOINT_INTERVAL);
+    }
+
+    pub fn interpreter(&self) -> &Interpreter {
+        &self.interpreter
+    }
+
+    // the step the view is at, counting from the start of the program
+    pub fn at(&self) -> usize {
+        self.at
+    }
+
+    // the oldest step that can still be gone back to
+    pub fn first_step(&self) -> usize {
+        self.first
+    }
+
+    // the number of steps run so far
+    pub fn steps(&self) -> usize {
+        self.first + self.journal.len()
+    }
+
+    pub fn entry(&self, step: usize) -> Option<&JournalEntry> {
+        self.journal.get(step.checked_sub(self.first)?)
+    }
+
+    // true when the program has ended and the view is at its last step
+    pub fn finished(&self) -> bool {
+        self.finished && self.at == self.steps()
+    }
+
+    // the error the program stopped with, when the view is at the end
+    pub fn error(&self) -> Option<&RuntimeError> {
+        self.error.as_ref().filter(|_| self.at == self.steps())
+    }
+
+    pub fn memory(&self) -> &[u32] {
+        &self.memory
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    // the command the view is at: the one that runs next, or the one that failed
+    pub fn position(&self) -> Option<usize> {
+        match self.entry(self.at) {
+            Some(entry) => Some(entry.position),
+            None => match self.error() {
+                Some(error) => Some(error.position),
+                None => self.interpreter.next_position(),
+            },
+        }
+    }
+
+    // the bytes , got from the I/O so far, after the provided input
+    pub fn input(&self) -> &[u8] {
+        self.interpreter.io::<TeeIo>().map_or(&[][..], |io| &io.input)
+    }
+
+    // the output written before the view's step
+    pub fn output(&self) -> &[u8] {
+        let output = self.interpreter.io::<TeeIo>().map_or(&[][..], |io| &io.output);
+        let len = match self.at.checked_sub(1).and_then(|step| self.entry(step)) {
+            Some(entry) => entry.output_len,
+            None => self.first_output,
+        };
+        &output[..len.min(output.len())]
+    }
+
+    // moves the view one step forward, running the interpreter when the view
+    // is at the newest step. returns false at the end of the program
+    pub fn step(&mut self) -> bool {
+        if self.at == self.steps() && !self.record() {
+            return false;
+        }
+        let entry = self.journal[self.at - self.first];
+        self.apply(&entry);
+        self.at += 1;
+        true
+    }
+
+    // moves the view one step back, returns false at the first recorded step
+    pub fn step_back(&mut self) -> bool {
+        if self.at == self.first {
+            return false;
+        }
+        self.at -= 1;
+        let entry = self.journal[self.at - self.first];
+        self.set_cell(entry.pointer, entry.old);
+        self.pointer = entry.pointer;
+        true
+    }
+
+    // moves the view to `step`, as far as the recording reaches. stepping
+    // past the newest step runs the interpreter
+    pub fn seek(&mut self, step: usize) {
+        let step = step.max(self.first);
+        let index = ((step - self.first) / CHECKPOINT_INTERVAL).min(self.checkpoints.len() - 1);
+        let start = self.first + index * CHECKPOINT_INTERVAL;
+        // only when going back, or when the checkpoint is ahead of the view
+        if step < self.at || start > self.at {
+            let checkpoint = &self.checkpoints[index];
+            self.memory.clone_from(&checkpoint.memory);
+            self.pointer = checkpoint.pointer;
+            self.at = start;
+        }
+        while self.at < step && self.step() {}
+    }
+
+    // the newest step before the view's that changed `cell`, i.e. what set
+    // the cell to its current value. None if it still has the value it
+    // started with, or the step that set it has been dropped
+    pub fn last_write(&self, cell: usize) -> Option<usize> {
+        (self.first..self.at).rev().find(|&step| {
+            let entry = &self.journal[step - self.first];
+            entry.pointer == cell && entry.old != entry.new
+        })
+    }
+
+    // runs the next instruction and adds it to the journal. false when the
+    // program has finished or failed
+    fn record(&mut self) -> bool {
+        if self.finished {
+            return false;
+        }
+        let pointer = self.interpreter.pointer();
+        let old = self.interpreter.memory().get(pointer).copied().unwrap_or(0);
+        match self.interpreter.step() {
+            Ok(true) => {}
+            Ok(false) => {
+                self.finished = true;
+                return false;
+            }
+            Err(e) => {
+                self.error = Some(e);
+                self.finished = true;
+                return false;
+            }
+        }
+        let output_len = self.interpreter.io::<TeeIo>().map_or(0, |io| io.output.len());
+        self.journal.push_back(JournalEntry {
+            position: self.interpreter.position(),
+            pointer,
+            old,
+            new: self.interpreter.memory()[pointer],
+            next_pointer: self.interpreter.pointer(),
+            output_len,
+        });
+        // the view is at the newest step here, so its tape is the one to keep
+        if self.journal.len().is_multiple_of(CHECKPOINT_INTERVAL) {
+            let entry = *self.journal.back().unwrap();
+            let mut memory = self.memory.clone();
+            let (cell, pointer) = (entry.pointer, entry.next_pointer);
+            if cell >= memory.len() {
+                memory.resize(cell + 1, 0);
+            }
+            memory[cell] = entry.new;
+            self.checkpoints.push_back(Checkpoint { memory, pointer });
+        }
+        if self.journal.len() > self.max_steps {
+            self.first_output = self.journal[CHECKPOINT_INTERVAL - 1].output_len;
+            self.journal.drain(..CHECKPOINT_INTERVAL);
+            self.checkpoints.pop_front();
+            self.first += CHECKPOINT_INTERVAL;
+        }
+        true
+    }
+
+    fn apply(&mut self, entry: &JournalEntry) {
+        self.set_cell(entry.pointer, entry.new);
+        self.pointer = entry.next_pointer;
+    }
+
+    // a growing tape can be longer than the view's copy
+    fn set_cell(&mut self, cell: usize, value: u32) {
+        if cell >= self.memory.len() {
+            self.memory.resize(cell + 1, 0);
+        }
+        self.memory[cell] = value;
+    }
+}
+
+// bumped when Session changes in a way older files can't be read as
+pub const SESSION_VERSION: u32 = 1;
+
+// a recorded run as saved by bfc --record: what it takes to run the program
+// again exactly, and the journal of its last steps to check that it did
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub version: u32,
+    pub source: String,
+    pub options: Vec<String>, // the bfc options that change how the program runs
+    pub input: Vec<u8>,       // everything , was given, in order
+    pub steps: usize,
+    pub first_step: usize, // the step of journal[0], older ones were dropped
+    pub journal: Vec<JournalEntry>,
+    pub output: Vec<u8>,
+    pub error: Option<String>,
+}
+
+impl Session {
+    // the run of `recording` up to its newest step. `provided` is the input
+    // given with provide_input, which , read before anything from the I/O
+    pub fn new(source: &str, options: Vec<String>, provided: &[u8], recording: &Recording) -> Self {
+        let steps = recording.steps();
+        Session {
+            version: SESSION_VERSION,
+            source: source.to_string(),
+            options,
+            input: [provided, recording.input()].concat(),
+            steps,
+            first_step: recording.first_step(),
+            journal: (recording.first_step()..steps).filter_map(|step| recording.entry(step)).copied().collect(),
+            output: recording.interpreter.io::<TeeIo>().map_or(Vec::new(), |io| io.output.clone()),
+            error: recording.error.as_ref().map(|error| error.to_string()),
+        }
+    }
+
+    // gzipped JSON, the journal repeats itself a lot
+    pub fn write_to(&self, writer: impl Write) -> io::Result<()> {
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        serde_json::to_writer(&mut encoder, self)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    pub fn read_from(reader: impl Read) -> io::Result<Session> {
+        let session: Session = serde_json::from_reader(GzDecoder::new(reader))?;
+        if session.version != SESSION_VERSION {
+            let message = format!("unsupported session version {} (expected {})", session.version, SESSION_VERSION);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        Ok(session)
+    }
+
+    // runs `recording` to the end, checking every step the journal has. it
+    // must have been started on the same program and options with `input`
+    // provided. Err is the first step that came out differently
+    pub fn replay(&self, recording: &mut Recording) -> Result<(), usize> {
+        loop {
+            let step = recording.at();
+            if !recording.step() {
+                break;
+            }
+            let expected = step.checked_sub(self.first_step).and_then(|index| self.journal.get(index));
+            if expected.is_some_and(|expected| Some(expected) != recording.entry(step)) {
+                return Err(step);
+            }
+        }
+        let steps = recording.steps();
+        let error = recording.error().map(|error| error.to_string());
+        if steps != self.steps || recording.output() != self.output || error != self.error {
+            return Err(steps.min(self.steps));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::EofBehavior;
+
+    fn recording(source: &str) -> Recording {
+        let ast = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+        Recording::new(Interpreter::new(), &ast).unwrap()
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut recording = recording("++>+++[<+>-]<.");
+        while recording.step() {}
+        assert!(recording.finished());
+        assert_eq!(&recording.memory()[..2], &[5, 0]);
+        assert_eq!(recording.output(), b"\x05");
+
+        // back before the . and then into the loop
+        assert!(recording.step_back());
+        assert_eq!(recording.output(), b"");
+        assert_eq!(recording.position(), Some(13));
+        // the ] that ended the loop isn't a step of its own, it went with the <
+        recording.step_back();
+        assert_eq!(recording.position(), Some(12));
+        assert_eq!((recording.memory()[0], recording.memory()[1], recording.pointer()), (5, 0, 1));
+        assert!(!recording.finished());
+
+        // forward again over the journal ends where the run did
+        while recording.step() {}
+        assert_eq!(&recording.memory()[..2], &[5, 0]);
+        assert_eq!(recording.steps(), recording.at());
+    }
+
+    #[test]
+    fn test_last_write() {
+        // cell 1 is set to 72 by the last + of the loop body in its last round
+        let mut recording = recording("+++++++++[>++++++++<-]>.");
+        while recording.step() {}
+        let step = recording.last_write(1).unwrap();
+        let entry = recording.entry(step).unwrap();
+        assert_eq!((entry.position, entry.old, entry.new), (18, 71, 72));
+        assert_eq!(recording.last_write(2), None);
+    }
+
+    #[test]
+    fn test_seek_and_drop() {
+        // long enough to need checkpoints and to drop the oldest steps
+        let mut recording = recording("++++++++++++++++[>++++++++[>++++++++[>++++++++<-]<-]<-]");
+        recording.set_max_steps(CHECKPOINT_INTERVAL * 2);
+        while recording.step() {}
+        let steps = recording.steps();
+        assert!(recording.first_step() > 0);
+
+        let target = recording.first_step() + CHECKPOINT_INTERVAL + 10;
+        recording.seek(target);
+        assert_eq!(recording.at(), target);
+        let (memory, pointer) = (recording.memory().to_vec(), recording.pointer());
+        // walking there from the end gives the same tape
+        recording.seek(steps);
+        while recording.at() > target {
+            recording.step_back();
+        }
+        assert_eq!((recording.memory(), recording.pointer()), (&memory[..], pointer));
+        // before the oldest kept step is as far back as it goes
+        recording.seek(0);
+        assert_eq!(recording.at(), recording.first_step());
+        assert!(!recording.step_back());
+    }
+
+    #[test]
+    fn test_session() {
+        let source = ",[.,]+>+<<";
+        let ast = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_eof_behavior(EofBehavior::Zero);
+        interpreter.provide_input(b"h");
+        let mut recorded = Recording::with_io(interpreter, &ast, Box::new(MemoryIo::new(b"i"))).unwrap();
+        while recorded.step() {}
+        let session = Session::new(source, vec!["--eof".to_string(), "zero".to_string()], b"h", &recorded);
+        assert_eq!((&session.input[..], &session.output[..]), (&b"hi"[..], &b"hi"[..]));
+        assert_eq!(session.error.as_deref(), Some("Pointer out of bounds at command 9 (instruction 11, pointer 0)"));
+
+        let mut file = Vec::new();
+        session.write_to(&mut file).unwrap();
+        let loaded = Session::read_from(&file[..]).unwrap();
+        assert_eq!(loaded, session);
+
+        // the same input plays back the same run, different input doesn't
+        let play = |input: &[u8]| {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_eof_behavior(EofBehavior::Zero);
+            interpreter.provide_input(input);
+            let mut recording = Recording::new(interpreter, &ast).unwrap();
+            loaded.replay(&mut recording)
+        };
+        assert_eq!(play(b"hi"), Ok(()));
+        assert_eq!(play(b"ho"), Err(3));
+    }
+}
@@ -0,0 +1,366 @@
+//! counters collected while a program runs, and the formats they are printed in
+
+use crate::lexer::Span;
+
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// what an engine recorded about its last run. maps are keyed by the
+// instruction's Debug name, e.g. "Add(3)", and sorted so output is stable
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExecutionStats {
+    // None when the engine doesn't count instructions, like the jit
+    pub total_instructions: Option<usize>,
+    pub elapsed_ms: Option<f64>,
+    // the most the tape took up at once, by cell width. only more than the
+    // starting size when the tape grows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peak_memory_bytes: Option<usize>,
+    // what the run cost by the engine's cost model, if it was given one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cycles: Option<usize>,
+    // the highest cell the pointer reached, how many cells were ever stored
+    // to and how many aren't zero at the end, for engines that track them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_pointer: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cells_written: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonzero_cells: Option<usize>,
+    pub instruction_counts: BTreeMap<String, usize>,
+    pub instruction_times_ms: BTreeMap<String, f64>,
+    // finished loop iterations per nesting depth, starting at 1
+    pub loop_iterations: BTreeMap<usize, usize>,
+    // the cells the program used, in tape order. empty unless the engine
+    // was asked to track them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub heatmap: Vec<CellAccess>,
+    // set when the engine sampled every this many instructions instead of
+    // timing each one, with what it saw busiest first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_interval: Option<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub samples: Vec<Sample>,
+    // every loop that ran, keyed by its [, the most iterations first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub loops: Vec<LoopStats>,
+}
+
+// what one loop of the program cost over the whole run. instructions and
+// time include the loops nested in it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LoopStats {
+    pub position: usize, // command index of the [
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<Span>, // where the [ is, once add_spans has been called
+    pub entries: usize,     // times the [ was reached
+    pub iterations: usize,
+    pub instructions: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_ms: Option<f64>, // None when the engine was sampling
+}
+
+// loops listed in the text report
+const HOT_LOOPS: usize = 10;
+
+// how often the sampling profiler found the program at one command
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sample {
+    pub position: usize, // command index in the source
+    pub instruction: String,
+    pub count: usize,
+}
+
+// samples listed in the text report, the rest are only in json and csv
+const HOT_SPOTS: usize = 20;
+
+// how often a run read and wrote one cell
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CellAccess {
+    pub cell: usize,
+    pub reads: usize,
+    pub writes: usize,
+}
+
+// width of the bars in the text heatmap
+const HEATMAP_BAR: usize = 20;
+
+// how --stats-format prints ExecutionStats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+impl StatsFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(StatsFormat::Text),
+            "json" => Some(StatsFormat::Json),
+            "csv" => Some(StatsFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+impl ExecutionStats {
+    // fills in where each loop is in the source. `spans` are those of the
+    // program's commands, indexed by position
+    pub fn add_spans(&mut self, spans: &[Span]) {
+        for stats in &mut self.loops {
+            stats.span = spans.get(stats.position).copied();
+        }
+    }
+
+    pub fn render(&self, format: StatsFormat) -> String {
+        match format {
+            StatsFormat::Text => self.to_text(),
+            StatsFormat::Json => self.to_json(),
+            StatsFormat::Csv => self.to_csv(),
+        }
+    }
+
+    // the report --stats has always printed
+    pub fn to_text(&self) -> String {
+        let mut text = String::from("\nExecution Statistics:\n");
+        match self.total_instructions {
+            Some(total) => writeln!(text, "Total instructions executed: {}", total).unwrap(),
+            None => text.push_str("(instruction counts are not tracked by this engine)\n"),
+        }
+        if let Some(elapsed) = self.elapsed_ms {
+            writeln!(text, "Total time: {:.3}ms", elapsed).unwrap();
+        }
+        if let Some(bytes) = self.peak_memory_bytes {
+            writeln!(text, "Peak tape memory: {} bytes", bytes).unwrap();
+        }
+        if let Some(cycles) = self.cycles {
+            writeln!(text, "Cycles: {}", cycles).unwrap();
+        }
+        if let (Some(max_pointer), Some(written), Some(nonzero)) = (self.max_pointer, self.cells_written, self.nonzero_cells) {
+            text.push_str("\nMemory usage:\n");
+            writeln!(text, "Highest cell reached: {}", max_pointer).unwrap();
+            writeln!(text, "Cells written: {}", written).unwrap();
+            writeln!(text, "Non-zero cells at the end: {}", nonzero).unwrap();
+        }
+        if !self.instruction_counts.is_empty() {
+            text.push_str("\nInstruction counts:\n");
+            for (instruction, count) in &self.instruction_counts {
+                writeln!(text, "{}: {} times", instruction, count).unwrap();
+            }
+        }
+        if !self.instruction_times_ms.is_empty() {
+            text.push_str("\nInstruction times:\n");
+            for (instruction, time) in &self.instruction_times_ms {
+                writeln!(text, "{}: {:.3}ms", instruction, time).unwrap();
+            }
+        }
+        if !self.loop_iterations.is_empty() {
+            text.push_str("\nLoop statistics:\n");
+            for (depth, iterations) in &self.loop_iterations {
+                writeln!(text, "Loop at depth {}: {} iterations", depth, iterations).unwrap();
+            }
+        }
+        if !self.loops.is_empty() {
+            text.push_str("\nHot loops:\n");
+            for stats in self.loops.iter().take(HOT_LOOPS) {
+                let runs = if stats.entries == 1 { "run" } else { "runs" };
+                write!(text, "[ at command {}", stats.position).unwrap();
+                if let Some(span) = stats.span {
+                    write!(text, " (line {}, column {})", span.line, span.column).unwrap();
+                }
+                write!(text, ": {} iterations in {} {}, {} instructions", stats.iterations, stats.entries, runs, stats.instructions).unwrap();
+                match stats.time_ms {
+                    Some(time) => writeln!(text, ", {:.3}ms", time).unwrap(),
+                    None => text.push('\n'),
+                }
+            }
+        }
+        if let Some(interval) = self.sample_interval {
+            let total: usize = self.samples.iter().map(|sample| sample.count).sum();
+            writeln!(text, "\nHot spots ({} samples, one every {} instructions):", total, interval).unwrap();
+            for sample in self.samples.iter().take(HOT_SPOTS) {
+                let share = sample.count as f64 * 100.0 / total as f64;
+                writeln!(text, "{:>5.1}%  command {}: {}", share, sample.position, sample.instruction).unwrap();
+            }
+        }
+        if !self.heatmap.is_empty() {
+            text.push_str(&self.heatmap_text());
+        }
+        text
+    }
+
+    // a row per used cell with a bar scaled to the busiest one, e.g.
+    //
+    //   cell     reads    writes
+    //      0         9        18  ####################
+    //      1         0         9  #######
+    pub fn heatmap_text(&self) -> String {
+        let busiest = self.heatmap.iter().map(|access| access.reads + access.writes).max().unwrap_or(0);
+        let (first, last) = match (self.heatmap.first(), self.heatmap.last()) {
+            (Some(first), Some(last)) => (first.cell, last.cell),
+            _ => return String::new(),
+        };
+        let mut text = format!("\nMemory heatmap (cells {} to {}):\n", first, last);
+        writeln!(text, "{:>6} {:>9} {:>9}", "cell", "reads", "writes").unwrap();
+        for access in &self.heatmap {
+            let total = access.reads + access.writes;
+            let bar = "#".repeat((total * HEATMAP_BAR).div_ceil(busiest.max(1)));
+            writeln!(text, "{:>6} {:>9} {:>9}  {}", access.cell, access.reads, access.writes, bar).unwrap();
+        }
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        // only maps with string or integer keys, which can't fail
+        serde_json::to_string_pretty(self).unwrap() + "\n"
+    }
+
+    // one row per number: `metric,name,value`, where name is the instruction,
+    // loop depth or cell a row is about, empty for totals
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("metric,name,value\n");
+        if let Some(total) = self.total_instructions {
+            writeln!(csv, "total_instructions,,{}", total).unwrap();
+        }
+        if let Some(elapsed) = self.elapsed_ms {
+            writeln!(csv, "elapsed_ms,,{}", elapsed).unwrap();
+        }
+        if let Some(bytes) = self.peak_memory_bytes {
+            writeln!(csv, "peak_memory_bytes,,{}", bytes).unwrap();
+        }
+        if let Some(cycles) = self.cycles {
+            writeln!(csv, "cycles,,{}", cycles).unwrap();
+        }
+        if let Some(max_pointer) = self.max_pointer {
+            writeln!(csv, "max_pointer,,{}", max_pointer).unwrap();
+        }
+        if let Some(written) = self.cells_written {
+            writeln!(csv, "cells_written,,{}", written).unwrap();
+        }
+        if let Some(nonzero) = self.nonzero_cells {
+            writeln!(csv, "nonzero_cells,,{}", nonzero).unwrap();
+        }
+        for (instruction, count) in &self.instruction_counts {
+            writeln!(csv, "instruction_count,{},{}", csv_field(instruction), count).unwrap();
+        }
+        for (instruction, time) in &self.instruction_times_ms {
+            writeln!(csv, "instruction_time_ms,{},{}", csv_field(instruction), time).unwrap();
+        }
+        for (depth, iterations) in &self.loop_iterations {
+            writeln!(csv, "loop_iterations,{},{}", depth, iterations).unwrap();
+        }
+        for stats in &self.loops {
+            writeln!(csv, "hot_loop_entries,{},{}", stats.position, stats.entries).unwrap();
+            writeln!(csv, "hot_loop_iterations,{},{}", stats.position, stats.iterations).unwrap();
+            writeln!(csv, "hot_loop_instructions,{},{}", stats.position, stats.instructions).unwrap();
+            if let Some(time) = stats.time_ms {
+                writeln!(csv, "hot_loop_time_ms,{},{}", stats.position, time).unwrap();
+            }
+        }
+        for sample in &self.samples {
+            let name = format!("{} {}", sample.position, sample.instruction);
+            writeln!(csv, "samples,{},{}", csv_field(&name), sample.count).unwrap();
+        }
+        for access in &self.heatmap {
+            writeln!(csv, "cell_reads,{},{}", access.cell, access.reads).unwrap();
+            writeln!(csv, "cell_writes,{},{}", access.cell, access.writes).unwrap();
+        }
+        csv
+    }
+}
+
+// quotes a field that would otherwise split the row, like "MulCopy { targets: [(1, 2)] }"
+pub(crate) fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> ExecutionStats {
+        ExecutionStats {
+            total_instructions: Some(4),
+            elapsed_ms: Some(0.5),
+            peak_memory_bytes: None,
+            cycles: None,
+            max_pointer: None,
+            cells_written: None,
+            nonzero_cells: None,
+            instruction_counts: BTreeMap::from([("Add(2)".to_string(), 1), ("MulCopy { targets: [(1, 1)] }".to_string(), 3)]),
+            instruction_times_ms: BTreeMap::new(),
+            loop_iterations: BTreeMap::from([(1, 2)]),
+            heatmap: Vec::new(),
+            sample_interval: None,
+            samples: Vec::new(),
+            loops: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(
+            sample().to_csv(),
+            "metric,name,value\ntotal_instructions,,4\nelapsed_ms,,0.5\ninstruction_count,Add(2),1\n\
+             instruction_count,\"MulCopy { targets: [(1, 1)] }\",3\nloop_iterations,1,2\n"
+        );
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let json = sample().to_json();
+        assert!(json.contains("\"total_instructions\": 4"));
+        assert_eq!(serde_json::from_str::<ExecutionStats>(&json).unwrap(), sample());
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let mut stats = sample();
+        stats.heatmap = vec![
+            CellAccess { cell: 0, reads: 3, writes: 1 },
+            CellAccess { cell: 2, reads: 0, writes: 1 },
+        ];
+        assert!(stats.to_text().ends_with(
+            "\nMemory heatmap (cells 0 to 2):\n  cell     reads    writes\n     0         3         1  ####################\n     2         0         1  #####\n"
+        ));
+        assert!(stats.to_csv().ends_with("cell_reads,0,3\ncell_writes,0,1\ncell_reads,2,0\ncell_writes,2,1\n"));
+        assert_eq!(serde_json::from_str::<ExecutionStats>(&stats.to_json()).unwrap(), stats);
+        // left out of the json when not tracked
+        assert!(!sample().to_json().contains("heatmap"));
+    }
+
+    #[test]
+    fn test_samples() {
+        let mut stats = sample();
+        stats.sample_interval = Some(100);
+        stats.samples = vec![
+            Sample { position: 4, instruction: "Add(2)".to_string(), count: 3 },
+            Sample { position: 0, instruction: "MulCopy { targets: [(1, 1)] }".to_string(), count: 1 },
+        ];
+        assert!(stats.to_text().ends_with(
+            "\nHot spots (4 samples, one every 100 instructions):\n 75.0%  command 4: Add(2)\n 25.0%  command 0: MulCopy { targets: [(1, 1)] }\n"
+        ));
+        assert!(stats.to_csv().ends_with("samples,4 Add(2),3\nsamples,\"0 MulCopy { targets: [(1, 1)] }\",1\n"));
+    }
+
+    #[test]
+    fn test_hot_loops() {
+        let mut stats = sample();
+        stats.loops = vec![
+            LoopStats { position: 3, span: None, entries: 2, iterations: 6, instructions: 24, time_ms: None },
+            LoopStats { position: 1, span: None, entries: 1, iterations: 2, instructions: 38, time_ms: Some(0.25) },
+        ];
+        stats.add_spans(&[Span { offset: 0, line: 1, column: 1 }, Span { offset: 2, line: 2, column: 1 }]);
+        assert!(stats.to_text().contains(
+            "\nHot loops:\n[ at command 3: 6 iterations in 2 runs, 24 instructions\n\
+             [ at command 1 (line 2, column 1): 2 iterations in 1 run, 38 instructions, 0.250ms\n"
+        ));
+        assert!(stats.to_csv().contains("hot_loop_iterations,3,6\n"));
+    }
+}
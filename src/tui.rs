@@ -0,0 +1,315 @@
+//! full-screen debugger for bfc debug --tui, built on ratatui
+//!
+//! the source is shown with the current command highlighted, next to the
+//! tape around the pointer and the output so far. the program runs through
+//! a Recording, so the debugger can step backwards as well as forwards
+
+use crate::interpreter::{Interpreter, RuntimeError};
+use crate::lexer::Span;
+use crate::parser::AstNode;
+use crate::recording::Recording;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span as TextSpan, Text};
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+use std::collections::BTreeSet;
+use std::io;
+use std::time::Duration;
+
+// instructions run between checks for a key press while continuing
+const CONTINUE_CHUNK: usize = 10_000;
+
+const HELP: &str = "s/r step fwd/back  c/R continue fwd/back  w last write  ←/→ cursor  b breakpoint  q quit";
+
+pub struct Debugger<'a> {
+    source: &'a str,
+    spans: Vec<Span>, // where each command starts, indexed by position
+    recording: Recording,
+    breakpoints: BTreeSet<usize>, // positions continue stops before
+    cursor: usize,                // position b toggles a breakpoint on
+    message: Option<String>,      // shown in the status line until the next key
+}
+
+impl<'a> Debugger<'a> {
+    // starts `ast` on `interpreter`. `spans` are the command spans of
+    // `source` as the program was lexed. , reads what was given with
+    // provide_input and then sees end of input
+    pub fn new(source: &'a str, spans: Vec<Span>, mut interpreter: Interpreter, ast: &AstNode) -> Result<Self, RuntimeError> {
+        // anything printed outside the panes would garble the screen
+        interpreter.set_debug(false);
+        interpreter.set_step_by_step(false);
+        interpreter.set_warn_on_eof(false);
+        Ok(Debugger {
+            source,
+            spans,
+            recording: Recording::new(interpreter, ast)?,
+            breakpoints: BTreeSet::new(),
+            cursor: 0,
+            message: None,
+        })
+    }
+
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+
+    pub fn step(&mut self) -> bool {
+        self.recording.step()
+    }
+
+    pub fn step_back(&mut self) -> bool {
+        self.recording.step_back()
+    }
+
+    // steps until the next command has a breakpoint, the program ends or
+    // `limit` instructions have run. returns true only in the last case
+    pub fn continue_for(&mut self, limit: usize) -> bool {
+        self.run_for(limit, Recording::step)
+    }
+
+    // continue_for backwards, stopping at the oldest recorded step
+    pub fn reverse_for(&mut self, limit: usize) -> bool {
+        self.run_for(limit, Recording::step_back)
+    }
+
+    fn run_for(&mut self, limit: usize, step: fn(&mut Recording) -> bool) -> bool {
+        for i in 0..limit {
+            // the breakpoint we are stopped at doesn't stop us again
+            if i > 0 && self.recording.position().is_some_and(|position| self.breakpoints.contains(&position)) {
+                return false;
+            }
+            if !step(&mut self.recording) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // goes back to the step that gave the cell at the pointer its value
+    pub fn find_last_write(&mut self) {
+        let cell = self.recording.pointer();
+        match self.recording.last_write(cell) {
+            Some(step) => {
+                self.recording.seek(step);
+                let value = self.recording.entry(step).map_or(0, |entry| entry.new);
+                self.message = Some(format!("Cell {} was set to {} by this command", cell, value));
+            }
+            None => self.message = Some(format!("Cell {} has not been changed in the recorded steps", cell)),
+        }
+    }
+
+    pub fn move_cursor(&mut self, by: isize) {
+        let last = self.spans.len().saturating_sub(1);
+        self.cursor = self.cursor.saturating_add_signed(by).min(last);
+    }
+
+    pub fn add_breakpoint(&mut self, position: usize) {
+        self.breakpoints.insert(position);
+    }
+
+    pub fn toggle_breakpoint(&mut self) {
+        if !self.breakpoints.remove(&self.cursor) && self.cursor < self.spans.len() {
+            self.breakpoints.insert(self.cursor);
+        }
+    }
+
+    pub fn draw(&self, frame: &mut Frame) {
+        let [source, bottom, status] =
+            Layout::vertical([Constraint::Min(5), Constraint::Length(12), Constraint::Length(2)]).areas(frame.area());
+        let [memory, output] = Layout::horizontal([Constraint::Length(28), Constraint::Min(10)]).areas(bottom);
+        self.draw_source(frame, source);
+        self.draw_memory(frame, memory);
+        let output_text = String::from_utf8_lossy(self.recording.output()).into_owned();
+        frame.render_widget(
+            Paragraph::new(output_text).wrap(Wrap { trim: false }).block(Block::bordered().title(" Output ")),
+            output,
+        );
+        frame.render_widget(Paragraph::new(vec![Line::raw(self.status()), Line::raw(HELP)]), status);
+    }
+
+    fn status(&self) -> String {
+        let recording = &self.recording;
+        let state = match recording.error() {
+            Some(error) => format!("Error: {}", error.kind),
+            None if recording.finished() => "Finished".to_string(),
+            None => "Paused".to_string(),
+        };
+        let mut status = format!("{} at step {} of {}", state, recording.at(), recording.steps());
+        if let Some(message) = &self.message {
+            status = format!("{} | {}", status, message);
+        }
+        status
+    }
+
+    fn draw_source(&self, frame: &mut Frame, area: Rect) {
+        let mut styles = vec![Style::new(); self.source.len()];
+        for &position in &self.breakpoints {
+            styles[self.spans[position].offset] = Style::new().fg(Color::Red).add_modifier(Modifier::BOLD);
+        }
+        if let Some(span) = self.spans.get(self.cursor) {
+            styles[span.offset] = styles[span.offset].add_modifier(Modifier::UNDERLINED);
+        }
+        let current = self.recording.position().and_then(|position| self.spans.get(position));
+        if let Some(span) = current {
+            let color = if self.recording.error().is_some() { Color::Red } else { Color::Yellow };
+            styles[span.offset] = styles[span.offset].bg(color).fg(Color::Black);
+        }
+
+        let mut offset = 0;
+        let lines: Vec<Line> = self
+            .source
+            .split('\n')
+            .map(|line| {
+                let spans = line
+                    .char_indices()
+                    .map(|(i, ch)| TextSpan::styled(ch.to_string(), styles[offset + i]))
+                    .collect::<Vec<_>>();
+                offset += line.len() + 1;
+                Line::from(spans)
+            })
+            .collect();
+
+        // keep the current command in the middle of the pane once it is far enough down
+        let visible = area.height.saturating_sub(2) as usize;
+        let line = current.or(self.spans.get(self.cursor)).map_or(0, |span| span.line - 1);
+        let scroll = line.saturating_sub(visible / 2) as u16;
+        frame.render_widget(
+            Paragraph::new(Text::from(lines)).scroll((scroll, 0)).block(Block::bordered().title(" Source ")),
+            area,
+        );
+    }
+
+    fn draw_memory(&self, frame: &mut Frame, area: Rect) {
+        let memory = self.recording.memory();
+        let pointer = self.recording.pointer();
+        let rows = area.height.saturating_sub(2) as usize;
+        let first = pointer.saturating_sub(rows / 2).min(memory.len().saturating_sub(rows));
+        let lines: Vec<Line> = (first..memory.len().min(first + rows))
+            .map(|i| {
+                let value = memory[i];
+                let shown = char::from_u32(value).filter(|ch| !ch.is_control()).unwrap_or(' ');
+                let text = format!("{}{:>6}: {:>5}  {}", if i == pointer { '>' } else { ' ' }, i, value, shown);
+                if i == pointer {
+                    Line::styled(text, Style::new().add_modifier(Modifier::REVERSED))
+                } else {
+                    Line::raw(text)
+                }
+            })
+            .collect();
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Memory ")), area);
+    }
+}
+
+// takes over the terminal until q is pressed. continuing checks for a key
+// press every CONTINUE_CHUNK instructions, and any key pauses it
+pub fn run(mut debugger: Debugger) -> io::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let result = event_loop(&mut terminal, &mut debugger);
+    ratatui::restore();
+    result
+}
+
+// which way c or R is running the program
+#[derive(Clone, Copy, PartialEq)]
+enum Running {
+    No,
+    Forwards,
+    Backwards,
+}
+
+fn event_loop(terminal: &mut DefaultTerminal, debugger: &mut Debugger) -> io::Result<()> {
+    let mut running = Running::No;
+    loop {
+        terminal.draw(|frame| debugger.draw(frame))?;
+        if running != Running::No && !event::poll(Duration::ZERO)? {
+            let more = match running {
+                Running::Backwards => debugger.reverse_for(CONTINUE_CHUNK),
+                _ => debugger.continue_for(CONTINUE_CHUNK),
+            };
+            if !more {
+                running = Running::No;
+            }
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        debugger.message = None;
+        if running != Running::No {
+            running = Running::No;
+            if !matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                continue;
+            }
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Char('s') | KeyCode::Char(' ') | KeyCode::Enter => {
+                debugger.step();
+            }
+            KeyCode::Char('r') | KeyCode::Backspace => {
+                debugger.step_back();
+            }
+            KeyCode::Char('c') => running = Running::Forwards,
+            KeyCode::Char('R') => running = Running::Backwards,
+            KeyCode::Char('w') => debugger.find_last_write(),
+            KeyCode::Char('b') => debugger.toggle_breakpoint(),
+            KeyCode::Left | KeyCode::Char('h') => debugger.move_cursor(-1),
+            KeyCode::Right | KeyCode::Char('l') => debugger.move_cursor(1),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    fn debugger(source: &str) -> Debugger<'_> {
+        let spans = Lexer::new(source).map(|token| token.span).collect();
+        let ast = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+        Debugger::new(source, spans, Interpreter::new(), &ast).unwrap()
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = debugger("+++[>+.<-]");
+        debugger.move_cursor(6);
+        debugger.toggle_breakpoint();
+        // stops before the first . and then each time round the loop
+        assert!(!debugger.continue_for(100));
+        assert_eq!(debugger.recording().position(), Some(6));
+        assert_eq!(debugger.recording().memory()[1], 1);
+        assert!(!debugger.continue_for(100));
+        assert_eq!(debugger.recording().output(), b"\x01");
+        // and going back stops there too
+        assert!(!debugger.reverse_for(100));
+        assert_eq!(debugger.recording().output(), b"");
+        debugger.toggle_breakpoint();
+        assert!(!debugger.continue_for(100));
+        assert!(debugger.recording().finished());
+        assert_eq!(debugger.recording().output(), b"\x01\x02\x03");
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut debugger = debugger("++\n<");
+        debugger.step();
+        debugger.step();
+        debugger.step();
+        let mut terminal = Terminal::new(TestBackend::new(80, 20)).unwrap();
+        terminal.draw(|frame| debugger.draw(frame)).unwrap();
+        let screen: String = terminal.backend().buffer().content().iter().map(|cell| cell.symbol()).collect();
+        assert!(screen.contains("Error: Pointer out of bounds at step 2 of 2"));
+        assert!(screen.contains(">     0:     2"));
+    }
+}
@@ -0,0 +1,264 @@
+//! checks the optimizer on one program: runs it as written in the tree
+//! interpreter and optimized on the vm, with the same input and fuel, and
+//! compares what they leave. when they differ, the program is shrunk to a
+//! small one that still does, which is what `bfc run --verify-opt` reports
+
+use crate::embed::{Config, Error};
+use crate::interpreter::{Interpreter, RuntimeError};
+use crate::io::MemoryIo;
+use crate::engine::Engine;
+use crate::parser::AstNode;
+use crate::vm::Vm;
+
+use std::fmt;
+
+// instructions either run may take when the Config has no limit of its own
+pub const FUEL: usize = 10_000_000;
+
+// programs the shrinking tries before settling for the smallest so far
+const MAX_SHRINK_RUNS: usize = 10_000;
+// each program it tries gets this many times the instructions the original
+// took as written, plus SHRINK_SLACK, as many of them loop forever
+const SHRINK_FUEL_FACTOR: usize = 4;
+const SHRINK_SLACK: usize = 10_000;
+// and it stops once all its runs together have taken this many
+const SHRINK_FUEL: usize = 100_000_000;
+
+// cells a report shows on each side of the ones it's about
+const WINDOW: usize = 4;
+
+// what a run that finished left behind
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub output: Vec<u8>,
+    pub memory: Vec<u32>, // without the zero cells after the last non-zero one
+    pub pointer: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Same,
+    // the program as written failed or ran out of fuel, so there's nothing
+    // to compare with: the optimizer may drop the moves that failed
+    Unverified(RuntimeError),
+    Diverged(Divergence),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub source: String, // the smallest program found that still diverges
+    pub input: Vec<u8>,
+    pub expected: Outcome, // as written
+    pub actual: Result<Outcome, RuntimeError>, // optimized
+}
+
+// runs `ast` both ways with `input` as everything , reads. the tape, cells,
+// end of input, output limit and partial evaluation come from `config`, its
+// `optimize` is ignored. a Config the interpreter can't run with is an error
+pub fn verify(ast: &AstNode, input: &[u8], config: &Config) -> Result<Verdict, Error> {
+    let fuel = config.max_instructions.unwrap_or(FUEL);
+    interpreter(input, config, fuel).map_err(Error::Config)?;
+    let AstNode::Program(body) = ast else {
+        return Ok(Verdict::Same);
+    };
+    let (expected, used) = match run_as_written(body, input, config, fuel) {
+        (Ok(outcome), used) => (outcome, used),
+        (Err(e), _) => return Ok(Verdict::Unverified(e)),
+    };
+    let (actual, _) = run_optimized(body, input, config, fuel);
+    if actual.as_ref() == Ok(&expected) {
+        return Ok(Verdict::Same);
+    }
+
+    let fuel = used.saturating_mul(SHRINK_FUEL_FACTOR).saturating_add(SHRINK_SLACK).min(fuel);
+    let mut smallest = (body.clone(), expected, actual);
+    let (mut runs, mut spent) = (0, 0);
+    'shrink: loop {
+        for candidate in shrinks(&smallest.0) {
+            runs += 1;
+            if runs > MAX_SHRINK_RUNS || spent > SHRINK_FUEL {
+                break 'shrink;
+            }
+            let (expected, used) = run_as_written(&candidate, input, config, fuel);
+            spent += used;
+            let Ok(expected) = expected else { continue };
+            let (actual, used) = run_optimized(&candidate, input, config, fuel);
+            spent += used;
+            if actual.as_ref() != Ok(&expected) {
+                smallest = (candidate, expected, actual);
+                continue 'shrink;
+            }
+        }
+        break;
+    }
+    let (body, expected, actual) = smallest;
+    Ok(Verdict::Diverged(Divergence { source: AstNode::Program(body).to_source(), input: input.to_vec(), expected, actual }))
+}
+
+fn interpreter(input: &[u8], config: &Config, fuel: usize) -> Result<Interpreter, String> {
+    Interpreter::builder()
+        .tape_size(config.tape_size)
+        .optimize(false)
+        .cell_width(config.cell_width)
+        .eof_behavior(config.eof_behavior)
+        .tape_policy(config.tape_policy)
+        .max_instructions(Some(fuel))
+        .max_output(config.max_output)
+        .input(input)
+        .build()
+}
+
+// what the run left, and the instructions it took
+fn run_as_written(body: &[AstNode], input: &[u8], config: &Config, fuel: usize) -> (Result<Outcome, RuntimeError>, usize) {
+    let mut interpreter = interpreter(input, config, fuel).expect("checked by verify");
+    let result = interpreter.run_and_capture_bytes(&AstNode::Program(body.to_vec()));
+    let outcome = result.map(|(output, memory, pointer)| Outcome::new(output, memory, pointer));
+    (outcome, interpreter.instruction_count())
+}
+
+fn run_optimized(body: &[AstNode], input: &[u8], config: &Config, fuel: usize) -> (Result<Outcome, RuntimeError>, usize) {
+    let mut vm = Vm::new();
+    vm.set_tape_size(config.tape_size).expect("checked by verify");
+    vm.set_cell_width(config.cell_width);
+    vm.set_eof_behavior(config.eof_behavior);
+    vm.set_tape_policy(config.tape_policy);
+    vm.set_max_instructions(Some(fuel));
+    vm.set_max_output(config.max_output);
+    vm.set_partial_evaluation(config.partial_evaluation);
+    vm.set_io(Some(Box::new(MemoryIo::new(input))));
+    if let Err(e) = vm.run(&AstNode::Program(body.to_vec())) {
+        return (Err(e), vm.instruction_count());
+    }
+    let output = vm.io::<MemoryIo>().expect("set above").output.clone();
+    (Ok(Outcome::new(output, vm.memory().to_vec(), vm.pointer())), vm.instruction_count())
+}
+
+// every program one step smaller than `body`: without one of its
+// instructions, or with a loop replaced by its body, at any depth
+fn shrinks(body: &[AstNode]) -> Vec<Vec<AstNode>> {
+    let mut smaller = Vec::new();
+    for (i, node) in body.iter().enumerate() {
+        let mut without = body.to_vec();
+        without.remove(i);
+        smaller.push(without);
+        if let AstNode::Loop(inner) = node {
+            let mut unwrapped = body[..i].to_vec();
+            unwrapped.extend(inner.iter().cloned());
+            unwrapped.extend(body[i + 1..].iter().cloned());
+            smaller.push(unwrapped);
+            for shrunk in shrinks(inner) {
+                let mut replaced = body.to_vec();
+                replaced[i] = AstNode::Loop(shrunk);
+                smaller.push(replaced);
+            }
+        }
+    }
+    smaller
+}
+
+impl Outcome {
+    fn new(output: Vec<u8>, mut memory: Vec<u32>, pointer: usize) -> Self {
+        let used = memory.iter().rposition(|&cell| cell != 0).map_or(0, |last| last + 1);
+        memory.truncate(used);
+        Outcome { output, memory, pointer }
+    }
+
+    fn cell(&self, index: usize) -> u32 {
+        self.memory.get(index).copied().unwrap_or(0)
+    }
+
+    // the output, the pointer and the cells around it and around `cell`,
+    // e.g. output "A\n", pointer 1, tape[0..6] [65, 0, 3, 0, 0, 0]
+    fn describe(&self, cell: Option<usize>) -> String {
+        let window = |at: usize| at.saturating_sub(WINDOW)..at + WINDOW + 1;
+        let mut ranges = vec![window(self.pointer)];
+        if let Some(cell) = cell.map(window) {
+            let first = &mut ranges[0];
+            if cell.start <= first.end && first.start <= cell.end {
+                *first = first.start.min(cell.start)..first.end.max(cell.end);
+            } else {
+                ranges.push(cell);
+                ranges.sort_by_key(|range| range.start);
+            }
+        }
+        let tape: Vec<String> = ranges
+            .into_iter()
+            .map(|range| format!("tape[{}..{}] {:?}", range.start, range.end, range.clone().map(|index| self.cell(index)).collect::<Vec<_>>()))
+            .collect();
+        format!("output \"{}\", pointer {}, {}", self.output.escape_ascii(), self.pointer, tape.join(", "))
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.describe(None))
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Optimizing changes what the program does, as in:")?;
+        writeln!(f, "  program:    {}", self.source)?;
+        writeln!(f, "  input:      \"{}\"", self.input.escape_ascii())?;
+        // the tapes are shown around the first cell they differ in too
+        let differs = self.actual.as_ref().ok().and_then(|actual| {
+            let len = self.expected.memory.len().max(actual.memory.len());
+            (0..len).find(|&index| self.expected.cell(index) != actual.cell(index))
+        });
+        writeln!(f, "  as written: {}", self.expected.describe(differs))?;
+        match &self.actual {
+            Ok(outcome) => write!(f, "  optimized:  {}", outcome.describe(differs)),
+            Err(e) => write!(f, "  optimized:  {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{EofBehavior, RuntimeErrorKind};
+
+    fn parse(source: &str) -> AstNode {
+        crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_verify() {
+        let config = Config { eof_behavior: EofBehavior::Zero, ..Config::default() };
+        let hello = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        assert_eq!(verify(&parse(hello), b"", &config), Ok(Verdict::Same));
+        assert_eq!(verify(&parse(",[>+<-]>.,."), b"ab", &config), Ok(Verdict::Same));
+        let limited = Config { max_instructions: Some(100), ..config.clone() };
+        let Ok(Verdict::Unverified(e)) = verify(&parse("+[]"), b"", &limited) else { panic!("expected the run to be cut off") };
+        assert_eq!(e.kind, RuntimeErrorKind::InstructionLimitExceeded(100));
+        assert!(matches!(verify(&parse("+"), b"", &Config { tape_size: 0, ..config }), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_shrinks() {
+        let AstNode::Program(body) = parse("+[->]") else { unreachable!() };
+        let smaller: Vec<String> = shrinks(&body).into_iter().map(|body| AstNode::Program(body).to_source()).collect();
+        assert_eq!(smaller, ["[->]", "+", "+->", "+[>]", "+[-]"]);
+    }
+
+    #[test]
+    fn test_describe() {
+        let outcome = Outcome::new(Vec::new(), vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7], 30000);
+        assert_eq!(outcome.describe(Some(10)), "output \"\", pointer 30000, tape[6..15] [0, 0, 0, 0, 7, 0, 0, 0, 0], tape[29996..30005] [0, 0, 0, 0, 0, 0, 0, 0, 0]");
+        assert_eq!(outcome.describe(Some(29992)).matches("tape").count(), 1);
+    }
+
+    #[test]
+    fn test_display() {
+        let divergence = Divergence {
+            source: "+.".to_string(),
+            input: b"a\n".to_vec(),
+            expected: Outcome::new(vec![1], vec![1, 0, 0], 0),
+            actual: Ok(Outcome::new(vec![2], vec![2], 0)),
+        };
+        assert_eq!(
+            divergence.to_string(),
+            "Optimizing changes what the program does, as in:\n  program:    +.\n  input:      \"a\\n\"\n  as written: output \"\\x01\", pointer 0, tape[0..5] [1, 0, 0, 0, 0]\n  optimized:  output \"\\x02\", pointer 0, tape[0..5] [2, 0, 0, 0, 0]"
+        );
+    }
+}
@@ -0,0 +1,774 @@
+//! a compact bytecode and the virtual machine that runs it, the default
+//! engine. the optimized program is flattened into a flat array of small
+//! `Copy` instructions with the targets of its jumps worked out ahead of
+//! time, so the machine is one `match` in a loop, without the tree walker's
+//! per-instruction bookkeeping
+
+use crate::engine::{Engine, Observer};
+use crate::io::BfIo;
+use crate::interpreter::{
+    CellWidth, EofBehavior, OutputBuffering, RuntimeError, RuntimeErrorKind, TapePolicy, DEFAULT_MAX_MEMORY, DEFAULT_TAPE_SIZE,
+    OUTPUT_BLOCK_SIZE, TAPE_PAGE_SIZE,
+};
+use crate::lexer::Token;
+use crate::ir::{self, Instr};
+use crate::optimizer::Optimizer;
+use crate::parser::AstNode;
+use crate::stats::ExecutionStats;
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Add(u32), // + - Add and Sub, wrapping at the cell width
+    Set(u32),
+    // Add and Set for the cell at an offset from the pointer
+    AddAt { offset: isize, amount: u32 },
+    SetAt { offset: isize, value: u32 },
+    Move(isize),
+    // [ jumps to the instruction after the matching ] when the cell is zero
+    JumpIfZero(usize),
+    // ] jumps back to the first instruction of the body when the cell isn't
+    JumpIfNonZero(usize),
+    // the targets are Bytecode::targets[start..start + len]
+    MulCopy { start: usize, len: usize },
+    Swap { offset: isize, temp: isize },
+    Scan(isize), // moves by the stride until a zero cell
+    Input,
+    Output,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bytecode {
+    pub ops: Vec<Op>,
+    pub positions: Vec<usize>, // source command index of each op
+    // the (offset, factor) pairs of every MulCopy, one after the other
+    pub targets: Vec<(isize, u32)>,
+}
+
+impl Bytecode {
+    // the bytecode for an optimized program body, see
+    // Optimizer::optimize_with_positions. pbrain procedures and # have no
+    // bytecode, the position of the first one is the error
+    pub fn compile(instructions: &[AstNode], positions: &[usize]) -> Result<Self, usize> {
+        let program = ir::Program::lower(instructions, positions)?;
+        let mut bytecode = Bytecode::default();
+        for (instr, &position) in program.instrs.into_iter().zip(&program.positions) {
+            let op = match instr {
+                Instr::Add { offset: 0, amount } => Op::Add(amount as u32),
+                Instr::Add { offset, amount } => Op::AddAt { offset, amount: amount as u32 },
+                Instr::Set { offset: 0, value } => Op::Set(value as u32),
+                Instr::Set { offset, value } => Op::SetAt { offset, value: value as u32 },
+                Instr::Move(n) => Op::Move(n),
+                Instr::MulCopy { targets } => {
+                    let start = bytecode.targets.len();
+                    bytecode.targets.extend(targets.iter().map(|&(offset, factor)| (offset, factor as u32)));
+                    Op::MulCopy { start, len: targets.len() }
+                }
+                Instr::Swap { offset, temp } => Op::Swap { offset, temp },
+                Instr::Scan(stride) => Op::Scan(stride),
+                Instr::In => Op::Input,
+                Instr::Out => Op::Output,
+                Instr::LoopStart { end } => Op::JumpIfZero(end),
+                Instr::LoopEnd { start } => Op::JumpIfNonZero(start),
+                Instr::Debug => return Err(position),
+            };
+            bytecode.ops.push(op);
+            bytecode.positions.push(position);
+        }
+        Ok(bytecode)
+    }
+
+    // the bytecode for the commands as they are, one op per command, with
+    // no syntax tree in between. the position of the first bracket without
+    // a match, pbrain command or # is the error
+    pub fn from_tokens(tokens: &[Token]) -> Result<Self, usize> {
+        let mut bytecode = Bytecode::default();
+        let mut open = Vec::new(); // index of each unclosed [
+        let commands = tokens.iter().filter(|token| !matches!(token, Token::Comment { .. }));
+        for (position, token) in commands.enumerate() {
+            let op = match token {
+                Token::Increment => Op::Add(1),
+                Token::Decrement => Op::Add(u32::MAX),
+                Token::IncrementPtr => Op::Move(1),
+                Token::DecrementPtr => Op::Move(-1),
+                Token::Input => Op::Input,
+                Token::Output => Op::Output,
+                Token::LoopStart => {
+                    open.push(position);
+                    Op::JumpIfZero(0)
+                }
+                Token::LoopEnd => {
+                    let start = open.pop().ok_or(position)?;
+                    bytecode.ops[start] = Op::JumpIfZero(position + 1);
+                    Op::JumpIfNonZero(start + 1)
+                }
+                _ => return Err(position),
+            };
+            bytecode.ops.push(op);
+            bytecode.positions.push(position);
+        }
+        match open.first() {
+            Some(&position) => Err(position),
+            None => Ok(bytecode),
+        }
+    }
+}
+
+pub struct Vm {
+    memory: Vec<u32>,
+    pointer: usize,
+    tape_size: usize,
+    cell_width: CellWidth,
+    tape_policy: TapePolicy,
+    max_memory: usize, // bytes a growing tape may take up
+    peak_tape_size: usize,
+    lazy_tape: bool,
+    origin: usize, // index of the cell the run started on, see Interpreter::origin
+    eof_behavior: EofBehavior,
+    warn_on_eof: bool,
+    max_instructions: Option<usize>,
+    max_output: Option<usize>, // bytes
+    run_output: usize,         // bytes written by the current run
+    partial_evaluation: bool,
+    input: VecDeque<u8>,
+    output: Option<Box<dyn Write + Send>>,
+    io: Option<Box<dyn BfIo>>,
+    buffering: OutputBuffering,
+    pending_output: Vec<u8>,
+    bytecode: Bytecode,
+    pc: usize,
+    instruction_count: usize,
+    elapsed: Duration,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Vm {
+            memory: vec![0; DEFAULT_TAPE_SIZE],
+            pointer: 0,
+            tape_size: DEFAULT_TAPE_SIZE,
+            cell_width: CellWidth::U8,
+            tape_policy: TapePolicy::Abort,
+            max_memory: DEFAULT_MAX_MEMORY,
+            peak_tape_size: DEFAULT_TAPE_SIZE,
+            lazy_tape: false,
+            origin: 0,
+            eof_behavior: EofBehavior::Unchanged,
+            warn_on_eof: false,
+            max_instructions: None,
+            max_output: None,
+            run_output: 0,
+            partial_evaluation: false,
+            input: VecDeque::new(),
+            output: None,
+            io: None,
+            buffering: OutputBuffering::Unbuffered,
+            pending_output: Vec::new(),
+            bytecode: Bytecode::default(),
+            pc: 0,
+            instruction_count: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub fn set_tape_size(&mut self, tape_size: usize) -> Result<(), String> {
+        if tape_size == 0 {
+            return Err("Tape size must be at least 1 cell".to_string());
+        }
+        self.tape_size = tape_size;
+        self.memory = vec![0; if self.lazy_tape { tape_size.min(TAPE_PAGE_SIZE) } else { tape_size }];
+        self.peak_tape_size = self.memory.len();
+        Ok(())
+    }
+
+    // allocates the tape a page at a time as the pointer gets there, like
+    // Interpreter::set_lazy_tape. clears the tape
+    pub fn set_lazy_tape(&mut self, lazy: bool) {
+        self.lazy_tape = lazy;
+        self.set_tape_size(self.tape_size).unwrap();
+    }
+
+    pub fn set_cell_width(&mut self, cell_width: CellWidth) {
+        self.cell_width = cell_width;
+    }
+
+    pub fn set_tape_policy(&mut self, tape_policy: TapePolicy) {
+        self.tape_policy = tape_policy;
+    }
+
+    // upper bound in bytes for a tape that grows
+    pub fn set_max_memory(&mut self, bytes: usize) {
+        self.max_memory = bytes;
+    }
+
+    pub fn set_eof_behavior(&mut self, eof_behavior: EofBehavior) {
+        self.eof_behavior = eof_behavior;
+    }
+
+    // print a one-time warning when , finds stdin exhausted
+    pub fn set_warn_on_eof(&mut self, warn: bool) {
+        self.warn_on_eof = warn;
+    }
+
+    // ends the run with an error once it has executed `limit` instructions
+    pub fn set_max_instructions(&mut self, limit: Option<usize>) {
+        self.max_instructions = limit;
+    }
+
+    // ends the run with an error when . would write more than `limit` bytes.
+    // what was written up to there is still flushed
+    pub fn set_max_output(&mut self, limit: Option<usize>) {
+        self.max_output = limit;
+    }
+
+    // runs the input-free start of each program while compiling it, see
+    // Optimizer::set_partial_evaluation
+    pub fn set_partial_evaluation(&mut self, enabled: bool) {
+        self.partial_evaluation = enabled;
+    }
+
+    // queues bytes for , to read before it falls back to stdin
+    pub fn provide_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    // sends output from . to `sink` instead of stdout
+    pub fn set_output_sink(&mut self, sink: Option<Box<dyn Write + Send>>) {
+        self.output = sink;
+    }
+
+    // the index in memory() of the cell the run started on, like
+    // Interpreter::origin
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    // routes , and . through `io` instead of stdin and stdout
+    pub fn set_io(&mut self, io: Option<Box<dyn BfIo>>) {
+        self.io = io;
+    }
+
+    // the installed I/O if it is a `T`, e.g. to collect a MemoryIo's output
+    pub fn io<T: BfIo>(&self) -> Option<&T> {
+        let io: &dyn std::any::Any = self.io.as_deref()?;
+        io.downcast_ref()
+    }
+
+    pub fn take_io(&mut self) -> Option<Box<dyn BfIo>> {
+        self.io.take()
+    }
+
+    pub fn set_output_buffering(&mut self, buffering: OutputBuffering) {
+        self.buffering = buffering;
+    }
+
+    // runs the commands unoptimized, straight from the lexer's tokens,
+    // which starts quicker than building and optimizing a syntax tree
+    pub fn run_tokens(&mut self, tokens: &[Token]) -> Result<(), RuntimeError> {
+        self.load(Bytecode::from_tokens(tokens))?;
+        self.run_loaded()
+    }
+
+    // every run starts on a clear tape
+    fn load(&mut self, bytecode: Result<Bytecode, usize>) -> Result<(), RuntimeError> {
+        self.memory.iter_mut().for_each(|cell| *cell = 0);
+        self.pointer = 0;
+        self.origin = 0;
+        self.pc = 0;
+        self.instruction_count = 0;
+        self.run_output = 0;
+        match bytecode {
+            Ok(bytecode) => self.bytecode = bytecode,
+            Err(position) => {
+                self.bytecode = Bytecode::default();
+                return Err(RuntimeError { position, ..self.error(RuntimeErrorKind::InvalidInstruction) });
+            }
+        }
+        Ok(())
+    }
+
+    fn run_loaded(&mut self) -> Result<(), RuntimeError> {
+        let start = Instant::now();
+        let result = self.execute(usize::MAX);
+        // output written before an error still goes out
+        let flushed = self.flush_output();
+        self.elapsed = start.elapsed();
+        result.and(flushed)
+    }
+
+    fn error(&self, kind: RuntimeErrorKind) -> RuntimeError {
+        RuntimeError {
+            kind,
+            position: self.bytecode.positions.get(self.pc).copied().unwrap_or(0),
+            instruction_count: self.instruction_count,
+            pointer: self.pointer,
+        }
+    }
+
+    // runs at most `budget` instructions from pc, returns false once the
+    // program has finished
+    fn execute(&mut self, budget: usize) -> Result<bool, RuntimeError> {
+        let mask = self.cell_width.mask();
+        let limit = self.max_instructions.unwrap_or(usize::MAX);
+        let end = self.instruction_count.saturating_add(budget);
+        while self.pc < self.bytecode.ops.len() {
+            if self.instruction_count >= end {
+                return Ok(true);
+            }
+            if self.instruction_count >= limit {
+                return Err(self.error(RuntimeErrorKind::InstructionLimitExceeded(limit)));
+            }
+            self.instruction_count += 1;
+            match self.bytecode.ops[self.pc] {
+                Op::Add(n) => {
+                    let cell = &mut self.memory[self.pointer];
+                    *cell = cell.wrapping_add(n) & mask;
+                }
+                Op::Set(value) => self.memory[self.pointer] = value & mask,
+                Op::AddAt { offset, amount } => {
+                    let index = self.cell_at(offset)?;
+                    let cell = &mut self.memory[index];
+                    *cell = cell.wrapping_add(amount) & mask;
+                }
+                Op::SetAt { offset, value } => {
+                    let index = self.cell_at(offset)?;
+                    self.memory[index] = value & mask;
+                }
+                Op::Move(n) => self.pointer = self.cell_at(n)?,
+                Op::JumpIfZero(target) => {
+                    if self.memory[self.pointer] == 0 {
+                        self.pc = target;
+                        continue;
+                    }
+                }
+                Op::JumpIfNonZero(target) => {
+                    if self.memory[self.pointer] != 0 {
+                        self.pc = target;
+                        continue;
+                    }
+                }
+                Op::MulCopy { start, len } => {
+                    let value = self.memory[self.pointer];
+                    if value != 0 {
+                        for i in start..start + len {
+                            let (offset, factor) = self.bytecode.targets[i];
+                            let index = self.cell_at(offset)?;
+                            let cell = &mut self.memory[index];
+                            *cell = cell.wrapping_add(value.wrapping_mul(factor)) & mask;
+                        }
+                        self.memory[self.pointer] = 0;
+                    }
+                }
+                Op::Swap { offset, temp } => {
+                    let other = self.cell_at(offset)?;
+                    // growing the tape at the start for temp moves the others along,
+                    // `other` already is where it is after its own lookup
+                    let pointer = self.pointer;
+                    let temp = self.cell_at(temp)?;
+                    let other = other + (self.pointer - pointer);
+                    let value = self.memory[self.pointer];
+                    self.memory[self.pointer] = self.memory[other];
+                    self.memory[other] = self.memory[temp].wrapping_add(value) & mask;
+                    self.memory[temp] = 0;
+                }
+                Op::Scan(stride) => {
+                    while self.memory[self.pointer] != 0 {
+                        self.pointer = self.cell_at(stride)?;
+                    }
+                }
+                Op::Input => self.input_byte()?,
+                Op::Output => self.output_byte()?,
+            }
+            self.pc += 1;
+        }
+        Ok(false)
+    }
+
+    // index of the cell `offset` away from the pointer, following the tape policy
+    fn cell_at(&mut self, offset: isize) -> Result<usize, RuntimeError> {
+        let target = self.pointer as isize + offset;
+        if target >= 0 && (target as usize) < self.memory.len() {
+            return Ok(target as usize);
+        }
+        let max_cells = self.max_memory / self.cell_width.bytes();
+        let index = match self.tape_policy {
+            _ if target >= 0 && (target as usize) < self.tape_size => target as usize,
+            TapePolicy::Wrap => target.rem_euclid(self.tape_size as isize) as usize,
+            TapePolicy::GrowBoth if target < 0 => {
+                // adds as many cells before the first as are allocated, or
+                // more if that isn't enough, up to the memory limit
+                let room = max_cells.saturating_sub(self.tape_size);
+                let needed = target.unsigned_abs();
+                if needed > room {
+                    return Err(self.error(RuntimeErrorKind::MemoryLimitExceeded(self.max_memory)));
+                }
+                let added = self.memory.len().max(needed).min(room);
+                self.memory.splice(0..0, std::iter::repeat_n(0, added));
+                self.tape_size += added;
+                self.peak_tape_size = self.peak_tape_size.max(self.memory.len());
+                self.pointer += added;
+                self.origin += added;
+                return Ok((target + added as isize) as usize);
+            }
+            TapePolicy::Grow | TapePolicy::GrowBoth if target > 0 => {
+                if target as usize >= max_cells {
+                    return Err(self.error(RuntimeErrorKind::MemoryLimitExceeded(self.max_memory)));
+                }
+                // doubles the tape, clamped to the memory limit
+                while target as usize >= self.tape_size {
+                    self.tape_size = (self.tape_size * 2).min(max_cells);
+                }
+                target as usize
+            }
+            _ => return Err(self.error(RuntimeErrorKind::PointerOutOfBounds)),
+        };
+        if index >= self.memory.len() {
+            let size = if self.lazy_tape { (index + 1).next_multiple_of(TAPE_PAGE_SIZE).min(self.tape_size) } else { self.tape_size };
+            self.memory.resize(size, 0);
+            self.peak_tape_size = self.peak_tape_size.max(size);
+        }
+        Ok(index)
+    }
+
+    fn input_byte(&mut self) -> Result<(), RuntimeError> {
+        let byte = match self.input.pop_front() {
+            Some(byte) => Some(byte),
+            None => {
+                // make sure any prompt is visible before blocking on input
+                self.flush_output()?;
+                match self.io.as_mut() {
+                    Some(io) => {
+                        let read = io.read_byte();
+                        read.map_err(|e| self.error(RuntimeErrorKind::Input(e.to_string())))?
+                    }
+                    None => {
+                        let mut byte = [0];
+                        io::stdin().read_exact(&mut byte).ok().map(|_| byte[0])
+                    }
+                }
+            }
+        };
+        match byte {
+            Some(byte) => self.memory[self.pointer] = byte as u32,
+            None => {
+                if self.warn_on_eof {
+                    self.warn_on_eof = false;
+                    eprintln!("Warning: , reached the end of a non-interactive input ({})", self.eof_behavior);
+                }
+                match self.eof_behavior {
+                    EofBehavior::Unchanged => {}
+                    EofBehavior::Zero => self.memory[self.pointer] = 0,
+                    EofBehavior::MaxValue => self.memory[self.pointer] = self.cell_width.mask(),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn output_byte(&mut self) -> Result<(), RuntimeError> {
+        if let Some(limit) = self.max_output {
+            if self.run_output >= limit {
+                return Err(self.error(RuntimeErrorKind::OutputLimitExceeded(limit)));
+            }
+            self.run_output += 1;
+        }
+        let byte = (self.memory[self.pointer] & 0xFF) as u8;
+        if let Some(io) = self.io.as_mut() {
+            let written = io.write_byte(byte);
+            return written.map_err(|e| self.error(RuntimeErrorKind::Output(e.to_string())));
+        }
+        self.pending_output.push(byte);
+        let flush = match self.buffering {
+            OutputBuffering::Unbuffered => true,
+            OutputBuffering::Line => byte == b'\n',
+            OutputBuffering::Block => self.pending_output.len() >= OUTPUT_BLOCK_SIZE,
+            OutputBuffering::Every(n) => self.pending_output.len() >= n,
+        };
+        if flush {
+            self.flush_output()?;
+        }
+        Ok(())
+    }
+
+    // writes any pending output to the sink, or stdout if there is none
+    pub fn flush_output(&mut self) -> Result<(), RuntimeError> {
+        if let Some(io) = self.io.as_mut() {
+            let flushed = io.flush();
+            flushed.map_err(|e| self.error(RuntimeErrorKind::Output(e.to_string())))?;
+        }
+        if self.pending_output.is_empty() {
+            return Ok(());
+        }
+        let bytes = &self.pending_output;
+        let written = match self.output.as_mut() {
+            Some(sink) => sink.write_all(bytes).and_then(|_| sink.flush()),
+            None => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(bytes).and_then(|_| stdout.flush())
+            }
+        };
+        written.map_err(|e| self.error(RuntimeErrorKind::Output(e.to_string())))?;
+        self.pending_output.clear();
+        Ok(())
+    }
+}
+
+impl Engine for Vm {
+    fn name(&self) -> &'static str {
+        "vm"
+    }
+
+    fn run(&mut self, ast: &AstNode) -> Result<(), RuntimeError> {
+        self.start(ast)?;
+        self.run_loaded()
+    }
+
+    fn start(&mut self, ast: &AstNode) -> Result<(), RuntimeError> {
+        let mut optimizer = Optimizer::new();
+        optimizer.set_partial_evaluation(self.partial_evaluation);
+        optimizer.set_tape(self.tape_size, self.tape_policy);
+        let (AstNode::Program(instructions), positions) = optimizer.optimize_with_positions(ast) else {
+            return Err(self.error(RuntimeErrorKind::ExpectedProgram));
+        };
+        self.load(Bytecode::compile(&instructions, &positions))
+    }
+
+    fn step(&mut self) -> Result<bool, RuntimeError> {
+        if self.pc >= self.bytecode.ops.len() {
+            self.flush_output()?;
+            return Ok(false);
+        }
+        self.execute(1)?;
+        Ok(true)
+    }
+
+    fn memory(&self) -> &[u32] {
+        &self.memory
+    }
+
+    fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    // the bytecode has no hook to call observers from
+    fn set_observer(&mut self, _observer: Option<Box<dyn Observer>>) {}
+
+    fn statistics(&self) -> ExecutionStats {
+        ExecutionStats {
+            total_instructions: Some(self.instruction_count),
+            elapsed_ms: Some(self.elapsed.as_secs_f64() * 1000.0),
+            peak_memory_bytes: Some(self.peak_tape_size * self.cell_width.bytes()),
+            nonzero_cells: Some(self.memory.iter().filter(|&&cell| cell != 0).count()),
+            ..ExecutionStats::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::io::MemoryIo;
+
+    fn parse(source: &str) -> AstNode {
+        crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap()
+    }
+
+    // reading past `input` is end of input, never stdin
+    fn run(vm: &mut Vm, source: &str, input: &[u8]) -> (Result<(), RuntimeError>, Vec<u8>) {
+        vm.set_eof_behavior(EofBehavior::Zero);
+        vm.set_io(Some(Box::new(MemoryIo::new(input))));
+        let result = vm.run(&parse(source));
+        (result, vm.io::<MemoryIo>().unwrap().output.clone())
+    }
+
+    #[test]
+    fn test_compile() {
+        let (AstNode::Program(instructions), positions) = Optimizer::new().optimize_with_positions(&parse("+++[->++<]>[-]<,[.>]")) else {
+            unreachable!()
+        };
+        let bytecode = Bytecode::compile(&instructions, &positions).unwrap();
+        assert_eq!(
+            bytecode.ops,
+            [
+                Op::Add(3),
+                Op::MulCopy { start: 0, len: 1 },
+                Op::Move(1),
+                Op::Set(0),
+                Op::Move(-1),
+                Op::Input,
+                Op::JumpIfZero(10),
+                Op::Output,
+                Op::Move(1),
+                Op::JumpIfNonZero(7),
+            ]
+        );
+        assert_eq!(bytecode.targets, [(1, 2)]);
+        assert_eq!(bytecode.positions, [0, 3, 10, 11, 14, 15, 16, 17, 18, 19]);
+        assert!(Bytecode::compile(&[AstNode::Increment, AstNode::ProcCall], &[0, 1]).is_err());
+
+        // the first cell has been visited, so <+> is a change at an offset
+        let (AstNode::Program(instructions), positions) = Optimizer::new().optimize_with_positions(&parse(",>,<+>-<.")) else {
+            unreachable!()
+        };
+        let bytecode = Bytecode::compile(&instructions, &positions).unwrap();
+        assert_eq!(
+            bytecode.ops,
+            [Op::Input, Op::Move(1), Op::Input, Op::AddAt { offset: -1, amount: 1 }, Op::Add(u32::MAX), Op::Move(-1), Op::Output]
+        );
+        assert_eq!(bytecode.positions, [0, 1, 2, 4, 6, 7, 8]);
+        let mut vm = Vm::new();
+        let (result, output) = run(&mut vm, ",>,<+>-<.", b"ab");
+        result.unwrap();
+        assert_eq!((output.as_slice(), &vm.memory()[..2]), (&b"b"[..], &[98, 97][..]));
+    }
+
+    #[test]
+    fn test_from_tokens() {
+        let tokens = crate::lexer::tokenize("+[->+<]").unwrap();
+        let bytecode = Bytecode::from_tokens(&tokens).unwrap();
+        assert_eq!(
+            bytecode.ops,
+            [Op::Add(1), Op::JumpIfZero(7), Op::Add(u32::MAX), Op::Move(1), Op::Add(1), Op::Move(-1), Op::JumpIfNonZero(2)]
+        );
+        assert_eq!(Bytecode::from_tokens(&crate::lexer::tokenize("+[[]").unwrap()), Err(1));
+        assert_eq!(Bytecode::from_tokens(&[Token::LoopEnd]), Err(0));
+
+        let mut vm = Vm::new();
+        vm.set_output_sink(Some(Box::new(std::io::sink())));
+        vm.run_tokens(&crate::lexer::tokenize("++[->+++<]>.").unwrap()).unwrap();
+        assert_eq!((vm.pointer(), vm.memory()[1], vm.instruction_count()), (1, 6, 19));
+    }
+
+    #[test]
+    fn test_matches_interpreter() {
+        let source = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.,[.,]";
+        let mut vm = Vm::new();
+        let (result, output) = run(&mut vm, source, b"abc");
+        result.unwrap();
+        assert_eq!(output, b"Hello World!\nabc");
+
+        let mut interpreter = Interpreter::new();
+        interpreter.set_eof_behavior(EofBehavior::Zero);
+        interpreter.set_io(Some(Box::new(MemoryIo::new(b"abc"))));
+        let (_, memory, pointer) = interpreter.run_and_capture_output(&parse(source)).unwrap();
+        assert_eq!((vm.memory(), vm.pointer()), (memory.as_slice(), pointer));
+
+        // the copies through the third cell are a swap
+        let mut vm = Vm::new();
+        run(&mut vm, "+++>+++++>[-]<<[->>+<<]>[-<+>]>[-<+>]", b"").0.unwrap();
+        assert_eq!((vm.pointer(), &vm.memory()[..3]), (2, &[5, 3, 0][..]));
+
+        // and one with a cell off the start of a tape that grows there
+        let source = "+++>+++++<[->>+<<]<[->+<]>>>[-<<<+>>>]<<<.>.>.>.";
+        let mut vm = Vm::new();
+        vm.set_tape_policy(TapePolicy::GrowBoth);
+        let (result, output) = run(&mut vm, source, b"");
+        result.unwrap();
+        assert_eq!(output, [3, 0, 5, 0]);
+        let mut interpreter = Interpreter::builder().tape_policy(TapePolicy::GrowBoth).build().unwrap();
+        let (expected, _, _) = interpreter.run_and_capture_bytes(&parse(source)).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_tape() {
+        // 16 bit cells wrap at 65536, and the tape grows past its end
+        let mut vm = Vm::new();
+        vm.set_cell_width(CellWidth::U16);
+        vm.set_tape_size(2).unwrap();
+        vm.set_tape_policy(TapePolicy::Grow);
+        let (result, _) = run(&mut vm, "->>>+", b"");
+        result.unwrap();
+        assert_eq!(&vm.memory()[..4], &[0xFFFF, 0, 0, 1]);
+
+        let mut vm = Vm::new();
+        vm.set_tape_size(3).unwrap();
+        vm.set_tape_policy(TapePolicy::Wrap);
+        let (result, _) = run(&mut vm, "<+", b"");
+        result.unwrap();
+        assert_eq!(vm.pointer(), 2);
+
+        let mut vm = Vm::new();
+        vm.set_tape_size(2).unwrap();
+        vm.set_tape_policy(TapePolicy::GrowBoth);
+        let (result, _) = run(&mut vm, "+<<<++>>>>", b"");
+        result.unwrap();
+        assert_eq!(vm.memory(), &[2, 0, 0, 1, 0]);
+        assert_eq!((vm.pointer(), vm.origin()), (4, 3));
+
+        // a lazy tape only allocates the pages the pointer reaches
+        let mut vm = Vm::new();
+        vm.set_lazy_tape(true);
+        vm.set_tape_size(100_000_000).unwrap();
+        let (result, _) = run(&mut vm, &format!("{}+", ">".repeat(TAPE_PAGE_SIZE + 1)), b"");
+        result.unwrap();
+        assert_eq!(vm.memory().len(), 2 * TAPE_PAGE_SIZE);
+        assert_eq!(vm.statistics().peak_memory_bytes, Some(2 * TAPE_PAGE_SIZE));
+
+        // >< is folded into Move(1), Move(-2), which fails at the first < like
+        // the optimizing interpreter does
+        let (result, _) = run(&mut Vm::new(), "+[-]><<", b"");
+        let err = result.unwrap_err();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_optimize(true);
+        let expected = interpreter.run(&parse("+[-]><<")).unwrap_err();
+        assert_eq!((err.kind, err.position, err.pointer), (expected.kind, expected.position, expected.pointer));
+        assert_eq!(err.position, 5);
+
+        // partial evaluation runs the start on the same tape: wrapping, and
+        // failing before any of the output when the start goes off the end
+        for partial_evaluation in [false, true] {
+            let mut vm = Vm::new();
+            vm.set_tape_size(2).unwrap();
+            vm.set_tape_policy(TapePolicy::Wrap);
+            vm.set_partial_evaluation(partial_evaluation);
+            let (result, output) = run(&mut vm, "+++[>>+<<-]>>.", b"");
+            result.unwrap();
+            assert_eq!(output, [0]);
+
+            let mut vm = Vm::new();
+            vm.set_tape_size(2).unwrap();
+            vm.set_partial_evaluation(partial_evaluation);
+            let (result, output) = run(&mut vm, "++[>>>+<<<-]>+.>.>.>.", b"");
+            assert_eq!(result.unwrap_err().kind, RuntimeErrorKind::PointerOutOfBounds);
+            assert!(output.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_limits_and_steps() {
+        let mut vm = Vm::new();
+        vm.set_max_instructions(Some(100));
+        let (result, _) = run(&mut vm, "+[]", b"");
+        assert_eq!(result.unwrap_err().kind, RuntimeErrorKind::InstructionLimitExceeded(100));
+        vm.set_max_instructions(None);
+        vm.set_max_output(Some(3));
+        let (result, output) = run(&mut vm, "+[.]", b"");
+        assert_eq!(result.unwrap_err().kind, RuntimeErrorKind::OutputLimitExceeded(3));
+        assert_eq!(output, [1, 1, 1]);
+
+        // +[-] is Add(1) and Set(0)
+        let mut vm = Vm::new();
+        vm.start(&parse("+[-]")).unwrap();
+        assert!(vm.step().unwrap());
+        assert_eq!(vm.memory()[0], 1);
+        assert!(vm.step().unwrap());
+        assert!(!vm.step().unwrap());
+        assert!(!vm.step().unwrap());
+        assert_eq!((vm.memory()[0], vm.instruction_count()), (0, 2));
+    }
+}
@@ -0,0 +1,140 @@
+//! runs a corpus of programs in the interpreter and as the code the
+//! generators emit for them, compiled with rustc and run with node, and
+//! checks that every one of them prints the same and leaves the same tape.
+//! a generator whose toolchain isn't installed is skipped
+
+use brainfuck_compiler::codegen::js::JsGenerator;
+use brainfuck_compiler::codegen::CodeGenerator;
+use brainfuck_compiler::interpreter::{Interpreter, DEFAULT_TAPE_SIZE};
+use brainfuck_compiler::io::MemoryIo;
+use brainfuck_compiler::optimizer::Optimizer;
+use brainfuck_compiler::parser::{self, AstNode};
+use brainfuck_compiler::lexer;
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// name, source and input. the generated Rust prints each byte as a char,
+// so the programs only print ASCII
+const CORPUS: &[(&str, &str, &[u8])] = &[
+    (
+        "hello",
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+        b"",
+    ),
+    ("cat", ",[.[-],]", b"Hello, world\n"),
+    ("add", "++>+++++[<+>-]++++++++[<++++++>-]<.", b""),
+    ("multiply", "+++[>+++++<-]>[>++<-]>+++++.", b""),
+    ("nested", "++[>++[>+++<-]<-]>>+++++++++++++++++++++++++++++++++++++++++++++++.", b""),
+    ("wrap", "--[-->+<]>.<-->>-", b""),
+    ("swap", "+++>+++++>[-]<<[->>+<<]>[-<+>]>[-<+>]<<++++++++++++++++++++++++++++++++++++++++++++++++.>.", b""),
+    ("scan", ">+>+>+>>+<<<<[>]+<<[<]>>>>>>>++++++++++++++++++++++++++++++++++++++++++++++++.", b""),
+    ("reverse", ">,----------[++++++++++>,----------]<[.<]", b"stressed\n"),
+    ("eof", ",,,,.", b"ab"),
+];
+
+// what a run printed and the tape it left, with the pointer when known
+struct Run {
+    output: Vec<u8>,
+    tape: Vec<u8>,
+    pointer: Option<usize>,
+}
+
+fn parse(source: &str) -> AstNode {
+    parser::parse(lexer::tokenize(source).unwrap()).unwrap()
+}
+
+fn interpret(ast: &AstNode, input: &[u8]) -> Run {
+    let mut interpreter = Interpreter::builder().io(Box::new(MemoryIo::new(input))).build().unwrap();
+    interpreter.run(ast).unwrap();
+    let output = interpreter.io::<MemoryIo>().unwrap().output.clone();
+    let tape = interpreter.memory().iter().map(|&cell| cell as u8).collect();
+    Run { output, tape, pointer: Some(interpreter.pointer()) }
+}
+
+// None when rustc isn't there
+fn run_rust(ast: &AstNode, input: &[u8], name: &str, precompute: bool) -> Option<Run> {
+    let mut generator = CodeGenerator::new();
+    generator.set_dump_tape(true);
+    generator.set_precompute(precompute);
+    let dir = std::env::temp_dir().join(format!("bfc-differential-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join(format!("{}.rs", name));
+    let binary: PathBuf = dir.join(name);
+    std::fs::write(&source, generator.generate(ast)).unwrap();
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let status = Command::new(rustc).arg("-o").arg(&binary).arg(&source).status().ok()?;
+    assert!(status.success(), "generated Rust for {} doesn't compile", name);
+
+    let mut child = Command::new(&binary).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let result = child.wait_with_output().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert!(result.status.success(), "generated Rust for {} failed", name);
+    let newline = result.stderr.iter().position(|&byte| byte == b'\n').unwrap();
+    let pointer = std::str::from_utf8(&result.stderr[..newline]).unwrap().parse().unwrap();
+    Some(Run { output: result.stdout, tape: result.stderr[newline + 1..].to_vec(), pointer: Some(pointer) })
+}
+
+// None when node isn't there
+fn run_js(ast: &AstNode, input: &[u8]) -> Option<Run> {
+    let code = JsGenerator::new().generate(ast).unwrap();
+    let script = format!(
+        "{}const output = []; const tape = run({:?}, byte => output.push(byte)); \
+         process.stdout.write(JSON.stringify([output, Array.from(tape)]));",
+        code, input,
+    );
+    let result = Command::new("node").arg("-e").arg(script).output().ok()?;
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    let (output, tape): (Vec<u8>, Vec<u8>) = serde_json::from_slice(&result.stdout).unwrap();
+    Some(Run { output, tape, pointer: None })
+}
+
+fn assert_same(name: &str, engine: &str, expected: &Run, actual: &Run) {
+    assert_eq!(actual.output, expected.output, "{} prints something else with {}", name, engine);
+    assert_eq!(actual.tape.len(), DEFAULT_TAPE_SIZE, "{} has the wrong tape size with {}", name, engine);
+    assert!(actual.tape == expected.tape, "{} leaves a different tape with {}", name, engine);
+    if let Some(pointer) = actual.pointer {
+        assert_eq!(Some(pointer), expected.pointer, "{} leaves the pointer elsewhere with {}", name, engine);
+    }
+}
+
+#[test]
+fn test_generated_code_matches_interpreter() {
+    let (mut rust, mut js) = (true, true);
+    for &(name, source, input) in CORPUS {
+        let ast = parse(source);
+        let expected = interpret(&ast, input);
+        assert!(expected.output.is_ascii(), "{} should only print ASCII", name);
+        assert_same(name, "the optimizer", &expected, &interpret(&Optimizer::new().optimize(&ast), input));
+
+        // both the program as parsed and as optimized, the way compile builds it
+        for (stage, ast) in [("ast", ast.clone()), ("optimized", Optimizer::new().optimize(&ast))] {
+            if rust {
+                match run_rust(&ast, input, &format!("{}_{}", name, stage), false) {
+                    Some(run) => assert_same(name, &format!("generated Rust ({})", stage), &expected, &run),
+                    None => rust = false,
+                }
+            }
+            if js {
+                match run_js(&ast, input) {
+                    Some(run) => assert_same(name, &format!("generated JavaScript ({})", stage), &expected, &run),
+                    None => js = false,
+                }
+            }
+        }
+        // and run ahead of time, for the programs that read no input
+        if rust {
+            if let Some(run) = run_rust(&ast, input, &format!("{}_precomputed", name), true) {
+                assert_same(name, "precomputed Rust", &expected, &run);
+            }
+        }
+    }
+    if !rust {
+        eprintln!("rustc not found, skipped the generated Rust");
+    }
+    if !js {
+        eprintln!("node not found, skipped the generated JavaScript");
+    }
+}